@@ -0,0 +1,212 @@
+//! A typed client for the PipeHub `/send/{key}` API.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), pipehub_client::Error> {
+//! use pipehub_client::{PipeHubClient, SendOptions};
+//!
+//! let client = PipeHubClient::new("https://www.pipehub.net", "your app key");
+//! client.send_text("Hello from PipeHub.").await?;
+//! client
+//!     .send("Deployed.", &SendOptions::new().to_party("2").dup_check_interval(60))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+pub use pipehub_types::{InvalidTargets, Response, SendResponse};
+use reqwest::Client;
+use std::fmt;
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request didn't reach PipeHub or the reply wasn't readable.
+    Http(reqwest::Error),
+    /// PipeHub rejected the message.
+    Api { status: u16, response: Response },
+    /// PipeHub replied with an unexpected status and no `Response` body.
+    Status(u16),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "{}", e),
+            Error::Api { status, response } => write!(
+                f,
+                "{} {} (request id: {})",
+                status, response.error_message, response.request_id
+            ),
+            Error::Status(status) => write!(f, "Unexpected status {}", status),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    Text,
+    Markdown,
+}
+
+impl MessageType {
+    fn as_str(self) -> &'static str {
+        match self {
+            MessageType::Text => "text",
+            MessageType::Markdown => "markdown",
+        }
+    }
+}
+
+/// Optional parameters of a send, built like `SendOptions::new().to_party("2")`.
+#[derive(Debug, Clone, Default)]
+pub struct SendOptions {
+    message_type: Option<MessageType>,
+    to_user: Option<String>,
+    to_party: Option<String>,
+    to_tag: Option<String>,
+    chat_id: Option<String>,
+    dup_check_interval: Option<u64>,
+    is_async: bool,
+}
+
+impl SendOptions {
+    pub fn new() -> Self {
+        SendOptions::default()
+    }
+
+    /// Overrides the default message type of the tenant.
+    pub fn message_type(mut self, message_type: MessageType) -> Self {
+        self.message_type = Some(message_type);
+        self
+    }
+
+    /// Sends to the members instead of everyone, multiple userids are joined by `|`.
+    pub fn to_user(mut self, to_user: impl Into<String>) -> Self {
+        self.to_user = Some(to_user.into());
+        self
+    }
+
+    /// Sends to the departments instead of everyone, multiple ids are joined by `|`.
+    pub fn to_party(mut self, to_party: impl Into<String>) -> Self {
+        self.to_party = Some(to_party.into());
+        self
+    }
+
+    /// Sends to the members of the tags instead of everyone, multiple ids are joined by `|`.
+    pub fn to_tag(mut self, to_tag: impl Into<String>) -> Self {
+        self.to_tag = Some(to_tag.into());
+        self
+    }
+
+    /// Sends to the group chat created by the app instead of members.
+    pub fn chat_id(mut self, chat_id: impl Into<String>) -> Self {
+        self.chat_id = Some(chat_id.into());
+        self
+    }
+
+    /// Enables WeChat's duplicate check in the given window, in seconds.
+    pub fn dup_check_interval(mut self, seconds: u64) -> Self {
+        self.dup_check_interval = Some(seconds);
+        self
+    }
+
+    /// Lets PipeHub reply right away and deliver in background.
+    pub fn asynchronous(mut self, is_async: bool) -> Self {
+        self.is_async = is_async;
+        self
+    }
+
+    fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query = Vec::new();
+        if let Some(message_type) = self.message_type {
+            query.push(("type", message_type.as_str().to_owned()));
+        }
+        if let Some(ref to_user) = self.to_user {
+            query.push(("to_user", to_user.clone()));
+        }
+        if let Some(ref to_party) = self.to_party {
+            query.push(("to_party", to_party.clone()));
+        }
+        if let Some(ref to_tag) = self.to_tag {
+            query.push(("to_tag", to_tag.clone()));
+        }
+        if let Some(ref chat_id) = self.chat_id {
+            query.push(("chat_id", chat_id.clone()));
+        }
+        if let Some(interval) = self.dup_check_interval {
+            query.push(("dup_check_interval", interval.to_string()));
+        }
+        if self.is_async {
+            query.push(("async", "true".to_owned()));
+        }
+        query
+    }
+}
+
+pub struct PipeHubClient {
+    http_client: Client,
+    send_url: String,
+}
+
+impl PipeHubClient {
+    /// `base_url` is where PipeHub is hosted, e.g. `https://www.pipehub.net`.
+    pub fn new(base_url: impl AsRef<str>, key: impl AsRef<str>) -> Self {
+        PipeHubClient::with_client(Client::new(), base_url, key)
+    }
+
+    pub fn with_client(
+        http_client: Client,
+        base_url: impl AsRef<str>,
+        key: impl AsRef<str>,
+    ) -> Self {
+        PipeHubClient {
+            http_client,
+            send_url: format!(
+                "{}/send/{}",
+                base_url.as_ref().trim_end_matches('/'),
+                key.as_ref()
+            ),
+        }
+    }
+
+    pub async fn send_text(&self, text: &str) -> Result<SendResponse> {
+        self.send(text, &SendOptions::default()).await
+    }
+
+    pub async fn send_markdown(&self, markdown: &str) -> Result<SendResponse> {
+        self.send(
+            markdown,
+            &SendOptions::default().message_type(MessageType::Markdown),
+        )
+        .await
+    }
+
+    pub async fn send(&self, text: &str, options: &SendOptions) -> Result<SendResponse> {
+        let response = self
+            .http_client
+            .post(&self.send_url)
+            .query(&options.query())
+            .body(text.to_owned())
+            .send()
+            .await?;
+
+        let status = response.status();
+        match response.json::<SendResponse>().await {
+            Ok(response) if status.is_success() && response.response.success => Ok(response),
+            Ok(response) => Err(Error::Api {
+                status: status.as_u16(),
+                response: response.response,
+            }),
+            Err(_) => Err(Error::Status(status.as_u16())),
+        }
+    }
+}
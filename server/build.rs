@@ -0,0 +1,40 @@
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    // Docker builds don't have the repository, so the commit can be passed as `GIT_COMMIT`.
+    let commit = env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(|| {
+            Command::new("git")
+                .args(&["rev-parse", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+                .map(|commit| commit.trim().to_owned())
+        })
+        .unwrap_or_else(|| "unknown".to_owned());
+    let built_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock is before UNIX epoch.")
+        .as_secs();
+
+    // The version of `2026-10-14-000023_pending_sends` is `20261014000023` in diesel.
+    let latest_migration = fs::read_dir("migrations")
+        .expect("Unable to list migrations.")
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|name| Some(name.split('_').next()?.replace('-', "")))
+        .max()
+        .expect("No migrations found.");
+
+    println!("cargo:rustc-env=PIPEHUB_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=PIPEHUB_BUILT_AT={}", built_at);
+    println!(
+        "cargo:rustc-env=PIPEHUB_LATEST_MIGRATION={}",
+        latest_migration
+    );
+}
@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    ADD DEFAULT_MESSAGE_TYPE VARCHAR;
@@ -0,0 +1,6 @@
+DROP TABLE SCHEDULED_MESSAGES;
+
+ALTER TABLE TENANTS
+    DROP COLUMN QUIET_HOURS_START,
+    DROP COLUMN QUIET_HOURS_END,
+    DROP COLUMN TIME_ZONE;
@@ -0,0 +1,16 @@
+ALTER TABLE TENANTS
+    ADD QUIET_HOURS_START VARCHAR,
+    ADD QUIET_HOURS_END   VARCHAR,
+    ADD TIME_ZONE         VARCHAR;
+
+CREATE TABLE SCHEDULED_MESSAGES
+(
+    ID         BIGSERIAL PRIMARY KEY,
+    TENANT_ID  BIGINT      NOT NULL,
+    REQUEST_ID UUID        NOT NULL,
+    PAYLOAD    TEXT        NOT NULL,
+    DELIVER_AT TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX SCHEDULED_MESSAGES_DELIVER_AT_INDEX
+    ON SCHEDULED_MESSAGES (DELIVER_AT);
@@ -0,0 +1,4 @@
+DROP INDEX WECHAT_WORKS_TENANT_ID_INDEX;
+
+CREATE UNIQUE INDEX WECHAT_WORKS_TENANT_ID_UINDEX
+    ON WECHAT_WORKS (TENANT_ID);
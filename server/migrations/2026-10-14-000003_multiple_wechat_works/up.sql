@@ -0,0 +1,4 @@
+DROP INDEX WECHAT_WORKS_TENANT_ID_UINDEX;
+
+CREATE INDEX WECHAT_WORKS_TENANT_ID_INDEX
+    ON WECHAT_WORKS (TENANT_ID);
@@ -0,0 +1,3 @@
+ALTER TABLE TENANTS
+    DROP COLUMN PREFIX,
+    DROP COLUMN SUFFIX;
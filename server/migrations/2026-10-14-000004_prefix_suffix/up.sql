@@ -0,0 +1,3 @@
+ALTER TABLE TENANTS
+    ADD PREFIX VARCHAR NOT NULL DEFAULT '',
+    ADD SUFFIX VARCHAR NOT NULL DEFAULT '';
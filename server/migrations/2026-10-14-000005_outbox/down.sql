@@ -0,0 +1,4 @@
+DROP TABLE OUTBOX;
+
+ALTER TABLE TENANTS
+    DROP COLUMN USE_OUTBOX;
@@ -0,0 +1,20 @@
+ALTER TABLE TENANTS
+    ADD USE_OUTBOX BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE OUTBOX
+(
+    ID            BIGSERIAL PRIMARY KEY,
+    TENANT_ID     BIGINT      NOT NULL,
+    REQUEST_ID    UUID        NOT NULL,
+    PAYLOAD       TEXT        NOT NULL,
+    STATUS        VARCHAR     NOT NULL DEFAULT 'queued',
+    ERROR_MESSAGE TEXT        NOT NULL DEFAULT '',
+    CREATED_AT    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UPDATED_AT    TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX OUTBOX_REQUEST_ID_UINDEX
+    ON OUTBOX (REQUEST_ID);
+
+CREATE INDEX OUTBOX_STATUS_INDEX
+    ON OUTBOX (STATUS);
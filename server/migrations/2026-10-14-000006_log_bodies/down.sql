@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    DROP COLUMN LOG_BODIES_UNTIL;
@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    ADD LOG_BODIES_UNTIL TIMESTAMPTZ NULL;
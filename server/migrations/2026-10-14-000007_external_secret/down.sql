@@ -0,0 +1,2 @@
+ALTER TABLE WECHAT_WORKS
+    DROP COLUMN EXTERNAL_SECRET;
@@ -0,0 +1,2 @@
+ALTER TABLE WECHAT_WORKS
+    ADD EXTERNAL_SECRET VARCHAR NULL;
@@ -0,0 +1 @@
+DROP TABLE USED_SEND_TOKENS;
@@ -0,0 +1,8 @@
+CREATE TABLE USED_SEND_TOKENS
+(
+    NONCE      UUID PRIMARY KEY,
+    EXPIRES_AT TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX USED_SEND_TOKENS_EXPIRES_AT_INDEX
+    ON USED_SEND_TOKENS (EXPIRES_AT);
@@ -0,0 +1,3 @@
+ALTER TABLE TENANTS
+    DROP COLUMN QUIET_DAYS,
+    DROP COLUMN DROP_IN_QUIET_HOURS;
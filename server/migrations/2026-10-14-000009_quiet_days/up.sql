@@ -0,0 +1,3 @@
+ALTER TABLE TENANTS
+    ADD QUIET_DAYS VARCHAR NULL,
+    ADD DROP_IN_QUIET_HOURS BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+DROP INDEX TENANTS_GITHUB_ID_UINDEX;
@@ -0,0 +1,2 @@
+CREATE UNIQUE INDEX TENANTS_GITHUB_ID_UINDEX
+    ON TENANTS (GITHUB_ID);
@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    ADD RATE_LIMITS VARCHAR NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    DROP COLUMN MAX_WECHAT_WORKS;
@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    ADD MAX_WECHAT_WORKS INTEGER NULL;
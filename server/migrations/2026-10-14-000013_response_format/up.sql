@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    ADD RESPONSE_FORMAT VARCHAR NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    DROP COLUMN DEFAULT_AGENT_ID;
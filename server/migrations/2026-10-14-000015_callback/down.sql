@@ -0,0 +1,3 @@
+ALTER TABLE TENANTS
+    DROP COLUMN CALLBACK_TOKEN,
+    DROP COLUMN CALLBACK_AES_KEY;
@@ -0,0 +1,3 @@
+ALTER TABLE TENANTS
+    ADD CALLBACK_TOKEN VARCHAR NULL,
+    ADD CALLBACK_AES_KEY VARCHAR NULL;
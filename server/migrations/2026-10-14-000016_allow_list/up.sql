@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    ADD ALLOW_LIST TEXT NULL;
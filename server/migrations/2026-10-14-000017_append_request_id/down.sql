@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    DROP COLUMN APPEND_REQUEST_ID;
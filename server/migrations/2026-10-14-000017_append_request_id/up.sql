@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    ADD APPEND_REQUEST_ID BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,11 @@
+CREATE TABLE TEMPLATES
+(
+    ID           BIGSERIAL PRIMARY KEY,
+    TENANT_ID    BIGINT  NOT NULL,
+    NAME         VARCHAR NOT NULL,
+    CONTENT      TEXT    NOT NULL,
+    MESSAGE_TYPE VARCHAR NULL
+);
+
+CREATE UNIQUE INDEX TEMPLATES_TENANT_ID_NAME_UINDEX
+    ON TEMPLATES (TENANT_ID, NAME);
@@ -0,0 +1,4 @@
+ALTER TABLE TENANTS
+    DROP COLUMN MATRIX_HOMESERVER,
+    DROP COLUMN MATRIX_ACCESS_TOKEN,
+    DROP COLUMN MATRIX_ROOM_ID;
@@ -0,0 +1,4 @@
+ALTER TABLE TENANTS
+    ADD MATRIX_HOMESERVER VARCHAR NULL,
+    ADD MATRIX_ACCESS_TOKEN VARCHAR NULL,
+    ADD MATRIX_ROOM_ID VARCHAR NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    DROP COLUMN CALLBACK_FORWARD_URL;
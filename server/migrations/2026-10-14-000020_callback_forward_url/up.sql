@@ -0,0 +1,2 @@
+ALTER TABLE TENANTS
+    ADD CALLBACK_FORWARD_URL VARCHAR NULL;
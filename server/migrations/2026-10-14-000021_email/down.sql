@@ -0,0 +1,8 @@
+ALTER TABLE TENANTS
+    DROP COLUMN SMTP_HOST,
+    DROP COLUMN SMTP_PORT,
+    DROP COLUMN SMTP_TLS,
+    DROP COLUMN SMTP_USERNAME,
+    DROP COLUMN SMTP_PASSWORD,
+    DROP COLUMN EMAIL_FROM,
+    DROP COLUMN EMAIL_TO;
@@ -0,0 +1,8 @@
+ALTER TABLE TENANTS
+    ADD SMTP_HOST VARCHAR NULL,
+    ADD SMTP_PORT INTEGER NULL,
+    ADD SMTP_TLS VARCHAR NULL,
+    ADD SMTP_USERNAME VARCHAR NULL,
+    ADD SMTP_PASSWORD VARCHAR NULL,
+    ADD EMAIL_FROM VARCHAR NULL,
+    ADD EMAIL_TO VARCHAR NULL;
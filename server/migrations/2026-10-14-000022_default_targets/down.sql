@@ -0,0 +1,4 @@
+ALTER TABLE TENANTS
+    DROP COLUMN DEFAULT_TO_USER,
+    DROP COLUMN DEFAULT_TO_PARTY,
+    DROP COLUMN DEFAULT_TO_TAG;
@@ -0,0 +1,4 @@
+ALTER TABLE TENANTS
+    ADD DEFAULT_TO_USER VARCHAR NULL,
+    ADD DEFAULT_TO_PARTY VARCHAR NULL,
+    ADD DEFAULT_TO_TAG VARCHAR NULL;
@@ -0,0 +1 @@
+DROP TABLE PENDING_SENDS;
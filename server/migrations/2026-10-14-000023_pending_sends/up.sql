@@ -0,0 +1,19 @@
+CREATE TABLE PENDING_SENDS
+(
+    ID              BIGSERIAL PRIMARY KEY,
+    TENANT_ID       BIGINT      NOT NULL,
+    REQUEST_ID      UUID        NOT NULL,
+    PAYLOAD         TEXT        NOT NULL,
+    STATUS          VARCHAR     NOT NULL DEFAULT 'pending',
+    ATTEMPTS        INTEGER     NOT NULL DEFAULT 0,
+    ERROR_MESSAGE   TEXT        NOT NULL DEFAULT '',
+    NEXT_ATTEMPT_AT TIMESTAMPTZ NOT NULL,
+    CREATED_AT      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UPDATED_AT      TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX PENDING_SENDS_REQUEST_ID_UINDEX
+    ON PENDING_SENDS (REQUEST_ID);
+
+CREATE INDEX PENDING_SENDS_STATUS_NEXT_ATTEMPT_AT_INDEX
+    ON PENDING_SENDS (STATUS, NEXT_ATTEMPT_AT);
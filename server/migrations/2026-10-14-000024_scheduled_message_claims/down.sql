@@ -0,0 +1,3 @@
+ALTER TABLE SCHEDULED_MESSAGES
+    DROP COLUMN STATUS,
+    DROP COLUMN UPDATED_AT;
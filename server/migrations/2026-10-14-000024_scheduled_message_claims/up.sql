@@ -0,0 +1,3 @@
+ALTER TABLE SCHEDULED_MESSAGES
+    ADD STATUS VARCHAR NOT NULL DEFAULT 'scheduled',
+    ADD UPDATED_AT TIMESTAMPTZ NOT NULL DEFAULT NOW();
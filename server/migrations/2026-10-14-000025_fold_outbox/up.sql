@@ -0,0 +1,19 @@
+INSERT INTO PENDING_SENDS (TENANT_ID, REQUEST_ID, PAYLOAD, STATUS, ERROR_MESSAGE, NEXT_ATTEMPT_AT, CREATED_AT,
+                           UPDATED_AT)
+SELECT TENANT_ID,
+       REQUEST_ID,
+       PAYLOAD,
+       CASE STATUS
+           WHEN 'queued' THEN 'pending'
+           WHEN 'sending' THEN 'pending'
+           WHEN 'delivered' THEN 'delivered'
+           ELSE 'dead'
+           END,
+       ERROR_MESSAGE,
+       NOW(),
+       CREATED_AT,
+       UPDATED_AT
+FROM OUTBOX
+ON CONFLICT (REQUEST_ID) DO NOTHING;
+
+DROP TABLE OUTBOX;
@@ -0,0 +1,491 @@
+use crate::config::PipeHubConfig;
+use crate::data::Pool;
+use crate::error::{Error, Result};
+use crate::logger::ApplicationLogger;
+use crate::metrics::Metrics;
+use crate::models::{Tenant, WechatWork};
+use crate::send::{self, OutboundLimiter};
+use crate::stats::Summary;
+use crate::wechat;
+use crate::AccessTokenCache;
+use actix_http::http::header;
+use actix_web::body::Body;
+use actix_web::web::Bytes;
+use actix_web::{get, post, put, web, Error as AWError, HttpRequest, HttpResponse};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+use uuid::Uuid;
+
+// Admin endpoints require `Authorization: Bearer {pipehub_admin_token}`, they are disabled
+// if the token isn't configured.
+pub fn authorize(req: &HttpRequest, config: &PipeHubConfig) -> Result<()> {
+    let admin_token = match config.admin_token {
+        Some(ref admin_token) if !admin_token.is_empty() => admin_token,
+        _ => return Err(Error::NotFound("Admin endpoints are disabled.")),
+    };
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes()))
+        .unwrap_or(false);
+
+    if authorized {
+        Ok(())
+    } else {
+        Err(Error::Forbidden("Invalid admin token."))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(Debug, Serialize)]
+struct Connectivity {
+    reachable: bool,
+    latency_ms: u128,
+    status: Option<u16>,
+    error_message: String,
+}
+
+// Tells whether WeChat can be reached at all, the token endpoint is called without credentials.
+#[get("/admin/wechat/connectivity")]
+pub async fn wechat_connectivity(
+    req: HttpRequest,
+    config: web::Data<PipeHubConfig>,
+    http_client: web::Data<Client>,
+    logger: web::Data<Arc<ApplicationLogger>>,
+) -> std::result::Result<HttpResponse, AWError> {
+    authorize(&req, &config)?;
+    let request_id: Uuid = req
+        .extensions()
+        .get::<Uuid>()
+        .cloned()
+        .expect("No request id found.");
+
+    let url = format!(
+        "{}/cgi-bin/gettoken",
+        config.wechat_api_url.trim_end_matches('/')
+    );
+    let start = Instant::now();
+    let result = http_client.get(&url).send().await;
+    let latency = start.elapsed();
+
+    let connectivity = match result {
+        Ok(response) => Connectivity {
+            reachable: true,
+            latency_ms: latency.as_millis(),
+            status: Some(response.status().as_u16()),
+            error_message: "".to_owned(),
+        },
+        Err(e) => Connectivity {
+            reachable: false,
+            latency_ms: latency.as_millis(),
+            status: None,
+            error_message: e.to_string(),
+        },
+    };
+    logger.track_dependency(
+        request_id,
+        "GET https://qyapi.weixin.qq.com/cgi-bin/gettoken",
+        "HTTPS",
+        latency,
+        "qyapi.weixin.qq.com",
+        &connectivity
+            .status
+            .map(|status| status.to_string())
+            .unwrap_or_default(),
+        &url,
+        connectivity.reachable,
+    );
+
+    Ok(HttpResponse::Ok().json(connectivity))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HealthQuery {
+    // The app key of the tenant.
+    key: String,
+    // Puts the fetched token into the cache for sending.
+    #[serde(default)]
+    refresh: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct CredentialsHealth {
+    healthy: bool,
+    latency_ms: u128,
+    error_code: Option<i64>,
+    error_message: String,
+}
+
+// Tells whether the WeChat credentials of a tenant still work by fetching a token, nothing is sent.
+#[get("/admin/wechat/health")]
+pub async fn wechat_health(
+    req: HttpRequest,
+    web::Query(query): web::Query<HealthQuery>,
+    config: web::Data<PipeHubConfig>,
+    pool: Pool,
+    http_client: web::Data<Client>,
+    limiter: web::Data<Arc<OutboundLimiter>>,
+    logger: web::Data<Arc<ApplicationLogger>>,
+    access_token_cache: web::Data<Arc<AccessTokenCache>>,
+) -> std::result::Result<HttpResponse, AWError> {
+    authorize(&req, &config)?;
+    let request_id: Uuid = req
+        .extensions()
+        .get::<Uuid>()
+        .cloned()
+        .expect("No request id found.");
+
+    let app_id = send::decode_app_id(&query.key)?;
+    let wechat = pool
+        .find_wechat_by_app_id(app_id)
+        .await?
+        .ok_or_else(|| Error::NotFound("No WeChat credentials configured."))?;
+
+    let start = Instant::now();
+    let result = send::check_credentials(
+        &http_client,
+        &limiter,
+        request_id,
+        &logger,
+        &access_token_cache,
+        &wechat,
+        query.refresh,
+    )
+    .await;
+    let latency_ms = start.elapsed().as_millis();
+
+    let health = match result {
+        Ok((0, _)) => CredentialsHealth {
+            healthy: true,
+            latency_ms,
+            error_code: Some(0),
+            error_message: "".to_owned(),
+        },
+        Ok((error_code, error_message)) => CredentialsHealth {
+            healthy: false,
+            latency_ms,
+            error_code: Some(error_code),
+            error_message,
+        },
+        Err(e) => CredentialsHealth {
+            healthy: false,
+            latency_ms,
+            error_code: None,
+            error_message: e.to_string(),
+        },
+    };
+
+    Ok(HttpResponse::Ok().json(health))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WechatWorksCap {
+    // Falls back to `pipehub_max_wechat_works` if null.
+    max_wechat_works: Option<i32>,
+}
+
+// Raises or lowers how many WeChat credentials a tenant can create, the existing ones are kept.
+#[put("/admin/tenants/{github_login}/max_wechat_works")]
+pub async fn update_max_wechat_works(
+    req: HttpRequest,
+    github_login: web::Path<String>,
+    web::Json(cap): web::Json<WechatWorksCap>,
+    config: web::Data<PipeHubConfig>,
+    pool: Pool,
+) -> std::result::Result<HttpResponse, AWError> {
+    authorize(&req, &config)?;
+    if cap.max_wechat_works.map_or(false, |max| max < 0) {
+        return Err(Error::User("max_wechat_works must not be negative.").into());
+    }
+
+    let tenant = pool
+        .find_tenant_by_github_login(&github_login)
+        .await?
+        .ok_or_else(|| Error::NotFound("Unknown tenant."))?;
+    pool.update_max_wechat_works(tenant.id, cap.max_wechat_works)
+        .await?;
+
+    Ok(HttpResponse::NoContent().body(Body::Empty))
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    last_hour: Summary,
+    last_day: Summary,
+}
+
+// Summarizes the deliveries of this instance for people, `/metrics` is for the monitoring.
+#[get("/admin/stats")]
+pub async fn stats(
+    req: HttpRequest,
+    config: web::Data<PipeHubConfig>,
+    pool: Pool,
+    metrics: web::Data<Arc<Metrics>>,
+) -> std::result::Result<HttpResponse, AWError> {
+    authorize(&req, &config)?;
+    let mut stats = Stats {
+        last_hour: metrics.activity().last_hour(),
+        last_day: metrics.activity().last_day(),
+    };
+
+    // The top tenants of the hour are usually those of the day, so they are looked up once.
+    let mut github_logins: HashMap<i64, Option<String>> = HashMap::new();
+    for tenant in stats
+        .last_hour
+        .top_tenants
+        .iter_mut()
+        .chain(stats.last_day.top_tenants.iter_mut())
+    {
+        if !github_logins.contains_key(&tenant.tenant_id) {
+            let github_login = pool
+                .find_tenant_by_id(tenant.tenant_id)
+                .await?
+                .map(|tenant| tenant.github_login);
+            github_logins.insert(tenant.tenant_id, github_login);
+        }
+        tenant.github_login = github_logins[&tenant.tenant_id].clone();
+    }
+
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    // Writes the valid rows even if others are invalid, nothing is written then by default.
+    #[serde(default)]
+    partial: bool,
+}
+
+// A row of the CSV, the header names the columns.
+#[derive(Debug, Deserialize)]
+struct ImportRow {
+    github_login: String,
+    corp_id: String,
+    agent_id: i64,
+    secret: String,
+    #[serde(default)]
+    external_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RowResult {
+    // The line in the file, the header is line 1.
+    line: u64,
+    github_login: String,
+    success: bool,
+    // created or updated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<&'static str>,
+    error_message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportReport {
+    // Whether anything was written.
+    committed: bool,
+    created: usize,
+    updated: usize,
+    failed: usize,
+    rows: Vec<RowResult>,
+}
+
+struct ImportTenant {
+    tenant: Tenant,
+    existing: Vec<WechatWork>,
+    // (corp id, agent id) of the rows before.
+    imported: HashSet<(String, i64)>,
+    created: usize,
+}
+
+// Checks the row against the tenant and the rows before it, returning the credentials and
+// whether they update existing ones.
+fn validate_row(
+    row: ImportRow,
+    tenant: &mut ImportTenant,
+    config: &PipeHubConfig,
+) -> Result<(WechatWork, bool)> {
+    let corp_id = row.corp_id.trim().to_string();
+    let secret = row.secret.trim().to_string();
+    if corp_id.is_empty() || secret.is_empty() {
+        return Err(Error::User("corp_id and secret are required."));
+    }
+    if !tenant.imported.insert((corp_id.clone(), row.agent_id)) {
+        return Err(Error::User("Duplicated WeChat credentials in the import."));
+    }
+
+    let matched = tenant
+        .existing
+        .iter()
+        .find(|existing| existing.corp_id == corp_id && existing.agent_id == row.agent_id)
+        .map(|existing| existing.id);
+    if matched.is_none() {
+        if tenant.existing.len() + tenant.created >= wechat::capacity(&tenant.tenant, config) {
+            return Err(Error::Conflict(
+                "Too many WeChat credentials for the tenant.",
+            ));
+        }
+        tenant.created += 1;
+    }
+
+    Ok((
+        WechatWork {
+            id: matched.unwrap_or_default(),
+            tenant_id: tenant.tenant.id,
+            corp_id,
+            agent_id: row.agent_id,
+            secret,
+            external_secret: row
+                .external_secret
+                .map(|secret| secret.trim().to_string())
+                .filter(|secret| !secret.is_empty()),
+        },
+        matched.is_some(),
+    ))
+}
+
+// Creates or updates the WeChat credentials of tenants from a CSV with the columns
+// github_login, corp_id, agent_id, secret and optionally external_secret. Credentials are
+// matched by corp id and agent id, tenants have to sign in once first. Every row is validated
+// before anything is written unless `partial`, the rows are then written in one transaction
+// rolled back at the first failure. Partial imports write and report the rows one by one.
+#[post("/admin/wechats/import")]
+pub async fn import_wechats(
+    req: HttpRequest,
+    web::Query(query): web::Query<ImportQuery>,
+    config: web::Data<PipeHubConfig>,
+    pool: Pool,
+    body: Bytes,
+) -> std::result::Result<HttpResponse, AWError> {
+    authorize(&req, &config)?;
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(&body[..]);
+    let headers = reader
+        .headers()
+        .map_err(|_| Error::User("Malformed CSV header."))?
+        .clone();
+    let mut tenants: HashMap<String, Option<ImportTenant>> = HashMap::new();
+    let mut rows: Vec<RowResult> = Vec::new();
+    let mut valid: Vec<(usize, WechatWork, bool)> = Vec::new();
+    for record in reader.records() {
+        let (line, row) = match record {
+            Ok(record) => {
+                let line = record.position().map_or(0, |position| position.line());
+                (line, record.deserialize::<ImportRow>(Some(&headers)))
+            }
+            Err(e) => (e.position().map_or(0, |position| position.line()), Err(e)),
+        };
+        let mut result = RowResult {
+            line,
+            github_login: String::new(),
+            success: false,
+            action: None,
+            error_message: "".to_owned(),
+        };
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                result.error_message = format!("Malformed row {}", e);
+                rows.push(result);
+                continue;
+            }
+        };
+        result.github_login = row.github_login.clone();
+
+        if !tenants.contains_key(&row.github_login) {
+            let tenant = match pool.find_tenant_by_github_login(&row.github_login).await? {
+                Some(tenant) => Some(ImportTenant {
+                    existing: pool.find_wechats_by_tenant_id(tenant.id).await?,
+                    tenant,
+                    imported: HashSet::new(),
+                    created: 0,
+                }),
+                None => None,
+            };
+            tenants.insert(row.github_login.clone(), tenant);
+        }
+        let validated = match tenants.get_mut(&row.github_login) {
+            Some(Some(tenant)) => validate_row(row, tenant, &config),
+            _ => Err(Error::NotFound(
+                "Unknown tenant, they have to sign in once first.",
+            )),
+        };
+        match validated {
+            Ok((wechat, matched)) => {
+                result.success = true;
+                result.action = Some(if matched { "updated" } else { "created" });
+                valid.push((rows.len(), wechat, matched));
+            }
+            Err(e) => result.error_message = e.to_string(),
+        }
+        rows.push(result);
+    }
+
+    let mut committed = query.partial || rows.iter().all(|row| row.success);
+    if committed && query.partial {
+        for (index, wechat, matched) in valid {
+            let written = if matched {
+                pool.update_wechat(wechat).await
+            } else {
+                match pool
+                    .insert_wechat(wechat, |tenant| wechat::capacity(tenant, &config))
+                    .await
+                {
+                    Ok(Some(_)) => Ok(()),
+                    Ok(None) => Err(Error::Conflict(
+                        "Too many WeChat credentials for the tenant.",
+                    )),
+                    Err(e) => Err(e),
+                }
+            };
+            if let Err(e) = written {
+                rows[index].success = false;
+                rows[index].action = None;
+                rows[index].error_message = e.to_string();
+            }
+        }
+    } else if committed {
+        let (indexes, wechats): (Vec<_>, Vec<_>) = valid
+            .into_iter()
+            .map(|(index, wechat, matched)| (index, (wechat, matched)))
+            .unzip();
+        if let Some((failed, e)) = pool
+            .write_wechats(wechats, |tenant| wechat::capacity(tenant, &config))
+            .await?
+        {
+            committed = false;
+            rows[indexes[failed]].success = false;
+            rows[indexes[failed]].error_message = e.to_string();
+            for row in rows.iter_mut() {
+                row.action = None;
+            }
+        }
+    } else {
+        for row in rows.iter_mut().filter(|row| row.success) {
+            row.action = None;
+        }
+    }
+
+    let count = |action: &str| rows.iter().filter(|row| row.action == Some(action)).count();
+    let report = ImportReport {
+        committed,
+        created: count("created"),
+        updated: count("updated"),
+        failed: rows.iter().filter(|row| !row.success).count(),
+        rows,
+    };
+    if committed {
+        Ok(HttpResponse::Ok().json(report))
+    } else {
+        Ok(HttpResponse::BadRequest().json(report))
+    }
+}
@@ -0,0 +1,300 @@
+use crate::config::PipeHubConfig;
+use crate::data::Pool;
+use crate::error::Error;
+use crate::models::{Tenant, UserTenant, WechatWork};
+use crate::user::{self, TENANT_ID_KEY};
+use crate::wechat;
+use actix_session::Session;
+use actix_web::{get, post, web, Error as AWError, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+// Bumped when the format changes incompatibly.
+const VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    // Secrets are omitted unless asked for, the export is then as sensitive as the secrets.
+    #[serde(default)]
+    include_secrets: bool,
+}
+
+// The settings of the tenant, the app key and the identity on GitHub belong to the instance.
+#[derive(Serialize, Deserialize)]
+struct Settings {
+    block_list: String,
+    #[serde(default)]
+    allow_list: Option<String>,
+    #[serde(default)]
+    default_message_type: Option<String>,
+    #[serde(default)]
+    quiet_hours_start: Option<String>,
+    #[serde(default)]
+    quiet_hours_end: Option<String>,
+    #[serde(default)]
+    time_zone: Option<String>,
+    #[serde(default)]
+    quiet_days: Option<String>,
+    #[serde(default)]
+    drop_in_quiet_hours: bool,
+    #[serde(default)]
+    rate_limits: Option<String>,
+    #[serde(default)]
+    response_format: Option<String>,
+    #[serde(default)]
+    default_agent_id: Option<i64>,
+    // Secrets like those of the credentials, they are kept if omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    callback_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    callback_aes_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    matrix_access_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    smtp_password: Option<String>,
+    #[serde(default)]
+    callback_forward_url: Option<String>,
+    #[serde(default)]
+    matrix_homeserver: Option<String>,
+    #[serde(default)]
+    matrix_room_id: Option<String>,
+    #[serde(default)]
+    smtp_host: Option<String>,
+    #[serde(default)]
+    smtp_port: Option<i32>,
+    #[serde(default)]
+    smtp_tls: Option<String>,
+    #[serde(default)]
+    smtp_username: Option<String>,
+    #[serde(default)]
+    email_from: Option<String>,
+    #[serde(default)]
+    email_to: Option<String>,
+    #[serde(default)]
+    default_to_user: Option<String>,
+    #[serde(default)]
+    default_to_party: Option<String>,
+    #[serde(default)]
+    default_to_tag: Option<String>,
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    suffix: String,
+    #[serde(default)]
+    append_request_id: bool,
+    #[serde(default)]
+    use_outbox: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedWechat {
+    corp_id: String,
+    agent_id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external_secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    version: u32,
+    settings: Settings,
+    // The first ones are the primary credentials.
+    wechats: Vec<ExportedWechat>,
+}
+
+#[get("/user/export")]
+pub async fn export(
+    session: Session,
+    pool: Pool,
+    web::Query(query): web::Query<ExportQuery>,
+) -> std::result::Result<HttpResponse, AWError> {
+    if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
+        if let Some(tenant) = pool.find_tenant_by_id(tenant_id).await? {
+            let wechats = pool
+                .find_wechats_by_tenant_id(tenant_id)
+                .await?
+                .into_iter()
+                .map(|wechat| ExportedWechat {
+                    corp_id: wechat.corp_id,
+                    agent_id: wechat.agent_id,
+                    secret: Some(wechat.secret).filter(|_| query.include_secrets),
+                    external_secret: wechat.external_secret.filter(|_| query.include_secrets),
+                })
+                .collect();
+            let backup = Backup {
+                version: VERSION,
+                settings: Settings {
+                    block_list: tenant.block_list,
+                    allow_list: tenant.allow_list,
+                    default_message_type: tenant.default_message_type,
+                    quiet_hours_start: tenant.quiet_hours_start,
+                    quiet_hours_end: tenant.quiet_hours_end,
+                    time_zone: tenant.time_zone,
+                    quiet_days: tenant.quiet_days,
+                    drop_in_quiet_hours: tenant.drop_in_quiet_hours,
+                    rate_limits: tenant.rate_limits,
+                    response_format: tenant.response_format,
+                    default_agent_id: tenant.default_agent_id,
+                    callback_token: tenant.callback_token.filter(|_| query.include_secrets),
+                    callback_aes_key: tenant.callback_aes_key.filter(|_| query.include_secrets),
+                    matrix_access_token: tenant
+                        .matrix_access_token
+                        .filter(|_| query.include_secrets),
+                    callback_forward_url: tenant.callback_forward_url,
+                    matrix_homeserver: tenant.matrix_homeserver,
+                    matrix_room_id: tenant.matrix_room_id,
+                    smtp_password: tenant.smtp_password.filter(|_| query.include_secrets),
+                    smtp_host: tenant.smtp_host,
+                    smtp_port: tenant.smtp_port,
+                    smtp_tls: tenant.smtp_tls,
+                    smtp_username: tenant.smtp_username,
+                    email_from: tenant.email_from,
+                    email_to: tenant.email_to,
+                    default_to_user: tenant.default_to_user,
+                    default_to_party: tenant.default_to_party,
+                    default_to_tag: tenant.default_to_tag,
+                    prefix: tenant.prefix,
+                    suffix: tenant.suffix,
+                    append_request_id: tenant.append_request_id,
+                    use_outbox: tenant.use_outbox,
+                },
+                wechats,
+            };
+
+            return Ok(HttpResponse::Ok()
+                .header(
+                    "Content-Disposition",
+                    "attachment; filename=\"pipehub.json\"",
+                )
+                .json(backup));
+        }
+    }
+
+    Err(Error::Unauthorized("Please sign in first.").into())
+}
+
+// Restores an export, the WeChat credentials are matched by corp id and agent id. Omitted
+// secrets are kept from the matched credentials and required for new ones. Credentials
+// absent from the export are left as they are.
+#[post("/user/import")]
+pub async fn import(
+    session: Session,
+    pool: Pool,
+    config: web::Data<PipeHubConfig>,
+    web::Json(backup): web::Json<Backup>,
+) -> std::result::Result<HttpResponse, AWError> {
+    if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
+        if let Some(tenant) = pool.find_tenant_by_id(tenant_id).await? {
+            if backup.version != VERSION {
+                return Err(Error::User("Unsupported export version.").into());
+            }
+            let settings = backup.settings;
+            let new_tenant = user::with_settings(
+                tenant.clone(),
+                Tenant {
+                    block_list: settings.block_list,
+                    allow_list: settings.allow_list,
+                    default_message_type: settings.default_message_type,
+                    quiet_hours_start: settings.quiet_hours_start,
+                    quiet_hours_end: settings.quiet_hours_end,
+                    time_zone: settings.time_zone,
+                    quiet_days: settings.quiet_days,
+                    drop_in_quiet_hours: settings.drop_in_quiet_hours,
+                    rate_limits: settings.rate_limits,
+                    response_format: settings.response_format,
+                    default_agent_id: settings.default_agent_id,
+                    callback_token: settings
+                        .callback_token
+                        .or_else(|| tenant.callback_token.clone()),
+                    callback_aes_key: settings
+                        .callback_aes_key
+                        .or_else(|| tenant.callback_aes_key.clone()),
+                    matrix_access_token: settings
+                        .matrix_access_token
+                        .or_else(|| tenant.matrix_access_token.clone()),
+                    callback_forward_url: settings.callback_forward_url,
+                    matrix_homeserver: settings.matrix_homeserver,
+                    matrix_room_id: settings.matrix_room_id,
+                    smtp_password: settings
+                        .smtp_password
+                        .or_else(|| tenant.smtp_password.clone()),
+                    smtp_host: settings.smtp_host,
+                    smtp_port: settings.smtp_port,
+                    smtp_tls: settings.smtp_tls,
+                    smtp_username: settings.smtp_username,
+                    email_from: settings.email_from,
+                    email_to: settings.email_to,
+                    default_to_user: settings.default_to_user,
+                    default_to_party: settings.default_to_party,
+                    default_to_tag: settings.default_to_tag,
+                    prefix: settings.prefix,
+                    suffix: settings.suffix,
+                    append_request_id: settings.append_request_id,
+                    use_outbox: settings.use_outbox,
+                    log_bodies_until: None,
+                    ..tenant
+                },
+                &config,
+            )?;
+
+            // Everything is validated before anything is written.
+            let existing = pool.find_wechats_by_tenant_id(tenant_id).await?;
+            let mut wechats: Vec<WechatWork> = Vec::with_capacity(backup.wechats.len());
+            for imported in backup.wechats {
+                let corp_id = imported.corp_id.trim().to_string();
+                if wechats
+                    .iter()
+                    .any(|wechat| wechat.corp_id == corp_id && wechat.agent_id == imported.agent_id)
+                {
+                    return Err(Error::User("Duplicated WeChat credentials in the import.").into());
+                }
+                let matched = existing.iter().find(|wechat| {
+                    wechat.corp_id == corp_id && wechat.agent_id == imported.agent_id
+                });
+                let secret = imported
+                    .secret
+                    .map(|secret| secret.trim().to_string())
+                    .filter(|secret| !secret.is_empty())
+                    .or_else(|| matched.map(|wechat| wechat.secret.clone()))
+                    .ok_or_else(|| {
+                        Error::User("The secret is required for new WeChat credentials.")
+                    })?;
+                let external_secret = imported
+                    .external_secret
+                    .map(|secret| secret.trim().to_string())
+                    .filter(|secret| !secret.is_empty())
+                    .or_else(|| matched.and_then(|wechat| wechat.external_secret.clone()));
+                wechats.push(WechatWork {
+                    id: matched.map(|wechat| wechat.id).unwrap_or_default(),
+                    tenant_id,
+                    corp_id,
+                    agent_id: imported.agent_id,
+                    secret,
+                    external_secret,
+                });
+            }
+            let added = wechats.iter().filter(|wechat| wechat.id == 0).count();
+            if existing.len() + added > wechat::capacity(&new_tenant, &config) {
+                return Err(Error::Conflict(
+                    "Too many WeChat credentials, please remove some before importing.",
+                )
+                .into());
+            }
+            // The default agent may be among either the imported or the existing credentials.
+            if user::validate_default_agent(&new_tenant, &wechats).is_err() {
+                user::validate_default_agent(&new_tenant, &existing)?;
+            }
+
+            // Nothing is restored if any write fails.
+            pool.import_tenant(new_tenant.clone(), wechats, |tenant| {
+                wechat::capacity(tenant, &config)
+            })
+            .await?;
+
+            return Ok(HttpResponse::Ok().json(UserTenant::from(new_tenant)));
+        }
+    }
+
+    Err(Error::Unauthorized("Please sign in first.").into())
+}
@@ -0,0 +1,444 @@
+use crate::config::PipeHubConfig;
+use crate::data::Pool;
+use crate::error::{Error, Result};
+use crate::logger::ApplicationLogger;
+use crate::models::Tenant;
+use crate::send;
+use crate::REQUEST_ID_HEADER;
+use actix_web::{get, post, web, Error as AWError, HttpRequest, HttpResponse};
+use chrono::Utc;
+use log::Level;
+use openssl::base64;
+use openssl::memcmp;
+use openssl::sha::sha1;
+use openssl::symm::{Cipher, Crypter, Mode};
+use regex::Regex;
+use reqwest::Client;
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::{Host, Url};
+use uuid::Uuid;
+
+// https://work.weixin.qq.com/api/doc/90000/90135/90930, messages are AES-256-CBC encrypted
+// with the key of EncodingAESKey and padded to 32 bytes.
+const AES_KEY_LENGTH: usize = 43;
+const MAX_TOKEN_LENGTH: usize = 32;
+const BLOCK_SIZE: usize = 32;
+// The random bytes and the length of the message before it.
+const HEADER_LENGTH: usize = 16 + 4;
+// Callbacks signed longer ago or ahead are refused, so captured ones can't be replayed later.
+const MAX_TIMESTAMP_SKEW: i64 = 5 * 60;
+// Forwarding happens after replying to WeChat, which only waits 5 seconds.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref ENCRYPT: Regex = Regex::new(r"<Encrypt><!\[CDATA\[([^\]]*)\]\]></Encrypt>").unwrap();
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    msg_signature: String,
+    timestamp: String,
+    nonce: String,
+    // Only in the verification of the url.
+    echostr: Option<String>,
+}
+
+pub fn validate_callback(tenant: &Tenant, config: &PipeHubConfig) -> Result<()> {
+    match (&tenant.callback_token, &tenant.callback_aes_key) {
+        (Some(token), Some(aes_key)) => {
+            if token.len() > MAX_TOKEN_LENGTH || !token.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(Error::User(
+                    "The callback token must be at most 32 letters or digits.",
+                ));
+            }
+            if aes_key.len() != AES_KEY_LENGTH || decode_aes_key(aes_key).is_err() {
+                return Err(Error::User(
+                    "The callback EncodingAESKey must be 43 letters or digits.",
+                ));
+            }
+        }
+        (None, None) => {}
+        _ => {
+            return Err(Error::User(
+                "Both token and EncodingAESKey of callbacks are required.",
+            ))
+        }
+    }
+    if let Some(ref forward_url) = tenant.callback_forward_url {
+        if tenant.callback_token.is_none() {
+            return Err(Error::User(
+                "Forwarding requires the token and EncodingAESKey of callbacks.",
+            ));
+        }
+        ensure_public_target(forward_url, &config.callback_forward_allowed_hosts)?;
+    }
+
+    Ok(())
+}
+
+fn is_allowed_host(url: &Url, allowed_hosts: &[String]) -> bool {
+    (url.scheme() == "https" || url.scheme() == "http")
+        && url.host_str().map_or(false, |host| {
+            allowed_hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        })
+}
+
+// Loopback, private, link-local, shared and other special addresses, so tenants can't reach
+// the network of the server.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if let (Some(mapped), [0, 0, 0, 0, 0, 0xffff, _, _]) = (ip.to_ipv4(), segments) {
+                return is_public(IpAddr::V4(mapped));
+            }
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || segments[0] & 0xfe00 == 0xfc00
+                || segments[0] & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+// Checked when saved and again before each forward, as the allowed hosts may have changed.
+// Domains resolved here could resolve to a private address again when connecting, and the
+// client can't be pinned to the checked address, so only the allowed hosts may be domains. The
+// guard is best effort for those, they are trusted as configured.
+fn ensure_public_target(forward_url: &str, allowed_hosts: &[String]) -> Result<()> {
+    let url = Url::parse(forward_url)
+        .ok()
+        .filter(|url| url.has_host())
+        .ok_or(Error::User("The forward url must be an https url."))?;
+    if is_allowed_host(&url, allowed_hosts) {
+        return Ok(());
+    }
+    if url.scheme() != "https" {
+        return Err(Error::User("The forward url must be an https url."));
+    }
+    let ip =
+        match url.host() {
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            Some(Host::Domain(_)) => return Err(Error::User(
+                "The forward url must be a public IP address unless the server allows its host.",
+            )),
+            None => return Err(Error::User("The forward url must be an https url.")),
+        };
+    if !is_public(ip) {
+        return Err(Error::User(
+            "The forward url must not be a private or loopback address.",
+        ));
+    }
+
+    Ok(())
+}
+
+fn decode_aes_key(aes_key: &str) -> Result<Vec<u8>> {
+    base64::decode_block(&format!("{}=", aes_key))
+        .ok()
+        .filter(|key| key.len() == 32)
+        .ok_or_else(|| Error::User("Malformed EncodingAESKey."))
+}
+
+// The timestamp is in seconds like WeChat signs it.
+fn is_fresh(timestamp: &str, now: i64) -> bool {
+    timestamp.parse::<i64>().map_or(false, |timestamp| {
+        (now - timestamp).abs() <= MAX_TIMESTAMP_SKEW
+    })
+}
+
+fn signature(token: &str, timestamp: &str, nonce: &str, encrypted: &str) -> String {
+    let mut parts = [token, timestamp, nonce, encrypted];
+    parts.sort();
+    sha1(parts.concat().as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Returns the message and the corp id it's for.
+fn decrypt(aes_key: &[u8], encrypted: &str) -> Result<(String, String)> {
+    let malformed = || Error::User("Malformed encrypted message.");
+    let encrypted = base64::decode_block(encrypted).map_err(|_| malformed())?;
+    if encrypted.is_empty() || encrypted.len() % BLOCK_SIZE != 0 {
+        return Err(malformed());
+    }
+
+    let cipher = Cipher::aes_256_cbc();
+    let mut crypter = Crypter::new(cipher, Mode::Decrypt, aes_key, Some(&aes_key[..16]))?;
+    crypter.pad(false);
+    let mut plain = vec![0; encrypted.len() + cipher.block_size()];
+    let mut length = crypter.update(&encrypted, &mut plain)?;
+    length += crypter.finalize(&mut plain[length..])?;
+    plain.truncate(length);
+
+    let padding = plain.last().copied().unwrap_or_default() as usize;
+    if padding == 0 || padding > BLOCK_SIZE || padding > plain.len() {
+        return Err(malformed());
+    }
+    plain.truncate(plain.len() - padding);
+    if plain.len() < HEADER_LENGTH {
+        return Err(malformed());
+    }
+    let message_length = u32::from_be_bytes(
+        plain[16..HEADER_LENGTH]
+            .try_into()
+            .map_err(|_| malformed())?,
+    ) as usize;
+    if plain.len() < HEADER_LENGTH + message_length {
+        return Err(malformed());
+    }
+    let (message, corp_id) = plain[HEADER_LENGTH..].split_at(message_length);
+
+    Ok((
+        String::from_utf8(message.to_vec()).map_err(|_| malformed())?,
+        String::from_utf8(corp_id.to_vec()).map_err(|_| malformed())?,
+    ))
+}
+
+// Authenticates the message by its signature and decrypts it, returning the tenant it's for.
+async fn open(
+    pool: &Pool,
+    key: &str,
+    query: &CallbackQuery,
+    encrypted: &str,
+) -> Result<(Tenant, String)> {
+    if !is_fresh(&query.timestamp, Utc::now().timestamp()) {
+        return Err(Error::Forbidden("The timestamp is too far from now."));
+    }
+    let app_id = send::decode_app_id(key)?;
+    let tenant = pool
+        .find_tenant_by_app_id(app_id)
+        .await?
+        .ok_or_else(|| Error::User("Unknown APP ID."))?;
+    let (token, aes_key) = match (&tenant.callback_token, &tenant.callback_aes_key) {
+        (Some(token), Some(aes_key)) => (token, decode_aes_key(aes_key)?),
+        _ => return Err(Error::NotFound("Callbacks aren't configured.")),
+    };
+
+    let expected = signature(token, &query.timestamp, &query.nonce, encrypted);
+    if expected.len() != query.msg_signature.len()
+        || !memcmp::eq(expected.as_bytes(), query.msg_signature.as_bytes())
+    {
+        return Err(Error::Forbidden("Invalid signature."));
+    }
+    let (message, corp_id) = decrypt(&aes_key, encrypted)?;
+    let wechats = pool.find_wechats_by_tenant_id(tenant.id).await?;
+    if !wechats.iter().any(|wechat| wechat.corp_id == corp_id) {
+        return Err(Error::Forbidden("The message is for another corp."));
+    }
+
+    Ok((tenant, message))
+}
+
+// Posts the decrypted XML as WeChat would without encryption, failures are only logged.
+// Redirects aren't followed, they could lead to a private address.
+#[derive(Clone)]
+pub struct ForwardClient(pub Client);
+
+async fn forward(
+    http_client: &Client,
+    logger: &ApplicationLogger,
+    config: &PipeHubConfig,
+    request_id: Uuid,
+    forward_url: &str,
+    message: String,
+) {
+    if let Err(e) = ensure_public_target(forward_url, &config.callback_forward_allowed_hosts) {
+        logger.track_trace(
+            request_id,
+            Level::Warn,
+            &format!("Refused to forward the WeChat event, {}", e),
+        );
+        return;
+    }
+    let start = Instant::now();
+    let result = http_client
+        .post(forward_url)
+        .header("Content-Type", "application/xml")
+        .header(REQUEST_ID_HEADER, request_id.to_string())
+        .body(message)
+        .timeout(FORWARD_TIMEOUT)
+        .send()
+        .await;
+    let host = Url::parse(forward_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_default();
+    let (result_code, success) = match result {
+        Ok(ref response) => (
+            response.status().as_str().to_owned(),
+            response.status().is_success(),
+        ),
+        Err(ref e) => (e.to_string(), false),
+    };
+
+    logger.track_dependency(
+        request_id,
+        &format!("POST {}", host),
+        "HTTP",
+        start.elapsed(),
+        &host,
+        &result_code,
+        forward_url,
+        success,
+    );
+    if !success {
+        logger.track_trace(
+            request_id,
+            Level::Warn,
+            &format!("Unable to forward the WeChat event, {}", result_code),
+        );
+    }
+}
+
+// WeChat Work verifies the callback url by asking to decrypt `echostr`.
+#[get("/callback/{key}")]
+pub async fn verify(
+    pool: Pool,
+    key: web::Path<String>,
+    web::Query(query): web::Query<CallbackQuery>,
+) -> std::result::Result<HttpResponse, AWError> {
+    let echostr = query
+        .echostr
+        .as_deref()
+        .ok_or_else(|| Error::User("No echostr is provided."))?;
+    let (_, echo) = open(&pool, &key, &query, echostr).await?;
+
+    Ok(HttpResponse::Ok().content_type("text/plain").body(echo))
+}
+
+// Events are logged, and forwarded in background if the tenant has a forward url.
+#[post("/callback/{key}")]
+pub async fn receive(
+    pool: Pool,
+    key: web::Path<String>,
+    web::Query(query): web::Query<CallbackQuery>,
+    payload: web::Bytes,
+    logger: web::Data<Arc<ApplicationLogger>>,
+    http_client: web::Data<ForwardClient>,
+    config: web::Data<PipeHubConfig>,
+    req: HttpRequest,
+) -> std::result::Result<HttpResponse, AWError> {
+    let request_id: Uuid = req
+        .extensions()
+        .get::<Uuid>()
+        .cloned()
+        .expect("No request id found.");
+    let body = String::from_utf8_lossy(&payload);
+    let encrypted = ENCRYPT
+        .captures(&body)
+        .and_then(|captures| captures.get(1))
+        .map(|encrypted| encrypted.as_str())
+        .ok_or_else(|| Error::User("No encrypted message is provided."))?;
+    let (tenant, event) = open(&pool, &key, &query, encrypted).await?;
+    logger.track_trace(
+        request_id,
+        Level::Info,
+        &format!("Received WeChat event {}", event),
+    );
+    if let Some(forward_url) = tenant.callback_forward_url {
+        actix_rt::spawn(async move {
+            forward(
+                &http_client.0,
+                &logger,
+                &config,
+                request_id,
+                &forward_url,
+                event,
+            )
+            .await;
+        });
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ensure_public_target, is_fresh, is_public};
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn refuses_private_addresses() {
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for ip in &[
+            "1.1.1.1",
+            "100.128.0.1",
+            "172.32.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn refuses_domains_unless_allowed() {
+        let allowed = vec!["hooks.internal.example.com".to_owned()];
+        let target = |url: &str| ensure_public_target(url, &allowed).is_ok();
+        assert!(target("https://1.1.1.1/events"));
+        assert!(target("https://[2606:4700::1111]/events"));
+        assert!(target("http://hooks.internal.example.com/events"));
+        assert!(target("https://HOOKS.internal.example.com/events"));
+        assert!(!target("https://example.com/events"));
+        assert!(!target("https://10.0.0.1/events"));
+        assert!(!target("http://1.1.1.1/events"));
+        assert!(!target("ftp://hooks.internal.example.com/events"));
+        assert!(!target("hooks"));
+    }
+
+    #[test]
+    fn refuses_stale_and_future_timestamps() {
+        let now = 1_600_000_000;
+        assert!(is_fresh("1600000000", now));
+        assert!(is_fresh("1599999700", now));
+        assert!(is_fresh("1600000300", now));
+        assert!(!is_fresh("1599999699", now));
+        assert!(!is_fresh("1600000301", now));
+        assert!(!is_fresh("", now));
+        assert!(!is_fresh("16e8", now));
+    }
+}
@@ -1,18 +1,229 @@
 use crate::error::Result;
-use config::{Config, Environment};
+use crate::i18n::Locale;
+use crate::send::Validation;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use config::{Config, ConfigError, Environment};
 use log::Level;
+use regex::Regex;
 use serde::Deserialize;
+use std::fs::{self, File};
+use std::time::{Duration, Instant};
+use url::Url;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct PipeHubConfig {
     pub host: String,
     pub port: u16,
     pub domain: String,
+    // How long to wait for connecting to WeChat and GitHub, failing to connect is retried.
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    // The proxy of calls to WeChat and GitHub, e.g. `http://10.0.0.3:3128`.
+    #[serde(default)]
+    pub proxy: Option<String>,
+    // The API of WeChat Work, e.g. a stub of it in tests.
+    #[serde(default = "default_wechat_api_url")]
+    pub wechat_api_url: String,
+    // Hosts WeChat events may be forwarded to though they are private or plain http, comma
+    // separated. Other forward urls must be https with public IP addresses, as domains may
+    // resolve differently when connected to.
+    #[serde(default, deserialize_with = "crate::util::comma_separated")]
+    pub callback_forward_allowed_hosts: Vec<String>,
     // If we need to make cookie secure.
     pub https: bool,
+    // Proxies whose `X-Forwarded-Proto: https` makes cookie secure, comma separated IPs.
+    #[serde(default, deserialize_with = "crate::util::comma_separated")]
+    pub trusted_proxies: Vec<String>,
+    // Origins like `https://app.example.com` that `/user?redirect=` may send users back to
+    // after signing in, comma separated. Paths on this site are always allowed.
+    #[serde(default, deserialize_with = "crate::util::comma_separated")]
+    pub allowed_redirects: Vec<String>,
+    // Origins of browsers that may call the send endpoints, comma separated. Any origin may by
+    // default with `*`, empty disables CORS so browsers can't read the replies.
+    #[serde(
+        default = "default_send_allowed_origins",
+        deserialize_with = "crate::util::comma_separated"
+    )]
+    pub send_allowed_origins: Vec<String>,
+    // Required by the admin endpoints as `Authorization: Bearer {admin_token}`, they are disabled if absent.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    // Signs the one-time send tokens with HMAC-SHA256, they are disabled if absent.
+    #[serde(default)]
+    pub send_token_secret: Option<String>,
+    // Requests to `/send` whose user agents contain any of these are rejected with 403,
+    // comma separated and case insensitive.
+    #[serde(default, deserialize_with = "crate::util::comma_separated")]
+    pub blocked_user_agents: Vec<String>,
+    // Assembled from the `db_` parts below if absent.
+    #[serde(default)]
     pub database_url: String,
+    #[serde(default)]
+    pub db_host: Option<String>,
+    #[serde(default)]
+    pub db_port: Option<u16>,
+    #[serde(default)]
+    pub db_name: Option<String>,
+    #[serde(default)]
+    pub db_user: Option<String>,
+    #[serde(default)]
+    pub db_password: Option<String>,
+    // Read instead of `db_password`, like the secrets mounted by Kubernetes.
+    #[serde(default)]
+    pub db_password_file: Option<String>,
+    // Overrides `sslmode` of the database url.
+    #[serde(default)]
+    pub database_ssl_mode: Option<SslMode>,
+    // The CA certificate to verify the database server with, in PEM.
+    #[serde(default)]
+    pub database_ssl_root_cert: Option<String>,
+    // The schema of the tables instead of `public`, it's created if missing.
+    #[serde(default)]
+    pub database_schema: Option<String>,
+    // Off leaves migrations to a separate job, the server only checks they are all run then, so
+    // replicas starting together don't race to migrate.
+    #[serde(default = "default_run_migrations")]
+    pub run_migrations: bool,
+    // Pings pooled connections before handing them out, so the stale ones after a database
+    // restart are replaced instead of failing requests.
+    #[serde(default = "default_database_test_on_checkout")]
+    pub database_test_on_checkout: bool,
+    // 32 bytes in base64 sealing WeChat secrets in the database, they are kept plain if empty.
+    // Rotated by moving it to `old_secret_keys`, which only open what they sealed.
+    #[serde(default)]
+    pub secret_key: String,
+    #[serde(default, deserialize_with = "crate::util::comma_separated")]
+    pub old_secret_keys: Vec<String>,
+    // Sends of the tenants seen in the seconds keep working while the database is failing,
+    // disabled if 0 and capped at 5 minutes.
+    #[serde(default)]
+    pub tenant_cache_ttl: u64,
+    // How long the status of an async message is kept, in seconds.
+    #[serde(default = "default_job_ttl")]
+    pub job_ttl: u64,
+    // Replies 504 if a request isn't handled in time, in seconds. It also caps the timeouts
+    // clients ask for with `X-PipeHub-Timeout-Ms`.
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    // Responses smaller than the bytes aren't compressed, 0 compresses all.
+    #[serde(default = "default_compress_min_size")]
+    pub compress_min_size: usize,
+    // How long browsers and CDNs cache the assets of the frontend with hashed names, in seconds.
+    // `index.html` and the other files are always revalidated. 0 revalidates all of them.
+    #[serde(default = "default_static_max_age")]
+    pub static_max_age: u64,
+    // Revalidates the files of the frontend by `ETag` and `Last-Modified`.
+    #[serde(default = "default_static_etag")]
+    pub static_etag: bool,
+    #[serde(default = "default_static_last_modified")]
+    pub static_last_modified: bool,
+    // The number of HTTP workers, one per CPU if absent.
+    #[serde(default)]
+    pub workers: Option<usize>,
+    // Browsers navigating to APIs without signing in are redirected here instead of getting the
+    // 401 JSON, or to the sign in page if the API tells. Requests not accepting HTML always get
+    // the JSON.
+    #[serde(default)]
+    pub unauthenticated_redirect: Option<String>,
+    // Persists all messages to the retry queue and delivers them in background, tenants can also
+    // opt in individually.
+    #[serde(default)]
+    pub outbox: bool,
+    // At most this many requests to WeChat are in flight, the others wait up to `outbound_wait_ms`
+    // and then fail with 503.
+    #[serde(default = "default_max_outbound_requests")]
+    pub max_outbound_requests: usize,
+    #[serde(default = "default_outbound_wait_ms")]
+    pub outbound_wait_ms: u64,
+    // At most this many sends retry at the same time across tenants, the others fail with their
+    // last error instead of retrying. Unlimited if 0.
+    #[serde(default)]
+    pub max_concurrent_retries: usize,
+    // Sends are rejected with 503 once this many messages wait in the retry queue or for quiet
+    // hours.
+    #[serde(default = "default_max_queued_messages")]
+    pub max_queued_messages: i64,
+    // Told to the rejected clients in `Retry-After`, in seconds.
+    #[serde(default = "default_queue_retry_after")]
+    pub queue_retry_after: u64,
+    #[serde(default = "default_max_wechat_works")]
+    pub max_wechat_works: usize,
     pub github: GitHubConfig,
     pub log: LogConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub levels: LevelConfig,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    #[serde(default)]
+    pub retry_queue: RetryQueueConfig,
+    // `strict` or `lenient`, sends may choose for themselves with `validation`.
+    #[serde(default)]
+    pub validation: Validation,
+    // Strips control characters but newlines and tabs from messages, WeChat rejects or garbles
+    // some of them like null bytes. Off passes messages through as they are.
+    #[serde(default = "default_strip_control_chars")]
+    pub strip_control_chars: bool,
+    // The language of hints in responses, `en` or `zh-CN`.
+    #[serde(default)]
+    pub locale: Locale,
+    // The IANA time zone of tenants without their own, like `Asia/Shanghai`. Quiet hours are in
+    // local time of it.
+    #[serde(
+        default = "default_time_zone",
+        deserialize_with = "crate::util::time_zone"
+    )]
+    pub time_zone: Tz,
+    // Logs the inbound body of every send at debug level, off by default for privacy. The timed
+    // opt-ins below log the messages at info level.
+    #[serde(default)]
+    pub log_bodies: bool,
+    // Logs the messages of all tenants until then, e.g. `2026-10-14T08:00:00Z`, for debugging.
+    #[serde(default)]
+    pub log_bodies_until: Option<DateTime<Utc>>,
+    // Logged bodies are truncated to the bytes.
+    #[serde(default = "default_log_body_max_length")]
+    pub log_body_max_length: usize,
+    // Regular expressions replaced with `***` in logged messages and bodies, comma separated with
+    // `\,` escaping a comma.
+    #[serde(default, deserialize_with = "crate::util::regexes")]
+    pub log_redactions: Vec<Regex>,
+    // Messages of any tenant matching these regular expressions are rejected, comma separated
+    // like the redactions.
+    #[serde(default, deserialize_with = "crate::util::regexes")]
+    pub content_policy: Vec<Regex>,
+    // Only requests taking at least the milliseconds are logged, errors are always logged. 0 logs
+    // all requests.
+    #[serde(default)]
+    pub log_slow_requests_ms: u64,
+    // The fraction of the other successful requests logged, from 0 to 1. All of them are logged
+    // if absent, or none if only slow requests are logged.
+    #[serde(default)]
+    pub log_sample_rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl SslMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            SslMode::Disable => "disable",
+            SslMode::Prefer => "prefer",
+            SslMode::Require => "require",
+            SslMode::VerifyCa => "verify-ca",
+            SslMode::VerifyFull => "verify-full",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -22,28 +233,464 @@ pub struct GitHubConfig {
     pub auth_url: String,
     pub token_url: String,
     pub callback_url: String,
+    // More callback urls of the OAuth app, comma separated, e.g. for staging and localhost sharing
+    // the app. The one of the host signing in is used, other hosts can't sign in then.
+    #[serde(default, deserialize_with = "crate::util::comma_separated")]
+    pub callback_urls: Vec<String>,
+    // Organizations whose members are allowed to sign in, comma separated.
+    #[serde(default, deserialize_with = "crate::util::comma_separated")]
+    pub allowed_orgs: Vec<String>,
+    // Teams in the form of `org/team-slug` whose members are allowed to sign in, comma separated.
+    #[serde(default, deserialize_with = "crate::util::comma_separated")]
+    pub allowed_teams: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogDestination {
+    Stdout,
+    // Files of `log_dir` rotated by size and time.
+    File,
+    // The local syslog daemon.
+    Syslog,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
 }
 
+// Where the logs go, e.g. `pipehub_log__destination=file`.
 #[derive(Debug, Clone, Deserialize)]
 pub struct LogConfig {
     #[serde(default)]
     pub instrumentation_key: String,
     #[serde(default)]
     pub log_dir: String,
+    // Stdout if absent, or files if `log_dir` is set.
+    #[serde(default)]
+    pub destination: Option<LogDestination>,
+    // A new file is started once the current one reaches the size or the next period, whichever
+    // is first. 0 doesn't rotate by size.
+    #[serde(default = "default_log_max_file_mb")]
+    pub max_file_mb: u64,
+    #[serde(default = "default_log_rotation")]
+    pub rotation: LogRotation,
+    // The oldest files are removed to keep this many, 0 keeps all of them.
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+    // The unix socket of the syslog daemon.
+    #[serde(default = "default_syslog_socket")]
+    pub syslog_socket: String,
     pub level: Level,
 }
 
+impl LogConfig {
+    pub fn destination(&self) -> LogDestination {
+        match self.destination {
+            Some(destination) => destination,
+            None if !self.log_dir.is_empty() => LogDestination::File,
+            None => LogDestination::Stdout,
+        }
+    }
+}
+
+// Retry policies of each channel, e.g. `pipehub_retry__wechat__max_retries=2`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RetryConfig {
+    #[serde(default)]
+    pub wechat: RetryPolicy,
+    #[serde(default)]
+    pub matrix: RetryPolicy,
+    #[serde(default)]
+    pub email: RetryPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RetryPolicy {
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    // The backoff doubles on each retry, starting from the base and capped at the max.
+    #[serde(default = "default_base_backoff_ms")]
+    pub base_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    // How long to wait for the reply of each attempt, it's not retried once timed out.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    // Set by clients for each request, attempts and backoffs past it aren't started.
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: default_max_retries(),
+            base_backoff_ms: default_base_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            timeout_ms: default_timeout_ms(),
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn backoff(&self, retry_count: u32) -> Duration {
+        let backoff = self
+            .base_backoff_ms
+            .saturating_mul(1u64 << retry_count.min(16));
+        Duration::from_millis(backoff.min(self.max_backoff_ms))
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms)
+    }
+
+    pub fn with_deadline(self, deadline: Option<Instant>) -> Self {
+        RetryPolicy { deadline, ..self }
+    }
+
+    // The time left before the deadline, if there is one.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        let timeout = Duration::from_millis(self.timeout_ms);
+        match self.remaining() {
+            Some(remaining) => timeout.min(remaining),
+            None => timeout,
+        }
+    }
+}
+
+// What is prepended to messages sent with `level`, e.g. `pipehub_levels__error=[ERROR]`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LevelConfig {
+    #[serde(default = "default_error_level")]
+    pub error: String,
+    #[serde(default = "default_warn_level")]
+    pub warn: String,
+    #[serde(default = "default_info_level")]
+    pub info: String,
+}
+
+impl Default for LevelConfig {
+    fn default() -> Self {
+        LevelConfig {
+            error: default_error_level(),
+            warn: default_warn_level(),
+            info: default_info_level(),
+        }
+    }
+}
+
+// Sends still failing after their retries are parked in the database and attempted again
+// later, so they survive restarts, e.g. `pipehub_retry_queue__enabled=true`. The delay doubles
+// on each attempt, those failing `max_attempts` times are dead.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RetryQueueConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_retry_queue_max_attempts")]
+    pub max_attempts: u32,
+    // In seconds.
+    #[serde(default = "default_retry_queue_base_delay")]
+    pub base_delay: u64,
+    #[serde(default = "default_retry_queue_max_delay")]
+    pub max_delay: u64,
+    // How long delivered and dead sends are kept for their status, in seconds.
+    #[serde(default = "default_retry_queue_retention")]
+    pub retention: u64,
+}
+
+impl Default for RetryQueueConfig {
+    fn default() -> Self {
+        RetryQueueConfig {
+            enabled: false,
+            max_attempts: default_retry_queue_max_attempts(),
+            base_delay: default_retry_queue_base_delay(),
+            max_delay: default_retry_queue_max_delay(),
+            retention: default_retry_queue_retention(),
+        }
+    }
+}
+
+impl RetryQueueConfig {
+    // Until the next attempt after `attempts` failed ones.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1u64 << attempts.saturating_sub(1).min(20));
+        Duration::from_secs(delay.min(self.max_delay))
+    }
+}
+
+// Messages per minute of each tenant and priority, 0 is unlimited, e.g.
+// `pipehub_rate_limits__low=10`. Tenants may override them.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub high: u32,
+    #[serde(default)]
+    pub normal: u32,
+    #[serde(default)]
+    pub low: u32,
+}
+
+fn default_error_level() -> String {
+    "❌".to_owned()
+}
+
+fn default_warn_level() -> String {
+    "⚠️".to_owned()
+}
+
+fn default_info_level() -> String {
+    "ℹ️".to_owned()
+}
+
+fn default_max_retries() -> u32 {
+    4
+}
+
+fn default_base_backoff_ms() -> u64 {
+    200
+}
+
+fn default_max_backoff_ms() -> u64 {
+    5000
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_wechat_api_url() -> String {
+    "https://qyapi.weixin.qq.com".to_owned()
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_max_outbound_requests() -> usize {
+    64
+}
+
+fn default_outbound_wait_ms() -> u64 {
+    1000
+}
+
+fn default_max_queued_messages() -> i64 {
+    10000
+}
+
+fn default_queue_retry_after() -> u64 {
+    30
+}
+
+fn default_max_wechat_works() -> usize {
+    5
+}
+
+fn default_database_test_on_checkout() -> bool {
+    true
+}
+
+fn default_run_migrations() -> bool {
+    true
+}
+
+fn default_retry_queue_max_attempts() -> u32 {
+    10
+}
+
+fn default_retry_queue_base_delay() -> u64 {
+    30
+}
+
+fn default_retry_queue_max_delay() -> u64 {
+    60 * 60
+}
+
+fn default_retry_queue_retention() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_strip_control_chars() -> bool {
+    true
+}
+
+fn default_time_zone() -> Tz {
+    Tz::UTC
+}
+
+fn default_send_allowed_origins() -> Vec<String> {
+    vec!["*".to_owned()]
+}
+
+fn default_job_ttl() -> u64 {
+    60 * 60
+}
+
+fn default_request_timeout() -> u64 {
+    60
+}
+
+fn default_compress_min_size() -> usize {
+    1024
+}
+
+fn default_static_max_age() -> u64 {
+    365 * 24 * 60 * 60
+}
+
+fn default_static_etag() -> bool {
+    true
+}
+
+fn default_static_last_modified() -> bool {
+    true
+}
+
+fn default_log_body_max_length() -> usize {
+    1024
+}
+
+fn default_log_max_file_mb() -> u64 {
+    100
+}
+
+fn default_log_rotation() -> LogRotation {
+    LogRotation::Daily
+}
+
+fn default_log_max_files() -> usize {
+    10
+}
+
+fn default_syslog_socket() -> String {
+    "/dev/log".to_owned()
+}
+
+// Credentials may have been revoked since, so they aren't trusted for long.
+const MAX_TENANT_CACHE_TTL: u64 = 5 * 60;
+
 impl PipeHubConfig {
     pub fn new() -> Result<Self> {
         let environment = Environment::new().prefix("pipehub").separator("__");
         let mut config = Config::new();
 
         config.merge(environment)?;
-        let config = config.try_into()?;
+        let mut config: PipeHubConfig = config.try_into()?;
+        if config.database_url.is_empty() {
+            config.database_url = config.assemble_database_url()?;
+        }
 
         Ok(config)
     }
 
+    fn assemble_database_url(&self) -> Result<String> {
+        let host = self.db_host.as_deref().ok_or_else(|| {
+            ConfigError::Message("Either database_url or db_host is required.".to_owned())
+        })?;
+        let mut url = Url::parse(&format!("postgres://{}", host))
+            .map_err(|e| ConfigError::Message(format!("Malformed db_host {}: {}.", host, e)))?;
+        url.set_port(self.db_port)
+            .map_err(|_| ConfigError::Message("db_port can't be set.".to_owned()))?;
+        if let Some(ref user) = self.db_user {
+            url.set_username(user)
+                .map_err(|_| ConfigError::Message("db_user can't be set.".to_owned()))?;
+        }
+        let password = match self.db_password_file {
+            Some(ref password_file) => Some(
+                fs::read_to_string(password_file)
+                    .map_err(|e| {
+                        ConfigError::Message(format!("Unable to read {}: {}.", password_file, e))
+                    })?
+                    // Files usually end with a newline, which is never part of the password.
+                    .trim_end_matches(&['\r', '\n'][..])
+                    .to_owned(),
+            ),
+            None => self.db_password.clone(),
+        };
+        if let Some(password) = password {
+            url.set_password(Some(&password))
+                .map_err(|_| ConfigError::Message("db_password can't be set.".to_owned()))?;
+        }
+        if let Some(ref name) = self.db_name {
+            url.set_path(&format!("/{}", name));
+        }
+
+        Ok(url.into_string())
+    }
+
+    pub fn log_sample_rate(&self) -> f64 {
+        match self.log_sample_rate {
+            Some(log_sample_rate) => log_sample_rate,
+            None if self.log_slow_requests_ms > 0 => 0.0,
+            None => 1.0,
+        }
+    }
+
+    // The database url with the SSL settings applied, which both diesel and sqlx understand.
+    pub fn connection_url(&self) -> Result<String> {
+        if self.database_ssl_mode.is_none() && self.database_ssl_root_cert.is_none() {
+            return Ok(self.database_url.clone());
+        }
+
+        let mut url = Url::parse(&self.database_url)
+            .map_err(|e| ConfigError::Message(format!("Malformed database url {}.", e)))?;
+        let mut pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(key, _)| key != "sslmode" && key != "sslrootcert")
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect();
+        if let Some(ssl_mode) = self.database_ssl_mode {
+            pairs.push(("sslmode".to_owned(), ssl_mode.as_str().to_owned()));
+        }
+        if let Some(ref root_cert) = self.database_ssl_root_cert {
+            // Fail at startup rather than on the first connection.
+            File::open(root_cert).map_err(|e| {
+                ConfigError::Message(format!("Unable to read {}: {}.", root_cert, e))
+            })?;
+            pairs.push(("sslrootcert".to_owned(), root_cert.clone()));
+        }
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+
+        Ok(url.into_string())
+    }
+
+    // Only plain lowercase names are accepted, so they never need quoting in SQL.
+    pub fn database_schema(&self) -> Result<Option<&str>> {
+        match self.database_schema.as_deref() {
+            Some(schema)
+                if schema.is_empty()
+                    || schema.starts_with(|c: char| c.is_ascii_digit())
+                    || !schema
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') =>
+            {
+                Err(ConfigError::Message(format!(
+                    "Malformed database schema {}, expected lowercase letters, digits and _.",
+                    schema
+                ))
+                .into())
+            }
+            schema => Ok(schema),
+        }
+    }
+
+    pub fn tenant_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.tenant_cache_ttl.min(MAX_TENANT_CACHE_TTL))
+    }
+
     pub fn bind_addr(&self) -> String {
         format!("{}:{}", self.host, self.port)
     }
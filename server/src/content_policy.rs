@@ -0,0 +1,59 @@
+use crate::config::PipeHubConfig;
+use crate::error::{Error, Result};
+use crate::logger::ApplicationLogger;
+use log::Level;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+const VIOLATION_MESSAGE: &str = "The message violates the content policy of the server.";
+
+// The strings of JSON payloads like template cards, each is checked on its own so a pattern
+// isn't defeated by the escaping of JSON.
+pub fn json_strings<'a>(value: &'a Value, strings: &mut Vec<&'a str>) {
+    match value {
+        Value::String(string) => strings.push(string),
+        Value::Array(values) => {
+            for value in values {
+                json_strings(value, strings);
+            }
+        }
+        Value::Object(object) => {
+            for value in object.values() {
+                json_strings(value, strings);
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+// Enforced by the operator for all tenants. Unlike the block lists of tenants, which the tenants
+// own, violations are refused with 422 and logged for audit with the pattern, never the content.
+// The texts are what's delivered, after templates are rendered and titles are prepended.
+pub fn check(
+    config: &PipeHubConfig,
+    logger: &ApplicationLogger,
+    request_id: Uuid,
+    tenant_id: i64,
+    texts: &[&str],
+) -> Result<()> {
+    let pattern = match config
+        .content_policy
+        .iter()
+        .find(|pattern| texts.iter().any(|text| pattern.is_match(text)))
+    {
+        Some(pattern) => pattern,
+        None => return Ok(()),
+    };
+    let mut properties = BTreeMap::new();
+    properties.insert("tenant_id".to_owned(), tenant_id.to_string());
+    properties.insert("pattern".to_owned(), pattern.as_str().to_owned());
+    logger.track_trace_with(
+        request_id,
+        Level::Warn,
+        "Rejected by the content policy.",
+        &properties,
+    );
+
+    Err(Error::Unprocessable(VIOLATION_MESSAGE))
+}
@@ -1,60 +1,44 @@
 use crate::error::{Error, Result};
-use crate::models::{Tenant, WechatWork};
+use crate::models::{PendingSend, ScheduledMessage, Template, Tenant, WechatWork};
+use crate::secret::SecretBox;
 use actix_http::Payload;
 use actix_web::{FromRequest, HttpRequest};
-use futures_util::future::{err, ok, BoxFuture, Ready};
-use sqlx::cursor::HasCursor;
-use sqlx::describe::Describe;
-use sqlx::executor::RefExecutor;
-use sqlx::postgres::PgCursor;
-use sqlx::{Cursor, Execute, Executor, PgPool, Postgres};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use futures_util::future::{err, ok, Ready};
+use log::{error, info};
+use sqlx::pool::PoolConnection;
+use sqlx::{Executor, PgConnection, PgPool, Transaction};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time;
+use uuid::Uuid;
+
+type PgTransaction = Transaction<PoolConnection<PgConnection>>;
 
 #[derive(Debug, Clone)]
 pub struct Pool {
     inner: PgPool,
+    health: Arc<Health>,
+    // WeChat secrets are sealed when written and opened when read.
+    secrets: Arc<SecretBox>,
+    schema: Option<Arc<str>>,
 }
 
-impl<'c> RefExecutor<'c> for &'c Pool {
-    type Database = Postgres;
-
-    fn fetch_by_ref<'q, E>(self, query: E) -> <Self::Database as HasCursor<'c, 'q>>::Cursor
-    where
-        E: Execute<'q, Self::Database>,
-    {
-        self.inner.fetch_by_ref(query)
-    }
+// The result of the periodic health check, connections going stale after a database restart
+// are tested and replaced on checkout.
+#[derive(Debug, Default)]
+struct Health {
+    unhealthy: AtomicBool,
+    failures: AtomicU64,
 }
 
-impl<'p> Executor for &'p Pool {
-    type Database = Postgres;
-
-    fn execute<'e, 'q: 'e, 'c: 'e, E: 'e>(
-        &'c mut self,
-        query: E,
-    ) -> BoxFuture<'e, std::result::Result<u64, sqlx::Error>>
-    where
-        E: Execute<'q, Self::Database>,
-    {
-        Box::pin(async move { (&self.inner).execute(query).await })
-    }
-
-    fn fetch<'e, 'q, E>(&'e mut self, query: E) -> <Self::Database as HasCursor<'_, 'q>>::Cursor
-    where
-        E: Execute<'q, Postgres>,
-    {
-        PgCursor::from_pool(&self.inner, query)
-    }
-
-    #[doc(hidden)]
-    fn describe<'e, 'q, E: 'e>(
-        &'e mut self,
-        query: E,
-    ) -> BoxFuture<'e, std::result::Result<Describe<Self::Database>, sqlx::Error>>
-    where
-        E: Execute<'q, Self::Database>,
-    {
-        Box::pin(async move { (&self.inner).describe(query).await })
-    }
+pub struct PoolStatus {
+    pub size: u32,
+    pub idle: usize,
+    pub healthy: bool,
+    pub failures: u64,
 }
 
 impl FromRequest for Pool {
@@ -73,17 +57,140 @@ impl FromRequest for Pool {
 }
 
 impl Pool {
-    pub async fn new(conn_str: &str) -> Result<Pool> {
+    pub async fn new(
+        conn_str: &str,
+        test_on_checkout: bool,
+        secrets: SecretBox,
+        schema: Option<&str>,
+    ) -> Result<Pool> {
         let num_cpus = num_cpus::get() as u32;
 
-        let inner = PgPool::builder().max_size(num_cpus).build(conn_str).await?;
+        let inner = PgPool::builder()
+            .max_size(num_cpus)
+            .test_on_acquire(test_on_checkout)
+            .build(conn_str)
+            .await?;
+
+        Ok(Pool {
+            inner,
+            health: Arc::new(Health::default()),
+            secrets: Arc::new(secrets),
+            schema: schema.map(Arc::from),
+        })
+    }
+
+    // sqlx can't run statements on new connections, so the search path is set on each one taken
+    // from the pool. It's only needed with a schema, which is a plain name and never quoted.
+    async fn connection(&self) -> Result<PoolConnection<PgConnection>> {
+        let mut connection = self.inner.acquire().await?;
+        if let Some(ref schema) = self.schema {
+            connection
+                .execute(format!("SET search_path TO {}", schema).as_str())
+                .await?;
+        }
+
+        Ok(connection)
+    }
+
+    async fn begin(&self) -> Result<PgTransaction> {
+        let mut tx = self.inner.begin().await?;
+        if let Some(ref schema) = self.schema {
+            tx.execute(format!("SET LOCAL search_path TO {}", schema).as_str())
+                .await?;
+        }
+
+        Ok(tx)
+    }
+
+    async fn check_connection(&self) -> Result<()> {
+        self.connection().await?.execute("SELECT 1").await?;
+
+        Ok(())
+    }
+
+    fn open_wechat(&self, mut wechat: WechatWork) -> Result<WechatWork> {
+        wechat.secret = self.secrets.open(&wechat.secret)?;
+        wechat.external_secret = wechat
+            .external_secret
+            .map(|secret| self.secrets.open(&secret))
+            .transpose()?;
+
+        Ok(wechat)
+    }
+
+    fn seal_wechat(&self, mut wechat: WechatWork) -> Result<WechatWork> {
+        wechat.secret = self.secrets.seal(&wechat.secret)?;
+        wechat.external_secret = wechat
+            .external_secret
+            .map(|secret| self.secrets.seal(&secret))
+            .transpose()?;
 
-        Ok(Pool { inner })
+        Ok(wechat)
+    }
+
+    // Seals the plain secrets and those of old keys with the current key, returning how many
+    // credentials are sealed again. Credentials changed meanwhile are left to the next startup.
+    pub async fn reseal_secrets(&self) -> Result<usize> {
+        let wechat_works = sqlx::query_as!(WechatWork, "SELECT * FROM wechat_works ORDER BY id")
+            .fetch_all(&mut self.connection().await?)
+            .await?;
+
+        let mut resealed = 0;
+        for stored in wechat_works {
+            let needs_resealing = self.secrets.needs_resealing(&stored.secret)
+                || stored
+                    .external_secret
+                    .as_deref()
+                    .map_or(false, |secret| self.secrets.needs_resealing(secret));
+            if !needs_resealing {
+                continue;
+            }
+            let sealed = self.seal_wechat(self.open_wechat(stored.clone())?)?;
+            resealed += sqlx::query!(
+                "UPDATE wechat_works
+                 SET secret          = $1,
+                     external_secret = $2
+                 WHERE id = $3 AND secret = $4",
+                sealed.secret,
+                sealed.external_secret,
+                stored.id,
+                stored.secret
+            )
+            .execute(&mut self.connection().await?)
+            .await? as usize;
+        }
+
+        Ok(resealed)
+    }
+
+    pub fn status(&self) -> PoolStatus {
+        PoolStatus {
+            size: self.inner.size(),
+            idle: self.inner.idle(),
+            healthy: !self.health.unhealthy.load(Ordering::Relaxed),
+            failures: self.health.failures.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn check_health(&self) {
+        match self.check_connection().await {
+            Ok(_) => {
+                if self.health.unhealthy.swap(false, Ordering::Relaxed) {
+                    info!("Database connections recovered.");
+                }
+            }
+            Err(e) => {
+                self.health.failures.fetch_add(1, Ordering::Relaxed);
+                if !self.health.unhealthy.swap(true, Ordering::Relaxed) {
+                    error!("Database health check failed {:?}.", e);
+                }
+            }
+        }
     }
 
     pub async fn find_tenant_by_id(&self, tenant_id: i64) -> Result<Option<Tenant>> {
         let tenant = sqlx::query_as!(Tenant, "SELECT * FROM tenants WHERE id = $1", tenant_id)
-            .fetch_optional(self)
+            .fetch_optional(&mut self.connection().await?)
             .await?;
 
         Ok(tenant)
@@ -95,7 +202,19 @@ impl Pool {
             "SELECT * FROM tenants WHERE github_id = $1",
             github_id
         )
-        .fetch_optional(self)
+        .fetch_optional(&mut self.connection().await?)
+        .await?;
+
+        Ok(tenant)
+    }
+
+    pub async fn find_tenant_by_github_login(&self, github_login: &str) -> Result<Option<Tenant>> {
+        let tenant = sqlx::query_as!(
+            Tenant,
+            "SELECT * FROM tenants WHERE github_login = $1",
+            github_login
+        )
+        .fetch_optional(&mut self.connection().await?)
         .await?;
 
         Ok(tenant)
@@ -103,34 +222,154 @@ impl Pool {
 
     pub async fn find_tenant_by_app_id(&self, app_id: i64) -> Result<Option<Tenant>> {
         let tenant = sqlx::query_as!(Tenant, "SELECT * FROM tenants WHERE app_id = $1", app_id)
-            .fetch_optional(self)
+            .fetch_optional(&mut self.connection().await?)
             .await?;
 
         Ok(tenant)
     }
 
+    // Returns the existing tenant of the GitHub user if any, so concurrent first logins
+    // don't fail or create duplicates.
     pub async fn insert_tenant(&self, tenant: Tenant) -> Result<Tenant> {
         let tenant = sqlx::query_as!(
             Tenant,
-            "INSERT INTO tenants (app_id, github_login, github_id) VALUES ($1, $2, $3) RETURNING *",
+            "INSERT INTO tenants (app_id, github_login, github_id) VALUES ($1, $2, $3)
+             ON CONFLICT (github_id) DO UPDATE SET github_login = EXCLUDED.github_login
+             RETURNING *",
             tenant.app_id,
             tenant.github_login,
             tenant.github_id
         )
-        .fetch_one(self)
+        .fetch_one(&mut self.connection().await?)
         .await?;
 
         Ok(tenant)
     }
 
     pub async fn update_tenant(&self, tenant: Tenant) -> Result<()> {
+        let mut tx = self.begin().await?;
+        Pool::update_tenant_in(&mut tx, tenant).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Updates the tenant and writes its credentials in one transaction, the ones with an id are
+    // updated and the others inserted within the capacity.
+    pub async fn import_tenant(
+        &self,
+        tenant: Tenant,
+        wechats: Vec<WechatWork>,
+        capacity: impl Fn(&Tenant) -> usize,
+    ) -> Result<()> {
+        let mut tx = self.begin().await?;
+        Pool::update_tenant_in(&mut tx, tenant).await?;
+        for wechat in wechats {
+            if wechat.id == 0 {
+                self.insert_wechat_in(&mut tx, wechat, &capacity)
+                    .await?
+                    .ok_or(Error::Conflict(
+                        "Too many WeChat credentials, please remove some before importing.",
+                    ))?;
+            } else {
+                self.update_wechat_in(&mut tx, wechat).await?;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn update_tenant_in(tx: &mut PgTransaction, tenant: Tenant) -> Result<()> {
         sqlx::query!(
-            "UPDATE tenants SET app_id = $1, block_list = $2 WHERE id = $3",
+            "UPDATE tenants
+             SET app_id               = $1,
+                 block_list           = $2,
+                 default_message_type = $3,
+                 quiet_hours_start    = $4,
+                 quiet_hours_end      = $5,
+                 time_zone            = $6,
+                 prefix               = $7,
+                 suffix               = $8,
+                 use_outbox           = $9,
+                 log_bodies_until     = $10,
+                 quiet_days           = $11,
+                 drop_in_quiet_hours  = $12,
+                 rate_limits          = $13,
+                 response_format      = $14,
+                 default_agent_id     = $15,
+                 callback_token       = $16,
+                 callback_aes_key     = $17,
+                 allow_list           = $18,
+                 append_request_id    = $19,
+                 matrix_homeserver    = $20,
+                 matrix_access_token  = $21,
+                 matrix_room_id       = $22,
+                 callback_forward_url = $23,
+                 smtp_host            = $24,
+                 smtp_port            = $25,
+                 smtp_tls             = $26,
+                 smtp_username        = $27,
+                 smtp_password        = $28,
+                 email_from           = $29,
+                 email_to             = $30,
+                 default_to_user      = $31,
+                 default_to_party     = $32,
+                 default_to_tag       = $33
+             WHERE id = $34",
             tenant.app_id,
             tenant.block_list,
+            tenant.default_message_type,
+            tenant.quiet_hours_start,
+            tenant.quiet_hours_end,
+            tenant.time_zone,
+            tenant.prefix,
+            tenant.suffix,
+            tenant.use_outbox,
+            tenant.log_bodies_until,
+            tenant.quiet_days,
+            tenant.drop_in_quiet_hours,
+            tenant.rate_limits,
+            tenant.response_format,
+            tenant.default_agent_id,
+            tenant.callback_token,
+            tenant.callback_aes_key,
+            tenant.allow_list,
+            tenant.append_request_id,
+            tenant.matrix_homeserver,
+            tenant.matrix_access_token,
+            tenant.matrix_room_id,
+            tenant.callback_forward_url,
+            tenant.smtp_host,
+            tenant.smtp_port,
+            tenant.smtp_tls,
+            tenant.smtp_username,
+            tenant.smtp_password,
+            tenant.email_from,
+            tenant.email_to,
+            tenant.default_to_user,
+            tenant.default_to_party,
+            tenant.default_to_tag,
             tenant.id
         )
-        .execute(self)
+        .execute(tx)
+        .await?;
+
+        Ok(())
+    }
+
+    // Kept out of `update_tenant`, so tenants updating their settings never touch it.
+    pub async fn update_max_wechat_works(
+        &self,
+        tenant_id: i64,
+        max_wechat_works: Option<i32>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE tenants SET max_wechat_works = $1 WHERE id = $2",
+            max_wechat_works,
+            tenant_id
+        )
+        .execute(&mut self.connection().await?)
         .await?;
 
         Ok(())
@@ -139,44 +378,514 @@ impl Pool {
     pub async fn find_wechat_by_id(&self, tenant_id: i64) -> Result<Option<WechatWork>> {
         let wechat_work = sqlx::query_as!(
             WechatWork,
-            "SELECT * FROM wechat_works WHERE tenant_id = $1",
+            "SELECT * FROM wechat_works WHERE tenant_id = $1 ORDER BY id LIMIT 1",
+            tenant_id
+        )
+        .fetch_optional(&mut self.connection().await?)
+        .await?;
+
+        wechat_work
+            .map(|wechat_work| self.open_wechat(wechat_work))
+            .transpose()
+    }
+
+    pub async fn find_wechats_by_tenant_id(&self, tenant_id: i64) -> Result<Vec<WechatWork>> {
+        let wechat_works = sqlx::query_as!(
+            WechatWork,
+            "SELECT * FROM wechat_works WHERE tenant_id = $1 ORDER BY id",
             tenant_id
         )
-        .fetch_optional(self)
+        .fetch_all(&mut self.connection().await?)
         .await?;
 
+        wechat_works
+            .into_iter()
+            .map(|wechat_work| self.open_wechat(wechat_work))
+            .collect()
+    }
+
+    // Inserts unless the tenant has as many credentials as the capacity already, returning
+    // none then.
+    pub async fn insert_wechat(
+        &self,
+        new_wechat: WechatWork,
+        capacity: impl Fn(&Tenant) -> usize,
+    ) -> Result<Option<WechatWork>> {
+        let mut tx = self.begin().await?;
+        let wechat_work = self
+            .insert_wechat_in(&mut tx, new_wechat, &capacity)
+            .await?;
+        tx.commit().await?;
+
         Ok(wechat_work)
     }
 
-    pub async fn upsert_wechat(&self, new_wechat: WechatWork) -> Result<()> {
-        sqlx::query!(
-            "INSERT INTO wechat_works (tenant_id, corp_id, agent_id, secret)
-             VALUES ($1, $2, $3, $4)
-             ON CONFLICT (tenant_id)
-                 DO UPDATE SET corp_id  = $2,
-                               agent_id = $3,
-                               secret   = $4
-            ",
+    pub async fn update_wechat(&self, wechat: WechatWork) -> Result<()> {
+        let mut tx = self.begin().await?;
+        self.update_wechat_in(&mut tx, wechat).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Writes all the credentials or none, the matched ones are updated and the others inserted.
+    // The index of the first one failing is returned with its error, the rest are rolled back.
+    pub async fn write_wechats(
+        &self,
+        wechats: Vec<(WechatWork, bool)>,
+        capacity: impl Fn(&Tenant) -> usize,
+    ) -> Result<Option<(usize, Error)>> {
+        let mut tx = self.begin().await?;
+        for (index, (wechat, matched)) in wechats.into_iter().enumerate() {
+            let written = if matched {
+                self.update_wechat_in(&mut tx, wechat).await
+            } else {
+                match self.insert_wechat_in(&mut tx, wechat, &capacity).await {
+                    Ok(Some(_)) => Ok(()),
+                    Ok(None) => Err(Error::Conflict(
+                        "Too many WeChat credentials for the tenant.",
+                    )),
+                    Err(e) => Err(e),
+                }
+            };
+            if let Err(e) = written {
+                tx.rollback().await?;
+                return Ok(Some((index, e)));
+            }
+        }
+        tx.commit().await?;
+
+        Ok(None)
+    }
+
+    // The tenant is locked until the transaction ends, so concurrent inserts are counted one
+    // after another and can't go past the capacity together.
+    async fn insert_wechat_in(
+        &self,
+        tx: &mut PgTransaction,
+        new_wechat: WechatWork,
+        capacity: &impl Fn(&Tenant) -> usize,
+    ) -> Result<Option<WechatWork>> {
+        let tenant = sqlx::query_as!(
+            Tenant,
+            "SELECT * FROM tenants WHERE id = $1 FOR UPDATE",
+            new_wechat.tenant_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(Error::NotFound("Unknown tenant."))?;
+        let row = sqlx::query!(
+            "SELECT COUNT(*) AS count FROM wechat_works WHERE tenant_id = $1",
+            tenant.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if row.count.unwrap_or_default() as usize >= capacity(&tenant) {
+            return Ok(None);
+        }
+
+        let new_wechat = self.seal_wechat(new_wechat)?;
+        let wechat_work = sqlx::query_as!(
+            WechatWork,
+            "INSERT INTO wechat_works (tenant_id, corp_id, agent_id, secret, external_secret) VALUES ($1, $2, $3, $4, $5) RETURNING *",
             new_wechat.tenant_id,
             new_wechat.corp_id,
             new_wechat.agent_id,
-            new_wechat.secret
+            new_wechat.secret,
+            new_wechat.external_secret
         )
-        .execute(self)
+        .fetch_one(tx)
+        .await?;
+
+        self.open_wechat(wechat_work).map(Some)
+    }
+
+    async fn update_wechat_in(&self, tx: &mut PgTransaction, wechat: WechatWork) -> Result<()> {
+        let wechat = self.seal_wechat(wechat)?;
+        sqlx::query!(
+            "UPDATE wechat_works
+             SET corp_id         = $1,
+                 agent_id        = $2,
+                 secret          = $3,
+                 external_secret = $4
+             WHERE id = $5 AND tenant_id = $6",
+            wechat.corp_id,
+            wechat.agent_id,
+            wechat.secret,
+            wechat.external_secret,
+            wechat.id,
+            wechat.tenant_id
+        )
+        .execute(tx)
         .await?;
 
         Ok(())
     }
 
+    pub async fn delete_wechat(&self, tenant_id: i64, id: i64) -> Result<bool> {
+        let deleted = sqlx::query!(
+            "DELETE FROM wechat_works WHERE id = $1 AND tenant_id = $2",
+            id,
+            tenant_id
+        )
+        .execute(&mut self.connection().await?)
+        .await?;
+
+        Ok(deleted > 0)
+    }
+
     pub async fn find_wechat_by_app_id(&self, app_id: i64) -> Result<Option<WechatWork>> {
         let wechat_work = sqlx::query_as!(
             WechatWork,
-            "SELECT wechat_works.* FROM wechat_works LEFT JOIN tenants ON wechat_works.tenant_id = tenants.id WHERE app_id = $1",
+            "SELECT wechat_works.* FROM wechat_works LEFT JOIN tenants ON wechat_works.tenant_id = tenants.id WHERE app_id = $1 ORDER BY wechat_works.id LIMIT 1",
             app_id
         )
-        .fetch_optional(self)
+        .fetch_optional(&mut self.connection().await?)
         .await?;
 
-        Ok(wechat_work)
+        wechat_work
+            .map(|wechat_work| self.open_wechat(wechat_work))
+            .transpose()
+    }
+
+    pub async fn find_templates_by_tenant_id(&self, tenant_id: i64) -> Result<Vec<Template>> {
+        let templates = sqlx::query_as!(
+            Template,
+            "SELECT * FROM templates WHERE tenant_id = $1 ORDER BY name",
+            tenant_id
+        )
+        .fetch_all(&mut self.connection().await?)
+        .await?;
+
+        Ok(templates)
+    }
+
+    pub async fn find_template(&self, tenant_id: i64, name: &str) -> Result<Option<Template>> {
+        let template = sqlx::query_as!(
+            Template,
+            "SELECT * FROM templates WHERE tenant_id = $1 AND name = $2",
+            tenant_id,
+            name
+        )
+        .fetch_optional(&mut self.connection().await?)
+        .await?;
+
+        Ok(template)
+    }
+
+    pub async fn upsert_template(&self, template: Template) -> Result<Template> {
+        let template = sqlx::query_as!(
+            Template,
+            "INSERT INTO templates (tenant_id, name, content, message_type) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (tenant_id, name) DO UPDATE SET content = EXCLUDED.content, message_type = EXCLUDED.message_type
+             RETURNING *",
+            template.tenant_id,
+            template.name,
+            template.content,
+            template.message_type
+        )
+        .fetch_one(&mut self.connection().await?)
+        .await?;
+
+        Ok(template)
+    }
+
+    pub async fn delete_template(&self, tenant_id: i64, name: &str) -> Result<bool> {
+        let deleted = sqlx::query!(
+            "DELETE FROM templates WHERE tenant_id = $1 AND name = $2",
+            tenant_id,
+            name
+        )
+        .execute(&mut self.connection().await?)
+        .await?;
+
+        Ok(deleted > 0)
+    }
+
+    pub async fn insert_scheduled_message(
+        &self,
+        tenant_id: i64,
+        request_id: Uuid,
+        payload: String,
+        deliver_at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO scheduled_messages (tenant_id, request_id, payload, deliver_at) VALUES ($1, $2, $3, $4)",
+            tenant_id,
+            request_id,
+            payload,
+            deliver_at
+        )
+        .execute(&mut self.connection().await?)
+        .await?;
+
+        Ok(())
+    }
+
+    // Claims the next due message, including one left in delivering by a crashed instance. It's
+    // removed once delivered, so that it's delivered only once across instances.
+    pub async fn claim_due_scheduled_message(&self) -> Result<Option<ScheduledMessage>> {
+        let message = sqlx::query_as!(
+            ScheduledMessage,
+            "UPDATE scheduled_messages
+             SET status     = 'delivering',
+                 updated_at = NOW()
+             WHERE id IN (SELECT id
+                          FROM scheduled_messages
+                          WHERE (status = 'scheduled' AND deliver_at <= NOW())
+                             OR (status = 'delivering' AND updated_at < NOW() - INTERVAL '5 minutes')
+                          ORDER BY deliver_at
+                          LIMIT 1 FOR UPDATE SKIP LOCKED)
+             RETURNING *"
+        )
+        .fetch_optional(&mut self.connection().await?)
+        .await?;
+
+        Ok(message)
+    }
+
+    pub async fn delete_scheduled_message(&self, id: i64) -> Result<()> {
+        sqlx::query!("DELETE FROM scheduled_messages WHERE id = $1", id)
+            .execute(&mut self.connection().await?)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn count_scheduled_messages(&self) -> Result<i64> {
+        let row = sqlx::query!("SELECT COUNT(*) AS depth FROM scheduled_messages")
+            .fetch_one(&mut self.connection().await?)
+            .await?;
+
+        Ok(row.depth.unwrap_or_default())
+    }
+
+    // Parked sends have failed their first attempt already, sends of the outbox have none.
+    pub async fn insert_pending_send(
+        &self,
+        tenant_id: i64,
+        request_id: Uuid,
+        payload: String,
+        attempts: i32,
+        error_message: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO pending_sends (tenant_id, request_id, payload, attempts, error_message, next_attempt_at)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            tenant_id,
+            request_id,
+            payload,
+            attempts,
+            error_message,
+            next_attempt_at
+        )
+        .execute(&mut self.connection().await?)
+        .await?;
+
+        Ok(())
+    }
+
+    // Sending ones are counted too, as they are put back if WeChat is too busy.
+    pub async fn count_waiting_pending_sends(&self) -> Result<i64> {
+        let row = sqlx::query!(
+            "SELECT COUNT(*) AS depth FROM pending_sends WHERE status IN ('pending', 'sending')"
+        )
+        .fetch_one(&mut self.connection().await?)
+        .await?;
+
+        Ok(row.depth.unwrap_or_default())
+    }
+
+    // Claims the next due send, including one left in sending by a crashed instance. One at a
+    // time, so no claim waits behind slow attempts until it goes stale.
+    pub async fn claim_pending_send(&self) -> Result<Option<PendingSend>> {
+        let send = sqlx::query_as!(
+            PendingSend,
+            "UPDATE pending_sends
+             SET status     = 'sending',
+                 updated_at = NOW()
+             WHERE id IN (SELECT id
+                          FROM pending_sends
+                          WHERE (status = 'pending' AND next_attempt_at <= NOW())
+                             OR (status = 'sending' AND updated_at < NOW() - INTERVAL '5 minutes')
+                          ORDER BY next_attempt_at
+                          LIMIT 1 FOR UPDATE SKIP LOCKED)
+             RETURNING *"
+        )
+        .fetch_optional(&mut self.connection().await?)
+        .await?;
+
+        Ok(send)
+    }
+
+    pub async fn update_pending_send(
+        &self,
+        id: i64,
+        status: &str,
+        attempts: i32,
+        error_message: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE pending_sends
+             SET status          = $1,
+                 attempts        = $2,
+                 error_message   = $3,
+                 next_attempt_at = $4,
+                 updated_at      = NOW()
+             WHERE id = $5",
+            status,
+            attempts,
+            error_message,
+            next_attempt_at,
+            id
+        )
+        .execute(&mut self.connection().await?)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn find_pending_send(&self, request_id: Uuid) -> Result<Option<PendingSend>> {
+        let send = sqlx::query_as!(
+            PendingSend,
+            "SELECT * FROM pending_sends WHERE request_id = $1",
+            request_id
+        )
+        .fetch_optional(&mut self.connection().await?)
+        .await?;
+
+        Ok(send)
+    }
+
+    // Delivered and dead sends are only kept for their status to be looked up.
+    pub async fn purge_pending_sends(&self, finished_before: DateTime<Utc>) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM pending_sends WHERE status IN ('delivered', 'dead') AND updated_at < $1",
+            finished_before
+        )
+        .execute(&mut self.connection().await?)
+        .await?;
+
+        Ok(())
+    }
+
+    // Returns false if the token has been used before.
+    pub async fn use_send_token(&self, nonce: Uuid, expires_at: DateTime<Utc>) -> Result<bool> {
+        let inserted = sqlx::query!(
+            "INSERT INTO used_send_tokens (nonce, expires_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            nonce,
+            expires_at
+        )
+        .execute(&mut self.connection().await?)
+        .await?;
+
+        Ok(inserted > 0)
+    }
+
+    pub async fn purge_used_send_tokens(&self) -> Result<()> {
+        sqlx::query!("DELETE FROM used_send_tokens WHERE expires_at < NOW()")
+            .execute(&mut self.connection().await?)
+            .await?;
+
+        Ok(())
+    }
+}
+
+pub async fn run_health_check(pool: Pool) {
+    let mut delay = time::interval(Duration::from_secs(10));
+    loop {
+        delay.tick().await;
+        pool.check_health().await;
+    }
+}
+
+const TENANT_UNAVAILABLE_MESSAGE: &str =
+    "The database is unavailable and the tenant isn't seen recently, please retry later.";
+const TENANT_UNAVAILABLE_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+fn tenant_unavailable() -> Error {
+    Error::Overloaded(TENANT_UNAVAILABLE_MESSAGE, TENANT_UNAVAILABLE_RETRY_AFTER)
+}
+
+// Remembers the tenants and credentials sends have looked up, so the recently seen tenants can
+// still send during short database outages. It's only read while the database is failing, there's
+// at most one entry of each tenant.
+pub struct TenantCache {
+    ttl: Duration,
+    tenants: DashMap<i64, (Instant, Tenant)>,
+    wechats: DashMap<i64, (Instant, Vec<WechatWork>)>,
+}
+
+impl TenantCache {
+    // Disabled if the ttl is zero.
+    pub fn new(ttl: Duration) -> Self {
+        TenantCache {
+            ttl,
+            tenants: DashMap::new(),
+            wechats: DashMap::new(),
+        }
+    }
+
+    fn is_fresh(&self, cached_at: Instant) -> bool {
+        cached_at.elapsed() < self.ttl
+    }
+
+    pub async fn find_tenant_by_app_id(&self, pool: &Pool, app_id: i64) -> Result<Option<Tenant>> {
+        if self.ttl == Duration::from_secs(0) {
+            return pool.find_tenant_by_app_id(app_id).await;
+        }
+        let cached = self
+            .tenants
+            .get(&app_id)
+            .filter(|cached| self.is_fresh(cached.0))
+            .map(|cached| cached.1.clone());
+        // Waiting for connections to time out would only delay the sends.
+        if let Some(tenant) = cached.clone().filter(|_| !pool.status().healthy) {
+            return Ok(Some(tenant));
+        }
+
+        match pool.find_tenant_by_app_id(app_id).await {
+            Ok(Some(tenant)) => {
+                self.tenants
+                    .insert(app_id, (Instant::now(), tenant.clone()));
+                Ok(Some(tenant))
+            }
+            Ok(None) => {
+                self.tenants.remove(&app_id);
+                Ok(None)
+            }
+            Err(Error::DataAccess(_)) => cached.map(Some).ok_or_else(tenant_unavailable),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn find_wechats_by_tenant_id(
+        &self,
+        pool: &Pool,
+        tenant_id: i64,
+    ) -> Result<Vec<WechatWork>> {
+        if self.ttl == Duration::from_secs(0) {
+            return pool.find_wechats_by_tenant_id(tenant_id).await;
+        }
+        let cached = self
+            .wechats
+            .get(&tenant_id)
+            .filter(|cached| self.is_fresh(cached.0))
+            .map(|cached| cached.1.clone());
+        if let Some(wechats) = cached.clone().filter(|_| !pool.status().healthy) {
+            return Ok(wechats);
+        }
+
+        match pool.find_wechats_by_tenant_id(tenant_id).await {
+            Ok(wechats) => {
+                self.wechats
+                    .insert(tenant_id, (Instant::now(), wechats.clone()));
+                Ok(wechats)
+            }
+            Err(Error::DataAccess(_)) => cached.ok_or_else(tenant_unavailable),
+            Err(e) => Err(e),
+        }
     }
 }
@@ -0,0 +1,314 @@
+use crate::config::RetryPolicy;
+use crate::error::{Error, Result};
+use crate::logger::ApplicationLogger;
+use crate::models::Tenant;
+use crate::send::{self, Delivery, OutboundLimiter};
+use crate::util;
+use lettre::message::{header, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio02Executor};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::time;
+use uuid::Uuid;
+
+const MAX_RECIPIENTS: usize = 20;
+const DEFAULT_SUBJECT: &str = "PipeHub";
+
+// How the connection to the SMTP server is secured, plain connections aren't supported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpTls {
+    // Upgrades the connection with STARTTLS, usually on port 587.
+    StartTls,
+    // Connects with TLS, usually on port 465.
+    Implicit,
+}
+
+impl FromStr for SmtpTls {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "starttls" => Ok(SmtpTls::StartTls),
+            "implicit" => Ok(SmtpTls::Implicit),
+            _ => Err(Error::User(
+                "Unknown SMTP TLS, expected starttls or implicit.",
+            )),
+        }
+    }
+}
+
+impl SmtpTls {
+    fn default_port(self) -> u16 {
+        match self {
+            SmtpTls::StartTls => 587,
+            SmtpTls::Implicit => 465,
+        }
+    }
+}
+
+// Plain text, with the HTML of markdown as the alternative.
+pub struct EmailMessage {
+    subject: String,
+    text: String,
+    html: Option<String>,
+}
+
+impl EmailMessage {
+    pub fn text(subject: Option<&str>, content: String) -> Self {
+        EmailMessage {
+            subject: subject.unwrap_or(DEFAULT_SUBJECT).to_owned(),
+            text: content,
+            html: None,
+        }
+    }
+
+    pub fn markdown(subject: Option<&str>, content: &str) -> Self {
+        EmailMessage {
+            subject: subject.unwrap_or(DEFAULT_SUBJECT).to_owned(),
+            text: util::strip_markdown(content),
+            html: Some(util::markdown_to_html(content)),
+        }
+    }
+}
+
+pub struct EmailChannel {
+    host: String,
+    port: u16,
+    tls: SmtpTls,
+    credentials: Option<Credentials>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+fn parse_mailbox(mailbox: &str) -> Result<Mailbox> {
+    mailbox
+        .trim()
+        .parse()
+        .map_err(|_| Error::User("Malformed email address."))
+}
+
+fn parse_recipients(to: &str) -> Result<Vec<Mailbox>> {
+    let recipients = to
+        .split(',')
+        .filter(|recipient| !recipient.trim().is_empty())
+        .map(parse_mailbox)
+        .collect::<Result<Vec<Mailbox>>>()?;
+    if recipients.is_empty() || recipients.len() > MAX_RECIPIENTS {
+        return Err(Error::User(
+            "Emails are sent to 1 to 20 recipients, separated by commas.",
+        ));
+    }
+
+    Ok(recipients)
+}
+
+pub fn validate_email(tenant: &Tenant) -> Result<()> {
+    channel_of(tenant)?;
+
+    Ok(())
+}
+
+// The channel is configured once the host, the sender and the recipients are.
+pub fn channel_of(tenant: &Tenant) -> Result<Option<EmailChannel>> {
+    let (host, from, to) = match (&tenant.smtp_host, &tenant.email_from, &tenant.email_to) {
+        (Some(host), Some(from), Some(to)) => (host, from, to),
+        (None, None, None) if tenant.smtp_username.is_none() && tenant.smtp_password.is_none() => {
+            return Ok(None)
+        }
+        _ => {
+            return Err(Error::User(
+                "SMTP host, sender and recipients of emails are all required.",
+            ))
+        }
+    };
+    if host.contains(|c: char| c.is_whitespace() || c == '/' || c == ':') {
+        return Err(Error::User(
+            "The SMTP host must be a host name like smtp.example.com.",
+        ));
+    }
+    let tls = tenant
+        .smtp_tls
+        .as_deref()
+        .map(str::parse::<SmtpTls>)
+        .transpose()?
+        .unwrap_or(SmtpTls::StartTls);
+    let port = match tenant.smtp_port {
+        Some(port) if port <= 0 || port > i32::from(u16::MAX) => {
+            return Err(Error::User("The SMTP port must be between 1 and 65535."))
+        }
+        Some(port) => port as u16,
+        None => tls.default_port(),
+    };
+    let credentials = match (&tenant.smtp_username, &tenant.smtp_password) {
+        (Some(username), Some(password)) => {
+            Some(Credentials::new(username.clone(), password.clone()))
+        }
+        (None, None) => None,
+        _ => {
+            return Err(Error::User(
+                "Both username and password of SMTP are required.",
+            ))
+        }
+    };
+
+    Ok(Some(EmailChannel {
+        host: host.clone(),
+        port,
+        tls,
+        credentials,
+        from: parse_mailbox(from)?,
+        to: parse_recipients(to)?,
+    }))
+}
+
+fn build_email(channel: &EmailChannel, message: &EmailMessage) -> Result<Message> {
+    let mut builder = Message::builder()
+        .from(channel.from.clone())
+        .subject(message.subject.clone());
+    for to in &channel.to {
+        builder = builder.to(to.clone());
+    }
+    let plain = SinglePart::builder()
+        .header(header::ContentType(
+            "text/plain; charset=utf-8".parse().unwrap(),
+        ))
+        .body(message.text.clone());
+    let email = match message.html {
+        Some(ref html) => builder.multipart(
+            MultiPart::alternative().singlepart(plain).singlepart(
+                SinglePart::builder()
+                    .header(header::ContentType(
+                        "text/html; charset=utf-8".parse().unwrap(),
+                    ))
+                    .body(html.clone()),
+            ),
+        ),
+        None => builder.singlepart(plain),
+    };
+
+    email.map_err(|e| Error::Unexpected(format!("Unable to build the email {:?}", e)))
+}
+
+fn transport(
+    channel: &EmailChannel,
+    timeout: Duration,
+) -> Result<AsyncSmtpTransport<Tokio02Executor>> {
+    let builder = match channel.tls {
+        SmtpTls::StartTls => AsyncSmtpTransport::<Tokio02Executor>::starttls_relay(&channel.host),
+        SmtpTls::Implicit => AsyncSmtpTransport::<Tokio02Executor>::relay(&channel.host),
+    }
+    .map_err(|e| Error::Dependency(format!("Unable to set up TLS for SMTP {:?}", e)))?
+    .port(channel.port)
+    .timeout(Some(timeout));
+    let builder = match channel.credentials {
+        Some(ref credentials) => builder.credentials(credentials.clone()),
+        None => builder,
+    };
+
+    Ok(builder.build())
+}
+
+// Sends the email, backing off on failures. A new connection is made for each attempt, so
+// dropped connections are retried like other failures.
+pub async fn deliver(
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    channel: &EmailChannel,
+    message: &EmailMessage,
+    retry_policy: RetryPolicy,
+) -> Result<Delivery> {
+    let email = build_email(channel, message)?;
+
+    let mut retry_count = 0;
+    let mut retry_permit = None;
+    loop {
+        if retry_policy.remaining() == Some(Duration::from_secs(0)) {
+            return Err(Error::Timeout(send::DEADLINE_MESSAGE));
+        }
+        let e = match send_email(
+            limiter,
+            request_id,
+            logger,
+            channel,
+            &email,
+            retry_policy.timeout(),
+        )
+        .await
+        {
+            Ok(()) => {
+                return Ok(Delivery {
+                    retry_count,
+                    msgid: None,
+                    raw_response: None,
+                    invalid_targets: Default::default(),
+                })
+            }
+            Err(e @ Error::Overloaded(..)) => return Err(e),
+            // The server may have taken the email, retrying could send it twice.
+            Err(e @ Error::Timeout(_)) => return Err(e),
+            Err(e) => e,
+        };
+        if retry_count >= retry_policy.max_retries {
+            return Err(e);
+        }
+
+        let backoff = retry_policy.backoff(retry_count);
+        if let Some(remaining) = retry_policy.remaining() {
+            if backoff >= remaining {
+                return Err(Error::Timeout(send::DEADLINE_MESSAGE));
+            }
+        }
+        // Held until the send is done, the last error is returned once too many are retrying.
+        if retry_permit.is_none() {
+            retry_permit = Some(limiter.try_retry().ok_or(e)?);
+        }
+        retry_count += 1;
+        time::delay_for(backoff).await;
+    }
+}
+
+async fn send_email(
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    channel: &EmailChannel,
+    email: &Message,
+    timeout: Duration,
+) -> Result<()> {
+    let _permit = limiter.acquire(request_id, logger).await?;
+    let start = Instant::now();
+    let mailer = transport(channel, timeout)?;
+    let result = time::timeout(timeout, mailer.send(email.clone())).await;
+    let (result, result_code) = match result {
+        Ok(Ok(response)) => (Ok(()), response.code().to_string()),
+        Ok(Err(e)) => {
+            let result_code = e.to_string();
+            (
+                Err(Error::Dependency(format!(
+                    "Unable to send the email {}",
+                    result_code
+                ))),
+                result_code,
+            )
+        }
+        Err(_) => (
+            Err(Error::Timeout("The SMTP server didn't reply in time.")),
+            "timeout".to_owned(),
+        ),
+    };
+
+    logger.track_dependency(
+        request_id,
+        &format!("SMTP {}", channel.host),
+        "SMTP",
+        start.elapsed(),
+        &channel.host,
+        &result_code,
+        &format!("{}:{}", channel.host, channel.port),
+        result.is_ok(),
+    );
+
+    result
+}
@@ -1,8 +1,9 @@
 use actix_http::body::Body;
-use actix_http::http::StatusCode;
+use actix_http::http::{header, HeaderValue, StatusCode};
 use actix_http::{Response, ResponseError};
 use std::fmt;
 use std::fmt::Display;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum Error {
@@ -11,8 +12,26 @@ pub enum Error {
     Execution(String),
     Io(std::io::Error),
     Dependency(String),
+    // WeChat replied the error code and message.
+    WeChat(i64, String),
     Unexpected(String),
     User(&'static str),
+    // Not signed in.
+    Unauthorized(&'static str),
+    Forbidden(&'static str),
+    NotFound(&'static str),
+    Conflict(&'static str),
+    // Well formed, but refused for what it says.
+    Unprocessable(&'static str),
+    // The resource existed but can't be acted on any longer.
+    Gone(&'static str),
+    Timeout(&'static str),
+    // We are too busy to take more, the client may retry after the duration.
+    Overloaded(&'static str, Duration),
+    // The dependency asks us to retry after the duration.
+    Throttled(Duration),
+    // The tenant sends faster than allowed, the client may retry after the duration.
+    RateLimited(Duration),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -27,12 +46,30 @@ impl ResponseError for Error {
     fn status_code(&self) -> StatusCode {
         match self {
             Error::User(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Gone(_) => StatusCode::GONE,
+            Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::Throttled(_) | Error::Overloaded(..) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> Response<Body> {
         let mut resp = Response::new(self.status_code());
+        if let Error::Throttled(retry_after)
+        | Error::Overloaded(_, retry_after)
+        | Error::RateLimited(retry_after) = self
+        {
+            resp.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs().max(1)),
+            );
+        }
         resp.extensions_mut().insert(self.to_string());
         resp.set_body(Body::Empty)
     }
@@ -100,6 +137,12 @@ impl std::convert::From<reqwest::Error> for Error {
     }
 }
 
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        Error::Unexpected(format!("{:?}", e))
+    }
+}
+
 impl From<sqlx::Error> for Error {
     fn from(e: sqlx::Error) -> Self {
         Error::DataAccess(e.to_string())
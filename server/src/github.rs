@@ -13,26 +13,136 @@ lazy_static! {
 pub struct GitHubClient {
     client_id: String,
     client_secret: String,
-    callback_url: Url,
+    // The first one is used unless there are more.
+    callback_urls: Vec<Url>,
+    allowed_orgs: Vec<String>,
+    // (org, team slug)
+    allowed_teams: Vec<(String, String)>,
 }
 
 impl GitHubClient {
-    pub fn new(client_id: String, client_secret: String, callback_url: impl Into<String>) -> Self {
+    pub fn new(
+        client_id: String,
+        client_secret: String,
+        callback_urls: Vec<String>,
+        allowed_orgs: Vec<String>,
+        allowed_teams: Vec<String>,
+    ) -> Self {
+        let allowed_teams = allowed_teams
+            .iter()
+            .map(|team| {
+                let mut parts = team.splitn(2, '/');
+                match (parts.next(), parts.next()) {
+                    (Some(org), Some(slug)) if !org.is_empty() && !slug.is_empty() => {
+                        (org.to_owned(), slug.to_owned())
+                    }
+                    _ => panic!("Malformed team {}, expected org/team-slug.", team),
+                }
+            })
+            .collect();
+
         GitHubClient {
             client_id,
             client_secret,
-            callback_url: Url::from_str(&callback_url.into()).expect("Malformed callback url."),
+            callback_urls: callback_urls
+                .iter()
+                .map(|callback_url| {
+                    Url::from_str(callback_url)
+                        .ok()
+                        .filter(Url::has_host)
+                        .unwrap_or_else(|| panic!("Malformed callback url {}.", callback_url))
+                })
+                .collect(),
+            allowed_orgs,
+            allowed_teams,
         }
     }
 
-    pub fn authorize_url(&self, state: &String) -> Url {
+    // With more than one callback url, the one of the host is chosen and unknown hosts are
+    // rejected. The host may come from `X-Forwarded-Host`, it only chooses among the configured.
+    fn callback_url_of(&self, host: &str) -> Result<&Url> {
+        if let [callback_url] = &self.callback_urls[..] {
+            return Ok(callback_url);
+        }
+
+        self.callback_urls
+            .iter()
+            .find(|callback_url| {
+                let callback_host = match (callback_url.host_str(), callback_url.port()) {
+                    (Some(callback_host), Some(port)) => format!("{}:{}", callback_host, port),
+                    (Some(callback_host), None) => callback_host.to_owned(),
+                    (None, _) => return false,
+                };
+                callback_host.eq_ignore_ascii_case(host)
+            })
+            .ok_or_else(|| Error::Forbidden("Signing in from this host isn't allowed."))
+    }
+
+    pub fn authorize_url(&self, state: &String, host: &str) -> Result<Url> {
+        let callback_url = self.callback_url_of(host)?;
         let mut url = AUTH_URL.clone();
         url.query_pairs_mut()
             .append_pair("client_id", &self.client_id)
             .append_pair("state", state)
-            .append_pair("redirect_uri", self.callback_url.as_str());
+            .append_pair("redirect_uri", callback_url.as_str());
+        // Membership can only be read with this scope, so we only ask for it when it's needed.
+        if self.is_gated() {
+            url.query_pairs_mut().append_pair("scope", "read:org");
+        }
+
+        Ok(url)
+    }
 
-        url
+    fn is_gated(&self) -> bool {
+        !self.allowed_orgs.is_empty() || !self.allowed_teams.is_empty()
+    }
+
+    // Rejects the user unless it's an active member of any allowed organization or team.
+    pub async fn ensure_member(
+        &self,
+        http_client: &Client,
+        token: &str,
+        user: &GithubUser,
+    ) -> Result<()> {
+        if !self.is_gated() {
+            return Ok(());
+        }
+
+        for org in &self.allowed_orgs {
+            let url = format!("https://api.github.com/user/memberships/orgs/{}", org);
+            if self.is_active_member(http_client, token, &url).await? {
+                return Ok(());
+            }
+        }
+
+        for (org, slug) in &self.allowed_teams {
+            let url = format!(
+                "https://api.github.com/orgs/{}/teams/{}/memberships/{}",
+                org, slug, user.login
+            );
+            if self.is_active_member(http_client, token, &url).await? {
+                return Ok(());
+            }
+        }
+
+        Err(Error::Forbidden(
+            "Your GitHub account is not a member of any organization or team allowed by this PipeHub instance.",
+        ))
+    }
+
+    async fn is_active_member(&self, http_client: &Client, token: &str, url: &str) -> Result<bool> {
+        let response = http_client
+            .get(url)
+            .header(header::USER_AGENT, "PipeHub")
+            .header(header::AUTHORIZATION, format!("token {}", token))
+            .send()
+            .await?;
+        // GitHub replies 404 or 403 for non-members.
+        if !response.status().is_success() {
+            return Ok(false);
+        }
+        let membership = response.json::<GitHubMembership>().await?;
+        Ok(membership.state == "active")
     }
 
     pub async fn exchange_code(&self, http_client: &Client, code: &str) -> Result<String> {
@@ -69,6 +179,12 @@ pub struct GithubUser {
     pub id: i64,
 }
 
+#[derive(Debug, Deserialize)]
+struct GitHubMembership {
+    // Either `active` or `pending`.
+    state: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct GitHubAccessToken {
     access_token: String,
@@ -0,0 +1,80 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+
+// The language of the hints in responses, messages of users are never translated.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Locale {
+    #[serde(rename = "en")]
+    En,
+    #[serde(rename = "zh-CN")]
+    ZhCn,
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Locale::En
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Hint {
+    NothingDelivered,
+    Dropped(DateTime<Utc>),
+    Deferred(DateTime<Utc>),
+    Poll(Uuid),
+    Parked(DateTime<Utc>, Uuid),
+    Retried(u32),
+    Recalled,
+    Unexpected,
+}
+
+impl Locale {
+    pub fn hint(self, hint: Hint) -> String {
+        match self {
+            Locale::En => match hint {
+                Hint::NothingDelivered => "Nothing is delivered.".to_owned(),
+                Hint::Dropped(until) => format!(
+                    "Dropped because of quiet hours until {}.",
+                    until.to_rfc3339()
+                ),
+                Hint::Deferred(until) => format!(
+                    "Deferred to {} because of quiet hours.",
+                    until.to_rfc3339()
+                ),
+                Hint::Poll(request_id) => format!(
+                    "Poll /send/status/{} for the delivery status.",
+                    request_id
+                ),
+                Hint::Parked(next_attempt_at, request_id) => format!(
+                    "Delivery failed and will be attempted again at {}, poll /send/status/{} for the delivery status.",
+                    next_attempt_at.to_rfc3339(),
+                    request_id
+                ),
+                Hint::Retried(retry_count) => format!("Retried {} times.", retry_count),
+                Hint::Recalled => "The message is recalled.".to_owned(),
+                Hint::Unexpected => "If you believe it's unexpected, please help us by creating an issue with this response at https://github.com/zhzy0077/pipehub.".to_owned(),
+            },
+            Locale::ZhCn => match hint {
+                Hint::NothingDelivered => "未推送任何消息.".to_owned(),
+                Hint::Dropped(until) => {
+                    format!("免打扰期间的消息已丢弃, 免打扰至 {}.", until.to_rfc3339())
+                }
+                Hint::Deferred(until) => {
+                    format!("免打扰期间, 消息将于 {} 推送.", until.to_rfc3339())
+                }
+                Hint::Poll(request_id) => {
+                    format!("可以通过 GET /send/status/{} 查询推送状态.", request_id)
+                }
+                Hint::Parked(next_attempt_at, request_id) => format!(
+                    "推送失败, 将于 {} 再次尝试, 可以通过 GET /send/status/{} 查询推送状态.",
+                    next_attempt_at.to_rfc3339(),
+                    request_id
+                ),
+                Hint::Retried(retry_count) => format!("重试了 {} 次.", retry_count),
+                Hint::Recalled => "消息已撤回.".to_owned(),
+                Hint::Unexpected => "如果你认为这是个问题, 请在 https://github.com/zhzy0077/pipehub 创建 issue 并附上这个响应.".to_owned(),
+            },
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::data::Pool;
+use crate::error::Error;
+use actix_web::{get, web, Error as AWError, HttpResponse};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Sending,
+    Delivered,
+    Failed,
+    // Parked in the retry queue until the next attempt.
+    Pending,
+    // Gave up after the attempts of the retry queue.
+    Dead,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Sending => "sending",
+            JobStatus::Delivered => "delivered",
+            JobStatus::Failed => "failed",
+            JobStatus::Pending => "pending",
+            JobStatus::Dead => "dead",
+        }
+    }
+
+    fn parse(status: &str) -> Option<JobStatus> {
+        match status {
+            "queued" => Some(JobStatus::Queued),
+            "sending" => Some(JobStatus::Sending),
+            "delivered" => Some(JobStatus::Delivered),
+            "failed" => Some(JobStatus::Failed),
+            "pending" => Some(JobStatus::Pending),
+            "dead" => Some(JobStatus::Dead),
+            _ => None,
+        }
+    }
+}
+
+struct Job {
+    status: JobStatus,
+    error_message: String,
+    updated_at: Instant,
+}
+
+// Keeps the status of messages delivered in background, the entries expire after the TTL.
+pub struct JobStore {
+    jobs: DashMap<Uuid, Job>,
+    ttl: Duration,
+}
+
+#[derive(Debug, Serialize)]
+struct JobResponse {
+    request_id: Uuid,
+    status: JobStatus,
+    error_message: String,
+}
+
+impl JobStore {
+    pub fn new(ttl: Duration) -> Self {
+        JobStore {
+            jobs: DashMap::new(),
+            ttl,
+        }
+    }
+
+    pub fn update(&self, request_id: Uuid, status: JobStatus, error_message: &str) {
+        self.jobs.insert(
+            request_id,
+            Job {
+                status,
+                error_message: error_message.to_owned(),
+                updated_at: Instant::now(),
+            },
+        );
+    }
+
+    fn get(&self, request_id: &Uuid) -> Option<JobResponse> {
+        self.jobs
+            .get(request_id)
+            .filter(|job| job.updated_at.elapsed() < self.ttl)
+            .map(|job| JobResponse {
+                request_id: *request_id,
+                status: job.status,
+                error_message: job.error_message.clone(),
+            })
+    }
+
+    pub fn purge_expired(&self) {
+        let ttl = self.ttl;
+        self.jobs.retain(|_, job| job.updated_at.elapsed() < ttl);
+    }
+}
+
+// Looks up messages delivered in background, either in memory or in the retry queue. Parked
+// ones and the outbox are looked up in the retry queue, they may be attempted by other instances.
+#[get("/send/status/{request_id}")]
+pub async fn status(
+    request_id: web::Path<Uuid>,
+    job_store: web::Data<Arc<JobStore>>,
+    pool: Pool,
+) -> std::result::Result<HttpResponse, AWError> {
+    let request_id = request_id.into_inner();
+    if let Some(job) = job_store
+        .get(&request_id)
+        .filter(|job| job.status != JobStatus::Pending)
+    {
+        return Ok(HttpResponse::Ok().json(job));
+    }
+
+    let job = pool.find_pending_send(request_id).await?.and_then(|send| {
+        JobStatus::parse(&send.status).map(|status| JobResponse {
+            request_id,
+            status,
+            error_message: send.error_message,
+        })
+    });
+    match job {
+        Some(job) => Ok(HttpResponse::Ok().json(job)),
+        None => Err(Error::NotFound("Unknown or expired request id.").into()),
+    }
+}
@@ -0,0 +1,180 @@
+use crate::config::LogRotation;
+use chrono::{NaiveDateTime, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const FILE_NAME_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
+const FILE_EXTENSION: &str = "log";
+const SYSLOG_TAG: &str = "pipehub";
+// https://tools.ietf.org/html/rfc3164#section-4.1.1, the user-level facility.
+const SYSLOG_FACILITY: u8 = 1;
+
+// Writes to files of the directory named by when they were started, a new one is started at
+// the first line past the size or the period. Lines are never split across files.
+pub struct RotatingFile {
+    dir: PathBuf,
+    max_bytes: u64,
+    rotation: LogRotation,
+    max_files: usize,
+    file: File,
+    written: u64,
+    period: i64,
+    at_line_start: bool,
+}
+
+fn period_of(rotation: LogRotation, timestamp: i64) -> i64 {
+    match rotation {
+        LogRotation::Never => 0,
+        LogRotation::Hourly => timestamp.div_euclid(60 * 60),
+        LogRotation::Daily => timestamp.div_euclid(24 * 60 * 60),
+    }
+}
+
+// Appends if the file exists, which happens when two are started in the same second.
+fn create_file(dir: &Path) -> io::Result<File> {
+    let name = format!("{}.{}", Utc::now().format(FILE_NAME_FORMAT), FILE_EXTENSION);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(name))
+}
+
+impl RotatingFile {
+    // Fails if the directory can't be created or written to, so it's found at startup.
+    pub fn open(
+        dir: &str,
+        max_file_mb: u64,
+        rotation: LogRotation,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+        let file = create_file(&dir)?;
+        let rotating_file = RotatingFile {
+            dir,
+            max_bytes: max_file_mb.saturating_mul(1024 * 1024),
+            rotation,
+            max_files,
+            file,
+            written: 0,
+            period: period_of(rotation, Utc::now().timestamp()),
+            at_line_start: true,
+        };
+        rotating_file.remove_old_files()?;
+
+        Ok(rotating_file)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file = create_file(&self.dir)?;
+        self.written = 0;
+        self.period = period_of(self.rotation, Utc::now().timestamp());
+        self.remove_old_files()
+    }
+
+    // Only the files named like the ones started here are counted.
+    fn remove_old_files(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return Ok(());
+        }
+        let mut files = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension().and_then(|extension| extension.to_str()) == Some(FILE_EXTENSION)
+                    && path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .map(|stem| NaiveDateTime::parse_from_str(stem, FILE_NAME_FORMAT).is_ok())
+                        .unwrap_or(false)
+            })
+            .collect::<Vec<_>>();
+        if files.len() <= self.max_files {
+            return Ok(());
+        }
+        // The names sort by time.
+        files.sort();
+        for path in &files[..files.len() - self.max_files] {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn is_due(&self) -> bool {
+        (self.max_bytes > 0 && self.written >= self.max_bytes)
+            || period_of(self.rotation, Utc::now().timestamp()) != self.period
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.at_line_start && self.is_due() {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Sends each record as a message of RFC 3164 to the local syslog daemon, which adds the time
+// and the host.
+pub struct SyslogLogger {
+    socket: UnixDatagram,
+    level: LevelFilter,
+}
+
+impl SyslogLogger {
+    pub fn connect(path: &str, level: LevelFilter) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+
+        Ok(SyslogLogger { socket, level })
+    }
+
+    fn severity(level: Level) -> u8 {
+        match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = format!(
+            "<{}>{}[{}]: {}",
+            SYSLOG_FACILITY * 8 + SyslogLogger::severity(record.level()),
+            SYSLOG_TAG,
+            process::id(),
+            record.args()
+        );
+        // Logging mustn't fail the request, the record is dropped if the daemon is gone.
+        let _ = self.socket.send(message.as_bytes());
+    }
+
+    fn flush(&self) {}
+}
@@ -1,33 +1,83 @@
-use crate::config::LogConfig;
+use crate::config::{LogConfig, LogDestination};
 use crate::error::Result;
+use crate::log_output::{RotatingFile, SyslogLogger};
 use actix_http::http::Uri;
 use actix_web::web;
 use appinsights::telemetry::{
     RemoteDependencyTelemetry, RequestTelemetry, SeverityLevel, Telemetry, TraceTelemetry,
 };
 use appinsights::{InMemoryChannel, TelemetryClient};
-use chrono::Utc;
-use log::{info, Level};
+use log::{debug, info, Level};
 use simplelog::{ConfigBuilder, TermLogger, TerminalMode, WriteLogger};
-use std::fs::File;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::time::Duration;
 use uuid::Uuid;
 
+// Quoted and escaped if it could be taken for another property or another line.
+fn escape(text: &str) -> Cow<'_, str> {
+    if !text.is_empty()
+        && !text
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '=')
+    {
+        Cow::Borrowed(text)
+    } else {
+        Cow::Owned(format!("{:?}", text))
+    }
+}
+
 pub struct ApplicationLogger {
     app_insight: Option<TelemetryClient<InMemoryChannel>>,
 }
 
 impl ApplicationLogger {
     pub fn track_trace(&self, id: Uuid, level: Level, message: &str) {
+        self.track_trace_with(id, level, message, &BTreeMap::new());
+    }
+
+    // The level is the severity of the telemetry, the line is logged at info level like always.
+    // The properties are attached to the telemetry and appended to the log line as `key=value`.
+    pub fn track_trace_with(
+        &self,
+        id: Uuid,
+        level: Level,
+        message: &str,
+        properties: &BTreeMap<String, String>,
+    ) {
         if let Some(ref app_insight) = self.app_insight {
             let mut event = TraceTelemetry::new(message, ApplicationLogger::severity(&level));
+            for (key, value) in properties {
+                event.properties_mut().insert(key.clone(), value.clone());
+            }
+            event
+                .properties_mut()
+                .insert("request_id".to_string(), id.to_string());
+            app_insight.track(event);
+        }
+
+        if properties.is_empty() {
+            info!("{} {}", id, message);
+        } else {
+            let properties: Vec<String> = properties
+                .iter()
+                .map(|(key, value)| format!("{}={}", escape(key), escape(value)))
+                .collect();
+            info!("{} {} {}", id, message, properties.join(" "));
+        }
+    }
+
+    // Only logged at debug level, like bodies of sends which are too noisy otherwise.
+    pub fn track_debug(&self, id: Uuid, message: &str) {
+        if let Some(ref app_insight) = self.app_insight {
+            let mut event = TraceTelemetry::new(message, SeverityLevel::Verbose);
             event
                 .properties_mut()
                 .insert("request_id".to_string(), id.to_string());
             app_insight.track(event);
         }
 
-        info!("{} {}", id, message);
+        debug!("{} {}", id, message);
     }
 
     pub fn track_request(
@@ -107,7 +157,8 @@ impl ApplicationLogger {
     pub async fn new(log_config: &LogConfig) -> Self {
         let i_key = log_config.instrumentation_key.clone();
         let level = log_config.level;
-        let log_dir = log_config.log_dir.clone();
+        let destination = log_config.destination();
+        let log_config = log_config.clone();
 
         web::block(move || -> Result<ApplicationLogger> {
             let app_insight = if i_key != String::default() {
@@ -115,25 +166,52 @@ impl ApplicationLogger {
             } else {
                 None
             };
-            if log_dir != String::default() {
-                let file = format!("{}/{}.log", log_dir, Utc::now().format("%Y-%m-%dT%H-%M-%S"));
-                WriteLogger::init(
-                    level.to_level_filter(),
-                    ConfigBuilder::new()
-                        .set_time_format_str("%Y-%m-%d %H:%M:%S%.3f")
-                        .build(),
-                    File::create(&file).unwrap(),
-                )
-                .expect("Unable to bind write logger.");
-            } else {
-                TermLogger::init(
-                    level.to_level_filter(),
-                    ConfigBuilder::new()
-                        .set_time_format_str("%Y-%m-%d %H:%M:%S%.3f")
-                        .build(),
-                    TerminalMode::Mixed,
-                )
-                .expect("Unable to bind terminal logger.");
+            match destination {
+                LogDestination::File => {
+                    if log_config.log_dir.is_empty() {
+                        panic!("pipehub_log__log_dir is required to log to files.");
+                    }
+                    let file = RotatingFile::open(
+                        &log_config.log_dir,
+                        log_config.max_file_mb,
+                        log_config.rotation,
+                        log_config.max_files,
+                    )
+                    .unwrap_or_else(|e| {
+                        panic!("Unable to write logs to {} {:?}.", log_config.log_dir, e)
+                    });
+                    WriteLogger::init(
+                        level.to_level_filter(),
+                        ConfigBuilder::new()
+                            .set_time_format_str("%Y-%m-%d %H:%M:%S%.3f")
+                            .build(),
+                        file,
+                    )
+                    .expect("Unable to bind write logger.");
+                }
+                LogDestination::Syslog => {
+                    let logger =
+                        SyslogLogger::connect(&log_config.syslog_socket, level.to_level_filter())
+                            .unwrap_or_else(|e| {
+                                panic!(
+                                    "Unable to connect to syslog at {} {:?}.",
+                                    log_config.syslog_socket, e
+                                )
+                            });
+                    log::set_boxed_logger(Box::new(logger))
+                        .map(|()| log::set_max_level(level.to_level_filter()))
+                        .expect("Unable to bind syslog logger.");
+                }
+                LogDestination::Stdout => {
+                    TermLogger::init(
+                        level.to_level_filter(),
+                        ConfigBuilder::new()
+                            .set_time_format_str("%Y-%m-%d %H:%M:%S%.3f")
+                            .build(),
+                        TerminalMode::Mixed,
+                    )
+                    .expect("Unable to bind terminal logger.");
+                }
             }
 
             Ok(ApplicationLogger { app_insight })
@@ -142,3 +220,18 @@ impl ApplicationLogger {
         .expect("Failed to initialize logger.")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_what_could_forge_properties_or_lines() {
+        assert_eq!(escape("billing"), "billing");
+        assert_eq!(escape("a b"), "\"a b\"");
+        assert_eq!(escape("a=b"), "\"a=b\"");
+        assert_eq!(escape("a\n0 fake"), "\"a\\n0 fake\"");
+        assert_eq!(escape("\"a\""), "\"\\\"a\\\"\"");
+        assert_eq!(escape(""), "\"\"");
+    }
+}
@@ -7,27 +7,35 @@ extern crate diesel_migrations;
 extern crate lazy_static;
 
 use crate::config::PipeHubConfig;
-use crate::data::Pool;
+use crate::data::{Pool, TenantCache};
 use crate::error::{Error, Result};
 use crate::github::GitHubClient;
+use crate::i18n::Hint;
+use crate::job::JobStore;
 use crate::logger::ApplicationLogger;
-use crate::send::WeChatAccessToken;
-use actix_cors::Cors;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
+use crate::secret::SecretBox;
+use crate::send::OutboundLimiter;
+use actix_cors::{Cors, CorsFactory};
 use actix_files::Files;
-use actix_http::body::{Body, MessageBody, ResponseBody};
-use actix_http::http::{header, Method, StatusCode, Uri};
-use actix_http::HttpMessage;
+use actix_http::body::{Body, BodySize, MessageBody, ResponseBody};
+use actix_http::http::{header, ContentEncoding, Cookie, Method, StatusCode, Uri};
+use actix_http::{HttpMessage, ResponseError};
 use actix_session::CookieSession;
-use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
-use actix_web::middleware::{Compress, Logger};
+use actix_web::dev::{BodyEncoding, Service, ServiceRequest, ServiceResponse};
+use actix_web::middleware::{Compress, Condition, Logger};
 use actix_web::web::Data;
 use actix_web::{web, App, HttpServer};
 use actix_web::{Error as AWError, HttpResponse};
-use dashmap::DashMap;
+use diesel::connection::SimpleConnection;
+use diesel::migration::MigrationConnection;
 use diesel::{Connection, PgConnection};
 use dotenv::dotenv;
+use futures_util::future::{ok, Either, Ready};
 use log::{info, Level};
-use reqwest::{Client, ClientBuilder};
+use reqwest::redirect::Policy;
+use reqwest::{Client, ClientBuilder, Proxy};
 use serde::Serialize;
 use std::future::Future;
 use std::io;
@@ -37,21 +45,43 @@ use std::time::{Duration, Instant};
 use tokio::time;
 use uuid::Uuid;
 
+mod admin;
+mod backup;
+mod callback;
 mod config;
+mod content_policy;
 mod data;
+mod email;
 mod error;
 mod github;
+mod i18n;
+mod job;
+mod log_output;
 mod logger;
+mod matrix;
+mod metrics;
 mod models;
+mod openapi;
+mod rate_limit;
+mod retry_queue;
+mod schedule;
 mod schema;
+mod secret;
 mod send;
+mod send_token;
+mod spa;
+mod stats;
+mod template;
 mod user;
 mod util;
+mod version;
 mod wechat;
 
-pub type AccessTokenCache = DashMap<i64, WeChatAccessToken>;
-const HINT: &str =
-    "If you believe it's unexpected, please help us by creating an issue with this response at https://github.com/zhzy0077/pipehub.";
+pub use crate::send::AccessTokenCache;
+pub use pipehub_types::Response;
+
+const SESSION_COOKIE: &str = "session";
+const LATEST_MIGRATION: &str = env!("PIPEHUB_LATEST_MIGRATION");
 
 embed_migrations!("./migrations");
 
@@ -61,81 +91,251 @@ async fn main() -> Result<()> {
     dotenv().ok();
 
     let config = PipeHubConfig::new()?;
-    migrate(&config);
+    let database_url = config.connection_url()?;
+    if config.run_migrations {
+        migrate(&database_url, config.database_schema()?);
+    } else {
+        check_migrated(&database_url, config.database_schema()?);
+    }
 
     let logger = Arc::new(ApplicationLogger::new(&config.log).await);
 
-    let pool = Pool::new(&config.database_url).await?;
+    let pool = Pool::new(
+        &database_url,
+        config.database_test_on_checkout,
+        SecretBox::new(&config)?,
+        config.database_schema()?,
+    )
+    .await?;
+    let resealed = pool.reseal_secrets().await?;
+    if resealed > 0 {
+        info!("Sealed the secrets of {} WeChat credentials.", resealed);
+    }
     let session_key: [u8; 32] = rand::random();
     let github_client = web::Data::new(client(&config));
     let https = config.https;
-    let access_token_cache: Arc<AccessTokenCache> = Arc::new(DashMap::new());
-    let http_client = http_client();
+    let trusted_proxies = config.trusted_proxies.clone();
+    let blocked_user_agents = config.blocked_user_agents.clone();
+    let request_timeout = Duration::from_secs(config.request_timeout);
+    let compress_min_size = config.compress_min_size;
+    let static_max_age = config.static_max_age;
+    let (static_etag, static_last_modified) = (config.static_etag, config.static_last_modified);
+    let unauthenticated_redirect = config.unauthenticated_redirect.clone();
+    let access_token_cache = Arc::new(AccessTokenCache::new(&config.wechat_api_url));
+    let http_client = http_client(&config);
+    let forward_client = callback::ForwardClient(
+        http_client_builder(&config)
+            .redirect(Policy::none())
+            .build()
+            .expect("Failed to create reqwest client."),
+    );
+    let job_store = Arc::new(JobStore::new(Duration::from_secs(config.job_ttl)));
+    let retry = config.retry.clone();
+    let app_config = config.clone();
+    let metrics = Arc::new(Metrics::default());
+    let rate_limiter = Arc::new(RateLimiter::default());
+    let tenant_cache = Arc::new(TenantCache::new(config.tenant_cache_ttl()));
+    let limiter = Arc::new(OutboundLimiter::new(
+        config.max_outbound_requests,
+        Duration::from_millis(config.outbound_wait_ms),
+        config.max_concurrent_retries,
+    ));
 
     let cloned_client = http_client.clone();
+    let ping_url = format!(
+        "{}/cgi-bin/gettoken",
+        config.wechat_api_url.trim_end_matches('/')
+    );
+    tokio::spawn(async move {
+        ping(cloned_client, ping_url).await;
+    });
+
+    actix_rt::spawn(schedule::run_worker(
+        pool.clone(),
+        http_client.clone(),
+        limiter.clone(),
+        logger.clone(),
+        access_token_cache.clone(),
+        retry.clone(),
+        metrics.clone(),
+    ));
+
+    actix_rt::spawn(retry_queue::run_worker(
+        pool.clone(),
+        http_client.clone(),
+        limiter.clone(),
+        logger.clone(),
+        access_token_cache.clone(),
+        retry.clone(),
+        metrics.clone(),
+        config.retry_queue,
+    ));
+
+    actix_rt::spawn(retry_queue::run_purger(pool.clone(), config.retry_queue));
+    actix_rt::spawn(metrics::run_refresher(pool.clone(), metrics.clone()));
+    actix_rt::spawn(send_token::run_purger(pool.clone()));
+    actix_rt::spawn(data::run_health_check(pool.clone()));
+    actix_rt::spawn(rate_limit::run_purger(rate_limiter.clone()));
+
+    let cloned_job_store = job_store.clone();
     tokio::spawn(async move {
-        ping(cloned_client).await;
+        purge_jobs(cloned_job_store).await;
     });
 
-    HttpServer::new(move || {
+    let workers = config.workers.filter(|&workers| workers > 0);
+    let server = HttpServer::new(move || {
+        let trusted_proxies = trusted_proxies.clone();
+        let unauthenticated_redirect = unauthenticated_redirect.clone();
+        let send_user_agents = blocked_user_agents.clone();
+        let echo_user_agents = blocked_user_agents.clone();
+        let raw_user_agents = blocked_user_agents.clone();
+        let recall_user_agents = blocked_user_agents.clone();
+        let send_allowed_origins = &app_config.send_allowed_origins;
         App::new()
             .app_data(pool.clone())
             .app_data(github_client.clone())
             .data(logger.clone())
             .data(access_token_cache.clone())
             .data(http_client.clone())
+            .data(forward_client.clone())
+            .data(job_store.clone())
+            .data(retry.clone())
+            .data(app_config.clone())
+            .data(metrics.clone())
+            .data(limiter.clone())
+            .data(rate_limiter.clone())
+            .data(tenant_cache.clone())
             .wrap_fn(head_request)
+            .wrap_fn(move |req, srv| timeout(req, srv, request_timeout))
             .wrap_fn(track_request)
+            .wrap_fn(move |req, srv| {
+                redirect_unauthenticated(req, srv, unauthenticated_redirect.as_deref())
+            })
             .wrap_fn(request_id_injector)
             .wrap(session(&session_key[..], https))
+            .wrap_fn(move |req, srv| forwarded_secure_cookie(req, srv, &trusted_proxies))
+            .wrap_fn(move |req, srv| skip_small_compression(req, srv, compress_min_size))
+            .wrap_fn(move |req, srv| cache_static(req, srv, static_max_age))
             .wrap(Compress::default())
             .wrap(Logger::default())
             .service(user::reset_key)
+            .service(user::callback_url)
+            .service(user::regenerate_callback_url)
             .service(user::user)
+            .service(user::login_url)
             .service(user::update)
             .service(user::callback)
             .service(user::login)
+            .service(backup::export)
+            .service(backup::import)
             .service(wechat::wechat)
             .service(wechat::update)
+            .service(wechat::list)
+            .service(wechat::create)
+            .service(wechat::remove)
+            .service(job::status)
+            .service(send::limits)
+            .service(metrics::metrics)
+            .service(version::version)
+            .service(openapi::openapi)
+            .service(admin::wechat_connectivity)
+            .service(admin::wechat_health)
+            .service(admin::update_max_wechat_works)
+            .service(admin::stats)
+            .service(admin::import_wechats)
+            .service(send_token::issue)
+            .service(template::list)
+            .service(template::update)
+            .service(template::remove)
+            .service(callback::verify)
+            .service(callback::receive)
             .service(
                 web::resource("/send/{key}")
-                    .wrap(
-                        Cors::new()
-                            .send_wildcard()
-                            .allowed_methods(vec!["GET", "POST"])
-                            .finish(),
-                    )
+                    .app_data(web::PayloadConfig::new(send::MAX_BODY_LENGTH))
+                    .wrap_fn(move |req, srv| block_user_agents(req, srv, &send_user_agents))
+                    .wrap(send_cors(&send_allowed_origins, vec!["GET", "POST"]))
                     .route(web::get().to(send::send))
                     .route(web::post().to(send::send)),
             )
-            .service(Files::new("/", "./static/").index_file("index.html"))
-    })
-    .bind(config.bind_addr())?
-    .run()
-    .await?;
+            .service(
+                web::resource("/send/{key}/echo")
+                    .app_data(web::PayloadConfig::new(send::MAX_BODY_LENGTH))
+                    .wrap_fn(move |req, srv| block_user_agents(req, srv, &echo_user_agents))
+                    .wrap(send_cors(&send_allowed_origins, vec!["POST"]))
+                    .route(web::post().to(send::echo)),
+            )
+            .service(
+                web::resource("/send/{key}/raw")
+                    .wrap_fn(move |req, srv| block_user_agents(req, srv, &raw_user_agents))
+                    .wrap(send_cors(&send_allowed_origins, vec!["POST"]))
+                    .route(web::post().to(send::raw)),
+            )
+            .service(
+                web::resource("/send/{key}/recall")
+                    .wrap_fn(move |req, srv| block_user_agents(req, srv, &recall_user_agents))
+                    .wrap(send_cors(&send_allowed_origins, vec!["POST"]))
+                    .route(web::post().to(send::recall)),
+            )
+            .service(
+                Files::new("/", spa::STATIC_DIR)
+                    .index_file("index.html")
+                    .use_etag(static_etag)
+                    .use_last_modified(static_last_modified)
+                    .default_handler(web::route().to(spa::fallback)),
+            )
+    });
+    let server = match workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    server.bind(config.bind_addr())?.run().await?;
 
     Ok(())
 }
 
-#[derive(Debug, Serialize)]
-pub struct Response {
-    request_id: Uuid,
-    success: bool,
-    error_message: String,
-    hint: String,
-}
-
-fn migrate(config: &PipeHubConfig) {
-    let connection =
-        PgConnection::establish(&config.database_url).expect("Unable to connect to DB.");
+fn migrate(database_url: &str, schema: Option<&str>) {
+    let connection = PgConnection::establish(database_url).expect("Unable to connect to DB.");
+    if let Some(schema) = schema {
+        // The pool sets the search path on its own connections, the database user is left as is.
+        connection
+            .batch_execute(&format!(
+                "CREATE SCHEMA IF NOT EXISTS {schema};
+                 SET search_path TO {schema};",
+                schema = schema
+            ))
+            .expect("Unable to set the database schema.");
+    }
 
     embedded_migrations::run_with_output(&connection, &mut io::stdout())
         .expect("Unable to migrate.");
 }
 
+// Newer migrations are fine, they are run before rolling out the servers taking them.
+fn check_migrated(database_url: &str, schema: Option<&str>) {
+    let connection = PgConnection::establish(database_url).expect("Unable to connect to DB.");
+    if let Some(schema) = schema {
+        connection
+            .batch_execute(&format!("SET search_path TO {};", schema))
+            .expect("Unable to set the database schema.");
+    }
+
+    match connection.latest_run_migration_version() {
+        Ok(Some(ref version)) if version.as_str() >= LATEST_MIGRATION => {}
+        Ok(version) => panic!(
+            "The database is migrated to {} but {} is expected, run the migrations first.",
+            version.as_deref().unwrap_or("nothing"),
+            LATEST_MIGRATION
+        ),
+        Err(e) => panic!(
+            "Unable to read the migrated version, run the migrations first {:?}.",
+            e
+        ),
+    }
+}
+
 fn session(key: &[u8], https: bool) -> CookieSession {
     CookieSession::private(key)
-        .name("session")
+        .name(SESSION_COOKIE)
         .secure(https)
         .http_only(true)
 }
@@ -144,7 +344,12 @@ fn client(config: &PipeHubConfig) -> GitHubClient {
     GitHubClient::new(
         config.github.client_id.clone(),
         config.github.client_secret.clone(),
-        &config.github.callback_url,
+        std::iter::once(&config.github.callback_url)
+            .chain(config.github.callback_urls.iter())
+            .cloned()
+            .collect(),
+        config.github.allowed_orgs.clone(),
+        config.github.allowed_teams.clone(),
     )
 }
 
@@ -160,6 +365,131 @@ fn request_id_injector<
     srv.call(req)
 }
 
+// When TLS is terminated by a trusted proxy, the session cookie is marked secure
+// if the client is on HTTPS even though we are speaking HTTP.
+fn forwarded_secure_cookie<
+    B: MessageBody,
+    S: Service<Response = ServiceResponse<B>, Request = ServiceRequest, Error = AWError>,
+>(
+    req: ServiceRequest,
+    srv: &mut S,
+    trusted_proxies: &[String],
+) -> impl Future<Output = std::result::Result<ServiceResponse<B>, AWError>> {
+    let from_trusted_proxy = req
+        .peer_addr()
+        .map(|addr| trusted_proxies.contains(&addr.ip().to_string()))
+        .unwrap_or(false);
+    let forwarded_https = from_trusted_proxy
+        && req
+            .headers()
+            .get("X-Forwarded-Proto")
+            .and_then(|proto| proto.to_str().ok())
+            .map(|proto| proto.eq_ignore_ascii_case("https"))
+            .unwrap_or(false);
+    let future = srv.call(req);
+    async move {
+        let mut res = future.await?;
+        if forwarded_https {
+            let cookies: Vec<Cookie<'static>> = res
+                .response()
+                .cookies()
+                .filter(|cookie| cookie.name() == SESSION_COOKIE)
+                .map(|cookie| cookie.into_owned())
+                .collect();
+            if !cookies.is_empty() {
+                res.response_mut().del_cookie(SESSION_COOKIE);
+            }
+            for mut cookie in cookies {
+                cookie.set_secure(true);
+                res.response_mut().add_cookie(&cookie)?;
+            }
+        }
+        Ok(res)
+    }
+}
+
+// The send endpoints answer browsers of the allowed origins including preflights, `*` allows any.
+// Without any CORS isn't applied at all, like the other endpoints.
+fn send_cors(allowed_origins: &[String], methods: Vec<&'static str>) -> Condition<CorsFactory> {
+    let mut cors = Cors::new().allowed_methods(methods);
+    if allowed_origins.iter().any(|origin| origin == "*") {
+        cors = cors.send_wildcard();
+    } else {
+        for origin in allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+    }
+
+    Condition::new(!allowed_origins.is_empty(), cors.finish())
+}
+
+// Rejects scanners and bots by their user agents before any key is looked up.
+fn block_user_agents<
+    S: Service<Response = ServiceResponse<Body>, Request = ServiceRequest, Error = AWError>,
+>(
+    req: ServiceRequest,
+    srv: &mut S,
+    blocked_user_agents: &[String],
+) -> Either<Ready<std::result::Result<ServiceResponse<Body>, AWError>>, S::Future> {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(|user_agent| user_agent.to_lowercase())
+        .unwrap_or_default();
+    let blocked = !user_agent.is_empty()
+        && blocked_user_agents
+            .iter()
+            .any(|blocked| user_agent.contains(&blocked.to_lowercase()));
+
+    if blocked {
+        Either::Left(ok(req.into_response(
+            Error::Forbidden("User agent is blocked.").error_response(),
+        )))
+    } else {
+        Either::Right(srv.call(req))
+    }
+}
+
+// Browsers get a redirect instead of the JSON they can't do anything with.
+fn redirect_unauthenticated<
+    S: Service<Response = ServiceResponse<Body>, Request = ServiceRequest, Error = AWError>,
+>(
+    req: ServiceRequest,
+    srv: &mut S,
+    redirect: Option<&str>,
+) -> impl Future<Output = std::result::Result<ServiceResponse<Body>, AWError>> {
+    let redirect = redirect
+        .filter(|_| {
+            req.headers()
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .map(|accept| accept.contains("text/html"))
+                .unwrap_or(false)
+        })
+        .map(str::to_owned);
+    let future = srv.call(req);
+    async move {
+        let res = future.await?;
+        match redirect {
+            Some(redirect) if res.status() == StatusCode::UNAUTHORIZED => {
+                let location = res
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .map(|location| location.to_owned())
+                    .unwrap_or(redirect);
+                Ok(res.into_response(
+                    HttpResponse::Found()
+                        .header(header::LOCATION, location)
+                        .finish(),
+                ))
+            }
+            _ => Ok(res),
+        }
+    }
+}
+
 fn track_request<
     S: Service<Response = ServiceResponse<Body>, Request = ServiceRequest, Error = AWError>,
 >(
@@ -168,6 +498,20 @@ fn track_request<
 ) -> impl Future<Output = std::result::Result<ServiceResponse<Body>, AWError>> {
     let logger: Data<Arc<ApplicationLogger>> =
         req.app_data().expect("No logger found in app_data().");
+    let config = req.app_data::<PipeHubConfig>();
+    let locale = config
+        .as_ref()
+        .map(|config| config.locale)
+        .unwrap_or_default();
+    let slow_request = config
+        .as_ref()
+        .map(|config| config.log_slow_requests_ms)
+        .filter(|&slow_request| slow_request > 0)
+        .map(Duration::from_millis);
+    let sample_rate = config
+        .as_ref()
+        .map(|config| config.log_sample_rate())
+        .unwrap_or(1.0);
     let request_id: Uuid = req
         .extensions()
         .get::<Uuid>()
@@ -184,15 +528,25 @@ fn track_request<
         match res {
             Ok(ref response)
                 if response.status() != StatusCode::BAD_REQUEST
+                    && response.response().extensions().get::<String>().is_none()
                     && !response.status().is_server_error() =>
             {
-                logger.track_request(
-                    request_id,
-                    &method,
-                    uri,
-                    duration,
-                    response.status().as_str(),
-                );
+                // Slow requests are always logged, the others are sampled.
+                let is_slow = slow_request
+                    .map(|slow_request| duration >= slow_request)
+                    .unwrap_or(false);
+                if is_slow
+                    || response.status().is_client_error()
+                    || rand::random::<f64>() < sample_rate
+                {
+                    logger.track_request(
+                        request_id,
+                        &method,
+                        uri,
+                        duration,
+                        response.status().as_str(),
+                    );
+                }
             }
             Ok(ref response) => {
                 let error_message = response
@@ -202,28 +556,85 @@ fn track_request<
                     .cloned()
                     .unwrap_or_else(|| "Unexpected error occurred.".to_owned());
                 let status = response.status();
+                let retry_after = response.headers().get(header::RETRY_AFTER).cloned();
+                let location = response.headers().get(header::LOCATION).cloned();
                 logger.track_trace(request_id, Level::Error, &error_message);
                 let status_str = response.status().to_string();
 
                 logger.track_request(request_id, &method, uri, duration, &status_str);
                 res = res.map(|res| {
+                    let mut error_response = HttpResponse::new(status);
+                    if let Some(retry_after) = retry_after {
+                        error_response
+                            .headers_mut()
+                            .insert(header::RETRY_AFTER, retry_after);
+                    }
+                    if let Some(location) = location {
+                        error_response
+                            .headers_mut()
+                            .insert(header::LOCATION, location);
+                    }
                     res.into_response(json(
-                        HttpResponse::new(status),
+                        error_response,
                         &Response {
                             request_id,
                             success: !status.is_server_error(),
                             error_message,
-                            hint: HINT.to_owned(),
+                            hint: locale.hint(Hint::Unexpected),
                         },
                     ))
                 })
             }
             Err(_) => unimplemented!("Should not reach here."),
         }
+        // Clients correlate their requests with the logs by it, whatever the body is like.
+        if let Ok(ref mut response) = res {
+            if let Ok(request_id) = header::HeaderValue::from_str(&request_id.to_string()) {
+                response.headers_mut().insert(
+                    header::HeaderName::from_static(REQUEST_ID_HEADER),
+                    request_id,
+                );
+            }
+        }
         res
     }
 }
 
+pub const REQUEST_ID_HEADER: &str = "x-pipehub-request-id";
+
+// Stops handling a request that takes too long, there are no long-polling routes so it applies to all.
+// Clients may tighten the timeout of their own requests, but never beyond `request_timeout`.
+pub const TIMEOUT_HEADER: &str = "X-PipeHub-Timeout-Ms";
+
+fn timeout<
+    S: Service<Response = ServiceResponse<Body>, Request = ServiceRequest, Error = AWError>,
+>(
+    req: ServiceRequest,
+    srv: &mut S,
+    request_timeout: Duration,
+) -> impl Future<Output = std::result::Result<ServiceResponse<Body>, AWError>> {
+    // Malformed or zero timeouts are ignored.
+    let request_timeout = req
+        .headers()
+        .get(TIMEOUT_HEADER)
+        .and_then(|timeout| timeout.to_str().ok())
+        .and_then(|timeout| timeout.trim().parse::<u64>().ok())
+        .filter(|&timeout| timeout > 0)
+        .map(|timeout| Duration::from_millis(timeout).min(request_timeout))
+        .unwrap_or(request_timeout);
+    let http_req = req.request().clone();
+    let future = srv.call(req);
+    async move {
+        match time::timeout(request_timeout, future).await {
+            Ok(res) => res,
+            Err(_) => Ok(ServiceResponse::new(
+                http_req,
+                Error::Timeout("Request timed out.").error_response(),
+            )),
+        }
+    }
+}
+
 fn head_request<
     S: Service<Response = ServiceResponse<Body>, Request = ServiceRequest, Error = AWError>,
 >(
@@ -245,6 +656,60 @@ fn head_request<
     }
 }
 
+// Only successful and not modified responses are cached, so a missing asset is looked up again.
+fn cache_static<
+    S: Service<Response = ServiceResponse<Body>, Request = ServiceRequest, Error = AWError>,
+>(
+    req: ServiceRequest,
+    srv: &mut S,
+    max_age: u64,
+) -> impl Future<Output = std::result::Result<ServiceResponse<Body>, AWError>> {
+    let cache_control = if req.method() == Method::GET || req.method() == Method::HEAD {
+        spa::cache_control(req.path(), max_age)
+    } else {
+        None
+    };
+    let future = srv.call(req);
+    async move {
+        let mut res: ServiceResponse<Body> = future.await?;
+        let is_cacheable = res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED;
+        if let Some(cache_control) = cache_control.filter(|_| is_cacheable) {
+            if !res.headers().contains_key(header::CACHE_CONTROL) {
+                if let Ok(cache_control) = header::HeaderValue::from_str(&cache_control) {
+                    res.headers_mut()
+                        .insert(header::CACHE_CONTROL, cache_control);
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+// Small bodies like those of `/send` and `/metrics` aren't worth compressing, `Compress` leaves
+// identity encoded responses alone.
+fn skip_small_compression<
+    S: Service<Response = ServiceResponse<Body>, Request = ServiceRequest, Error = AWError>,
+>(
+    req: ServiceRequest,
+    srv: &mut S,
+    min_size: usize,
+) -> impl Future<Output = std::result::Result<ServiceResponse<Body>, AWError>> {
+    let future = srv.call(req);
+    async move {
+        let mut res: ServiceResponse<Body> = future.await?;
+        let is_small = match res.response().body().size() {
+            BodySize::None | BodySize::Empty => true,
+            BodySize::Sized(size) => size < min_size,
+            BodySize::Sized64(size) => size < min_size as u64,
+            BodySize::Stream => false,
+        };
+        if is_small {
+            res.response_mut().encoding(ContentEncoding::Identity);
+        }
+        Ok(res)
+    }
+}
+
 fn json<T: Serialize>(mut resp: HttpResponse, value: &T) -> HttpResponse {
     match serde_json::to_string(value) {
         Ok(body) => {
@@ -257,23 +722,38 @@ fn json<T: Serialize>(mut resp: HttpResponse, value: &T) -> HttpResponse {
     }
 }
 
-fn http_client() -> Client {
-    ClientBuilder::new()
-        .connect_timeout(Duration::from_secs(5))
-        .timeout(Duration::from_secs(5))
-        .pool_idle_timeout(Duration::from_secs(60))
+// Besides `pipehub_proxy`, `http_proxy` and `https_proxy` are picked up by reqwest.
+fn http_client(config: &PipeHubConfig) -> Client {
+    http_client_builder(config)
         .build()
         .expect("Failed to create reqwest client.")
 }
 
-async fn ping(client: Client) {
+fn http_client_builder(config: &PipeHubConfig) -> ClientBuilder {
+    let mut builder = ClientBuilder::new()
+        .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+        .timeout(Duration::from_secs(5))
+        .pool_idle_timeout(Duration::from_secs(60));
+    if let Some(ref proxy) = config.proxy {
+        builder = builder.proxy(Proxy::all(proxy).expect("Malformed proxy url."));
+    }
+
+    builder
+}
+
+async fn ping(client: Client, url: String) {
     let mut delay = time::interval(Duration::from_secs(30));
     loop {
         delay.tick().await;
-        let resp = client
-            .get("https://qyapi.weixin.qq.com/cgi-bin/gettoken")
-            .send()
-            .await;
+        let resp = client.get(&url).send().await;
         info!("Ping gettoken result {:?}.", resp)
     }
 }
+
+async fn purge_jobs(job_store: Arc<JobStore>) {
+    let mut delay = time::interval(Duration::from_secs(60));
+    loop {
+        delay.tick().await;
+        job_store.purge_expired();
+    }
+}
@@ -0,0 +1,273 @@
+use crate::config::RetryPolicy;
+use crate::error::{Error, Result};
+use crate::logger::ApplicationLogger;
+use crate::models::Tenant;
+use crate::send::{self, Delivery, OutboundLimiter};
+use crate::util;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::time;
+use url::Url;
+use uuid::Uuid;
+
+// https://spec.matrix.org/v1.8/client-server-api/#put_matrixclientv3roomsroomidsendeventtypetxnid
+const SEND_API: &str = "m.room.message";
+const HTML_FORMAT: &str = "org.matrix.custom.html";
+
+// https://spec.matrix.org/v1.8/client-server-api/#mroommessage
+#[derive(Debug, Serialize)]
+pub struct MatrixMessage {
+    msgtype: &'static str,
+    body: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    formatted_body: Option<String>,
+}
+
+impl MatrixMessage {
+    // Clients notify of `m.text` like messages of people, bots usually send `m.notice`.
+    fn msgtype(notify: bool) -> &'static str {
+        if notify {
+            "m.text"
+        } else {
+            "m.notice"
+        }
+    }
+
+    pub fn text(content: String, notify: bool) -> Self {
+        MatrixMessage {
+            msgtype: MatrixMessage::msgtype(notify),
+            body: content,
+            format: None,
+            formatted_body: None,
+        }
+    }
+
+    // The plain body is for clients not rendering HTML.
+    pub fn markdown(content: &str, notify: bool) -> Self {
+        MatrixMessage {
+            msgtype: MatrixMessage::msgtype(notify),
+            body: util::strip_markdown(content),
+            format: Some(HTML_FORMAT),
+            formatted_body: Some(util::markdown_to_html(content)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MatrixError {
+    #[serde(default)]
+    errcode: String,
+    #[serde(default)]
+    error: String,
+    // Only with M_LIMIT_EXCEEDED.
+    #[serde(default)]
+    retry_after_ms: Option<u64>,
+}
+
+pub struct MatrixRoom {
+    homeserver: Url,
+    access_token: String,
+    room_id: String,
+}
+
+fn parse_homeserver(homeserver: &str) -> Result<Url> {
+    Url::parse(homeserver)
+        .ok()
+        .filter(|url| (url.scheme() == "https" || url.scheme() == "http") && url.has_host())
+        .ok_or_else(|| Error::User("The Matrix homeserver must be an url like https://matrix.org."))
+}
+
+pub fn validate_matrix(tenant: &Tenant) -> Result<()> {
+    match (
+        &tenant.matrix_homeserver,
+        &tenant.matrix_access_token,
+        &tenant.matrix_room_id,
+    ) {
+        (Some(homeserver), Some(access_token), Some(room_id)) => {
+            parse_homeserver(homeserver)?;
+            if access_token.chars().any(char::is_whitespace) {
+                return Err(Error::User("Malformed Matrix access token."));
+            }
+            // Aliases like `#room:matrix.org` would have to be resolved first.
+            if !room_id.starts_with('!') || !room_id.contains(':') {
+                return Err(Error::User(
+                    "The Matrix room id must be like !abc:matrix.org, aliases aren't supported.",
+                ));
+            }
+        }
+        (None, None, None) => {}
+        _ => {
+            return Err(Error::User(
+                "Homeserver, access token and room id of Matrix are all required.",
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+pub fn room_of(tenant: &Tenant) -> Result<Option<MatrixRoom>> {
+    match (
+        &tenant.matrix_homeserver,
+        &tenant.matrix_access_token,
+        &tenant.matrix_room_id,
+    ) {
+        (Some(homeserver), Some(access_token), Some(room_id)) => Ok(Some(MatrixRoom {
+            homeserver: parse_homeserver(homeserver)?,
+            access_token: access_token.clone(),
+            room_id: room_id.clone(),
+        })),
+        _ => Ok(None),
+    }
+}
+
+// The request id is the transaction id, so the homeserver posts the message once however many
+// times it's retried, even after timeouts.
+fn send_url(room: &MatrixRoom, request_id: Uuid) -> Result<Url> {
+    let mut url = room.homeserver.clone();
+    url.path_segments_mut()
+        .map_err(|_| Error::User("Malformed Matrix homeserver."))?
+        .pop_if_empty()
+        .extend(&[
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            &room.room_id,
+            "send",
+            SEND_API,
+            &request_id.to_string(),
+        ]);
+
+    Ok(url)
+}
+
+// Posts the message to the room, backing off on failures and as long as the homeserver asks
+// when rate limited.
+pub async fn deliver(
+    http_client: &Client,
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    room: &MatrixRoom,
+    message: &MatrixMessage,
+    retry_policy: RetryPolicy,
+) -> Result<Delivery> {
+    let url = send_url(room, request_id)?;
+
+    let mut retry_count = 0;
+    let mut retry_permit = None;
+    loop {
+        if retry_policy.remaining() == Some(Duration::from_secs(0)) {
+            return Err(Error::Timeout(send::DEADLINE_MESSAGE));
+        }
+        let e = match put_message(
+            http_client,
+            limiter,
+            request_id,
+            logger,
+            room,
+            &url,
+            message,
+            retry_policy.timeout(),
+        )
+        .await
+        {
+            Ok(()) => {
+                return Ok(Delivery {
+                    retry_count,
+                    msgid: None,
+                    raw_response: None,
+                    invalid_targets: Default::default(),
+                })
+            }
+            Err(e @ Error::Overloaded(..)) => return Err(e),
+            Err(e) => e,
+        };
+        if retry_count >= retry_policy.max_retries {
+            return Err(e);
+        }
+
+        let backoff = match e {
+            Error::Throttled(retry_after) => retry_after.min(retry_policy.max_backoff()),
+            _ => retry_policy.backoff(retry_count),
+        };
+        if let Some(remaining) = retry_policy.remaining() {
+            if backoff >= remaining {
+                return Err(Error::Timeout(send::DEADLINE_MESSAGE));
+            }
+        }
+        // Held until the send is done, the last error is returned once too many are retrying.
+        if retry_permit.is_none() {
+            retry_permit = Some(limiter.try_retry().ok_or(e)?);
+        }
+        retry_count += 1;
+        time::delay_for(backoff).await;
+    }
+}
+
+async fn put_message(
+    client: &Client,
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    room: &MatrixRoom,
+    url: &Url,
+    message: &MatrixMessage,
+    timeout: Duration,
+) -> Result<()> {
+    let _permit = limiter.acquire(request_id, logger).await?;
+    let start = Instant::now();
+    let host = room.homeserver.host_str().unwrap_or_default();
+    let result = client
+        .put(url.clone())
+        .bearer_auth(&room.access_token)
+        .json(message)
+        .timeout(timeout)
+        .send()
+        .await;
+    let response = match result {
+        Ok(response) => response,
+        Err(e) if e.is_timeout() => return Err(Error::Timeout("Matrix didn't reply in time.")),
+        Err(e) => {
+            return Err(Error::Dependency(format!(
+                "Unable to reach the Matrix homeserver {:?}",
+                e
+            )))
+        }
+    };
+    let status = response.status();
+    let reply = if status.is_success() {
+        None
+    } else {
+        Some(response.json::<MatrixError>().await.unwrap_or_default())
+    };
+
+    logger.track_dependency(
+        request_id,
+        &format!("PUT {}/_matrix/client/v3/rooms/send", host),
+        "HTTPS",
+        start.elapsed(),
+        host,
+        status.as_str(),
+        &format!("{}/_matrix/client/v3/rooms/{}/send", host, room.room_id),
+        status.is_success(),
+    );
+
+    match reply {
+        None => Ok(()),
+        Some(MatrixError {
+            retry_after_ms: Some(retry_after_ms),
+            ..
+        }) if status == StatusCode::TOO_MANY_REQUESTS => {
+            Err(Error::Throttled(Duration::from_millis(retry_after_ms)))
+        }
+        Some(reply) => Err(Error::Dependency(format!(
+            "Matrix replied {} {} {}",
+            status, reply.errcode, reply.error
+        ))),
+    }
+}
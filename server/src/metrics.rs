@@ -0,0 +1,309 @@
+use crate::admin::authorize;
+use crate::config::PipeHubConfig;
+use crate::data::{Pool, PoolStatus};
+use crate::error::{Error, Result};
+use crate::send::{Channel, OutboundLimiter, Outgoing};
+use crate::stats::Activity;
+use actix_web::{get, web, Error as AWError, HttpRequest, HttpResponse};
+use chrono::Utc;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use log::error;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time;
+
+const PRUNE_INTERVAL: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Outcome {
+    Delivered,
+    Failed,
+    Blocked,
+    PolicyViolation,
+    RateLimited,
+    Deduplicated,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Delivered => "delivered",
+            Outcome::Failed => "failed",
+            Outcome::Blocked => "blocked",
+            Outcome::PolicyViolation => "policy_violation",
+            Outcome::RateLimited => "rate_limited",
+            Outcome::Deduplicated => "deduplicated",
+        }
+    }
+}
+
+// Counts messages by tenant and outcome, the labels never include content to keep cardinality bounded.
+#[derive(Default)]
+pub struct Metrics {
+    messages: DashMap<(i64, Outcome), u64>,
+    activity: Activity,
+    // The sends delivered with a duplicate check by tenant and digest, with when they were
+    // delivered and the interval. WeChat drops the same send within the interval and replies as
+    // if it was delivered, so it's counted as deduplicated. Pruned once the interval is over.
+    checked: DashMap<(i64, u64), (i64, u64)>,
+    pruned_at: AtomicI64,
+    // Refreshed in background, absent if the database couldn't tell.
+    queue_depths: RwLock<Option<(i64, i64)>>,
+}
+
+impl Metrics {
+    pub fn record(&self, tenant_id: i64, outcome: Outcome) {
+        *self.messages.entry((tenant_id, outcome)).or_insert(0) += 1;
+    }
+
+    pub fn record_send<T>(&self, tenant_id: i64, outgoing: &Outgoing, result: &Result<T>) {
+        if result.is_ok()
+            && self.is_duplicate(
+                tenant_id,
+                outgoing.duplicate_check(),
+                Utc::now().timestamp(),
+            )
+        {
+            self.record(tenant_id, Outcome::Deduplicated);
+            return;
+        }
+        self.record_delivery(tenant_id, outgoing.channel(), result);
+    }
+
+    // Remembers the first send of a window, the window isn't extended by the duplicates.
+    fn is_duplicate(&self, tenant_id: i64, check: Option<(u64, u64)>, now: i64) -> bool {
+        let (digest, interval) = match check {
+            Some(check) => check,
+            None => return false,
+        };
+        self.prune(now);
+        match self.checked.entry((tenant_id, digest)) {
+            Entry::Occupied(mut entry) => {
+                let (delivered_at, window) = *entry.get();
+                if now < delivered_at + window as i64 {
+                    return true;
+                }
+                entry.insert((now, interval));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((now, interval));
+            }
+        }
+        false
+    }
+
+    fn prune(&self, now: i64) {
+        let pruned_at = self.pruned_at.load(Ordering::Relaxed);
+        // Only one of the concurrent records of a new minute prunes.
+        if now < pruned_at + PRUNE_INTERVAL
+            || self
+                .pruned_at
+                .compare_exchange(pruned_at, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        self.checked
+            .retain(|_, (delivered_at, window)| now < *delivered_at + *window as i64);
+    }
+
+    pub fn record_delivery<T>(&self, tenant_id: i64, channel: Channel, result: &Result<T>) {
+        let outcome = match result {
+            Ok(_) => Outcome::Delivered,
+            Err(Error::Throttled(_)) | Err(Error::Overloaded(..)) | Err(Error::RateLimited(_)) => {
+                Outcome::RateLimited
+            }
+            Err(_) => Outcome::Failed,
+        };
+        self.record(tenant_id, outcome);
+        self.activity
+            .record(tenant_id, channel, outcome == Outcome::Delivered);
+    }
+
+    pub fn activity(&self) -> &Activity {
+        &self.activity
+    }
+
+    fn render(&self) -> String {
+        let mut messages: Vec<((i64, Outcome), u64)> = self
+            .messages
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+        messages.sort();
+
+        let mut body = String::new();
+        body.push_str("# HELP pipehub_messages_total Messages handled by tenant and outcome.\n");
+        body.push_str("# TYPE pipehub_messages_total counter\n");
+        for ((tenant_id, outcome), count) in messages {
+            writeln!(
+                body,
+                "pipehub_messages_total{{tenant=\"{}\",outcome=\"{}\"}} {}",
+                tenant_id,
+                outcome.as_str(),
+                count
+            )
+            .expect("Unable to write metrics.");
+        }
+        body
+    }
+}
+
+// The gauges other than the queue depths are read when scraped. The queue depths are omitted if
+// the database can't tell, the other metrics are still worth scraping then.
+fn render_gauges(
+    queue_depths: Option<(i64, i64)>,
+    limiter: &OutboundLimiter,
+    pool: &PoolStatus,
+) -> String {
+    let mut body = String::new();
+    if let Some((retry, scheduled)) = queue_depths {
+        body.push_str(
+            "# HELP pipehub_queue_depth Messages waiting to be delivered in background.\n",
+        );
+        body.push_str("# TYPE pipehub_queue_depth gauge\n");
+        writeln!(body, "pipehub_queue_depth{{queue=\"retry\"}} {}", retry)
+            .expect("Unable to write metrics.");
+        writeln!(
+            body,
+            "pipehub_queue_depth{{queue=\"scheduled\"}} {}",
+            scheduled
+        )
+        .expect("Unable to write metrics.");
+    }
+    body.push_str("# HELP pipehub_outbound_in_flight Requests to WeChat in flight.\n");
+    body.push_str("# TYPE pipehub_outbound_in_flight gauge\n");
+    writeln!(body, "pipehub_outbound_in_flight {}", limiter.in_flight())
+        .expect("Unable to write metrics.");
+    body.push_str("# HELP pipehub_outbound_retries_in_flight Sends backing off or retrying.\n");
+    body.push_str("# TYPE pipehub_outbound_retries_in_flight gauge\n");
+    writeln!(
+        body,
+        "pipehub_outbound_retries_in_flight {}",
+        limiter.retries_in_flight()
+    )
+    .expect("Unable to write metrics.");
+    body.push_str(
+        "# HELP pipehub_outbound_retries_denied_total Retries given up as too many sends were retrying.\n",
+    );
+    body.push_str("# TYPE pipehub_outbound_retries_denied_total counter\n");
+    writeln!(
+        body,
+        "pipehub_outbound_retries_denied_total {}",
+        limiter.retries_denied()
+    )
+    .expect("Unable to write metrics.");
+
+    body.push_str("# HELP pipehub_db_connections Pooled database connections by state.\n");
+    body.push_str("# TYPE pipehub_db_connections gauge\n");
+    writeln!(
+        body,
+        "pipehub_db_connections{{state=\"idle\"}} {}",
+        pool.idle
+    )
+    .expect("Unable to write metrics.");
+    writeln!(
+        body,
+        "pipehub_db_connections{{state=\"in_use\"}} {}",
+        (pool.size as usize).saturating_sub(pool.idle)
+    )
+    .expect("Unable to write metrics.");
+    body.push_str("# HELP pipehub_db_healthy Whether the last database health check passed.\n");
+    body.push_str("# TYPE pipehub_db_healthy gauge\n");
+    writeln!(
+        body,
+        "pipehub_db_healthy {}",
+        if pool.healthy { 1 } else { 0 }
+    )
+    .expect("Unable to write metrics.");
+    body.push_str("# HELP pipehub_db_health_check_failures_total Failed database health checks.\n");
+    body.push_str("# TYPE pipehub_db_health_check_failures_total counter\n");
+    writeln!(
+        body,
+        "pipehub_db_health_check_failures_total {}",
+        pool.failures
+    )
+    .expect("Unable to write metrics.");
+    body
+}
+
+// Counts the queues in background, so scrapes don't query the database.
+pub async fn run_refresher(pool: Pool, metrics: Arc<Metrics>) {
+    let mut delay = time::interval(Duration::from_secs(15));
+    loop {
+        delay.tick().await;
+        let queue_depths = match (
+            pool.count_waiting_pending_sends().await,
+            pool.count_scheduled_messages().await,
+        ) {
+            (Ok(retry), Ok(scheduled)) => Some((retry, scheduled)),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Unable to count the queues {:?}.", e);
+                None
+            }
+        };
+        *metrics
+            .queue_depths
+            .write()
+            .expect("Unable to refresh metrics.") = queue_depths;
+    }
+}
+
+// Exposes the counters and gauges in the Prometheus text format, to admins like the admin
+// endpoints. Prometheus sends the token with `authorization` of the scrape config.
+#[get("/metrics")]
+pub async fn metrics(
+    req: HttpRequest,
+    config: web::Data<PipeHubConfig>,
+    counters: web::Data<Arc<Metrics>>,
+    pool: Pool,
+    limiter: web::Data<Arc<OutboundLimiter>>,
+) -> std::result::Result<HttpResponse, AWError> {
+    authorize(&req, &config)?;
+    let queue_depths = *counters
+        .queue_depths
+        .read()
+        .expect("Unable to read metrics.");
+    let body = counters.render() + &render_gauges(queue_depths, &limiter, &pool.status());
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_duplicates_within_the_interval_of_the_first_send() {
+        let metrics = Metrics::default();
+        assert!(!metrics.is_duplicate(1, Some((7, 60)), 1000));
+        assert!(metrics.is_duplicate(1, Some((7, 60)), 1030));
+        assert!(metrics.is_duplicate(1, Some((7, 60)), 1059));
+        assert!(!metrics.is_duplicate(1, Some((7, 60)), 1060));
+        assert!(metrics.is_duplicate(1, Some((7, 60)), 1061));
+    }
+
+    #[test]
+    fn counts_duplicates_by_tenant_and_digest() {
+        let metrics = Metrics::default();
+        assert!(!metrics.is_duplicate(1, Some((7, 60)), 1000));
+        assert!(!metrics.is_duplicate(2, Some((7, 60)), 1001));
+        assert!(!metrics.is_duplicate(1, Some((8, 60)), 1002));
+        assert!(!metrics.is_duplicate(1, None, 1003));
+        assert!(!metrics.is_duplicate(1, None, 1004));
+    }
+
+    #[test]
+    fn prunes_the_sends_past_their_interval() {
+        let metrics = Metrics::default();
+        metrics.is_duplicate(1, Some((7, 60)), 1000);
+        metrics.is_duplicate(1, Some((8, 3600)), 1000);
+        metrics.is_duplicate(1, Some((9, 60)), 1100);
+        assert_eq!(metrics.checked.len(), 2);
+    }
+}
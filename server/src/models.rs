@@ -1,6 +1,8 @@
 use base58::ToBase58;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::env;
+use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Tenant {
@@ -11,6 +13,94 @@ pub struct Tenant {
     pub github_login: String,
     pub github_id: i64,
     pub block_list: String,
+    // Comma separated like the block list, only messages containing any are delivered if present.
+    #[serde(default)]
+    pub allow_list: Option<String>,
+    // Used when the message type is not specified in the request.
+    #[serde(default)]
+    pub default_message_type: Option<String>,
+    // Messages are deferred between start and end(`HH:MM`) unless they are urgent.
+    #[serde(default)]
+    pub quiet_hours_start: Option<String>,
+    #[serde(default)]
+    pub quiet_hours_end: Option<String>,
+    // IANA time zone of the quiet hours, UTC if absent.
+    #[serde(default)]
+    pub time_zone: Option<String>,
+    // Added around the content of text and markdown messages.
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub suffix: String,
+    // Appends a line with the request id to text and markdown messages, for finding their logs.
+    #[serde(default)]
+    pub append_request_id: bool,
+    // Messages are persisted and delivered in background.
+    #[serde(default)]
+    pub use_outbox: bool,
+    // Messages are logged until then for debugging.
+    #[serde(default)]
+    pub log_bodies_until: Option<DateTime<Utc>>,
+    // Days of week quiet all day in addition to the quiet hours, like `sat,sun`.
+    #[serde(default)]
+    pub quiet_days: Option<String>,
+    // Messages in quiet hours are dropped instead of deferred.
+    #[serde(default)]
+    pub drop_in_quiet_hours: bool,
+    // Messages per minute of each priority overriding the global ones, like `high=120,low=10`.
+    #[serde(default)]
+    pub rate_limits: Option<String>,
+    // The shape of successful replies to sends, `native`, `wechat` or `text`.
+    #[serde(default)]
+    pub response_format: Option<String>,
+    // Sends use the credentials of the agent unless `X-PipeHub-Agent` chooses another, the
+    // primary ones if absent.
+    #[serde(default)]
+    pub default_agent_id: Option<i64>,
+    // Overrides how many WeChat credentials the tenant can create, only admins can change it.
+    #[serde(skip)]
+    pub max_wechat_works: Option<i32>,
+    // The Token and EncodingAESKey of the callback url configured in WeChat Work.
+    #[serde(default)]
+    pub callback_token: Option<String>,
+    #[serde(default)]
+    pub callback_aes_key: Option<String>,
+    // Received messages and events are posted there as they are decrypted.
+    #[serde(default)]
+    pub callback_forward_url: Option<String>,
+    // Messages are posted to the room with the access token of a bot user when chosen, or when
+    // there are no WeChat credentials.
+    #[serde(default)]
+    pub matrix_homeserver: Option<String>,
+    #[serde(default)]
+    pub matrix_access_token: Option<String>,
+    #[serde(default)]
+    pub matrix_room_id: Option<String>,
+    // Emails are sent when chosen, or when there's no other channel. The port defaults to the one
+    // of the TLS, `starttls` or `implicit`.
+    #[serde(default)]
+    pub smtp_host: Option<String>,
+    #[serde(default)]
+    pub smtp_port: Option<i32>,
+    #[serde(default)]
+    pub smtp_tls: Option<String>,
+    #[serde(default)]
+    pub smtp_username: Option<String>,
+    #[serde(default)]
+    pub smtp_password: Option<String>,
+    #[serde(default)]
+    pub email_from: Option<String>,
+    // Comma separated.
+    #[serde(default)]
+    pub email_to: Option<String>,
+    // Where WeChat messages go when the request names no userids, parties or tags, instead
+    // of everyone.
+    #[serde(default)]
+    pub default_to_user: Option<String>,
+    #[serde(default)]
+    pub default_to_party: Option<String>,
+    #[serde(default)]
+    pub default_to_tag: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -21,6 +111,22 @@ pub struct UserTenant {
     callback_url: String,
 }
 
+// Only what's needed to send, for frontends not showing the settings.
+#[derive(Serialize)]
+pub struct MinimalUserTenant {
+    app_key: String,
+    callback_url: String,
+}
+
+impl From<UserTenant> for MinimalUserTenant {
+    fn from(t: UserTenant) -> Self {
+        MinimalUserTenant {
+            app_key: t.app_key,
+            callback_url: t.callback_url,
+        }
+    }
+}
+
 impl From<Tenant> for UserTenant {
     fn from(t: Tenant) -> Self {
         let app_key = t.app_id.to_le_bytes().to_base58();
@@ -42,17 +148,92 @@ impl Tenant {
             github_login,
             github_id,
             block_list: "".to_string(),
+            allow_list: None,
+            default_message_type: None,
+            quiet_hours_start: None,
+            quiet_hours_end: None,
+            time_zone: None,
+            prefix: "".to_string(),
+            suffix: "".to_string(),
+            append_request_id: false,
+            use_outbox: false,
+            log_bodies_until: None,
+            quiet_days: None,
+            drop_in_quiet_hours: false,
+            rate_limits: None,
+            response_format: None,
+            default_agent_id: None,
+            max_wechat_works: None,
+            callback_token: None,
+            callback_aes_key: None,
+            callback_forward_url: None,
+            matrix_homeserver: None,
+            matrix_access_token: None,
+            matrix_room_id: None,
+            smtp_host: None,
+            smtp_port: None,
+            smtp_tls: None,
+            smtp_username: None,
+            smtp_password: None,
+            email_from: None,
+            email_to: None,
+            default_to_user: None,
+            default_to_party: None,
+            default_to_tag: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct WechatWork {
-    #[serde(skip)]
+    #[serde(skip_deserializing)]
     pub id: i64,
     #[serde(skip)]
     pub tenant_id: i64,
     pub corp_id: String,
     pub agent_id: i64,
     pub secret: String,
+    // The secret of customer contact, required to message external contacts.
+    #[serde(default)]
+    pub external_secret: Option<String>,
+}
+
+// Content with slots like `{{service}}`, which sends fill with their `var.` parameters.
+#[derive(Serialize, Deserialize)]
+pub struct Template {
+    #[serde(skip)]
+    pub id: i64,
+    #[serde(skip)]
+    pub tenant_id: i64,
+    // Taken from the path.
+    #[serde(skip_deserializing)]
+    pub name: String,
+    pub content: String,
+    // Used unless the send specifies the type, then the tenant's default applies.
+    #[serde(default)]
+    pub message_type: Option<String>,
+}
+
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub tenant_id: i64,
+    pub request_id: Uuid,
+    pub payload: String,
+    pub deliver_at: DateTime<Utc>,
+    pub status: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+pub struct PendingSend {
+    pub id: i64,
+    pub tenant_id: i64,
+    pub request_id: Uuid,
+    pub payload: String,
+    // One of pending, sending, delivered and dead.
+    pub status: String,
+    pub attempts: i32,
+    pub error_message: String,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
 }
@@ -0,0 +1,89 @@
+use actix_web::{get, HttpResponse};
+
+lazy_static! {
+    // Hand maintained, the tests check its paths and methods against the routes.
+    static ref OPENAPI: String =
+        include_str!("openapi.json").replace("__VERSION__", env!("CARGO_PKG_VERSION"));
+}
+
+// The OpenAPI 3 description of the send API and the management endpoints.
+#[get("/openapi.json")]
+pub async fn openapi() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(OPENAPI.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::Path;
+
+    const METHODS: &[&str] = &["get", "post", "put", "delete", "patch"];
+    // It signs in with a GitHub token for the e2e tests.
+    const UNDOCUMENTED: &[(&str, &str)] = &[("post", "/login")];
+
+    // The routes of the handler attributes, and of the resources registered in main.rs.
+    fn registered_routes() -> BTreeSet<(String, String)> {
+        let attribute = Regex::new(r#"#\[(get|post|put|delete|patch)\("([^"]+)"\)\]"#).unwrap();
+        let resource = Regex::new(r#"(?s)web::resource\("([^"]+)"\)(.*?)\n\s*\)"#).unwrap();
+        let route = Regex::new(r"\.route\(web::(get|post|put|delete|patch)\(\)").unwrap();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut routes = BTreeSet::new();
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("rs") {
+                continue;
+            }
+            let source = fs::read_to_string(&path).unwrap();
+            for captures in attribute.captures_iter(&source) {
+                routes.insert((captures[1].to_owned(), captures[2].to_owned()));
+            }
+        }
+        let main = fs::read_to_string(dir.join("main.rs")).unwrap();
+        for captures in resource.captures_iter(&main) {
+            for method in route.captures_iter(&captures[2]) {
+                routes.insert((method[1].to_owned(), captures[1].to_owned()));
+            }
+        }
+
+        routes
+    }
+
+    fn documented_routes() -> BTreeSet<(String, String)> {
+        let spec: Value = serde_json::from_str(&OPENAPI).unwrap();
+        let mut routes = BTreeSet::new();
+        for (path, operations) in spec["paths"].as_object().unwrap() {
+            for method in operations.as_object().unwrap().keys() {
+                if METHODS.contains(&method.as_str()) {
+                    routes.insert((method.clone(), path.clone()));
+                }
+            }
+        }
+
+        routes
+    }
+
+    #[test]
+    fn documents_the_registered_routes() {
+        let mut registered = registered_routes();
+        for (method, path) in UNDOCUMENTED {
+            assert!(registered.remove(&(method.to_string(), path.to_string())));
+        }
+        let documented = documented_routes();
+        assert!(registered.len() > 30);
+
+        let undocumented: Vec<_> = registered.difference(&documented).collect();
+        let unknown: Vec<_> = documented.difference(&registered).collect();
+        assert!(
+            undocumented.is_empty() && unknown.is_empty(),
+            "Undocumented routes {:?}, documented but unknown routes {:?}.",
+            undocumented,
+            unknown
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use crate::config::RateLimitConfig;
+use crate::error::{Error, Result};
+use crate::models::Tenant;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+// Idle buckets are full again by then, so they are dropped.
+const IDLE_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Each priority has its own allowance, so noisy low-priority messages don't use up the
+// allowance of critical ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    fn per_minute(self, limits: &RateLimitConfig) -> u32 {
+        match self {
+            Priority::High => limits.high,
+            Priority::Normal => limits.normal,
+            Priority::Low => limits.low,
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            _ => Err(Error::User(
+                "Unknown priority, expected high, normal or low.",
+            )),
+        }
+    }
+}
+
+// Parses the overrides of a tenant like `high=120,low=10`, in messages per minute.
+fn parse_overrides(overrides: Option<&str>) -> Result<Vec<(Priority, u32)>> {
+    overrides
+        .unwrap_or("")
+        .split(',')
+        .map(|limit| limit.trim())
+        .filter(|limit| !limit.is_empty())
+        .map(|limit| -> Result<(Priority, u32)> {
+            let mut parts = limit.splitn(2, '=');
+            let priority = parts.next().unwrap_or("").trim().parse()?;
+            let per_minute = parts
+                .next()
+                .and_then(|per_minute| per_minute.trim().parse::<u32>().ok())
+                .ok_or_else(|| {
+                    Error::User("Malformed rate limits, expected limits like high=120,low=10.")
+                })?;
+            Ok((priority, per_minute))
+        })
+        .collect()
+}
+
+pub fn validate_rate_limits(tenant: &Tenant) -> Result<()> {
+    parse_overrides(tenant.rate_limits.as_deref())?;
+
+    Ok(())
+}
+
+// The global limits with the overrides of the tenant applied.
+pub fn tenant_limits(tenant: &Tenant, defaults: &RateLimitConfig) -> Result<RateLimitConfig> {
+    let mut limits = *defaults;
+    for (priority, per_minute) in parse_overrides(tenant.rate_limits.as_deref())? {
+        match priority {
+            Priority::High => limits.high = per_minute,
+            Priority::Normal => limits.normal = per_minute,
+            Priority::Low => limits.low = per_minute,
+        }
+    }
+
+    Ok(limits)
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+// Token buckets of each tenant and priority, refilled continuously up to the allowance of a minute.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: DashMap<(i64, Priority), Bucket>,
+}
+
+impl RateLimiter {
+    pub fn acquire(
+        &self,
+        tenant_id: i64,
+        priority: Priority,
+        limits: &RateLimitConfig,
+    ) -> Result<()> {
+        let per_minute = priority.per_minute(limits);
+        // Unlimited.
+        if per_minute == 0 {
+            return Ok(());
+        }
+
+        let capacity = f64::from(per_minute);
+        let now = Instant::now();
+        let mut bucket = self
+            .buckets
+            .entry((tenant_id, priority))
+            .or_insert_with(|| Bucket {
+                tokens: capacity,
+                updated_at: now,
+            });
+        let refilled = now.duration_since(bucket.updated_at).as_secs_f64() * capacity / 60.0;
+        bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let retry_after = ((1.0 - bucket.tokens) * 60.0 / capacity).ceil() as u64;
+        Err(Error::RateLimited(Duration::from_secs(retry_after.max(1))))
+    }
+
+    fn purge_idle(&self) {
+        self.buckets
+            .retain(|_, bucket| bucket.updated_at.elapsed() < IDLE_TTL);
+    }
+}
+
+pub async fn run_purger(limiter: Arc<RateLimiter>) {
+    let mut delay = time::interval(Duration::from_secs(60 * 10));
+    loop {
+        delay.tick().await;
+        limiter.purge_idle();
+    }
+}
@@ -0,0 +1,174 @@
+use crate::config::{RetryConfig, RetryQueueConfig};
+use crate::data::Pool;
+use crate::error::{Error, Result};
+use crate::job::JobStatus;
+use crate::logger::ApplicationLogger;
+use crate::metrics::Metrics;
+use crate::send::{self, deliver_for_tenant, OutboundLimiter, Outgoing};
+use crate::AccessTokenCache;
+use chrono::{DateTime, Utc};
+use log::{error, Level};
+use reqwest::Client;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+use uuid::Uuid;
+
+const CLAIM_BATCH_SIZE: usize = 100;
+
+// Failures retried in process may go away later too, like WeChat being down for a while.
+// Timeouts aren't, the message may have been delivered, nor rejections like an invalid secret.
+pub fn is_transient(e: &Error) -> bool {
+    send::is_retryable(e)
+}
+
+fn next_attempt_at(config: &RetryQueueConfig, attempts: u32) -> DateTime<Utc> {
+    Utc::now()
+        + chrono::Duration::from_std(config.delay(attempts))
+            .unwrap_or_else(|_| chrono::Duration::zero())
+}
+
+// Parks the send after its first attempt failed, returns when it's attempted again.
+pub async fn park(
+    pool: &Pool,
+    config: &RetryQueueConfig,
+    tenant_id: i64,
+    request_id: Uuid,
+    outgoing: &Outgoing,
+    e: &Error,
+) -> Result<DateTime<Utc>> {
+    let next_attempt_at = next_attempt_at(config, 1);
+    let payload = serde_json::to_string(outgoing)?;
+    pool.insert_pending_send(
+        tenant_id,
+        request_id,
+        payload,
+        1,
+        &e.to_string(),
+        next_attempt_at,
+    )
+    .await?;
+
+    Ok(next_attempt_at)
+}
+
+// Queues the send of the outbox, it's attempted on the next tick and retried like the parked
+// ones. Queued even if parking is disabled.
+pub async fn enqueue(
+    pool: &Pool,
+    tenant_id: i64,
+    request_id: Uuid,
+    outgoing: &Outgoing,
+) -> Result<()> {
+    let payload = serde_json::to_string(outgoing)?;
+    pool.insert_pending_send(tenant_id, request_id, payload, 0, "", Utc::now())
+        .await
+}
+
+// Attempts the due sends of the retry queue, backing off until they are delivered or dead.
+pub async fn run_worker(
+    pool: Pool,
+    http_client: Client,
+    limiter: Arc<OutboundLimiter>,
+    logger: Arc<ApplicationLogger>,
+    access_token_cache: Arc<AccessTokenCache>,
+    retry: RetryConfig,
+    metrics: Arc<Metrics>,
+    config: RetryQueueConfig,
+) {
+    let mut delay = time::interval(Duration::from_secs(5));
+    loop {
+        delay.tick().await;
+        for _ in 0..CLAIM_BATCH_SIZE {
+            let send = match pool.claim_pending_send().await {
+                Ok(Some(send)) => send,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Unable to claim pending sends {:?}.", e);
+                    break;
+                }
+            };
+
+            let result = deliver_for_tenant(
+                &pool,
+                &http_client,
+                &limiter,
+                &logger,
+                &access_token_cache,
+                &retry,
+                &metrics,
+                send.tenant_id,
+                send.request_id,
+                &send.payload,
+            )
+            .await;
+            let attempts = send.attempts + 1;
+            let updated = match result {
+                Ok(_) => {
+                    pool.update_pending_send(
+                        send.id,
+                        JobStatus::Delivered.as_str(),
+                        attempts,
+                        "",
+                        Utc::now(),
+                    )
+                    .await
+                }
+                // Not an attempt, it's picked up again on the next tick.
+                Err(Error::Overloaded(..)) => {
+                    pool.update_pending_send(
+                        send.id,
+                        JobStatus::Pending.as_str(),
+                        send.attempts,
+                        &send.error_message,
+                        Utc::now(),
+                    )
+                    .await
+                }
+                Err(ref e) if is_transient(e) && (attempts as u32) < config.max_attempts => {
+                    pool.update_pending_send(
+                        send.id,
+                        JobStatus::Pending.as_str(),
+                        attempts,
+                        &e.to_string(),
+                        next_attempt_at(&config, attempts as u32),
+                    )
+                    .await
+                }
+                Err(e) => {
+                    let error_message = e.to_string();
+                    logger.track_trace(
+                        send.request_id,
+                        Level::Error,
+                        &format!("Gave up after {} attempts {}", attempts, error_message),
+                    );
+                    pool.update_pending_send(
+                        send.id,
+                        JobStatus::Dead.as_str(),
+                        attempts,
+                        &error_message,
+                        Utc::now(),
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = updated {
+                error!("Unable to update pending send {:?}.", e);
+            }
+        }
+    }
+}
+
+// Delivered and dead sends are kept for the retention, so their status can be looked up.
+pub async fn run_purger(pool: Pool, config: RetryQueueConfig) {
+    let mut delay = time::interval(Duration::from_secs(60 * 10));
+    loop {
+        delay.tick().await;
+        let finished_before = Utc::now()
+            - chrono::Duration::from_std(Duration::from_secs(config.retention))
+                .unwrap_or_else(|_| chrono::Duration::zero());
+        if let Err(e) = pool.purge_pending_sends(finished_before).await {
+            error!("Unable to purge pending sends {:?}.", e);
+        }
+    }
+}
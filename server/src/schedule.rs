@@ -0,0 +1,317 @@
+use crate::config::RetryConfig;
+use crate::data::Pool;
+use crate::error::{Error, Result};
+use crate::logger::ApplicationLogger;
+use crate::metrics::Metrics;
+use crate::models::Tenant;
+use crate::send::{deliver_for_tenant, OutboundLimiter};
+use crate::AccessTokenCache;
+use chrono::{
+    DateTime, Datelike, Duration as ChronoDuration, NaiveDateTime, NaiveTime, TimeZone, Utc,
+    Weekday,
+};
+use chrono_tz::Tz;
+use log::{error, Level};
+use reqwest::Client;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+const FLUSH_BATCH_SIZE: usize = 100;
+
+pub fn parse_time_zone(time_zone: &str) -> Result<Tz> {
+    time_zone
+        .parse()
+        .map_err(|_| Error::User("Unknown time zone, expected an IANA name like Asia/Shanghai."))
+}
+
+// Tenants without their own time zone follow the server's.
+pub fn time_zone_of(tenant: &Tenant, default_time_zone: Tz) -> Result<Tz> {
+    match tenant.time_zone {
+        Some(ref time_zone) => parse_time_zone(time_zone),
+        None => Ok(default_time_zone),
+    }
+}
+
+fn parse_quiet_hour(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .map_err(|_| Error::User("Malformed quiet hours, expected HH:MM."))
+}
+
+fn parse_quiet_days(days: Option<&str>) -> Result<Vec<Weekday>> {
+    days.unwrap_or("")
+        .split(',')
+        .map(|day| day.trim())
+        .filter(|day| !day.is_empty())
+        .map(|day| {
+            day.parse()
+                .map_err(|_| Error::User("Malformed quiet days, expected days like sat,sun."))
+        })
+        .collect()
+}
+
+pub fn validate_quiet_hours(tenant: &Tenant) -> Result<()> {
+    match (&tenant.quiet_hours_start, &tenant.quiet_hours_end) {
+        (Some(start), Some(end)) => {
+            parse_quiet_hour(start)?;
+            parse_quiet_hour(end)?;
+        }
+        (None, None) => {}
+        _ => {
+            return Err(Error::User(
+                "Both start and end of quiet hours are required.",
+            ))
+        }
+    }
+    let mut days = parse_quiet_days(tenant.quiet_days.as_deref())?;
+    days.sort_by_key(|day| day.num_days_from_monday());
+    days.dedup();
+    if days.len() == 7 {
+        return Err(Error::User("At least one day must not be quiet."));
+    }
+    if let Some(ref time_zone) = tenant.time_zone {
+        parse_time_zone(time_zone)?;
+    }
+
+    Ok(())
+}
+
+// Returns the end of the quiet hours if the time is in them.
+fn quiet_hours_end(
+    start: NaiveTime,
+    end: NaiveTime,
+    local: NaiveDateTime,
+) -> Option<NaiveDateTime> {
+    let time = local.time();
+    let is_quiet = if start < end {
+        start <= time && time < end
+    } else if start > end {
+        // The quiet hours span midnight, e.g. 22:00 - 08:00.
+        time >= start || time < end
+    } else {
+        false
+    };
+    if !is_quiet {
+        return None;
+    }
+
+    let end_date = if time < end {
+        local.date()
+    } else {
+        local.date().succ()
+    };
+    Some(end_date.and_time(end))
+}
+
+// Returns when the quiet period ends if it's quiet for the tenant now.
+pub fn quiet_until(
+    tenant: &Tenant,
+    default_time_zone: Tz,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>> {
+    let hours = match (&tenant.quiet_hours_start, &tenant.quiet_hours_end) {
+        (Some(start), Some(end)) => Some((parse_quiet_hour(start)?, parse_quiet_hour(end)?)),
+        _ => None,
+    };
+    let days = parse_quiet_days(tenant.quiet_days.as_deref())?;
+    if hours.is_none() && days.is_empty() {
+        return Ok(None);
+    }
+    let time_zone = time_zone_of(tenant, default_time_zone)?;
+
+    let local = now.with_timezone(&time_zone).naive_local();
+    let mut until = local;
+    // Quiet days and quiet hours may follow each other, e.g. from Friday 22:00 to Monday 08:00,
+    // which takes at most two steps a day as not all days are quiet.
+    for _ in 0..16 {
+        if days.contains(&until.weekday()) {
+            until = until.date().succ().and_hms(0, 0, 0);
+        } else if let Some(end) = hours.and_then(|(start, end)| quiet_hours_end(start, end, until))
+        {
+            until = end;
+        } else {
+            break;
+        }
+    }
+    if until == local {
+        return Ok(None);
+    }
+
+    let deliver_at = time_zone
+        .from_local_datetime(&until)
+        .earliest()
+        .map(|deliver_at| deliver_at.with_timezone(&Utc))
+        // The end doesn't exist on that day because of DST.
+        .unwrap_or_else(|| now + ChronoDuration::hours(1));
+
+    Ok(Some(deliver_at))
+}
+
+// Delivers the deferred messages once they are due.
+pub async fn run_worker(
+    pool: Pool,
+    http_client: Client,
+    limiter: Arc<OutboundLimiter>,
+    logger: Arc<ApplicationLogger>,
+    access_token_cache: Arc<AccessTokenCache>,
+    retry: RetryConfig,
+    metrics: Arc<Metrics>,
+) {
+    let mut delay = time::interval(Duration::from_secs(10));
+    loop {
+        delay.tick().await;
+        // Claimed one at a time, so no claim waits behind slow deliveries until it goes stale.
+        for _ in 0..FLUSH_BATCH_SIZE {
+            let message = match pool.claim_due_scheduled_message().await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Unable to load scheduled messages {:?}.", e);
+                    break;
+                }
+            };
+
+            if let Err(e) = deliver_for_tenant(
+                &pool,
+                &http_client,
+                &limiter,
+                &logger,
+                &access_token_cache,
+                &retry,
+                &metrics,
+                message.tenant_id,
+                message.request_id,
+                &message.payload,
+            )
+            .await
+            {
+                logger.track_trace(
+                    message.request_id,
+                    Level::Error,
+                    &format!("Failed to deliver scheduled message {:?}.", e),
+                );
+            }
+            // Left claimed if it can't be removed, it's delivered again once the claim is stale.
+            if let Err(e) = pool.delete_scheduled_message(message.id).await {
+                error!("Unable to remove scheduled message {:?}.", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tenant(start: &str, end: &str, time_zone: Option<&str>) -> Tenant {
+        serde_json::from_value(json!({
+            "github_login": "octocat",
+            "github_id": 1,
+            "block_list": "",
+            "quiet_hours_start": start,
+            "quiet_hours_end": end,
+            "time_zone": time_zone,
+        }))
+        .unwrap()
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    fn until(tenant: &Tenant, now: &str) -> Option<DateTime<Utc>> {
+        quiet_until(tenant, Tz::UTC, at(now)).unwrap()
+    }
+
+    #[test]
+    fn defers_across_midnight() {
+        let tenant = tenant("22:00", "08:00", None);
+        assert_eq!(
+            until(&tenant, "2026-10-14T23:30:00Z"),
+            Some(at("2026-10-15T08:00:00Z"))
+        );
+        assert_eq!(
+            until(&tenant, "2026-10-15T03:00:00Z"),
+            Some(at("2026-10-15T08:00:00Z"))
+        );
+        assert_eq!(until(&tenant, "2026-10-15T12:00:00Z"), None);
+    }
+
+    #[test]
+    fn starts_quiet_at_the_start_and_ends_at_the_end() {
+        let overnight = tenant("22:00", "08:00", None);
+        assert_eq!(until(&overnight, "2026-10-14T21:59:59Z"), None);
+        assert_eq!(
+            until(&overnight, "2026-10-14T22:00:00Z"),
+            Some(at("2026-10-15T08:00:00Z"))
+        );
+        assert_eq!(
+            until(&overnight, "2026-10-15T07:59:59Z"),
+            Some(at("2026-10-15T08:00:00Z"))
+        );
+        assert_eq!(until(&overnight, "2026-10-15T08:00:00Z"), None);
+
+        let lunch = tenant("12:00", "13:00", None);
+        assert_eq!(until(&lunch, "2026-10-14T11:59:59Z"), None);
+        assert_eq!(
+            until(&lunch, "2026-10-14T12:00:00Z"),
+            Some(at("2026-10-14T13:00:00Z"))
+        );
+        assert_eq!(until(&lunch, "2026-10-14T13:00:00Z"), None);
+
+        // The same start and end is never quiet.
+        assert_eq!(
+            until(&tenant("08:00", "08:00", None), "2026-10-14T08:00:00Z"),
+            None
+        );
+    }
+
+    #[test]
+    fn follows_the_time_zone_of_the_tenant() {
+        let shanghai = tenant("22:00", "08:00", Some("Asia/Shanghai"));
+        // 07:00 in Shanghai, but 23:00 in UTC.
+        assert_eq!(
+            until(&shanghai, "2026-10-14T23:00:00Z"),
+            Some(at("2026-10-15T00:00:00Z"))
+        );
+        // 21:00 in Shanghai.
+        assert_eq!(until(&shanghai, "2026-10-14T13:00:00Z"), None);
+
+        // Tenants without one follow the server's.
+        let default_zone = tenant("22:00", "08:00", None);
+        let shanghai_zone: Tz = "Asia/Shanghai".parse().unwrap();
+        assert_eq!(
+            quiet_until(&default_zone, shanghai_zone, at("2026-10-14T23:00:00Z")).unwrap(),
+            Some(at("2026-10-15T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn ends_at_local_time_across_dst_transitions() {
+        // New York springs forward on 2026-03-08, the night is an hour shorter.
+        let new_york = tenant("22:00", "08:00", Some("America/New_York"));
+        assert_eq!(
+            until(&new_york, "2026-03-08T04:00:00Z"),
+            Some(at("2026-03-08T12:00:00Z"))
+        );
+        // And falls back on 2026-11-01, the night is an hour longer.
+        assert_eq!(
+            until(&new_york, "2026-11-01T03:00:00Z"),
+            Some(at("2026-11-01T13:00:00Z"))
+        );
+
+        // 02:30 is skipped on 2026-03-08, so it's deferred an hour.
+        let skipped = tenant("22:00", "02:30", Some("America/New_York"));
+        assert_eq!(
+            until(&skipped, "2026-03-08T06:00:00Z"),
+            Some(at("2026-03-08T07:00:00Z"))
+        );
+        // 01:30 happens twice on 2026-11-01, the first one ends it.
+        let repeated = tenant("22:00", "01:30", Some("America/New_York"));
+        assert_eq!(
+            until(&repeated, "2026-11-01T03:00:00Z"),
+            Some(at("2026-11-01T05:30:00Z"))
+        );
+    }
+}
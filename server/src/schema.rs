@@ -5,6 +5,65 @@ table! {
         github_login -> Varchar,
         github_id -> Int8,
         block_list -> Text,
+        default_message_type -> Nullable<Varchar>,
+        quiet_hours_start -> Nullable<Varchar>,
+        quiet_hours_end -> Nullable<Varchar>,
+        time_zone -> Nullable<Varchar>,
+        prefix -> Varchar,
+        suffix -> Varchar,
+        use_outbox -> Bool,
+        log_bodies_until -> Nullable<Timestamptz>,
+        quiet_days -> Nullable<Varchar>,
+        drop_in_quiet_hours -> Bool,
+        rate_limits -> Nullable<Varchar>,
+        max_wechat_works -> Nullable<Int4>,
+        response_format -> Nullable<Varchar>,
+        default_agent_id -> Nullable<Int8>,
+        callback_token -> Nullable<Varchar>,
+        callback_aes_key -> Nullable<Varchar>,
+        allow_list -> Nullable<Text>,
+        append_request_id -> Bool,
+        matrix_homeserver -> Nullable<Varchar>,
+        matrix_access_token -> Nullable<Varchar>,
+        matrix_room_id -> Nullable<Varchar>,
+        callback_forward_url -> Nullable<Varchar>,
+        smtp_host -> Nullable<Varchar>,
+        smtp_port -> Nullable<Int4>,
+        smtp_tls -> Nullable<Varchar>,
+        smtp_username -> Nullable<Varchar>,
+        smtp_password -> Nullable<Varchar>,
+        email_from -> Nullable<Varchar>,
+        email_to -> Nullable<Varchar>,
+        default_to_user -> Nullable<Varchar>,
+        default_to_party -> Nullable<Varchar>,
+        default_to_tag -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    pending_sends (id) {
+        id -> Int8,
+        tenant_id -> Int8,
+        request_id -> Uuid,
+        payload -> Text,
+        status -> Varchar,
+        attempts -> Int4,
+        error_message -> Text,
+        next_attempt_at -> Timestamptz,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    scheduled_messages (id) {
+        id -> Int8,
+        tenant_id -> Int8,
+        request_id -> Uuid,
+        payload -> Text,
+        deliver_at -> Timestamptz,
+        status -> Varchar,
+        updated_at -> Timestamptz,
     }
 }
 
@@ -15,7 +74,32 @@ table! {
         corp_id -> Varchar,
         agent_id -> Int8,
         secret -> Varchar,
+        external_secret -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    templates (id) {
+        id -> Int8,
+        tenant_id -> Int8,
+        name -> Varchar,
+        content -> Text,
+        message_type -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    used_send_tokens (nonce) {
+        nonce -> Uuid,
+        expires_at -> Timestamptz,
     }
 }
 
-allow_tables_to_appear_in_same_query!(tenants, wechat_works,);
+allow_tables_to_appear_in_same_query!(
+    pending_sends,
+    scheduled_messages,
+    templates,
+    tenants,
+    used_send_tokens,
+    wechat_works,
+);
@@ -0,0 +1,147 @@
+use crate::config::PipeHubConfig;
+use crate::error::{Error, Result};
+use openssl::base64;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::fmt;
+
+// Sealed secrets look like `enc:v1:<key id>:<base64 of nonce, ciphertext and tag>`, the rest
+// are plain ones written before the key was configured.
+const PREFIX: &str = "enc:v1:";
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+struct SecretKey {
+    id: String,
+    key: Vec<u8>,
+}
+
+impl SecretKey {
+    fn parse(key: &str) -> Result<Self> {
+        let key = base64::decode_block(key.trim())
+            .ok()
+            .filter(|key| key.len() == KEY_LENGTH)
+            .ok_or_else(|| {
+                Error::Unexpected("Secret keys must be 32 bytes encoded in base64.".to_owned())
+            })?;
+        // Tells which key sealed a secret without revealing the key.
+        let id = sha256(&key)[..4]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        Ok(SecretKey { id, key })
+    }
+}
+
+// Seals and opens WeChat secrets stored in the database with AES-256-GCM. Secrets are sealed
+// with the current key and opened with whichever key sealed them, so keys can be rotated by
+// moving the current one to the old ones.
+pub struct SecretBox {
+    current: Option<SecretKey>,
+    old: Vec<SecretKey>,
+}
+
+// Keys are never printed.
+impl fmt::Debug for SecretBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecretBox")
+            .field("current", &self.current.as_ref().map(|key| &key.id))
+            .field(
+                "old",
+                &self.old.iter().map(|key| &key.id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl SecretBox {
+    pub fn new(config: &PipeHubConfig) -> Result<Self> {
+        let current = Some(config.secret_key.as_str())
+            .filter(|key| !key.is_empty())
+            .map(SecretKey::parse)
+            .transpose()?;
+        let old = config
+            .old_secret_keys
+            .iter()
+            .map(|key| SecretKey::parse(key))
+            .collect::<Result<Vec<SecretKey>>>()?;
+
+        Ok(SecretBox { current, old })
+    }
+
+    fn key_of(&self, id: &str) -> Option<&SecretKey> {
+        self.current
+            .iter()
+            .chain(self.old.iter())
+            .find(|key| key.id == id)
+    }
+
+    // Secrets are kept plain until a key is configured.
+    pub fn seal(&self, secret: &str) -> Result<String> {
+        let key = match self.current {
+            Some(ref key) => key,
+            None => return Ok(secret.to_owned()),
+        };
+        let mut nonce = [0; NONCE_LENGTH];
+        rand_bytes(&mut nonce)?;
+        let mut tag = [0; TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key.key,
+            Some(&nonce),
+            &[],
+            secret.as_bytes(),
+            &mut tag,
+        )?;
+        let sealed = [&nonce[..], &ciphertext, &tag].concat();
+
+        Ok(format!(
+            "{}{}:{}",
+            PREFIX,
+            key.id,
+            base64::encode_block(&sealed)
+        ))
+    }
+
+    pub fn open(&self, stored: &str) -> Result<String> {
+        let sealed = match stored.strip_prefix(PREFIX) {
+            Some(sealed) => sealed,
+            None => return Ok(stored.to_owned()),
+        };
+        let malformed = || Error::Unexpected("Malformed sealed secret.".to_owned());
+        let mut parts = sealed.splitn(2, ':');
+        let id = parts.next().unwrap_or_default();
+        let key = self
+            .key_of(id)
+            .ok_or_else(|| Error::Unexpected(format!("The secret key {} isn't configured.", id)))?;
+        let sealed =
+            base64::decode_block(parts.next().ok_or_else(malformed)?).map_err(|_| malformed())?;
+        if sealed.len() < NONCE_LENGTH + TAG_LENGTH {
+            return Err(malformed());
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_LENGTH);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+        let secret = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key.key,
+            Some(nonce),
+            &[],
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| Error::Unexpected("Unable to open the sealed secret.".to_owned()))?;
+
+        String::from_utf8(secret).map_err(|_| malformed())
+    }
+
+    // Plain secrets and those sealed with old keys are sealed again with the current key.
+    pub fn needs_resealing(&self, stored: &str) -> bool {
+        match self.current {
+            Some(ref key) => !stored.starts_with(&format!("{}{}:", PREFIX, key.id)),
+            None => false,
+        }
+    }
+}
@@ -1,21 +1,58 @@
-use crate::data::Pool;
+use crate::config::{LevelConfig, PipeHubConfig, RetryConfig, RetryPolicy};
+use crate::content_policy;
+use crate::data::{Pool, TenantCache};
+use crate::email::{self, EmailChannel, EmailMessage};
 use crate::error::{Error, Result};
+use crate::i18n::Hint;
+use crate::job::{JobStatus, JobStore};
 use crate::logger::ApplicationLogger;
-use crate::models::WechatWork;
-use crate::{AccessTokenCache, Response};
-use actix_web::{web, Error as AWError, HttpRequest, HttpResponse};
+use crate::matrix::{self, MatrixMessage, MatrixRoom};
+use crate::metrics::{Metrics, Outcome};
+use crate::models::{Tenant, WechatWork};
+use crate::rate_limit::{self, Priority, RateLimiter};
+use crate::retry_queue;
+use crate::schedule;
+use crate::send_token::{self, Scope};
+use crate::template;
+use crate::util;
+use crate::{Response, TIMEOUT_HEADER};
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::http::Method;
+use actix_web::{get, web, Error as AWError, HttpMessage, HttpRequest, HttpResponse};
 use base58::FromBase58;
-use reqwest::Client;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use log::Level;
+use openssl::base64;
+use openssl::sha::Sha256;
+use percent_encoding::percent_decode_str;
+use pipehub_types::{InvalidTargets, SendResponse};
+use regex::Regex;
+use reqwest::header;
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Semaphore, SemaphorePermit};
+use tokio::time;
+use url::form_urlencoded;
 use uuid::Uuid;
 
 #[derive(Debug, Deserialize)]
 pub struct WeChatAccessToken {
     #[serde(rename = "errcode")]
-    error_code: u64,
+    error_code: i64,
     #[serde(rename = "errmsg")]
     error_message: String,
     access_token: String,
@@ -29,135 +66,2671 @@ struct WeChatMessage {
     to_user: Option<String>,
     #[serde(rename = "toparty")]
     to_party: Option<String>,
+    #[serde(rename = "totag", skip_serializing_if = "Option::is_none")]
+    to_tag: Option<String>,
     #[serde(rename = "agentid")]
     agent_id: i64,
-    #[serde(rename = "msgtype")]
-    message_type: String,
-    text: WeChatMessageText,
+    #[serde(flatten)]
+    body: WeChatMessageBody,
     #[serde(serialize_with = "crate::util::bool_to_int")]
     enable_duplicate_check: bool,
     duplicate_check_interval: u64,
 }
 
-#[derive(Debug, Serialize)]
-struct WeChatMessageText {
-    content: String,
+// https://work.weixin.qq.com/api/doc/90000/90135/90248, the group chat must be created by the
+// app, its members get the message from the app.
+#[derive(Debug, Serialize)]
+struct AppChatMessage {
+    chatid: String,
+    #[serde(flatten)]
+    body: WeChatMessageBody,
+}
+
+// https://work.weixin.qq.com/api/doc/90000/90135/92135, members confirm it in WeChat Work before
+// it's sent.
+#[derive(Debug, Serialize)]
+struct ExternalContactMessage {
+    chat_type: &'static str,
+    external_userid: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sender: Option<String>,
+    text: WeChatMessageText,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "msgtype", rename_all = "snake_case")]
+enum WeChatMessageBody {
+    Text { text: WeChatMessageText },
+    Markdown { markdown: WeChatMessageText },
+    // Payloads are kept as JSON, so lenient validation passes through what PipeHub doesn't model.
+    MiniprogramNotice { miniprogram_notice: Value },
+    TemplateCard { template_card: Value },
+    // The media id is filled in once the image of `Outgoing` is uploaded.
+    Image { image: WeChatMedia },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WeChatMedia {
+    media_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImagePayload {
+    image_base64: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WeChatUploadResponse {
+    #[serde(rename = "errcode")]
+    error_code: i64,
+    #[serde(rename = "errmsg")]
+    error_message: String,
+    #[serde(default)]
+    media_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WeChatMessageText {
+    content: String,
+}
+
+impl WeChatMessageBody {
+    // The content of free text messages and its type.
+    fn content_mut(&mut self) -> Option<(&mut String, MessageType)> {
+        match self {
+            WeChatMessageBody::Text { text } => Some((&mut text.content, MessageType::Text)),
+            WeChatMessageBody::Markdown { markdown } => {
+                Some((&mut markdown.content, MessageType::Markdown))
+            }
+            WeChatMessageBody::MiniprogramNotice { .. }
+            | WeChatMessageBody::TemplateCard { .. }
+            | WeChatMessageBody::Image { .. } => None,
+        }
+    }
+
+    // Whether the content fits the channel, before it's decorated.
+    fn fits(&mut self, channel: Channel) -> bool {
+        match self.content_mut() {
+            Some((content, message_type)) => channel
+                .max_content_bytes(message_type)
+                .map_or(true, |max_length| content.len() <= max_length),
+            None => true,
+        }
+    }
+
+    // Prefix and suffix are kept, the content is truncated if it gets too long for the channel.
+    // Decorations longer than the limit on their own are truncated too, the suffix first.
+    fn decorate(&mut self, prefix: &str, suffix: &str, channel: Channel) {
+        if let Some((content, message_type)) = self.content_mut() {
+            let max_length = channel.max_content_bytes(message_type);
+            if let Some(max_length) = max_length {
+                util::truncate(
+                    content,
+                    max_length.saturating_sub(prefix.len() + suffix.len()),
+                );
+            }
+            *content = format!("{}{}{}", prefix, content, suffix);
+            if let Some(max_length) = max_length {
+                util::truncate(content, max_length);
+            }
+        }
+    }
+}
+
+// https://work.weixin.qq.com/api/doc/90000/90135/90236#小程序通知消息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MiniprogramNotice {
+    appid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    page: Option<String>,
+    title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(default)]
+    emphasis_first_item: bool,
+    #[serde(default)]
+    content_item: Vec<MiniprogramNoticeItem>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MiniprogramNoticeItem {
+    key: String,
+    value: String,
+}
+
+impl MiniprogramNotice {
+    fn validate(&self) -> Result<()> {
+        // Mini program app ids look like wx0123456789abcdef.
+        if self.appid.len() != 18
+            || !self.appid.starts_with("wx")
+            || !self.appid.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(Error::User("Malformed appid of the mini program."));
+        }
+        if let Some(ref page) = self.page {
+            if page.is_empty() || page.starts_with('/') || page.chars().any(char::is_whitespace) {
+                return Err(Error::User(
+                    "Malformed page of the mini program, expected a path like pages/index?id=1.",
+                ));
+            }
+        }
+        if !NOTICE_TITLE.allows(&self.title) {
+            return Err(Error::User(
+                "Title of miniprogram_notice must be 4 to 12 characters.",
+            ));
+        }
+        if !NOTICE_DESCRIPTION.allows_optional(&self.description) {
+            return Err(Error::User(
+                "Description of miniprogram_notice must be 4 to 12 characters.",
+            ));
+        }
+        if self.content_item.len() > 10 {
+            return Err(Error::User(
+                "miniprogram_notice allows at most 10 content items.",
+            ));
+        }
+        if self.content_item.iter().any(|item| {
+            !NOTICE_ITEM_KEY.allows(&item.key) || !NOTICE_ITEM_VALUE.allows(&item.value)
+        }) {
+            return Err(Error::User(
+                "Key and value of a content item allow at most 10 and 30 characters.",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// https://work.weixin.qq.com/api/doc/90000/90135/90236#模板卡片消息, only text_notice is supported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateCard {
+    card_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<CardSource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    main_title: Option<CardTitle>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    emphasis_content: Option<CardTitle>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sub_title_text: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    jump_list: Vec<CardJump>,
+    card_action: CardAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CardSource {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    icon_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    desc: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CardTitle {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    desc: Option<String>,
+}
+
+// Type 0 is plain text, 1 opens the url and 2 opens the mini program.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CardJump {
+    #[serde(rename = "type", default)]
+    jump_type: u8,
+    title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    appid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pagepath: Option<String>,
+}
+
+// Type 1 opens the url and 2 opens the mini program.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CardAction {
+    #[serde(rename = "type")]
+    action_type: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    appid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pagepath: Option<String>,
+}
+
+fn is_blank(value: &Option<String>) -> bool {
+    value
+        .as_ref()
+        .map(|value| value.trim().is_empty())
+        .unwrap_or(true)
+}
+
+impl TemplateCard {
+    fn validate(&self) -> Result<()> {
+        if self.card_type != "text_notice" {
+            return Err(Error::User(
+                "Only text_notice is supported for card_type of template_card.",
+            ));
+        }
+        let main_title = self.main_title.as_ref().map(|main_title| &main_title.title);
+        if main_title.map(is_blank).unwrap_or(true) && is_blank(&self.sub_title_text) {
+            return Err(Error::User(
+                "template_card requires main_title.title or sub_title_text.",
+            ));
+        }
+        if let Some(ref main_title) = self.main_title {
+            if !CARD_MAIN_TITLE.allows_optional(&main_title.title)
+                || !CARD_MAIN_DESC.allows_optional(&main_title.desc)
+            {
+                return Err(Error::User(
+                    "main_title of template_card allows at most 36 characters in title and 44 in desc.",
+                ));
+            }
+        }
+        if !CARD_SUB_TITLE.allows_optional(&self.sub_title_text) {
+            return Err(Error::User(
+                "sub_title_text of template_card allows at most 160 characters.",
+            ));
+        }
+        if self.jump_list.len() > 3 {
+            return Err(Error::User(
+                "jump_list of template_card allows at most 3 items.",
+            ));
+        }
+        for jump in &self.jump_list {
+            if jump.title.trim().is_empty() {
+                return Err(Error::User("Each item of jump_list requires a title."));
+            }
+            match jump.jump_type {
+                0 => {}
+                1 if !is_blank(&jump.url) => {}
+                1 => {
+                    return Err(Error::User(
+                        "An item of jump_list with type 1 requires url.",
+                    ))
+                }
+                2 if !is_blank(&jump.appid) => {}
+                2 => {
+                    return Err(Error::User(
+                        "An item of jump_list with type 2 requires appid.",
+                    ))
+                }
+                _ => return Err(Error::User("type of jump_list items must be 0, 1 or 2.")),
+            }
+        }
+        match self.card_action.action_type {
+            1 if !is_blank(&self.card_action.url) => {}
+            1 => return Err(Error::User("card_action with type 1 requires url.")),
+            2 if !is_blank(&self.card_action.appid) => {}
+            2 => return Err(Error::User("card_action with type 2 requires appid.")),
+            _ => return Err(Error::User("type of card_action must be 1 or 2.")),
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WeChatSendResponse {
+    #[serde(rename = "errcode")]
+    error_code: i64,
+    #[serde(rename = "errmsg")]
+    error_message: String,
+    // Only replied by message/send, which is what recalls take.
+    #[serde(default)]
+    msgid: String,
+    // Joined by `|`, the message is still delivered to the others.
+    #[serde(default)]
+    invaliduser: String,
+    #[serde(default)]
+    invalidparty: String,
+    #[serde(default)]
+    invalidtag: String,
+}
+
+// The targets WeChat couldn't deliver to, like members who have left.
+fn invalid_targets_of(reply: &WeChatSendResponse) -> InvalidTargets {
+    let split = |ids: &str| {
+        ids.split('|')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::to_owned)
+            .collect()
+    };
+    InvalidTargets {
+        invalid_users: split(&reply.invaliduser),
+        invalid_parties: split(&reply.invalidparty),
+        invalid_tags: split(&reply.invalidtag),
+    }
+}
+
+// What a delivery ends up with.
+#[derive(Debug)]
+pub struct Delivery {
+    pub retry_count: u32,
+    pub msgid: Option<String>,
+    // The reply of WeChat as is, for debugging.
+    pub raw_response: Option<Value>,
+    pub invalid_targets: InvalidTargets,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LengthUnit {
+    Bytes,
+    Chars,
+}
+
+// How long a field of a msgtype may be in WeChat.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LengthLimit {
+    pub msgtype: &'static str,
+    // The path in the message like `main_title.title`.
+    pub field: &'static str,
+    pub min: usize,
+    pub max: usize,
+    pub unit: LengthUnit,
+}
+
+impl LengthLimit {
+    const fn bytes(msgtype: &'static str, field: &'static str, max: usize) -> Self {
+        LengthLimit {
+            msgtype,
+            field,
+            min: 0,
+            max,
+            unit: LengthUnit::Bytes,
+        }
+    }
+
+    const fn chars(msgtype: &'static str, field: &'static str, min: usize, max: usize) -> Self {
+        LengthLimit {
+            msgtype,
+            field,
+            min,
+            max,
+            unit: LengthUnit::Chars,
+        }
+    }
+
+    pub fn length_of(&self, value: &str) -> usize {
+        match self.unit {
+            LengthUnit::Bytes => value.len(),
+            LengthUnit::Chars => value.chars().count(),
+        }
+    }
+
+    pub fn allows(&self, value: &str) -> bool {
+        (self.min..=self.max).contains(&self.length_of(value))
+    }
+
+    // Absent optional fields are allowed.
+    fn allows_optional(&self, value: &Option<String>) -> bool {
+        value.as_deref().map_or(true, |value| self.allows(value))
+    }
+}
+
+// https://work.weixin.qq.com/api/doc/90000/90135/90236, WeChat counts the content of text and
+// markdown in bytes and the rest in characters.
+pub const TEXT_CONTENT: LengthLimit = LengthLimit::bytes("text", "content", 2048);
+pub const MARKDOWN_CONTENT: LengthLimit = LengthLimit::bytes("markdown", "content", 4096);
+pub const NOTICE_TITLE: LengthLimit = LengthLimit::chars("miniprogram_notice", "title", 4, 12);
+pub const NOTICE_DESCRIPTION: LengthLimit =
+    LengthLimit::chars("miniprogram_notice", "description", 4, 12);
+pub const NOTICE_ITEM_KEY: LengthLimit =
+    LengthLimit::chars("miniprogram_notice", "content_item.key", 0, 10);
+pub const NOTICE_ITEM_VALUE: LengthLimit =
+    LengthLimit::chars("miniprogram_notice", "content_item.value", 0, 30);
+pub const CARD_MAIN_TITLE: LengthLimit =
+    LengthLimit::chars("template_card", "main_title.title", 0, 36);
+pub const CARD_MAIN_DESC: LengthLimit =
+    LengthLimit::chars("template_card", "main_title.desc", 0, 44);
+pub const CARD_SUB_TITLE: LengthLimit =
+    LengthLimit::chars("template_card", "sub_title_text", 0, 160);
+
+// All the limits above, new msgtypes add theirs here too.
+pub const LENGTH_LIMITS: &[LengthLimit] = &[
+    TEXT_CONTENT,
+    MARKDOWN_CONTENT,
+    NOTICE_TITLE,
+    NOTICE_DESCRIPTION,
+    NOTICE_ITEM_KEY,
+    NOTICE_ITEM_VALUE,
+    CARD_MAIN_TITLE,
+    CARD_MAIN_DESC,
+    CARD_SUB_TITLE,
+];
+
+// WeChat allows a duplicate check window of at most 4 hours.
+const MAX_DUPLICATE_CHECK_INTERVAL: u64 = 4 * 60 * 60;
+// The limits of images WeChat takes.
+const MIN_IMAGE_LENGTH: usize = 5;
+const MAX_IMAGE_LENGTH: usize = 10 * 1024 * 1024;
+// Bodies are larger than the default of actix for images in base64.
+pub const MAX_BODY_LENGTH: usize = MAX_IMAGE_LENGTH / 3 * 4 + 1024 * 1024;
+// GET is for short messages only, which is also the most WeChat shows for a text message.
+const MAX_QUERY_TEXT_LENGTH: usize = TEXT_CONTENT.max;
+const MAX_EXTERNAL_CONTACTS: usize = 10000;
+const MAX_TAGS: usize = 100;
+const MAX_USERS: usize = 1000;
+const MAX_PARTIES: usize = 100;
+const MAX_CHAT_ID_LENGTH: usize = 32;
+// Metadata is for correlating logs only, it's never sent to WeChat.
+const META_PREFIX: &str = "meta.";
+const MAX_META_FIELDS: usize = 10;
+const MAX_META_KEY_LENGTH: usize = 64;
+const MAX_META_VALUE_LENGTH: usize = 256;
+const IN_FLIGHT_MESSAGE: &str = "Too many messages in flight, please retry later.";
+const IN_FLIGHT_RETRY_AFTER: Duration = Duration::from_secs(1);
+// The key is known but there's nowhere to deliver, unlike unknown keys and WeChat's rejections.
+const NO_CHANNEL_MESSAGE: &str =
+    "No delivery channel configured, please add WeChat credentials, a Matrix room or an SMTP server on the user page first.";
+const NO_MATRIX_MESSAGE: &str = "No Matrix room configured, please add one on the user page first.";
+const NO_EMAIL_MESSAGE: &str = "No SMTP server configured, please add one on the user page first.";
+// WeChat only recalls messages sent within 24 hours, replying the code for older ones.
+const RECALL_API: &str = "message/recall";
+const RECALL_EXPIRED_ERROR_CODE: i64 = 301059;
+const RECALL_EXPIRED_MESSAGE: &str =
+    "The message is too old to recall, only those sent within 24 hours can be.";
+pub const DEADLINE_MESSAGE: &str = "The deadline passed before the message was delivered.";
+// https://work.weixin.qq.com/api/doc/90000/90139/90313, the system being busy and the frequency
+// limits of the API, the only codes worth retrying.
+const BUSY_ERROR_CODES: [i64; 3] = [-1, 45009, 45033];
+// The token is invalid or expired, it's dropped so that the next send fetches a new one.
+const TOKEN_ERROR_CODES: [i64; 2] = [40014, 42001];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageType {
+    Text,
+    Markdown,
+    // The payload is the JSON of `miniprogram_notice`.
+    MiniprogramNotice,
+    // The payload is the JSON of `template_card`.
+    TemplateCard,
+    // The payload is like `{"image_base64": "..."}` of a JPG or PNG image.
+    Image,
+}
+
+impl FromStr for MessageType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(MessageType::Text),
+            "markdown" => Ok(MessageType::Markdown),
+            "miniprogram_notice" => Ok(MessageType::MiniprogramNotice),
+            "template_card" => Ok(MessageType::TemplateCard),
+            "image" => Ok(MessageType::Image),
+            _ => Err(Error::User(
+                "Unknown message type, expected text, markdown, miniprogram_notice, template_card or image.",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AlertLevel {
+    Error,
+    Warn,
+    Info,
+}
+
+impl AlertLevel {
+    fn prefix(self, levels: &LevelConfig) -> String {
+        let prefix = match self {
+            AlertLevel::Error => &levels.error,
+            AlertLevel::Warn => &levels.warn,
+            AlertLevel::Info => &levels.info,
+        };
+        if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{} ", prefix)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Message {
+    // Query strings are form decoded, so `+` is a space and a plus sign is `%2B`.
+    text: Option<String>,
+    // The first line of text and markdown messages.
+    title: Option<String>,
+    // Falls back to the tenant's default, then text.
+    #[serde(rename = "type", alias = "msgtype")]
+    message_type: Option<MessageType>,
+    // Userids joined by `|`, the targets default to the tenant's, then everyone.
+    #[serde(alias = "touser")]
+    to_user: Option<String>,
+    // Department ids joined by `,` or `|`.
+    to_party: Option<String>,
+    // Sends to the members of the tags, multiple ids are joined by `,` or `|`.
+    #[serde(alias = "totag")]
+    to_tag: Option<String>,
+    // In seconds, duplicate check is disabled if absent.
+    dup_check_interval: Option<u64>,
+    // Replies 202 right away and delivers in background, the status can be polled with request id.
+    #[serde(rename = "async", default)]
+    is_async: bool,
+    // Urgent messages are delivered even in quiet hours.
+    #[serde(default)]
+    urgent: bool,
+    // False delivers even in quiet hours, like urgent.
+    respect_quiet_hours: Option<bool>,
+    // Prepends the emoji of the severity to text and markdown messages.
+    level: Option<AlertLevel>,
+    // Plain text of markdown messages for where markdown isn't supported, like external
+    // contacts. The markdown is stripped instead if absent.
+    text_fallback: Option<String>,
+    // Sends to the external contacts instead, multiple ids are joined by `|`.
+    external_userid: Option<String>,
+    // The member sending to external contacts, any member having them as customers if absent.
+    sender: Option<String>,
+    // Sends to the group chat created by the app instead of members.
+    #[serde(alias = "chatid")]
+    chat_id: Option<String>,
+    // Selects the rate limit bucket, normal if absent.
+    #[serde(default)]
+    priority: Priority,
+    // Falls back to text if `Accept` asks for plain text, then the tenant's, then native.
+    #[serde(alias = "format")]
+    response_format: Option<ResponseFormat>,
+    // Bounds the delivery including retries like `X-PipeHub-Timeout-Ms`, in milliseconds.
+    timeout_ms: Option<u64>,
+    // Renders the named template of the tenant with the `var.` parameters instead of taking text.
+    template: Option<String>,
+    // Falls back to the server's.
+    validation: Option<Validation>,
+    channel: Option<Channel>,
+    // Replies the response of WeChat as is too, like the users it couldn't deliver to.
+    #[serde(default)]
+    raw_response: bool,
+}
+
+impl Message {
+    fn respects_quiet_hours(&self) -> bool {
+        !self.urgent && self.respect_quiet_hours.unwrap_or(true)
+    }
+}
+
+// Where messages are delivered, the first configured one unless chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Wechat,
+    Matrix,
+    Email,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Channel::Wechat
+    }
+}
+
+// Homeservers reject events over 64 KiB, markdown is sent both stripped and as HTML.
+const MATRIX_MAX_TEXT_LENGTH: usize = 32 * 1024;
+const MATRIX_MAX_MARKDOWN_LENGTH: usize = 16 * 1024;
+
+impl Channel {
+    // The most bytes of text and markdown the channel takes, the channels of new message
+    // types have theirs here. Emails have no limit but the one of the SMTP server on the size.
+    pub fn max_content_bytes(self, message_type: MessageType) -> Option<usize> {
+        match (self, message_type) {
+            (Channel::Wechat, MessageType::Text) => Some(TEXT_CONTENT.max),
+            (Channel::Wechat, MessageType::Markdown) => Some(MARKDOWN_CONTENT.max),
+            (Channel::Matrix, MessageType::Text) => Some(MATRIX_MAX_TEXT_LENGTH),
+            (Channel::Matrix, MessageType::Markdown) => Some(MATRIX_MAX_MARKDOWN_LENGTH),
+            // The JSON payloads of WeChat are validated by field instead.
+            _ => None,
+        }
+    }
+}
+
+fn channel_of(destination: &Destination) -> Channel {
+    match destination {
+        Destination::Wechat(_) => Channel::Wechat,
+        Destination::Matrix(_) => Channel::Matrix,
+        Destination::Email(_) => Channel::Email,
+    }
+}
+
+enum Destination {
+    Wechat(WechatWork),
+    Matrix(MatrixRoom),
+    Email(EmailChannel),
+}
+
+// How thoroughly messages are checked before they are delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Validation {
+    // Rejects what WeChat would with detailed 400s.
+    Strict,
+    // Only checks what PipeHub needs to build the message, WeChat rejects the rest.
+    Lenient,
+}
+
+impl Default for Validation {
+    fn default() -> Self {
+        Validation::Strict
+    }
+}
+
+// The shape of successful replies to sends, errors are always replied in the native format.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    // `Response` of PipeHub.
+    Native,
+    // Mimics WeChat, so clients parsing its replies work unchanged.
+    Wechat,
+    // Just `ok`, for the clients that can't parse JSON. The request id is in the header.
+    Text,
+}
+
+impl FromStr for ResponseFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "native" => Ok(ResponseFormat::Native),
+            "wechat" => Ok(ResponseFormat::Wechat),
+            "text" => Ok(ResponseFormat::Text),
+            _ => Err(Error::User(
+                "Unknown response format, expected native, wechat or text.",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct WeChatCompatibleResponse {
+    errcode: u64,
+    errmsg: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    msgid: Option<String>,
+}
+
+// The raw response is only replied if the delivery has one, which is when it's asked for.
+fn reply(
+    mut builder: HttpResponseBuilder,
+    response_format: ResponseFormat,
+    response: Response,
+    delivery: Option<Delivery>,
+) -> HttpResponse {
+    let (msgid, raw_response, invalid_targets) = delivery
+        .map(|delivery| {
+            (
+                delivery.msgid,
+                delivery.raw_response,
+                delivery.invalid_targets,
+            )
+        })
+        .unwrap_or_default();
+    match response_format {
+        ResponseFormat::Native => builder.json(SendResponse {
+            response,
+            msgid,
+            raw_response,
+            invalid_targets,
+        }),
+        // It's already in the format of WeChat.
+        ResponseFormat::Wechat => match raw_response {
+            Some(raw_response) => builder.json(raw_response),
+            None => builder.json(WeChatCompatibleResponse {
+                errcode: 0,
+                errmsg: "ok",
+                msgid,
+            }),
+        },
+        ResponseFormat::Text => builder.content_type("text/plain; charset=utf-8").body("ok"),
+    }
+}
+
+fn accepts_plain_text(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("text/plain") && !accept.contains("application/json"))
+        .unwrap_or(false)
+}
+
+// What to deliver, it's persisted as JSON when the delivery is deferred.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Outgoing {
+    body: WeChatMessageBody,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to_user: Option<String>,
+    to_party: Option<String>,
+    // Tag ids joined by `|` like WeChat expects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to_tag: Option<String>,
+    duplicate_check_interval: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external: Option<ExternalContacts>,
+    // Sent to the group chat with `appchat/send` instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chat_id: Option<String>,
+    // Sent with the credentials of the agent instead of the primary ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    agent_id: Option<i64>,
+    #[serde(default)]
+    priority: Priority,
+    // Recorded with the message for correlating logs, like `meta.service=billing`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    meta: BTreeMap<String, String>,
+    // The image in base64, uploaded when it's delivered as the media expires in 3 days.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(default)]
+    channel: Channel,
+    // The title is the subject of emails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subject: Option<String>,
+}
+
+impl Outgoing {
+    // What the recipients read, the content with its title and decorations and the subject.
+    fn texts(&self) -> Vec<&str> {
+        let mut texts = Vec::new();
+        match self.body {
+            WeChatMessageBody::Text { ref text } => texts.push(text.content.as_str()),
+            WeChatMessageBody::Markdown { ref markdown } => texts.push(markdown.content.as_str()),
+            WeChatMessageBody::MiniprogramNotice {
+                ref miniprogram_notice,
+            } => content_policy::json_strings(miniprogram_notice, &mut texts),
+            WeChatMessageBody::TemplateCard { ref template_card } => {
+                content_policy::json_strings(template_card, &mut texts)
+            }
+            WeChatMessageBody::Image { .. } => {}
+        }
+        texts.extend(self.subject.as_deref());
+
+        texts
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    // The digest of what WeChat compares to drop duplicates and the interval, if it's asked to.
+    // Images are uploaded again each time, so they are never duplicates.
+    pub fn duplicate_check(&self) -> Option<(u64, u64)> {
+        let interval = self.duplicate_check_interval?;
+        if self.channel != Channel::Wechat || self.chat_id.is_some() || self.image.is_some() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(&self.body).ok()?.hash(&mut hasher);
+        (&self.to_user, &self.to_party, &self.to_tag, self.agent_id).hash(&mut hasher);
+
+        Some((hasher.finish(), interval))
+    }
+}
+
+// Returns the decoded image and its file name, which tells WeChat the format.
+fn decode_image(image_base64: &str) -> Result<(Vec<u8>, &'static str)> {
+    let image: String = image_base64
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
+    let image = base64::decode_block(&image)
+        .map_err(|_| Error::User("Malformed image_base64, expected standard base64."))?;
+    if image.len() < MIN_IMAGE_LENGTH || image.len() > MAX_IMAGE_LENGTH {
+        return Err(Error::User("The image must be between 5 bytes and 10 MB."));
+    }
+    let file_name = if image.starts_with(b"\x89PNG") {
+        "image.png"
+    } else if image.starts_with(b"\xFF\xD8\xFF") {
+        "image.jpg"
+    } else {
+        return Err(Error::User("The image must be a JPG or PNG."));
+    };
+
+    Ok((image, file_name))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExternalContacts {
+    external_userid: Vec<String>,
+    sender: Option<String>,
+}
+
+fn parse_external_contacts(message: &Message) -> Result<Option<ExternalContacts>> {
+    let external_userid = match message.external_userid {
+        Some(ref external_userid) => external_userid
+            .split('|')
+            .map(|userid| userid.trim().to_owned())
+            .filter(|userid| !userid.is_empty())
+            .collect::<Vec<_>>(),
+        None => return Ok(None),
+    };
+    if external_userid.is_empty() || external_userid.len() > MAX_EXTERNAL_CONTACTS {
+        return Err(Error::User(
+            "external_userid must have 1 to 10000 ids joined by |.",
+        ));
+    }
+    if message.to_user.is_some() || message.to_party.is_some() || message.to_tag.is_some() {
+        return Err(Error::User(
+            "external_userid can't be combined with to_user, to_party or to_tag.",
+        ));
+    }
+
+    Ok(Some(ExternalContacts {
+        external_userid,
+        sender: message.sender.clone().filter(|sender| !sender.is_empty()),
+    }))
+}
+
+// Chat ids are the ones given when the app created the chat, at most 32 letters and digits.
+fn parse_chat_id(message: &Message) -> Result<Option<String>> {
+    let chat_id = match message.chat_id {
+        Some(ref chat_id) => chat_id.trim(),
+        None => return Ok(None),
+    };
+    if chat_id.is_empty()
+        || chat_id.len() > MAX_CHAT_ID_LENGTH
+        || !chat_id.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return Err(Error::User(
+            "Malformed chat_id, expected 1 to 32 letters and digits.",
+        ));
+    }
+    if message.to_user.is_some()
+        || message.to_party.is_some()
+        || message.to_tag.is_some()
+        || message.external_userid.is_some()
+    {
+        return Err(Error::User(
+            "chat_id can't be combined with to_user, to_party, to_tag or external_userid.",
+        ));
+    }
+    if message.dup_check_interval.is_some() {
+        return Err(Error::User(
+            "dup_check_interval doesn't apply to group chats.",
+        ));
+    }
+
+    Ok(Some(chat_id.to_owned()))
+}
+
+fn parse_to_user(to_user: &str) -> Result<String> {
+    let to_user = to_user
+        .split('|')
+        .map(|userid| userid.trim())
+        .filter(|userid| !userid.is_empty())
+        .collect::<Vec<_>>();
+    if to_user
+        .iter()
+        .any(|userid| userid.chars().any(char::is_whitespace))
+    {
+        return Err(Error::User(
+            "Malformed to_user, expected userids like zhangsan|lisi.",
+        ));
+    }
+    if to_user.is_empty() || to_user.len() > MAX_USERS {
+        return Err(Error::User(
+            "to_user must have 1 to 1000 userids joined by |.",
+        ));
+    }
+
+    Ok(to_user.join("|"))
+}
+
+// Ids of parties and tags are joined by `,` or `|`, WeChat expects `|`.
+fn parse_ids(ids: &str, max_ids: usize, error: &'static str) -> Result<String> {
+    let ids = ids
+        .split(&[',', '|'][..])
+        .map(|id| id.trim())
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse::<u32>()
+                .map(|_| id)
+                .map_err(|_| Error::User(error))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if ids.is_empty() || ids.len() > max_ids {
+        return Err(Error::User(error));
+    }
+
+    Ok(ids.join("|"))
+}
+
+fn parse_to_tag(to_tag: &str) -> Result<String> {
+    parse_ids(
+        to_tag,
+        MAX_TAGS,
+        "Malformed to_tag, expected 1 to 100 tag ids like 1,2.",
+    )
+}
+
+fn parse_to_party(to_party: &str) -> Result<String> {
+    parse_ids(
+        to_party,
+        MAX_PARTIES,
+        "Malformed to_party, expected 1 to 100 party ids like 1,2.",
+    )
+}
+
+// The defaults are checked when they are saved, so messages aren't refused for them later.
+pub fn validate_default_targets(tenant: &Tenant) -> Result<()> {
+    if let Some(ref to_user) = tenant.default_to_user {
+        parse_to_user(to_user)?;
+    }
+    if let Some(ref to_party) = tenant.default_to_party {
+        parse_to_party(to_party)?;
+    }
+    if let Some(ref to_tag) = tenant.default_to_tag {
+        parse_to_tag(to_tag)?;
+    }
+
+    Ok(())
+}
+
+// Messages to nobody in particular go to the tenant's default targets, if any. The targets of
+// the request replace the defaults instead of adding to them.
+fn use_default_targets(outgoing: &mut Outgoing, tenant: &Tenant) -> Result<()> {
+    if outgoing.external.is_some()
+        || outgoing.chat_id.is_some()
+        || outgoing.to_user.is_some()
+        || outgoing.to_party.is_some()
+        || outgoing.to_tag.is_some()
+    {
+        return Ok(());
+    }
+    outgoing.to_user = tenant
+        .default_to_user
+        .as_deref()
+        .map(parse_to_user)
+        .transpose()?;
+    outgoing.to_party = tenant
+        .default_to_party
+        .as_deref()
+        .map(parse_to_party)
+        .transpose()?;
+    outgoing.to_tag = tenant
+        .default_to_tag
+        .as_deref()
+        .map(parse_to_tag)
+        .transpose()?;
+
+    Ok(())
+}
+
+// External contacts are messaged with the token of the customer contact secret.
+fn external_credentials(wechat: &WechatWork) -> Result<WechatWork> {
+    match wechat.external_secret {
+        Some(ref external_secret) if !external_secret.is_empty() => Ok(WechatWork {
+            id: wechat.id,
+            tenant_id: wechat.tenant_id,
+            corp_id: wechat.corp_id.clone(),
+            agent_id: wechat.agent_id,
+            secret: external_secret.clone(),
+            external_secret: None,
+        }),
+        _ => Err(Error::User(
+            "No external contact secret configured, it's required to send to external_userid.",
+        )),
+    }
+}
+
+// The `{key}` segment is matched before percent-decoding, so it's decoded here.
+fn decode_key(key: &str) -> Result<Vec<u8>> {
+    let key = percent_decode_str(key)
+        .decode_utf8()
+        .map_err(|_| Error::User("Malformed App Key."))?;
+    key.from_base58()
+        .map_err(|_| Error::User("Malformed App Key."))
+}
+
+// App keys are exactly the 8 bytes of app id, longer ones are send tokens.
+fn app_id_of(app_key: &[u8]) -> Result<i64> {
+    let app_id = app_key
+        .try_into()
+        .map_err(|_| Error::User("Malformed App Key."))?;
+
+    Ok(i64::from_le_bytes(app_id))
+}
+
+pub fn decode_app_id(key: &str) -> Result<i64> {
+    app_id_of(&decode_key(key)?)
+}
+
+// WeChat only takes UTF-8, so bodies in the charset of `Content-Type` like GBK are transcoded.
+// It's UTF-8 if the charset is absent.
+fn decode_body(req: &HttpRequest, payload: &[u8]) -> Result<String> {
+    let encoding = req.encoding().map_err(|_| {
+        Error::User("Unsupported charset in Content-Type, please send UTF-8 or GBK.")
+    })?;
+
+    encoding
+        .decode_without_bom_handling_and_without_replacement(payload)
+        .map(Cow::into_owned)
+        .ok_or_else(|| Error::User("The body isn't encoded in its charset."))
+}
+
+// Takes the text from the query, or the body of POST.
+fn read_text(message: &Message, req: &HttpRequest, payload: &[u8]) -> Result<String> {
+    let is_get = req.method() == Method::GET;
+    let text = if let Some(text) = message.text.clone() {
+        text
+    } else if is_get {
+        return Err(Error::User("No text is provided in the query."));
+    } else {
+        decode_body(req, payload)?
+    };
+    if text.is_empty() {
+        return Err(Error::User("Text is empty."));
+    }
+    let title_length = message.title.as_ref().map(String::len).unwrap_or_default();
+    if is_get && text.len() + title_length > MAX_QUERY_TEXT_LENGTH {
+        return Err(Error::User(
+            "Text in the query is too long, please POST it instead.",
+        ));
+    }
+
+    Ok(text)
+}
+
+// Messages are only logged when the tenant or the operator opts in, and only for a while.
+fn should_log_body(tenant: &Tenant, config: &PipeHubConfig, now: DateTime<Utc>) -> bool {
+    [tenant.log_bodies_until, config.log_bodies_until]
+        .iter()
+        .flatten()
+        .any(|until| now < *until)
+}
+
+fn redact(text: &str, redactions: &[Regex]) -> String {
+    redactions.iter().fold(text.to_owned(), |text, redaction| {
+        redaction.replace_all(&text, "***").into_owned()
+    })
+}
+
+// Redacts before truncating, so a secret cut in half is still redacted.
+fn loggable_body(body: &str, config: &PipeHubConfig) -> String {
+    let mut body = redact(body, &config.log_redactions);
+    let length = body.len();
+    util::truncate(&mut body, config.log_body_max_length);
+    if body.len() < length {
+        body.push_str("...");
+    }
+
+    body
+}
+
+fn words(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(|word| word.trim())
+        .filter(|word| !word.is_empty())
+}
+
+// The allow list applies first, the message must contain any of its words if there are some.
+// Then it must not contain any word of the block list, which wins if both match.
+fn is_blocked(tenant: &Tenant, text: &str) -> bool {
+    let allowed = tenant
+        .allow_list
+        .as_deref()
+        .map(|allow_list| {
+            let mut allow_words = words(allow_list).peekable();
+            allow_words.peek().is_none() || allow_words.any(|allow_word| text.contains(allow_word))
+        })
+        .unwrap_or(true);
+
+    !allowed || words(&tenant.block_list).any(|block_word| text.contains(block_word))
+}
+
+// Payloads validated leniently only need to be JSON objects.
+fn read_json_object(text: &str) -> Result<Value> {
+    match serde_json::from_str(text) {
+        Ok(Value::Object(object)) => Ok(Value::Object(object)),
+        _ => Err(Error::User("Malformed payload, expected a JSON object.")),
+    }
+}
+
+// Bodies of `text/markdown` are markdown unless the message has a type, other content types
+// don't tell and fall back to the tenant's default.
+fn is_markdown_body(req: &HttpRequest) -> bool {
+    match req.mime_type() {
+        Ok(Some(mime)) => mime.type_() == "text" && mime.subtype() == "markdown",
+        _ => false,
+    }
+}
+
+fn message_type_of(tenant: &Tenant, message: &Message) -> Result<MessageType> {
+    match message.message_type {
+        Some(message_type) => Ok(message_type),
+        None => match tenant.default_message_type {
+            Some(ref message_type) => message_type.parse(),
+            None => Ok(MessageType::Text),
+        },
+    }
+}
+
+// The text of the message, rendered from the template if asked. The type of the template, or
+// the content type of the body, applies unless the message has one.
+async fn read_text_or_template(
+    pool: &Pool,
+    tenant: &Tenant,
+    message: &mut Message,
+    req: &HttpRequest,
+    payload: &[u8],
+) -> Result<String> {
+    let name = match message.template {
+        Some(ref name) => name.trim(),
+        None => {
+            let text = read_text(message, req, payload)?;
+            if message.message_type.is_none() && message.text.is_none() && is_markdown_body(req) {
+                message.message_type = Some(MessageType::Markdown);
+            }
+            return Ok(text);
+        }
+    };
+    if message.text.is_some() || !payload.is_empty() {
+        return Err(Error::User("Text can't be given with a template."));
+    }
+    let template = pool
+        .find_template(tenant.id, name)
+        .await?
+        .ok_or_else(|| Error::NotFound("Unknown template."))?;
+    if message.message_type.is_none() {
+        message.message_type = template
+            .message_type
+            .as_deref()
+            .map(str::parse)
+            .transpose()?;
+    }
+
+    template::render(
+        &template,
+        message_type_of(tenant, message)?,
+        &template::read_vars(req)?,
+    )
+}
+
+fn build_outgoing(
+    tenant: &Tenant,
+    message: &Message,
+    text: String,
+    channel: Channel,
+    config: &PipeHubConfig,
+    request_id: Uuid,
+) -> Result<Outgoing> {
+    let is_strict = message.validation.unwrap_or(config.validation) == Validation::Strict;
+    if let Some(interval) = message.dup_check_interval {
+        if is_strict && (interval == 0 || interval > MAX_DUPLICATE_CHECK_INTERVAL) {
+            return Err(Error::User(
+                "dup_check_interval must be between 1 and 14400 seconds.",
+            ));
+        }
+    }
+
+    let sanitize = |text: String| {
+        if config.strip_control_chars {
+            util::strip_control_chars(text)
+        } else {
+            text
+        }
+    };
+    let text = sanitize(text);
+    let title = message
+        .title
+        .clone()
+        .filter(|title| !title.is_empty())
+        .map(sanitize);
+
+    let message_type = message_type_of(tenant, message)?;
+    let text_fallback = message
+        .text_fallback
+        .clone()
+        .filter(|text_fallback| !text_fallback.trim().is_empty())
+        .map(sanitize);
+    if let Some(ref text_fallback) = text_fallback {
+        if message_type != MessageType::Markdown {
+            return Err(Error::User(
+                "text_fallback only applies to markdown messages.",
+            ));
+        }
+        if !TEXT_CONTENT.allows(text_fallback) {
+            return Err(Error::User("text_fallback is too long."));
+        }
+    }
+    let external = parse_external_contacts(message)?;
+    let to_user = message.to_user.as_deref().map(parse_to_user).transpose()?;
+    let to_party = message
+        .to_party
+        .as_deref()
+        .map(parse_to_party)
+        .transpose()?;
+    let to_tag = message.to_tag.as_deref().map(parse_to_tag).transpose()?;
+    let (message_type, text) = match message_type {
+        // External contacts only take text. Markdown may be longer than text, so what's left
+        // of it is truncated to fit.
+        MessageType::Markdown if external.is_some() => (
+            MessageType::Text,
+            text_fallback.unwrap_or_else(|| {
+                let mut stripped = util::strip_markdown(&text);
+                util::truncate(&mut stripped, TEXT_CONTENT.max);
+                stripped
+            }),
+        ),
+        message_type => (message_type, text),
+    };
+    if external.is_some() && message_type != MessageType::Text {
+        return Err(Error::User(
+            "Only text and markdown messages can be sent to external contacts.",
+        ));
+    }
+    let chat_id = parse_chat_id(message)?;
+    if chat_id.is_some()
+        && (message_type == MessageType::MiniprogramNotice
+            || message_type == MessageType::TemplateCard)
+    {
+        return Err(Error::User(
+            "Group chats don't take miniprogram_notice and template_card messages.",
+        ));
+    }
+
+    let text = match title {
+        Some(ref title) => match message_type {
+            MessageType::Text => format!("{}\n{}", title, text),
+            MessageType::Markdown => format!("**{}**\n{}", title, text),
+            MessageType::MiniprogramNotice | MessageType::TemplateCard => {
+                return Err(Error::User(
+                    "Put the title of miniprogram_notice and template_card in the payload.",
+                ))
+            }
+            MessageType::Image => {
+                return Err(Error::User("Images can't have a title."));
+            }
+        },
+        _ => text,
+    };
+
+    let mut image = None;
+    let mut body = match message_type {
+        MessageType::Text => WeChatMessageBody::Text {
+            text: WeChatMessageText { content: text },
+        },
+        MessageType::Markdown => WeChatMessageBody::Markdown {
+            markdown: WeChatMessageText { content: text },
+        },
+        MessageType::MiniprogramNotice if is_strict => {
+            let miniprogram_notice: MiniprogramNotice = serde_json::from_str(&text)
+                .map_err(|_| Error::User("Malformed miniprogram_notice, expected JSON."))?;
+            miniprogram_notice.validate()?;
+            WeChatMessageBody::MiniprogramNotice {
+                miniprogram_notice: serde_json::to_value(miniprogram_notice)?,
+            }
+        }
+        MessageType::MiniprogramNotice => WeChatMessageBody::MiniprogramNotice {
+            miniprogram_notice: read_json_object(&text)?,
+        },
+        MessageType::TemplateCard if is_strict => {
+            let template_card: TemplateCard = serde_json::from_str(&text).map_err(|_| {
+                Error::User("Malformed template_card, expected JSON of a text_notice card.")
+            })?;
+            template_card.validate()?;
+            WeChatMessageBody::TemplateCard {
+                template_card: serde_json::to_value(template_card)?,
+            }
+        }
+        MessageType::TemplateCard => WeChatMessageBody::TemplateCard {
+            template_card: read_json_object(&text)?,
+        },
+        MessageType::Image => {
+            let payload: ImagePayload = serde_json::from_str(&text).map_err(|_| {
+                Error::User("Malformed image, expected JSON like {\"image_base64\": \"...\"}.")
+            })?;
+            decode_image(&payload.image_base64)?;
+            image = Some(payload.image_base64);
+            WeChatMessageBody::Image {
+                image: WeChatMedia {
+                    media_id: String::new(),
+                },
+            }
+        }
+    };
+    if is_strict && !body.fits(channel) {
+        return Err(Error::User("The content is too long for the channel."));
+    }
+    let prefix = match message.level {
+        Some(level) => level.prefix(&config.levels) + &tenant.prefix,
+        None => tenant.prefix.clone(),
+    };
+    let suffix = if tenant.append_request_id {
+        format!("{}\nRequest ID: {}", tenant.suffix, request_id)
+    } else {
+        tenant.suffix.clone()
+    };
+    body.decorate(&prefix, &suffix, channel);
+
+    Ok(Outgoing {
+        body,
+        to_user,
+        to_party,
+        to_tag,
+        duplicate_check_interval: message.dup_check_interval,
+        external,
+        chat_id,
+        agent_id: None,
+        priority: message.priority,
+        meta: BTreeMap::new(),
+        image,
+        channel,
+        subject: title,
+    })
+}
+
+// Collects `meta.{key}={value}` of the query, they can't be fields of `Message` as
+// `serde_urlencoded` doesn't support flattening typed fields.
+fn read_meta(req: &HttpRequest) -> Result<BTreeMap<String, String>> {
+    let mut meta = BTreeMap::new();
+    for (key, value) in form_urlencoded::parse(req.query_string().as_bytes()) {
+        let key = match key.strip_prefix(META_PREFIX) {
+            Some(key) => key.trim(),
+            None => continue,
+        };
+        if key.is_empty() || key.chars().count() > MAX_META_KEY_LENGTH {
+            return Err(Error::User("Meta keys must have 1 to 64 characters."));
+        }
+        if !key.chars().all(is_meta_key_char) {
+            return Err(Error::User(
+                "Meta keys may only have letters, digits, `_`, `-` and `.`.",
+            ));
+        }
+        if value.chars().count() > MAX_META_VALUE_LENGTH {
+            return Err(Error::User("Meta values must have at most 256 characters."));
+        }
+        // They would start lines of their own in logs.
+        if value.chars().any(char::is_control) {
+            return Err(Error::User("Meta values can't have control characters."));
+        }
+        meta.insert(key.to_owned(), value.into_owned());
+    }
+    if meta.len() > MAX_META_FIELDS {
+        return Err(Error::User("At most 10 meta fields are allowed."));
+    }
+
+    Ok(meta)
+}
+
+fn is_meta_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+// Chooses among the agents of the tenant, e.g. `X-PipeHub-Agent: 1000002`.
+const AGENT_HEADER: &str = "X-PipeHub-Agent";
+
+fn read_agent_id(req: &HttpRequest) -> Result<Option<i64>> {
+    match req.headers().get(AGENT_HEADER) {
+        Some(agent_id) => agent_id
+            .to_str()
+            .ok()
+            .and_then(|agent_id| agent_id.trim().parse().ok())
+            .map(Some)
+            .ok_or(Error::User(
+                "Malformed X-PipeHub-Agent, expected the agent id.",
+            )),
+        None => Ok(None),
+    }
+}
+
+// When synchronous deliveries give up with 504, from `timeout_ms` or else the header. Malformed or
+// zero timeouts are ignored like the timeout of requests, and `request_timeout` caps both.
+fn read_deadline(
+    req: &HttpRequest,
+    timeout_ms: Option<u64>,
+    config: &PipeHubConfig,
+) -> Option<Instant> {
+    timeout_ms
+        .or_else(|| {
+            req.headers()
+                .get(TIMEOUT_HEADER)
+                .and_then(|timeout| timeout.to_str().ok())
+                .and_then(|timeout| timeout.trim().parse::<u64>().ok())
+        })
+        .filter(|&timeout| timeout > 0)
+        .map(|timeout| {
+            let timeout =
+                Duration::from_millis(timeout).min(Duration::from_secs(config.request_timeout));
+            Instant::now() + timeout
+        })
+}
+
+// The credentials of the chosen agent, otherwise the tenant's default agent, otherwise the
+// primary ones. A removed default agent falls back to the primary credentials.
+async fn find_wechat(
+    pool: &Pool,
+    tenant: &Tenant,
+    agent_id: Option<i64>,
+) -> Result<Option<WechatWork>> {
+    let wechats = pool.find_wechats_by_tenant_id(tenant.id).await?;
+    Ok(choose_wechat(wechats, tenant, agent_id))
+}
+
+// The credentials are ordered by id, so the primary ones come first.
+fn choose_wechat(
+    mut wechats: Vec<WechatWork>,
+    tenant: &Tenant,
+    agent_id: Option<i64>,
+) -> Option<WechatWork> {
+    match (agent_id, tenant.default_agent_id) {
+        (Some(agent_id), _) => wechats
+            .into_iter()
+            .find(|wechat| wechat.agent_id == agent_id),
+        (None, Some(default_agent_id)) => {
+            match wechats
+                .iter()
+                .position(|wechat| wechat.agent_id == default_agent_id)
+            {
+                Some(index) => Some(wechats.swap_remove(index)),
+                None => wechats.into_iter().next(),
+            }
+        }
+        (None, None) => wechats.into_iter().next(),
+    }
+}
+
+// WeChat, Matrix and email are tried in order unless one is chosen.
+fn choose_destination(
+    wechats: Vec<WechatWork>,
+    tenant: &Tenant,
+    agent_id: Option<i64>,
+    channel: Option<Channel>,
+) -> Result<Destination> {
+    let is_chosen = |candidate: Channel| channel.map_or(true, |channel| channel == candidate);
+    if is_chosen(Channel::Wechat) {
+        match choose_wechat(wechats, tenant, agent_id) {
+            Some(wechat) => return Ok(Destination::Wechat(wechat)),
+            None if agent_id.is_some() => {
+                return Err(Error::User("Unknown agent in X-PipeHub-Agent."))
+            }
+            None if channel.is_some() => return Err(Error::Conflict(NO_CHANNEL_MESSAGE)),
+            None => {}
+        }
+    }
+    if is_chosen(Channel::Matrix) {
+        match matrix::room_of(tenant)? {
+            Some(room) => return Ok(Destination::Matrix(room)),
+            None if channel.is_some() => return Err(Error::Conflict(NO_MATRIX_MESSAGE)),
+            None => {}
+        }
+    }
+    match email::channel_of(tenant)? {
+        Some(email) => Ok(Destination::Email(email)),
+        None if channel.is_some() => Err(Error::Conflict(NO_EMAIL_MESSAGE)),
+        None => Err(Error::Conflict(NO_CHANNEL_MESSAGE)),
+    }
+}
+
+// Matrix only takes text and markdown, high priority ones notify like messages of people.
+fn matrix_message(outgoing: &Outgoing) -> Result<MatrixMessage> {
+    if outgoing.external.is_some()
+        || outgoing.chat_id.is_some()
+        || outgoing.to_user.is_some()
+        || outgoing.to_party.is_some()
+        || outgoing.to_tag.is_some()
+    {
+        return Err(Error::User(
+            "External contacts, chat_id, to_user, to_party and to_tag only apply to WeChat.",
+        ));
+    }
+    let notify = outgoing.priority == Priority::High;
+    match outgoing.body {
+        WeChatMessageBody::Text { ref text } => {
+            Ok(MatrixMessage::text(text.content.clone(), notify))
+        }
+        WeChatMessageBody::Markdown { ref markdown } => {
+            Ok(MatrixMessage::markdown(&markdown.content, notify))
+        }
+        _ => Err(Error::User(
+            "Only text and markdown messages can be sent to Matrix.",
+        )),
+    }
+}
+
+fn email_message(outgoing: &Outgoing) -> Result<EmailMessage> {
+    if outgoing.external.is_some()
+        || outgoing.chat_id.is_some()
+        || outgoing.to_user.is_some()
+        || outgoing.to_party.is_some()
+        || outgoing.to_tag.is_some()
+    {
+        return Err(Error::User(
+            "External contacts, chat_id, to_user, to_party and to_tag only apply to WeChat.",
+        ));
+    }
+    let subject = outgoing.subject.as_deref();
+    match outgoing.body {
+        WeChatMessageBody::Text { ref text } => {
+            Ok(EmailMessage::text(subject, text.content.clone()))
+        }
+        WeChatMessageBody::Markdown { ref markdown } => {
+            Ok(EmailMessage::markdown(subject, &markdown.content))
+        }
+        _ => Err(Error::User(
+            "Only text and markdown messages can be sent by email.",
+        )),
+    }
+}
+
+// Rejects right away instead of piling up, the queues only drain at the pace of WeChat.
+fn ensure_queue_room(depth: i64, config: &PipeHubConfig) -> Result<()> {
+    if depth >= config.max_queued_messages {
+        return Err(Error::Overloaded(
+            "Too many messages queued, please retry later.",
+            Duration::from_secs(config.queue_retry_after),
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct EchoResponse {
+    #[serde(flatten)]
+    response: Response,
+    message: Outgoing,
+}
+
+// Authenticates the key and parses the message like `send` does, then replies with
+// what would be delivered instead of delivering it.
+pub async fn echo(
+    pool: Pool,
+    key: web::Path<String>,
+    payload: web::Bytes,
+    web::Query(mut message): web::Query<Message>,
+    logger: web::Data<Arc<ApplicationLogger>>,
+    config: web::Data<PipeHubConfig>,
+    req: HttpRequest,
+) -> std::result::Result<HttpResponse, AWError> {
+    let request_id: Uuid = req
+        .extensions()
+        .get::<Uuid>()
+        .cloned()
+        .expect("No request id found.");
+    let app_id = decode_app_id(&key)?;
+
+    let tenant = pool
+        .find_tenant_by_app_id(app_id)
+        .await?
+        .ok_or_else(|| Error::User("Unknown APP ID."))?;
+
+    let text = read_text_or_template(&pool, &tenant, &mut message, &req, &payload).await?;
+    if is_blocked(&tenant, &text) {
+        return Err(Error::User("Message blocked.").into());
+    }
+    let channel = message.channel.unwrap_or_default();
+    let mut outgoing = build_outgoing(&tenant, &message, text, channel, &config, request_id)?;
+    content_policy::check(&config, &logger, request_id, tenant.id, &outgoing.texts())?;
+    outgoing.meta = read_meta(&req)?;
+
+    Ok(HttpResponse::Ok().json(EchoResponse {
+        response: Response {
+            request_id,
+            success: true,
+            error_message: "".to_owned(),
+            hint: config.locale.hint(Hint::NothingDelivered),
+        },
+        message: outgoing,
+    }))
+}
+
+pub async fn send(
+    pool: Pool,
+    key: web::Path<String>,
+    payload: web::Bytes,
+    web::Query(mut message): web::Query<Message>,
+    logger: web::Data<Arc<ApplicationLogger>>,
+    access_token_cache: web::Data<Arc<AccessTokenCache>>,
+    http_client: web::Data<Client>,
+    job_store: web::Data<Arc<JobStore>>,
+    retry: web::Data<RetryConfig>,
+    config: web::Data<PipeHubConfig>,
+    metrics: web::Data<Arc<Metrics>>,
+    limiter: web::Data<Arc<OutboundLimiter>>,
+    rate_limiter: web::Data<Arc<RateLimiter>>,
+    tenant_cache: web::Data<Arc<TenantCache>>,
+    req: HttpRequest,
+) -> std::result::Result<HttpResponse, AWError> {
+    let request_id: Uuid = req
+        .extensions()
+        .get::<Uuid>()
+        .cloned()
+        .expect("No request id found.");
+    let app_key = decode_key(&key)?;
+    let app_id = if send_token::is_token(&app_key) {
+        send_token::redeem(&pool, &config, &app_key, Scope::Send).await?
+    } else {
+        app_id_of(&app_key)?
+    };
+
+    let tenant = tenant_cache
+        .find_tenant_by_app_id(&pool, app_id)
+        .await?
+        .ok_or_else(|| Error::User("Unknown APP ID."))?;
+    let response_format = match message.response_format {
+        Some(response_format) => response_format,
+        None if accepts_plain_text(&req) => ResponseFormat::Text,
+        None => tenant
+            .response_format
+            .as_deref()
+            .map(str::parse::<ResponseFormat>)
+            .transpose()?
+            .unwrap_or(ResponseFormat::Native),
+    };
+    let agent_id = read_agent_id(&req)?;
+    let meta = read_meta(&req)?;
+    let wechats = tenant_cache
+        .find_wechats_by_tenant_id(&pool, tenant.id)
+        .await?;
+    let destination = choose_destination(wechats, &tenant, agent_id, message.channel)?;
+
+    if config.log_bodies {
+        // Logged before parsing, so the malformed ones can be diagnosed too.
+        let body = if payload.is_empty() {
+            message.text.clone().unwrap_or_default()
+        } else {
+            decode_body(&req, &payload)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&payload).into_owned())
+        };
+        logger.track_debug(
+            request_id,
+            &format!("Inbound body {}", loggable_body(&body, &config)),
+        );
+    }
+    let text = read_text_or_template(&pool, &tenant, &mut message, &req, &payload).await?;
+    if should_log_body(&tenant, &config, Utc::now()) {
+        logger.track_trace(
+            request_id,
+            Level::Info,
+            &format!("Message {}", redact(&text, &config.log_redactions)),
+        );
+    }
+    if is_blocked(&tenant, &text) {
+        metrics.record(tenant.id, Outcome::Blocked);
+        return Err(Error::User("Message blocked.").into());
+    }
+    logger.track_trace_with(
+        request_id,
+        Level::Info,
+        &format!("Sending with {} priority.", message.priority.as_str()),
+        &meta,
+    );
+    let limits = rate_limit::tenant_limits(&tenant, &config.rate_limits)?;
+    if let Err(e) = rate_limiter.acquire(tenant.id, message.priority, &limits) {
+        metrics.record(tenant.id, Outcome::RateLimited);
+        return Err(e.into());
+    }
+    let mut outgoing = build_outgoing(
+        &tenant,
+        &message,
+        text,
+        channel_of(&destination),
+        &config,
+        request_id,
+    )?;
+    if let Err(e) =
+        content_policy::check(&config, &logger, request_id, tenant.id, &outgoing.texts())
+    {
+        metrics.record(tenant.id, Outcome::PolicyViolation);
+        return Err(e.into());
+    }
+    outgoing.agent_id = agent_id;
+    outgoing.meta = meta;
+    match destination {
+        Destination::Wechat(ref wechat) => {
+            if outgoing.external.is_some() {
+                external_credentials(wechat)?;
+            }
+            use_default_targets(&mut outgoing, &tenant)?;
+        }
+        Destination::Matrix(_) => {
+            matrix_message(&outgoing)?;
+        }
+        Destination::Email(_) => {
+            email_message(&outgoing)?;
+        }
+    }
+
+    if message.respects_quiet_hours() {
+        if let Some(deliver_at) = schedule::quiet_until(&tenant, config.time_zone, Utc::now())? {
+            if tenant.drop_in_quiet_hours {
+                return Ok(reply(
+                    HttpResponse::Ok(),
+                    response_format,
+                    Response {
+                        request_id,
+                        success: true,
+                        error_message: "".to_owned(),
+                        hint: config.locale.hint(Hint::Dropped(deliver_at)),
+                    },
+                    None,
+                ));
+            }
+
+            ensure_queue_room(pool.count_scheduled_messages().await?, &config)?;
+            let payload = serde_json::to_string(&outgoing).map_err(Error::from)?;
+            pool.insert_scheduled_message(tenant.id, request_id, payload, deliver_at)
+                .await?;
+
+            return Ok(reply(
+                HttpResponse::Accepted(),
+                response_format,
+                Response {
+                    request_id,
+                    success: true,
+                    error_message: "".to_owned(),
+                    hint: config.locale.hint(Hint::Deferred(deliver_at)),
+                },
+                None,
+            ));
+        }
+    }
+
+    if config.outbox || tenant.use_outbox {
+        ensure_queue_room(pool.count_waiting_pending_sends().await?, &config)?;
+        retry_queue::enqueue(&pool, tenant.id, request_id, &outgoing).await?;
+
+        return Ok(reply(
+            HttpResponse::Accepted(),
+            response_format,
+            Response {
+                request_id,
+                success: true,
+                error_message: "".to_owned(),
+                hint: config.locale.hint(Hint::Poll(request_id)),
+            },
+            None,
+        ));
+    }
+
+    if message.is_async {
+        // The client wouldn't learn about it until polling otherwise.
+        if limiter.is_saturated() {
+            return Err(Error::Overloaded(IN_FLIGHT_MESSAGE, IN_FLIGHT_RETRY_AFTER).into());
+        }
+        job_store.update(request_id, JobStatus::Queued, "");
+        let retry_queue_config = config.retry_queue;
+        actix_rt::spawn(async move {
+            job_store.update(request_id, JobStatus::Sending, "");
+            let result = deliver_to(
+                &destination,
+                &http_client,
+                &limiter,
+                request_id,
+                &logger,
+                &access_token_cache,
+                &outgoing,
+                &retry,
+                None,
+            )
+            .await;
+            metrics.record_send(tenant.id, &outgoing, &result);
+            match result {
+                Ok(_) => job_store.update(request_id, JobStatus::Delivered, ""),
+                Err(ref e) if retry_queue_config.enabled && retry_queue::is_transient(e) => {
+                    let parked = retry_queue::park(
+                        &pool,
+                        &retry_queue_config,
+                        tenant.id,
+                        request_id,
+                        &outgoing,
+                        e,
+                    )
+                    .await;
+                    match parked {
+                        Ok(_) => job_store.update(request_id, JobStatus::Pending, &e.to_string()),
+                        Err(_) => job_store.update(request_id, JobStatus::Failed, &e.to_string()),
+                    }
+                }
+                Err(e) => job_store.update(request_id, JobStatus::Failed, &e.to_string()),
+            }
+        });
+
+        return Ok(reply(
+            HttpResponse::Accepted(),
+            response_format,
+            Response {
+                request_id,
+                success: true,
+                error_message: "".to_owned(),
+                hint: config.locale.hint(Hint::Poll(request_id)),
+            },
+            None,
+        ));
+    }
+
+    let deadline = read_deadline(&req, message.timeout_ms, &config);
+    let result = deliver_to(
+        &destination,
+        &http_client,
+        &limiter,
+        request_id,
+        &logger,
+        &access_token_cache,
+        &outgoing,
+        &retry,
+        deadline,
+    )
+    .await;
+    metrics.record_send(tenant.id, &outgoing, &result);
+    let mut delivery = match result {
+        Ok(delivery) => delivery,
+        // Accepted like the deferred ones, it's delivered once the failure goes away.
+        Err(ref e) if config.retry_queue.enabled && retry_queue::is_transient(e) => {
+            let next_attempt_at = retry_queue::park(
+                &pool,
+                &config.retry_queue,
+                tenant.id,
+                request_id,
+                &outgoing,
+                e,
+            )
+            .await?;
+            return Ok(reply(
+                HttpResponse::Accepted(),
+                response_format,
+                Response {
+                    request_id,
+                    success: true,
+                    error_message: "".to_owned(),
+                    hint: config
+                        .locale
+                        .hint(Hint::Parked(next_attempt_at, request_id)),
+                },
+                None,
+            ));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !message.raw_response {
+        delivery.raw_response = None;
+    }
+
+    Ok(reply(
+        HttpResponse::Ok(),
+        response_format,
+        Response {
+            request_id,
+            success: true,
+            error_message: "".to_owned(),
+            hint: config.locale.hint(Hint::Retried(delivery.retry_count)),
+        },
+        Some(delivery),
+    ))
+}
+
+// Parses the message of WeChat, `agentid` is always the one of the credentials.
+fn read_raw_message(payload: &[u8], agent_id: i64) -> Result<Map<String, Value>> {
+    if payload.is_empty() {
+        return Err(Error::User("No message is provided."));
+    }
+    let mut message: Map<String, Value> = serde_json::from_slice(payload)
+        .map_err(|_| Error::User("Malformed message, expected a JSON object of WeChat."))?;
+    if message.is_empty() {
+        return Err(Error::User("Message is empty."));
+    }
+    message.insert("agentid".to_owned(), Value::from(agent_id));
+
+    Ok(message)
+}
+
+// Forwards a message of WeChat verbatim for the types we don't support, WeChat validates it.
+// It's always delivered right away, neither deferred nor decorated.
+pub async fn raw(
+    pool: Pool,
+    key: web::Path<String>,
+    payload: web::Bytes,
+    logger: web::Data<Arc<ApplicationLogger>>,
+    access_token_cache: web::Data<Arc<AccessTokenCache>>,
+    http_client: web::Data<Client>,
+    retry: web::Data<RetryConfig>,
+    config: web::Data<PipeHubConfig>,
+    metrics: web::Data<Arc<Metrics>>,
+    limiter: web::Data<Arc<OutboundLimiter>>,
+    rate_limiter: web::Data<Arc<RateLimiter>>,
+    tenant_cache: web::Data<Arc<TenantCache>>,
+    req: HttpRequest,
+) -> std::result::Result<HttpResponse, AWError> {
+    let request_id: Uuid = req
+        .extensions()
+        .get::<Uuid>()
+        .cloned()
+        .expect("No request id found.");
+    let app_key = decode_key(&key)?;
+    let app_id = if send_token::is_token(&app_key) {
+        send_token::redeem(&pool, &config, &app_key, Scope::Raw).await?
+    } else {
+        app_id_of(&app_key)?
+    };
+
+    let tenant = tenant_cache
+        .find_tenant_by_app_id(&pool, app_id)
+        .await?
+        .ok_or_else(|| Error::User("Unknown APP ID."))?;
+    let agent_id = read_agent_id(&req)?;
+    let meta = read_meta(&req)?;
+    let wechats = tenant_cache
+        .find_wechats_by_tenant_id(&pool, tenant.id)
+        .await?;
+    let wechat = match choose_wechat(wechats, &tenant, agent_id) {
+        Some(wechat) => wechat,
+        None if agent_id.is_some() => {
+            return Err(Error::User("Unknown agent in X-PipeHub-Agent.").into())
+        }
+        None => return Err(Error::Conflict(NO_CHANNEL_MESSAGE).into()),
+    };
+
+    let body = String::from_utf8_lossy(&payload);
+    if config.log_bodies {
+        logger.track_debug(
+            request_id,
+            &format!("Inbound body {}", loggable_body(&body, &config)),
+        );
+    }
+    if should_log_body(&tenant, &config, Utc::now()) {
+        logger.track_trace(
+            request_id,
+            Level::Info,
+            &format!("Message {}", redact(&body, &config.log_redactions)),
+        );
+    }
+    let message = read_raw_message(&payload, wechat.agent_id)?;
+    let mut texts = Vec::new();
+    for value in message.values() {
+        content_policy::json_strings(value, &mut texts);
+    }
+    if let Err(e) = content_policy::check(&config, &logger, request_id, tenant.id, &texts) {
+        metrics.record(tenant.id, Outcome::PolicyViolation);
+        return Err(e.into());
+    }
+    if is_blocked(&tenant, &body) {
+        metrics.record(tenant.id, Outcome::Blocked);
+        return Err(Error::User("Message blocked.").into());
+    }
+    logger.track_trace_with(request_id, Level::Info, "Sending raw message.", &meta);
+    let limits = rate_limit::tenant_limits(&tenant, &config.rate_limits)?;
+    if let Err(e) = rate_limiter.acquire(tenant.id, Priority::default(), &limits) {
+        metrics.record(tenant.id, Outcome::RateLimited);
+        return Err(e.into());
+    }
+
+    let deadline = read_deadline(&req, None, &config);
+    let result = deliver_request(
+        &http_client,
+        &limiter,
+        request_id,
+        &logger,
+        &access_token_cache,
+        &wechat,
+        "message/send",
+        &Value::Object(message),
+        retry.wechat.with_deadline(deadline),
+    )
+    .await;
+    metrics.record_delivery(tenant.id, Channel::Wechat, &result);
+    let delivery = result?;
+
+    Ok(HttpResponse::Ok().json(SendResponse {
+        response: Response {
+            request_id,
+            success: true,
+            error_message: "".to_owned(),
+            hint: config.locale.hint(Hint::Retried(delivery.retry_count)),
+        },
+        msgid: delivery.msgid,
+        raw_response: None,
+        invalid_targets: delivery.invalid_targets,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecallQuery {
+    // The msgid replied by a synchronous send.
+    msgid: String,
+}
+
+// Tells clients how long fields may be before they send.
+#[get("/send/limits")]
+pub async fn limits() -> HttpResponse {
+    HttpResponse::Ok().json(LENGTH_LIMITS)
+}
+
+// Recalls a message sent within 24 hours, with the credentials it was sent with.
+pub async fn recall(
+    pool: Pool,
+    key: web::Path<String>,
+    web::Query(query): web::Query<RecallQuery>,
+    logger: web::Data<Arc<ApplicationLogger>>,
+    access_token_cache: web::Data<Arc<AccessTokenCache>>,
+    http_client: web::Data<Client>,
+    retry: web::Data<RetryConfig>,
+    config: web::Data<PipeHubConfig>,
+    limiter: web::Data<Arc<OutboundLimiter>>,
+    req: HttpRequest,
+) -> std::result::Result<HttpResponse, AWError> {
+    let request_id: Uuid = req
+        .extensions()
+        .get::<Uuid>()
+        .cloned()
+        .expect("No request id found.");
+    let app_key = decode_key(&key)?;
+    let app_id = if send_token::is_token(&app_key) {
+        send_token::redeem(&pool, &config, &app_key, Scope::Recall).await?
+    } else {
+        app_id_of(&app_key)?
+    };
+
+    let tenant = pool
+        .find_tenant_by_app_id(app_id)
+        .await?
+        .ok_or_else(|| Error::User("Unknown APP ID."))?;
+    let agent_id = read_agent_id(&req)?;
+    let wechat = match find_wechat(&pool, &tenant, agent_id).await? {
+        Some(wechat) => wechat,
+        None if agent_id.is_some() => {
+            return Err(Error::User("Unknown agent in X-PipeHub-Agent.").into())
+        }
+        None => return Err(Error::Conflict(NO_CHANNEL_MESSAGE).into()),
+    };
+    let msgid = query.msgid.trim();
+    if msgid.is_empty() {
+        return Err(Error::User("No msgid is provided.").into());
+    }
+
+    let mut body = Map::new();
+    body.insert("msgid".to_owned(), Value::from(msgid));
+    let deadline = read_deadline(&req, None, &config);
+    deliver_request(
+        &http_client,
+        &limiter,
+        request_id,
+        &logger,
+        &access_token_cache,
+        &wechat,
+        RECALL_API,
+        &Value::Object(body),
+        retry.wechat.with_deadline(deadline),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(Response {
+        request_id,
+        success: true,
+        error_message: "".to_owned(),
+        hint: config.locale.hint(Hint::Recalled),
+    }))
+}
+
+// Limits the concurrent requests to WeChat, the others wait for a while before giving up.
+// Retries have their own budget, so an outage doesn't multiply the load with everyone retrying.
+pub struct OutboundLimiter {
+    semaphore: Semaphore,
+    max_requests: usize,
+    wait: Duration,
+    retries: Option<Semaphore>,
+    max_retries: usize,
+    retries_denied: AtomicU64,
+}
+
+// Held while backing off and retrying, it holds nothing if retries are unlimited.
+pub struct RetryPermit<'a> {
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+impl OutboundLimiter {
+    pub fn new(max_requests: usize, wait: Duration, max_retries: usize) -> Self {
+        OutboundLimiter {
+            semaphore: Semaphore::new(max_requests),
+            max_requests,
+            wait,
+            retries: Some(max_retries)
+                .filter(|max_retries| *max_retries > 0)
+                .map(Semaphore::new),
+            max_retries,
+            retries_denied: AtomicU64::new(0),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_requests - self.semaphore.available_permits()
+    }
+
+    pub fn retries_in_flight(&self) -> usize {
+        match self.retries {
+            Some(ref retries) => self.max_retries - retries.available_permits(),
+            None => 0,
+        }
+    }
+
+    pub fn retries_denied(&self) -> u64 {
+        self.retries_denied.load(Ordering::Relaxed)
+    }
+
+    // Never waits, the send fails right away once the budget is used up.
+    pub fn try_retry(&self) -> Option<RetryPermit<'_>> {
+        match self.retries {
+            Some(ref retries) => match retries.try_acquire() {
+                Ok(permit) => Some(RetryPermit {
+                    _permit: Some(permit),
+                }),
+                Err(_) => {
+                    self.retries_denied.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+            },
+            None => Some(RetryPermit { _permit: None }),
+        }
+    }
+
+    fn is_saturated(&self) -> bool {
+        self.semaphore.available_permits() == 0
+    }
+
+    pub async fn acquire(
+        &self,
+        request_id: Uuid,
+        logger: &ApplicationLogger,
+    ) -> Result<SemaphorePermit<'_>> {
+        if self.is_saturated() {
+            logger.track_trace(
+                request_id,
+                Level::Warn,
+                "Concurrent requests to WeChat reached the limit.",
+            );
+        }
+
+        time::timeout(self.wait, self.semaphore.acquire())
+            .await
+            .map_err(|_| Error::Overloaded(IN_FLIGHT_MESSAGE, IN_FLIGHT_RETRY_AFTER))
+    }
+}
+
+// Sends the message with a cached token, refreshing the token and backing off on failures.
+// Returns how many times it has retried.
+pub async fn deliver(
+    http_client: &Client,
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    access_token_cache: &AccessTokenCache,
+    wechat: &WechatWork,
+    outgoing: &Outgoing,
+    retry_policy: RetryPolicy,
+) -> Result<Delivery> {
+    let external_wechat;
+    let wechat = if outgoing.external.is_some() {
+        external_wechat = external_credentials(wechat)?;
+        &external_wechat
+    } else {
+        wechat
+    };
+    let (api, mut body) = wechat_request(wechat, outgoing)?;
+    if let Some(ref image) = outgoing.image {
+        let media_id = upload_image(
+            http_client,
+            limiter,
+            request_id,
+            logger,
+            access_token_cache,
+            wechat,
+            image,
+            retry_policy.timeout(),
+        )
+        .await?;
+        body["image"]["media_id"] = Value::from(media_id);
+    }
+
+    deliver_request(
+        http_client,
+        limiter,
+        request_id,
+        logger,
+        access_token_cache,
+        wechat,
+        api,
+        &body,
+        retry_policy,
+    )
+    .await
 }
 
-#[derive(Debug, Deserialize)]
-struct WeChatSendResponse {
-    #[serde(rename = "errcode")]
-    error_code: u64,
-    #[serde(rename = "errmsg")]
-    error_message: String,
+async fn deliver_to(
+    destination: &Destination,
+    http_client: &Client,
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    access_token_cache: &AccessTokenCache,
+    outgoing: &Outgoing,
+    retry: &RetryConfig,
+    deadline: Option<Instant>,
+) -> Result<Delivery> {
+    match destination {
+        Destination::Wechat(wechat) => {
+            deliver(
+                http_client,
+                limiter,
+                request_id,
+                logger,
+                access_token_cache,
+                wechat,
+                outgoing,
+                retry.wechat.with_deadline(deadline),
+            )
+            .await
+        }
+        Destination::Matrix(room) => {
+            matrix::deliver(
+                http_client,
+                limiter,
+                request_id,
+                logger,
+                room,
+                &matrix_message(outgoing)?,
+                retry.matrix.with_deadline(deadline),
+            )
+            .await
+        }
+        Destination::Email(channel) => {
+            email::deliver(
+                limiter,
+                request_id,
+                logger,
+                channel,
+                &email_message(outgoing)?,
+                retry.email.with_deadline(deadline),
+            )
+            .await
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Message {
-    text: Option<String>,
-    to_party: Option<String>,
+// Posts the body to the API of WeChat, the token is added to the url.
+async fn deliver_request(
+    http_client: &Client,
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    access_token_cache: &AccessTokenCache,
+    wechat: &WechatWork,
+    api: &str,
+    body: &Value,
+    retry_policy: RetryPolicy,
+) -> Result<Delivery> {
+    let mut access_token = cached_token(
+        http_client,
+        limiter,
+        request_id,
+        logger,
+        access_token_cache,
+        wechat,
+    )
+    .await?;
+
+    let mut retry_count = 0;
+    let mut retry_permit = None;
+    loop {
+        if retry_policy.remaining() == Some(Duration::from_secs(0)) {
+            return Err(Error::Timeout(DEADLINE_MESSAGE));
+        }
+        let e = match do_send(
+            http_client,
+            limiter,
+            request_id,
+            logger,
+            &access_token_cache.api_url,
+            &access_token,
+            api,
+            body,
+            retry_policy.timeout(),
+        )
+        .await
+        {
+            Ok((msgid, invalid_targets, raw_response)) => {
+                return Ok(Delivery {
+                    retry_count,
+                    msgid,
+                    raw_response: Some(raw_response),
+                    invalid_targets,
+                })
+            }
+            // Retrying would only add to the load.
+            Err(e @ Error::Overloaded(..)) => return Err(e),
+            // WeChat may have got the message, retrying could deliver it twice.
+            Err(e @ Error::Timeout(_)) => return Err(e),
+            Err(e @ Error::Gone(_)) => return Err(e),
+            Err(e) if is_retryable(&e) => e,
+            Err(e) => {
+                if let Error::WeChat(ref code, _) = e {
+                    if TOKEN_ERROR_CODES.contains(code) {
+                        access_token_cache.remove(&token_key(wechat));
+                    }
+                }
+                return Err(e);
+            }
+        };
+        if retry_count >= retry_policy.max_retries {
+            return Err(e);
+        }
+
+        let backoff = match e {
+            Error::Throttled(retry_after) => retry_after.min(retry_policy.max_backoff()),
+            _ => retry_policy.backoff(retry_count),
+        };
+        // Sleeping past the deadline would only delay the 504.
+        if let Some(remaining) = retry_policy.remaining() {
+            if backoff >= remaining {
+                return Err(Error::Timeout(DEADLINE_MESSAGE));
+            }
+        }
+        // Held until the send is done, the last error is returned once too many are retrying.
+        if retry_permit.is_none() {
+            retry_permit = Some(limiter.try_retry().ok_or(e)?);
+        }
+        retry_count += 1;
+        time::delay_for(backoff).await;
+
+        access_token = cached_token(
+            http_client,
+            limiter,
+            request_id,
+            logger,
+            access_token_cache,
+            wechat,
+        )
+        .await?;
+    }
 }
 
-pub async fn send(
-    pool: Pool,
-    key: web::Path<String>,
-    payload: web::Bytes,
-    web::Query(message): web::Query<Message>,
-    logger: web::Data<Arc<ApplicationLogger>>,
-    access_token_cache: web::Data<Arc<AccessTokenCache>>,
-    http_client: web::Data<Client>,
-    req: HttpRequest,
-) -> std::result::Result<HttpResponse, AWError> {
-    let request_id: Uuid = req
-        .extensions()
-        .get::<Uuid>()
-        .cloned()
-        .expect("No request id found.");
-    let app_key = key.into_inner().from_base58().map_err(Error::from)?;
-    let app_id = i64::from_le_bytes((&app_key[0..8]).try_into().expect("Unexpected"));
+// Retrying only helps a busy WeChat and failing to reach it, other codes like an invalid secret
+// or agent fail the same way again.
+pub fn is_retryable(e: &Error) -> bool {
+    match e {
+        Error::WeChat(code, _) => BUSY_ERROR_CODES.contains(code),
+        Error::Dependency(_) | Error::Throttled(_) => true,
+        _ => false,
+    }
+}
 
+// Delivers a persisted message, the credentials are loaded again as they may have changed.
+pub async fn deliver_for_tenant(
+    pool: &Pool,
+    http_client: &Client,
+    limiter: &OutboundLimiter,
+    logger: &ApplicationLogger,
+    access_token_cache: &AccessTokenCache,
+    retry: &RetryConfig,
+    metrics: &Metrics,
+    tenant_id: i64,
+    request_id: Uuid,
+    payload: &str,
+) -> Result<Delivery> {
+    let outgoing: Outgoing = serde_json::from_str(payload)?;
     let tenant = pool
-        .find_tenant_by_app_id(app_id)
-        .await?
-        .ok_or_else(|| Error::User("Unknown APP ID."))?;
-    let wechat = pool
-        .find_wechat_by_app_id(app_id)
+        .find_tenant_by_id(tenant_id)
         .await?
-        .ok_or_else(|| Error::User("No WeChat credentials configured."))?;
-
-    let text = if let Message {
-        text: Some(text),
-        to_party: _,
-    } = message
-    {
-        text
-    } else if let Ok(text) = String::from_utf8(payload.to_vec()) {
-        text
-    } else {
-        return Err(Error::User("No message is provided.").into());
+        .ok_or_else(|| Error::Unexpected("Unknown tenant.".to_owned()))?;
+    let destination = match outgoing.channel {
+        Channel::Wechat => Destination::Wechat(
+            find_wechat(pool, &tenant, outgoing.agent_id)
+                .await?
+                .ok_or_else(|| Error::Unexpected("No WeChat credentials configured.".to_owned()))?,
+        ),
+        Channel::Matrix => Destination::Matrix(
+            matrix::room_of(&tenant)?
+                .ok_or_else(|| Error::Unexpected("No Matrix room configured.".to_owned()))?,
+        ),
+        Channel::Email => Destination::Email(
+            email::channel_of(&tenant)?
+                .ok_or_else(|| Error::Unexpected("No SMTP server configured.".to_owned()))?,
+        ),
     };
 
-    if tenant
-        .block_list
-        .split(',')
-        .map(|word| word.trim())
-        .filter(|word| !word.is_empty())
-        .any(|block_word| text.contains(block_word))
-    {
-        return Err(Error::User("Message blocked.").into());
+    let result = deliver_to(
+        &destination,
+        http_client,
+        limiter,
+        request_id,
+        logger,
+        access_token_cache,
+        &outgoing,
+        retry,
+        None,
+    )
+    .await;
+    metrics.record_send(tenant_id, &outgoing, &result);
+    result
+}
+
+type TokenKey = [u8; 32];
+
+// Tenants sharing the same credentials share the token, so the cache is keyed by the credentials,
+// including the agent. They are hashed, a collision would hand out the token of others.
+fn token_key(wechat: &WechatWork) -> TokenKey {
+    let parts: [&[u8]; 3] = [
+        wechat.corp_id.as_bytes(),
+        &wechat.agent_id.to_be_bytes(),
+        wechat.secret.as_bytes(),
+    ];
+    let mut hasher = Sha256::new();
+    for part in &parts {
+        hasher.update(&(part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.finish()
+}
+
+// The tokens of the credentials and the locks of their fetches, so concurrent sends missing the
+// cache wait for one fetch instead of each fetching. The tokens only work with the API they are
+// fetched from.
+pub struct AccessTokenCache {
+    api_url: String,
+    tokens: DashMap<TokenKey, WeChatAccessToken>,
+    fetches: DashMap<TokenKey, Arc<Mutex<()>>>,
+}
+
+impl AccessTokenCache {
+    pub fn new(api_url: &str) -> Self {
+        AccessTokenCache {
+            api_url: api_url.trim_end_matches('/').to_owned(),
+            tokens: DashMap::new(),
+            fetches: DashMap::new(),
+        }
     }
 
-    let mut token = access_token_cache.get(&app_id);
-    if token.is_none() || token.as_ref().unwrap().expires_at.le(&Instant::now()) {
-        let new_token = get_token(&http_client, request_id, &logger, &wechat).await?;
-        access_token_cache.insert(app_id, new_token);
-        token = access_token_cache.get(&app_id);
+    fn get(&self, key: &TokenKey) -> Option<String> {
+        self.tokens
+            .get(key)
+            .filter(|token| token.expires_at.gt(&Instant::now()))
+            .map(|token| token.access_token.clone())
     }
 
-    let mut token = token.unwrap();
-    let mut retry_count = 0;
-    while let Err(e) = do_send(
-        &http_client,
-        request_id,
-        &logger,
-        &wechat,
-        token.value(),
-        text.clone(),
-        message.to_party.clone(),
-    )
-    .await
+    fn insert(&self, key: TokenKey, token: WeChatAccessToken) {
+        self.tokens.insert(key, token);
+    }
+
+    fn remove(&self, key: &TokenKey) {
+        self.tokens.remove(key);
+    }
+
+    async fn get_or_fetch<F, Fut>(&self, key: TokenKey, fetch: F) -> Result<String>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<WeChatAccessToken>>,
     {
-        if retry_count > 3 {
-            return Err(e.into());
-        } else {
-            retry_count += 1;
+        if let Some(access_token) = self.get(&key) {
+            return Ok(access_token);
+        }
+        let lock = self
+            .fetches
+            .entry(key)
+            .or_insert_with(Default::default)
+            .clone();
+        let _fetching = lock.lock().await;
+        // Fetched by another send while waiting.
+        if let Some(access_token) = self.get(&key) {
+            return Ok(access_token);
         }
-        let new_token = get_token(&http_client, request_id, &logger, &wechat).await?;
-        access_token_cache.insert(app_id, new_token);
-        token = access_token_cache.get(&app_id).unwrap();
+        let token = fetch().await?;
+        let access_token = token.access_token.clone();
+        self.insert(key, token);
+
+        Ok(access_token)
     }
+}
 
-    Ok(HttpResponse::Ok().json(Response {
+// Fetches a token to tell whether the credentials work, it's only cached if asked to refresh.
+// Returns the error code and message of WeChat.
+pub async fn check_credentials(
+    http_client: &Client,
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    access_token_cache: &AccessTokenCache,
+    wechat: &WechatWork,
+    refresh: bool,
+) -> Result<(i64, String)> {
+    let token = get_token(
+        http_client,
+        limiter,
         request_id,
-        success: true,
-        error_message: "".to_owned(),
-        hint: format!("Retried {} times.", retry_count),
-    }))
+        logger,
+        &access_token_cache.api_url,
+        wechat,
+    )
+    .await?;
+    let result = (token.error_code, token.error_message.clone());
+    if refresh && token.error_code == 0 {
+        access_token_cache.insert(token_key(wechat), token);
+    }
+
+    Ok(result)
+}
+
+async fn cached_token(
+    http_client: &Client,
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    access_token_cache: &AccessTokenCache,
+    wechat: &WechatWork,
+) -> Result<String> {
+    access_token_cache
+        .get_or_fetch(token_key(wechat), || {
+            fetch_token(
+                http_client,
+                limiter,
+                request_id,
+                logger,
+                &access_token_cache.api_url,
+                wechat,
+            )
+        })
+        .await
+}
+
+// Uploads the image as a temporary media, returning its id. It isn't retried, the message is.
+async fn upload_image(
+    http_client: &Client,
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    access_token_cache: &AccessTokenCache,
+    wechat: &WechatWork,
+    image: &str,
+    timeout: Duration,
+) -> Result<String> {
+    let (image, file_name) = decode_image(image)?;
+    let access_token = cached_token(
+        http_client,
+        limiter,
+        request_id,
+        logger,
+        access_token_cache,
+        wechat,
+    )
+    .await?;
+
+    let _permit = limiter.acquire(request_id, logger).await?;
+    let start = Instant::now();
+    let url = format!(
+        "{}/cgi-bin/media/upload?access_token={}&type=image",
+        access_token_cache.api_url, access_token
+    );
+    let form = Form::new().part("media", Part::bytes(image).file_name(file_name));
+    let response = http_client
+        .post(&url)
+        .multipart(form)
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(send_error)?;
+    let reply: WeChatUploadResponse = response.json().await.map_err(send_error)?;
+
+    logger.track_dependency(
+        request_id,
+        "POST https://qyapi.weixin.qq.com/cgi-bin/media/upload",
+        "HTTPS",
+        start.elapsed(),
+        "qyapi.weixin.qq.com",
+        &reply.error_message,
+        &url,
+        reply.error_code == 0,
+    );
+
+    if reply.error_code != 0 {
+        return Err(Error::WeChat(reply.error_code, reply.error_message));
+    }
+
+    Ok(reply.media_id)
+}
+
+async fn fetch_token(
+    http_client: &Client,
+    limiter: &OutboundLimiter,
+    request_id: Uuid,
+    logger: &ApplicationLogger,
+    api_url: &str,
+    wechat: &WechatWork,
+) -> Result<WeChatAccessToken> {
+    let token = get_token(http_client, limiter, request_id, logger, api_url, wechat).await?;
+    // Like an invalid secret, which isn't cached.
+    if token.error_code != 0 {
+        return Err(Error::WeChat(token.error_code, token.error_message));
+    }
+
+    Ok(token)
 }
 
 async fn get_token(
     client: &Client,
+    limiter: &OutboundLimiter,
     request_id: Uuid,
     logger: &ApplicationLogger,
+    api_url: &str,
     wechat: &WechatWork,
 ) -> Result<WeChatAccessToken> {
+    let _permit = limiter.acquire(request_id, logger).await?;
     let corpid = &wechat.corp_id;
     let secret = &wechat.secret;
 
     let start = Instant::now();
     let url = format!(
-        "https://qyapi.weixin.qq.com/cgi-bin/gettoken?corpid={}&corpsecret={}",
-        corpid, secret
+        "{}/cgi-bin/gettoken?corpid={}&corpsecret={}",
+        api_url, corpid, secret
     );
 
     let response = client.get(&url).send().await?;
@@ -177,42 +2750,117 @@ async fn get_token(
     Ok(token)
 }
 
+// Failing to connect is retried, but timing out while waiting for the reply is not.
+fn send_error(e: reqwest::Error) -> Error {
+    let mut source = e.source();
+    while let Some(inner) = source {
+        if let Some(hyper_error) = inner.downcast_ref::<hyper::Error>() {
+            if hyper_error.is_connect() {
+                return Error::Dependency(format!("Unable to connect to WeChat {:?}", e));
+            }
+        }
+        source = inner.source();
+    }
+
+    if e.is_timeout() {
+        Error::Timeout("WeChat didn't reply in time.")
+    } else {
+        Error::from(e)
+    }
+}
+
+// The API and the body of WeChat for the message.
+fn wechat_request(wechat: &WechatWork, outgoing: &Outgoing) -> Result<(&'static str, Value)> {
+    if let Some(ref chat_id) = outgoing.chat_id {
+        let message = AppChatMessage {
+            chatid: chat_id.clone(),
+            body: outgoing.body.clone(),
+        };
+        return Ok(("appchat/send", serde_json::to_value(message)?));
+    }
+    let request = match (&outgoing.external, &outgoing.body) {
+        (Some(external), WeChatMessageBody::Text { text }) => (
+            "externalcontact/add_msg_template",
+            serde_json::to_value(ExternalContactMessage {
+                chat_type: "single",
+                external_userid: external.external_userid.clone(),
+                sender: external.sender.clone(),
+                text: text.clone(),
+            })?,
+        ),
+        (Some(_), _) => {
+            return Err(Error::User(
+                "Only text messages can be sent to external contacts.",
+            ))
+        }
+        (None, _) => {
+            let to_party = outgoing.to_party.clone();
+            let to_tag = outgoing.to_tag.clone();
+            (
+                "message/send",
+                serde_json::to_value(WeChatMessage {
+                    to_user: match outgoing.to_user {
+                        Some(ref to_user) => Some(to_user.clone()),
+                        None if to_party.is_none() && to_tag.is_none() => Some("@all".to_owned()),
+                        None => None,
+                    },
+                    to_party,
+                    to_tag,
+                    agent_id: wechat.agent_id,
+                    body: outgoing.body.clone(),
+                    enable_duplicate_check: outgoing.duplicate_check_interval.is_some(),
+                    duplicate_check_interval: outgoing.duplicate_check_interval.unwrap_or_default(),
+                })?,
+            )
+        }
+    };
+
+    Ok(request)
+}
+
 async fn do_send(
     client: &Client,
+    limiter: &OutboundLimiter,
     request_id: Uuid,
     logger: &ApplicationLogger,
-    wechat: &WechatWork,
-    token: &WeChatAccessToken,
-    msg: String,
-    to_party: Option<String>,
-) -> Result<()> {
+    api_url: &str,
+    access_token: &str,
+    api: &str,
+    body: &Value,
+    timeout: Duration,
+) -> Result<(Option<String>, InvalidTargets, Value)> {
+    let _permit = limiter.acquire(request_id, logger).await?;
     let start = Instant::now();
-    let url = format!(
-        "https://qyapi.weixin.qq.com/cgi-bin/message/send?access_token={}",
-        token.access_token
-    );
+    let url = format!("{}/cgi-bin/{}?access_token={}", api_url, api, access_token);
     let response = client
         .post(&url)
-        .json(&WeChatMessage {
-            to_user: match to_party {
-                Some(_) => None,
-                None => Some("@all".to_owned()),
-            },
-            to_party,
-            agent_id: wechat.agent_id,
-            message_type: "text".to_string(),
-            text: WeChatMessageText { content: msg },
-            enable_duplicate_check: false,
-            duplicate_check_interval: 0,
-        })
+        .json(body)
+        .timeout(timeout)
         .send()
-        .await?;
+        .await
+        .map_err(send_error)?;
+
+    if response.status() == StatusCode::TOO_MANY_REQUESTS
+        || response.status() == StatusCode::SERVICE_UNAVAILABLE
+    {
+        let retry_after = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|retry_after| retry_after.to_str().ok())
+            .and_then(|retry_after| retry_after.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        if let Some(retry_after) = retry_after {
+            return Err(Error::Throttled(retry_after));
+        }
+    }
 
-    let reply: WeChatSendResponse = response.json().await?;
+    let raw_response: Value = response.json().await.map_err(send_error)?;
+    let reply: WeChatSendResponse = serde_json::from_value(raw_response.clone())
+        .map_err(|e| Error::Dependency(format!("Unknown reply of WeChat {:?}", e)))?;
 
     logger.track_dependency(
         request_id,
-        "POST https://qyapi.weixin.qq.com/cgi-bin/message/send",
+        &format!("POST https://qyapi.weixin.qq.com/cgi-bin/{}", api),
         "HTTPS",
         start.elapsed(),
         "qyapi.weixin.qq.com",
@@ -221,5 +2869,318 @@ async fn do_send(
         reply.error_code == 0,
     );
 
-    Ok(())
+    if api == RECALL_API && reply.error_code == RECALL_EXPIRED_ERROR_CODE {
+        return Err(Error::Gone(RECALL_EXPIRED_MESSAGE));
+    }
+    if reply.error_code != 0 {
+        return Err(Error::WeChat(reply.error_code, reply.error_message));
+    }
+
+    let invalid_targets = invalid_targets_of(&reply);
+    if !invalid_targets.is_empty() {
+        logger.track_trace(
+            request_id,
+            Level::Warn,
+            &format!(
+                "WeChat couldn't deliver to users {:?} parties {:?} tags {:?}",
+                invalid_targets.invalid_users,
+                invalid_targets.invalid_parties,
+                invalid_targets.invalid_tags
+            ),
+        );
+    }
+
+    Ok((
+        Some(reply.msgid).filter(|msgid| !msgid.is_empty()),
+        invalid_targets,
+        raw_response,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::join;
+    use std::sync::atomic::AtomicUsize;
+
+    fn wechat(tenant_id: i64, secret: &str) -> WechatWork {
+        WechatWork {
+            id: tenant_id,
+            tenant_id,
+            corp_id: "ww0123456789abcdef".to_owned(),
+            agent_id: 1000002,
+            secret: secret.to_owned(),
+            external_secret: None,
+        }
+    }
+
+    fn config() -> PipeHubConfig {
+        serde_json::from_value(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": 8080,
+            "domain": "localhost",
+            "https": false,
+            "github": {
+                "client_id": "",
+                "client_secret": "",
+                "auth_url": "",
+                "token_url": "",
+                "callback_url": "",
+            },
+            "log": { "level": "info" },
+        }))
+        .unwrap()
+    }
+
+    fn outgoing_of(query: &str) -> Result<Outgoing> {
+        let message = web::Query::<Message>::from_query(query)
+            .unwrap()
+            .into_inner();
+        let tenant = Tenant::new(1, "octocat".to_owned(), 1);
+        build_outgoing(
+            &tenant,
+            &message,
+            "hi".to_owned(),
+            Channel::Wechat,
+            &config(),
+            Uuid::new_v4(),
+        )
+    }
+
+    #[test]
+    fn joins_the_parties_of_the_request_like_the_tags() {
+        let outgoing = outgoing_of("to_party=1,%202&to_tag=3,4").unwrap();
+        assert_eq!(outgoing.to_party.as_deref(), Some("1|2"));
+        assert_eq!(outgoing.to_tag.as_deref(), Some("3|4"));
+        assert_eq!(
+            outgoing_of("to_party=1|2").unwrap().to_party.as_deref(),
+            Some("1|2")
+        );
+        assert!(outgoing_of("to_party=1,sales").is_err());
+        assert!(outgoing_of("to_party=,").is_err());
+    }
+
+    fn meta_of(query: &str) -> Result<BTreeMap<String, String>> {
+        read_meta(
+            &actix_web::test::TestRequest::with_uri(&format!("/send/key?{}", query))
+                .to_http_request(),
+        )
+    }
+
+    #[test]
+    fn reads_meta_of_the_query() {
+        let meta = meta_of("text=hi&meta.service=billing&meta.run-id=a%20b").unwrap();
+        assert_eq!(meta.len(), 2);
+        assert_eq!(meta["service"], "billing");
+        assert_eq!(meta["run-id"], "a b");
+    }
+
+    #[test]
+    fn rejects_meta_which_could_forge_log_lines() {
+        assert!(meta_of("meta.service=billing%0A1%20fake%20entry").is_err());
+        assert!(meta_of("meta.service=billing%1B%5B2K").is_err());
+        assert!(meta_of("meta.a%3Db=c").is_err());
+        assert!(meta_of("meta.a%20b=c").is_err());
+        assert!(meta_of(&format!("meta.{}=a", "k".repeat(65))).is_err());
+        assert!(meta_of(&format!("meta.service={}", "v".repeat(257))).is_err());
+        assert!(meta_of(&format!("meta.service={}", "v".repeat(256))).is_ok());
+    }
+
+    fn token(access_token: &str) -> WeChatAccessToken {
+        WeChatAccessToken {
+            error_code: 0,
+            error_message: "ok".to_owned(),
+            access_token: access_token.to_owned(),
+            expires_at: Instant::now() + Duration::from_secs(7200),
+        }
+    }
+
+    #[test]
+    fn keys_tokens_by_the_credentials() {
+        assert_eq!(
+            token_key(&wechat(1, "secret")),
+            token_key(&wechat(2, "secret"))
+        );
+        assert_ne!(
+            token_key(&wechat(1, "secret")),
+            token_key(&wechat(1, "other"))
+        );
+        let mut other_agent = wechat(1, "secret");
+        other_agent.agent_id = 1000003;
+        assert_ne!(token_key(&wechat(1, "secret")), token_key(&other_agent));
+        // The parts can't run into each other.
+        let mut shifted = wechat(1, "secret");
+        shifted.corp_id.push('s');
+        shifted.secret = "ecret".to_owned();
+        assert_ne!(token_key(&wechat(1, "secret")), token_key(&shifted));
+    }
+
+    #[tokio::test]
+    async fn fetches_the_token_once_for_concurrent_sends() {
+        let cache = AccessTokenCache::new("https://qyapi.weixin.qq.com");
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            time::delay_for(Duration::from_millis(50)).await;
+            Ok(token("shared"))
+        };
+
+        let (first, second) = join(
+            cache.get_or_fetch(token_key(&wechat(1, "secret")), fetch),
+            cache.get_or_fetch(token_key(&wechat(2, "secret")), fetch),
+        )
+        .await;
+        let third = cache
+            .get_or_fetch(token_key(&wechat(3, "secret")), fetch)
+            .await;
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        for access_token in &[first, second, third] {
+            assert_eq!(access_token.as_ref().unwrap(), "shared");
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_again_after_failures_and_for_other_credentials() {
+        let cache = AccessTokenCache::new("https://qyapi.weixin.qq.com");
+        let failed = cache
+            .get_or_fetch(token_key(&wechat(1, "secret")), || async {
+                Err(Error::WeChat(40001, "invalid secret".to_owned()))
+            })
+            .await;
+        assert!(failed.is_err());
+
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(token("fresh"))
+        };
+        cache
+            .get_or_fetch(token_key(&wechat(1, "secret")), fetch)
+            .await
+            .unwrap();
+        cache
+            .get_or_fetch(token_key(&wechat(1, "other")), fetch)
+            .await
+            .unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    fn body(message_type: MessageType, content: &str) -> WeChatMessageBody {
+        let text = WeChatMessageText {
+            content: content.to_owned(),
+        };
+        match message_type {
+            MessageType::Text => WeChatMessageBody::Text { text },
+            _ => WeChatMessageBody::Markdown { markdown: text },
+        }
+    }
+
+    fn content_of(body: &mut WeChatMessageBody) -> String {
+        body.content_mut().unwrap().0.clone()
+    }
+
+    #[test]
+    fn limits_the_content_of_each_channel() {
+        let limit = |channel: Channel, message_type| channel.max_content_bytes(message_type);
+        assert_eq!(limit(Channel::Wechat, MessageType::Text), Some(2048));
+        assert_eq!(limit(Channel::Wechat, MessageType::Markdown), Some(4096));
+        assert_eq!(limit(Channel::Matrix, MessageType::Text), Some(32 * 1024));
+        assert_eq!(
+            limit(Channel::Matrix, MessageType::Markdown),
+            Some(16 * 1024)
+        );
+        assert_eq!(limit(Channel::Email, MessageType::Text), None);
+        assert_eq!(limit(Channel::Email, MessageType::Markdown), None);
+        for &message_type in &[
+            MessageType::MiniprogramNotice,
+            MessageType::TemplateCard,
+            MessageType::Image,
+        ] {
+            assert_eq!(limit(Channel::Wechat, message_type), None);
+        }
+    }
+
+    #[test]
+    fn keeps_content_fitting_the_limit_with_the_decoration() {
+        let (prefix, suffix) = ("[ci] ", " -- bot");
+        for &(channel, message_type, max) in &[
+            (Channel::Wechat, MessageType::Text, 2048),
+            (Channel::Wechat, MessageType::Markdown, 4096),
+            (Channel::Matrix, MessageType::Text, 32 * 1024),
+            (Channel::Matrix, MessageType::Markdown, 16 * 1024),
+        ] {
+            let fitting = "a".repeat(max - prefix.len() - suffix.len());
+            let decorated = format!("{}{}{}", prefix, fitting, suffix);
+
+            let mut at_limit = body(message_type, &fitting);
+            at_limit.decorate(prefix, suffix, channel);
+            assert_eq!(content_of(&mut at_limit), decorated);
+
+            let mut past_limit = body(message_type, &format!("{}b", fitting));
+            past_limit.decorate(prefix, suffix, channel);
+            assert_eq!(content_of(&mut past_limit), decorated);
+            assert_eq!(content_of(&mut past_limit).len(), max);
+        }
+    }
+
+    #[test]
+    fn truncates_decorated_content_between_characters() {
+        let mut long = body(MessageType::Text, &"好".repeat(1000));
+        long.decorate("> ", "", Channel::Wechat);
+        let content = content_of(&mut long);
+        // 2046 bytes are left for the content, which hold 682 characters of 3 bytes.
+        assert_eq!(content, format!("> {}", "好".repeat(682)));
+    }
+
+    #[test]
+    fn leaves_unlimited_content_alone() {
+        let long = "a".repeat(64 * 1024);
+        let mut email = body(MessageType::Text, &long);
+        assert!(email.fits(Channel::Email));
+        email.decorate("[ci] ", "", Channel::Email);
+        assert_eq!(content_of(&mut email), format!("[ci] {}", long));
+    }
+
+    #[test]
+    fn truncates_undecorated_content_to_the_limit() {
+        let mut undecorated = body(MessageType::Text, &"a".repeat(64 * 1024));
+        assert!(!undecorated.fits(Channel::Wechat));
+        undecorated.decorate("", "", Channel::Wechat);
+        assert_eq!(content_of(&mut undecorated), "a".repeat(2048));
+
+        let mut fitting = body(MessageType::Markdown, &"a".repeat(4096));
+        assert!(fitting.fits(Channel::Wechat));
+        assert!(!body(MessageType::Markdown, &"a".repeat(4097)).fits(Channel::Wechat));
+    }
+
+    #[test]
+    fn truncates_decorations_longer_than_the_limit() {
+        let prefix = "p".repeat(2000);
+        let suffix = "s".repeat(100);
+        let mut decorated = body(MessageType::Text, "hello");
+        decorated.decorate(&prefix, &suffix, Channel::Wechat);
+        let content = content_of(&mut decorated);
+        assert_eq!(content.len(), 2048);
+        assert_eq!(content, format!("{}{}", prefix, "s".repeat(48)));
+    }
+
+    #[test]
+    fn retries_busy_codes_and_transport_errors() {
+        for code in &BUSY_ERROR_CODES {
+            assert!(is_retryable(&Error::WeChat(*code, "busy".to_owned())));
+        }
+        assert!(is_retryable(&Error::Dependency(
+            "Unable to connect to WeChat".to_owned()
+        )));
+        assert!(is_retryable(&Error::Throttled(Duration::from_secs(1))));
+    }
+
+    #[test]
+    fn fails_fast_on_permanent_codes() {
+        for code in &[40001, 40014, 40056, 60020, 81013] {
+            assert!(!is_retryable(&Error::WeChat(*code, "invalid".to_owned())));
+        }
+        assert!(!is_retryable(&Error::Timeout(DEADLINE_MESSAGE)));
+    }
 }
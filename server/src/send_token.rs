@@ -0,0 +1,183 @@
+use crate::config::PipeHubConfig;
+use crate::data::Pool;
+use crate::error::{Error, Result};
+use crate::send;
+use actix_web::{post, web, Error as AWError, HttpResponse};
+use base58::ToBase58;
+use chrono::{DateTime, TimeZone, Utc};
+use log::error;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time;
+use uuid::Uuid;
+
+// A token is `app_id | expires_at | nonce | flags | HMAC-SHA256 of them`, encoded in base58
+// like app keys. The flags hold whether it's reusable and its scope.
+const PAYLOAD_LENGTH: usize = 8 + 8 + 16 + 1;
+const TOKEN_LENGTH: usize = PAYLOAD_LENGTH + 32;
+const REUSABLE_FLAG: u8 = 1;
+const DEFAULT_TTL: i64 = 5 * 60;
+const MAX_TTL: i64 = 60 * 60;
+// Reusable tokens are for sharing sending for a while, like with a one-off integration.
+const MAX_REUSABLE_TTL: i64 = 30 * 24 * 60 * 60;
+
+// The endpoint a token can be used with, any if absent.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Send,
+    Raw,
+    Recall,
+}
+
+impl Scope {
+    fn bits(scope: Option<Scope>) -> u8 {
+        let bits = match scope {
+            None => 0,
+            Some(Scope::Send) => 1,
+            Some(Scope::Raw) => 2,
+            Some(Scope::Recall) => 3,
+        };
+        bits << 1
+    }
+
+    fn allows(flags: u8, scope: Scope) -> bool {
+        let bits = flags & (0b11 << 1);
+        bits == 0 || bits == Scope::bits(Some(scope))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenQuery {
+    // In seconds.
+    ttl: Option<i64>,
+    // Accepted until it expires instead of once, revoked with the key by resetting it.
+    #[serde(default)]
+    reusable: bool,
+    scope: Option<Scope>,
+}
+
+#[derive(Debug, Serialize)]
+struct SendToken {
+    token: String,
+    // The send url with the token in place of the key.
+    url: String,
+    expires_at: DateTime<Utc>,
+}
+
+fn secret(config: &PipeHubConfig) -> Result<&[u8]> {
+    match config.send_token_secret {
+        Some(ref secret) if !secret.is_empty() => Ok(secret.as_bytes()),
+        _ => Err(Error::NotFound("Send tokens are disabled.")),
+    }
+}
+
+fn sign(secret: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(payload)?;
+
+    Ok(signer.sign_to_vec()?)
+}
+
+fn read_i64(bytes: &[u8]) -> i64 {
+    let mut buffer = [0; 8];
+    buffer.copy_from_slice(bytes);
+    i64::from_le_bytes(buffer)
+}
+
+pub fn is_token(key: &[u8]) -> bool {
+    key.len() == TOKEN_LENGTH
+}
+
+// Mints a short-lived token standing in for the app key once or until it expires, so the key
+// itself isn't exposed to browsers or other semi-trusted callers.
+#[post("/send/{key}/token")]
+pub async fn issue(
+    pool: Pool,
+    key: web::Path<String>,
+    web::Query(query): web::Query<TokenQuery>,
+    config: web::Data<PipeHubConfig>,
+) -> std::result::Result<HttpResponse, AWError> {
+    let secret = secret(&config)?;
+    let app_id = send::decode_app_id(&key)?;
+    pool.find_tenant_by_app_id(app_id)
+        .await?
+        .ok_or_else(|| Error::User("Unknown APP ID."))?;
+
+    let ttl = query.ttl.unwrap_or(DEFAULT_TTL);
+    if query.reusable && (ttl <= 0 || ttl > MAX_REUSABLE_TTL) {
+        return Err(
+            Error::User("ttl of reusable tokens must be between 1 and 2592000 seconds.").into(),
+        );
+    }
+    if !query.reusable && (ttl <= 0 || ttl > MAX_TTL) {
+        return Err(Error::User("ttl must be between 1 and 3600 seconds.").into());
+    }
+    let flags = Scope::bits(query.scope) | if query.reusable { REUSABLE_FLAG } else { 0 };
+    let expires_at = Utc.timestamp(Utc::now().timestamp() + ttl, 0);
+
+    let mut token = Vec::with_capacity(TOKEN_LENGTH);
+    token.extend_from_slice(&app_id.to_le_bytes());
+    token.extend_from_slice(&expires_at.timestamp().to_le_bytes());
+    token.extend_from_slice(Uuid::new_v4().as_bytes());
+    token.push(flags);
+    let signature = sign(secret, &token)?;
+    token.extend_from_slice(&signature);
+    let token = token.to_base58();
+
+    Ok(HttpResponse::Created().json(SendToken {
+        url: format!("{}/send/{}", config.domain, token),
+        token,
+        expires_at,
+    }))
+}
+
+// Returns the app id of the token, tokens which aren't reusable are accepted only once.
+pub async fn redeem(
+    pool: &Pool,
+    config: &PipeHubConfig,
+    token: &[u8],
+    scope: Scope,
+) -> Result<i64> {
+    let secret = secret(config)?;
+    let (payload, signature) = token.split_at(PAYLOAD_LENGTH);
+    if !memcmp::eq(&sign(secret, payload)?, signature) {
+        return Err(Error::Forbidden("Invalid send token."));
+    }
+
+    let app_id = read_i64(&payload[0..8]);
+    let expires_at = Utc.timestamp(read_i64(&payload[8..16]), 0);
+    if expires_at <= Utc::now() {
+        return Err(Error::Forbidden("The send token has expired."));
+    }
+    let flags = payload[PAYLOAD_LENGTH - 1];
+    if !Scope::allows(flags, scope) {
+        return Err(Error::Forbidden("The send token isn't for this endpoint."));
+    }
+    if flags & REUSABLE_FLAG != 0 {
+        return Ok(app_id);
+    }
+    let nonce = Uuid::from_slice(&payload[16..PAYLOAD_LENGTH - 1])
+        .map_err(|_| Error::Forbidden("Invalid send token."))?;
+    if !pool.use_send_token(nonce, expires_at).await? {
+        return Err(Error::Forbidden("The send token has been used."));
+    }
+
+    Ok(app_id)
+}
+
+// Expired tokens are rejected anyway, so the used ones are only kept until then.
+pub async fn run_purger(pool: Pool) {
+    let mut delay = time::interval(Duration::from_secs(60 * 10));
+    loop {
+        delay.tick().await;
+        if let Err(e) = pool.purge_used_send_tokens().await {
+            error!("Unable to purge used send tokens {:?}.", e);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use crate::error::Error;
+use actix_files::NamedFile;
+use actix_http::http::Method;
+use actix_web::{Error as AWError, HttpRequest};
+
+pub const STATIC_DIR: &str = "./static/";
+const INDEX_FILE: &str = "index.html";
+// Where the frontend build puts the assets with content hashes in their names, a changed asset
+// has a new name.
+const HASHED_ASSETS_PREFIX: &str = "/static/";
+
+// The API and the OAuth flow keep replying 404 for unknown paths, the SPA would only render a
+// blank page for clients expecting JSON or a redirect.
+const EXCLUDED_PREFIXES: &[&str] = &[
+    "/send",
+    "/api",
+    "/callback",
+    "/login",
+    "/user",
+    "/wechat",
+    "/wechats",
+    "/templates",
+    "/admin",
+    "/metrics",
+    "/version",
+];
+
+fn is_excluded(path: &str) -> bool {
+    EXCLUDED_PREFIXES.iter().any(|prefix| {
+        path.strip_prefix(prefix)
+            .map(|rest| rest.is_empty() || rest.starts_with('/'))
+            .unwrap_or(false)
+    })
+}
+
+// Hashed assets are cached for the max age, everything else of the frontend is revalidated so
+// a new `index.html` is picked up right away. The API sets its own headers, if any.
+pub fn cache_control(path: &str, max_age: u64) -> Option<String> {
+    if is_excluded(path) {
+        None
+    } else if max_age > 0 && path.starts_with(HASHED_ASSETS_PREFIX) {
+        Some(format!("public, max-age={}, immutable", max_age))
+    } else {
+        Some("no-cache".to_owned())
+    }
+}
+
+// Paths of the SPA router like `/settings` have no file, so `index.html` is served for them and
+// the router renders the page. Missing assets like `/main.js` are still 404.
+pub async fn fallback(req: HttpRequest) -> Result<NamedFile, AWError> {
+    let path = req.path();
+    let is_asset = path
+        .rsplit('/')
+        .next()
+        .map(|name| name.contains('.'))
+        .unwrap_or(false);
+    if req.method() != Method::GET || is_asset || is_excluded(path) {
+        return Err(Error::NotFound("Unknown path.").into());
+    }
+
+    Ok(NamedFile::open(format!("{}{}", STATIC_DIR, INDEX_FILE))?)
+}
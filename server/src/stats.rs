@@ -0,0 +1,155 @@
+use crate::send::Channel;
+use chrono::Utc;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+const TOP_TENANTS: usize = 10;
+
+// Deliveries counted by the minute for the last hour and by the hour for the last day, keyed
+// by the start of the bucket, tenant, channel and whether it's delivered. Buckets past the
+// window are pruned once a minute, so the memory and the cost of a summary stay bounded by
+// the number of active tenants. It's per instance and starts over when restarted.
+#[derive(Default)]
+pub struct Activity {
+    minutes: DashMap<(i64, i64, Channel, bool), u64>,
+    hours: DashMap<(i64, i64, Channel, bool), u64>,
+    pruned_at: AtomicI64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelSummary {
+    channel: Channel,
+    total: u64,
+    delivered: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TenantSummary {
+    pub tenant_id: i64,
+    pub github_login: Option<String>,
+    total: u64,
+    delivered: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    total: u64,
+    delivered: u64,
+    failed: u64,
+    // Absent if nothing was sent.
+    success_rate: Option<f64>,
+    channels: Vec<ChannelSummary>,
+    // By volume, at most 10.
+    pub top_tenants: Vec<TenantSummary>,
+}
+
+fn bucket_of(now: i64, size: i64) -> i64 {
+    now - now.rem_euclid(size)
+}
+
+fn summarize(buckets: &DashMap<(i64, i64, Channel, bool), u64>, since: i64) -> Summary {
+    let mut channels: Vec<ChannelSummary> = Vec::new();
+    let mut tenants: HashMap<i64, (u64, u64)> = HashMap::new();
+    let (mut total, mut delivered) = (0, 0);
+    for entry in buckets.iter() {
+        let ((start, tenant_id, channel, is_delivered), count) = (*entry.key(), *entry.value());
+        if start < since {
+            continue;
+        }
+        let delivered_count = if is_delivered { count } else { 0 };
+        total += count;
+        delivered += delivered_count;
+        match channels
+            .iter_mut()
+            .find(|summary| summary.channel == channel)
+        {
+            Some(summary) => {
+                summary.total += count;
+                summary.delivered += delivered_count;
+            }
+            None => channels.push(ChannelSummary {
+                channel,
+                total: count,
+                delivered: delivered_count,
+            }),
+        }
+        let tenant = tenants.entry(tenant_id).or_insert((0, 0));
+        tenant.0 += count;
+        tenant.1 += delivered_count;
+    }
+    let mut top_tenants: Vec<TenantSummary> = tenants
+        .into_iter()
+        .map(|(tenant_id, (total, delivered))| TenantSummary {
+            tenant_id,
+            github_login: None,
+            total,
+            delivered,
+        })
+        .collect();
+    top_tenants.sort_by(|a, b| b.total.cmp(&a.total).then(a.tenant_id.cmp(&b.tenant_id)));
+    top_tenants.truncate(TOP_TENANTS);
+
+    Summary {
+        total,
+        delivered,
+        failed: total - delivered,
+        success_rate: Some(total)
+            .filter(|total| *total > 0)
+            .map(|total| delivered as f64 / total as f64),
+        channels,
+        top_tenants,
+    }
+}
+
+impl Activity {
+    pub fn record(&self, tenant_id: i64, channel: Channel, delivered: bool) {
+        let now = Utc::now().timestamp();
+        *self
+            .minutes
+            .entry((bucket_of(now, MINUTE), tenant_id, channel, delivered))
+            .or_insert(0) += 1;
+        *self
+            .hours
+            .entry((bucket_of(now, HOUR), tenant_id, channel, delivered))
+            .or_insert(0) += 1;
+        self.prune(now);
+    }
+
+    fn prune(&self, now: i64) {
+        let minute = bucket_of(now, MINUTE);
+        let pruned_at = self.pruned_at.load(Ordering::Relaxed);
+        // Only one of the concurrent records of a new minute prunes.
+        if pruned_at >= minute
+            || self
+                .pruned_at
+                .compare_exchange(pruned_at, minute, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        self.minutes.retain(|key, _| key.0 > minute - HOUR);
+        self.hours
+            .retain(|key, _| key.0 > bucket_of(now, HOUR) - DAY);
+    }
+
+    // The last hour is the last 60 minutes, the last day the last 24 hours including the
+    // current one.
+    pub fn last_hour(&self) -> Summary {
+        summarize(
+            &self.minutes,
+            bucket_of(Utc::now().timestamp(), MINUTE) - HOUR + MINUTE,
+        )
+    }
+
+    pub fn last_day(&self) -> Summary {
+        summarize(
+            &self.hours,
+            bucket_of(Utc::now().timestamp(), HOUR) - DAY + HOUR,
+        )
+    }
+}
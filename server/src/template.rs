@@ -0,0 +1,167 @@
+use crate::data::Pool;
+use crate::error::{Error, Result};
+use crate::models::Template;
+use crate::send::MessageType;
+use crate::user::TENANT_ID_KEY;
+use actix_session::Session;
+use actix_web::body::Body;
+use actix_web::{delete, get, put, web, Error as AWError, HttpRequest, HttpResponse};
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use url::form_urlencoded;
+
+const MAX_TEMPLATES: usize = 50;
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_CONTENT_LENGTH: usize = 4096;
+// Values of the slots are passed like `var.service=billing`.
+const VAR_PREFIX: &str = "var.";
+const MAX_VARS: usize = 50;
+
+lazy_static! {
+    static ref SLOT: Regex = Regex::new(r"\{\{\s*([A-Za-z0-9_.-]+)\s*\}\}").unwrap();
+}
+
+fn validate(template: &Template) -> Result<()> {
+    if template.name.is_empty()
+        || template.name.len() > MAX_NAME_LENGTH
+        || !template
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(Error::User(
+            "Template names must be 1 to 64 letters, digits, _ or -.",
+        ));
+    }
+    if template.content.trim().is_empty() || template.content.len() > MAX_CONTENT_LENGTH {
+        return Err(Error::User(
+            "Template content must be between 1 and 4096 bytes.",
+        ));
+    }
+    if let Some(ref message_type) = template.message_type {
+        if message_type.parse::<MessageType>()? == MessageType::Image {
+            return Err(Error::User("Images can't be templated."));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read_vars(req: &HttpRequest) -> Result<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+    for (key, value) in form_urlencoded::parse(req.query_string().as_bytes()) {
+        if let Some(key) = key.strip_prefix(VAR_PREFIX) {
+            vars.insert(key.trim().to_owned(), value.into_owned());
+        }
+    }
+    if vars.len() > MAX_VARS {
+        return Err(Error::User("At most 50 template values are allowed."));
+    }
+
+    Ok(vars)
+}
+
+// Values can't change the formatting of the template, so markdown is escaped and the JSON of
+// payloads stays valid.
+fn escape(value: &str, message_type: MessageType) -> String {
+    match message_type {
+        MessageType::Text | MessageType::Image => value.to_owned(),
+        MessageType::Markdown => {
+            let mut escaped = String::with_capacity(value.len());
+            for c in value.chars() {
+                match c {
+                    '\\' | '`' | '*' | '_' | '[' | ']' | '#' | '>' => {
+                        escaped.push('\\');
+                        escaped.push(c);
+                    }
+                    '<' => escaped.push_str("&lt;"),
+                    _ => escaped.push(c),
+                }
+            }
+            escaped
+        }
+        MessageType::MiniprogramNotice | MessageType::TemplateCard => {
+            let quoted = serde_json::Value::from(value).to_string();
+            quoted[1..quoted.len() - 1].to_owned()
+        }
+    }
+}
+
+pub fn render(
+    template: &Template,
+    message_type: MessageType,
+    vars: &HashMap<String, String>,
+) -> Result<String> {
+    if SLOT
+        .captures_iter(&template.content)
+        .any(|slot| !vars.contains_key(&slot[1]))
+    {
+        return Err(Error::User(
+            "Some slots of the template have no value, pass them like var.name=value.",
+        ));
+    }
+
+    Ok(SLOT
+        .replace_all(&template.content, |slot: &Captures| {
+            escape(&vars[&slot[1]], message_type)
+        })
+        .into_owned())
+}
+
+#[get("/templates")]
+pub async fn list(session: Session, pool: Pool) -> std::result::Result<HttpResponse, AWError> {
+    if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
+        let templates = pool.find_templates_by_tenant_id(tenant_id).await?;
+        Ok(HttpResponse::Ok().json(templates))
+    } else {
+        Err(Error::Unauthorized("Please sign in first.").into())
+    }
+}
+
+// Creates the template or replaces the one of the same name.
+#[put("/templates/{name}")]
+pub async fn update(
+    session: Session,
+    pool: Pool,
+    name: web::Path<String>,
+    web::Json(mut entity): web::Json<Template>,
+) -> std::result::Result<HttpResponse, AWError> {
+    if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
+        entity.tenant_id = tenant_id;
+        entity.name = name.trim().to_string();
+        entity.message_type = entity
+            .message_type
+            .map(|message_type| message_type.trim().to_string())
+            .filter(|message_type| !message_type.is_empty());
+        validate(&entity)?;
+        if pool.find_template(tenant_id, &entity.name).await?.is_none()
+            && pool.find_templates_by_tenant_id(tenant_id).await?.len() >= MAX_TEMPLATES
+        {
+            return Err(Error::Conflict(
+                "Too many templates, please remove one before adding another.",
+            )
+            .into());
+        }
+        let template = pool.upsert_template(entity).await?;
+        Ok(HttpResponse::Ok().json(template))
+    } else {
+        Err(Error::Unauthorized("Please sign in first.").into())
+    }
+}
+
+#[delete("/templates/{name}")]
+pub async fn remove(
+    session: Session,
+    pool: Pool,
+    name: web::Path<String>,
+) -> std::result::Result<HttpResponse, AWError> {
+    if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
+        if pool.delete_template(tenant_id, &name).await? {
+            Ok(HttpResponse::NoContent().body(Body::Empty))
+        } else {
+            Err(Error::NotFound("Unknown template.").into())
+        }
+    } else {
+        Err(Error::Unauthorized("Please sign in first.").into())
+    }
+}
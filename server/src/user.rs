@@ -1,36 +1,192 @@
+use crate::callback;
+use crate::config::PipeHubConfig;
 use crate::data::Pool;
+use crate::email;
+use crate::error::{Error, Result};
 use crate::github::GitHubClient;
-use crate::models::{Tenant, UserTenant};
+use crate::matrix;
+use crate::models::{MinimalUserTenant, Tenant, UserTenant, WechatWork};
+use crate::rate_limit;
+use crate::schedule;
+use crate::send::{self, MessageType, ResponseFormat};
 use actix_http::body::Body;
 use actix_session::Session;
 use actix_web::error::Error as AWError;
-use actix_web::{get, post, put, web, HttpResponse};
+use actix_web::http::{header, HeaderValue};
+use actix_web::{get, post, put, web, HttpRequest, HttpResponse};
 use base58::ToBase58;
+use chrono::{DateTime, Duration, Utc};
+use openssl::memcmp;
 use rand::{thread_rng, Rng};
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use url::{ParseError, Url};
 
 pub const TENANT_ID_KEY: &str = "tenant_id";
 pub const STATE_KEY: &str = "state";
+pub const REDIRECT_KEY: &str = "redirect";
+const DEFAULT_REDIRECT: &str = "/#/user";
+// Stands for this site when resolving redirects, only whether the origin changes matters.
+const REDIRECT_BASE: &str = "https://pipehub.invalid/";
+// Messages are logged for debugging at most this long.
+const MAX_LOG_BODIES_HOURS: i64 = 24;
+// The state is only accepted once and for a while, so neither it nor the code can be replayed.
+const STATE_TTL_MINUTES: i64 = 10;
+
+#[derive(Serialize, Deserialize)]
+struct LoginState {
+    state: String,
+    issued_at: DateTime<Utc>,
+}
+
+impl LoginState {
+    fn accepts(&self, state: &str, now: DateTime<Utc>) -> bool {
+        now - self.issued_at < Duration::minutes(STATE_TTL_MINUTES)
+            && self.state.len() == state.len()
+            && memcmp::eq(self.state.as_bytes(), state.as_bytes())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LoginRedirect {
+    // Where to go after signing in, defaults to the user page.
+    redirect: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TenantView {
+    // The settings as well.
+    Full,
+    // The app key and the callback url only.
+    Minimal,
+}
+
+impl Default for TenantView {
+    fn default() -> Self {
+        TenantView::Full
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ViewQuery {
+    #[serde(default)]
+    view: TenantView,
+}
 
 #[get("/user")]
 pub async fn user(
+    req: HttpRequest,
     session: Session,
     client: web::Data<GitHubClient>,
+    config: web::Data<PipeHubConfig>,
     pool: Pool,
+    web::Query(login_redirect): web::Query<LoginRedirect>,
+    web::Query(view): web::Query<ViewQuery>,
 ) -> std::result::Result<HttpResponse, AWError> {
     if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
         if let Some(tenant) = pool.find_tenant_by_id(tenant_id).await? {
-            return Ok(HttpResponse::Ok().json(UserTenant::from(tenant)));
+            let tenant = UserTenant::from(tenant);
+            return Ok(match view.view {
+                TenantView::Full => HttpResponse::Ok().json(tenant),
+                TenantView::Minimal => HttpResponse::Ok().json(MinimalUserTenant::from(tenant)),
+            });
         };
     }
 
+    // The frontend follows the location to sign in.
+    let url = start_login(&req, &session, &client, &config, login_redirect.redirect)?;
+    let mut res = HttpResponse::from_error(Error::Unauthorized("Please sign in first.").into());
+    res.headers_mut().insert(
+        header::LOCATION,
+        HeaderValue::from_str(url.as_str())
+            .map_err(|_| Error::Unexpected("Malformed login URL.".to_owned()))?,
+    );
+    Ok(res)
+}
+
+#[derive(Serialize)]
+struct LoginUrl {
+    url: String,
+}
+
+// The same as `user` but the frontend redirects by itself.
+#[get("/user/login_url")]
+pub async fn login_url(
+    req: HttpRequest,
+    session: Session,
+    client: web::Data<GitHubClient>,
+    config: web::Data<PipeHubConfig>,
+    web::Query(login_redirect): web::Query<LoginRedirect>,
+) -> std::result::Result<HttpResponse, AWError> {
+    let url = start_login(&req, &session, &client, &config, login_redirect.redirect)?;
+    Ok(HttpResponse::Ok().json(LoginUrl {
+        url: url.to_string(),
+    }))
+}
+
+// Stores a new state and the redirect in the session, and returns the authorize url.
+fn start_login(
+    req: &HttpRequest,
+    session: &Session,
+    client: &GitHubClient,
+    config: &PipeHubConfig,
+    redirect: Option<String>,
+) -> Result<Url> {
     let state = new_csrf_token();
-    let url = client.authorize_url(&state);
-    session.set(STATE_KEY, state)?;
-    Ok(HttpResponse::Unauthorized()
-        .header("Location", url.to_string())
-        .body(Body::Empty))
+    let url = client.authorize_url(&state, req.connection_info().host())?;
+    match redirect {
+        Some(redirect) => {
+            validate_redirect(&redirect, &config.allowed_redirects)?;
+            session.set(REDIRECT_KEY, redirect)?;
+        }
+        None => session.remove(REDIRECT_KEY),
+    }
+    session.set(
+        STATE_KEY,
+        LoginState {
+            state,
+            issued_at: Utc::now(),
+        },
+    )?;
+
+    Ok(url)
+}
+
+// Only paths on this site and the allowed origins are accepted, otherwise it's an open redirect.
+// Browsers drop tabs and newlines of the `Location`, e.g. `/\t/evil.com` goes to `//evil.com`, so
+// no control characters or whitespace are accepted. Paths are resolved like browsers do and must
+// stay on the site.
+fn validate_redirect(redirect: &str, allowed_redirects: &[String]) -> Result<()> {
+    if redirect
+        .chars()
+        .any(|c| c.is_control() || c.is_whitespace())
+    {
+        return Err(Error::User("The redirect is not allowed."));
+    }
+    let allowed = match Url::parse(redirect) {
+        Ok(url) => {
+            let origin = url.origin().ascii_serialization();
+            allowed_redirects
+                .iter()
+                .any(|allowed| allowed.trim_end_matches('/') == origin)
+        }
+        Err(ParseError::RelativeUrlWithoutBase) => {
+            let site = Url::parse(REDIRECT_BASE).expect("Malformed redirect base.");
+            redirect.starts_with('/')
+                && site
+                    .join(redirect)
+                    .map(|url| url.origin() == site.origin())
+                    .unwrap_or(false)
+        }
+        Err(_) => false,
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::User("The redirect is not allowed."))
+    }
 }
 
 fn new_csrf_token() -> String {
@@ -60,6 +216,9 @@ pub async fn login(
 ) -> std::result::Result<HttpResponse, AWError> {
     let access_token = login.access_token;
     let github_user = github_client.get_user(&http_client, &access_token).await?;
+    github_client
+        .ensure_member(&http_client, &access_token, &github_user)
+        .await?;
     match pool.find_tenant_by_github_id(github_user.id).await? {
         Some(tenant) => session.set(TENANT_ID_KEY, tenant.id)?,
         None => {
@@ -70,7 +229,7 @@ pub async fn login(
         }
     }
     Ok(HttpResponse::Found()
-        .header("Location", "/#/user")
+        .header("Location", DEFAULT_REDIRECT)
         .body(Body::Empty))
 }
 
@@ -82,12 +241,17 @@ pub async fn callback(
     pool: Pool,
     web::Query(callback): web::Query<Callback>,
 ) -> std::result::Result<HttpResponse, AWError> {
-    match session.get::<String>(STATE_KEY)? {
-        Some(state) if state == callback.state => {
+    let login_state = session.get::<LoginState>(STATE_KEY)?;
+    session.remove(STATE_KEY);
+    match login_state {
+        Some(login_state) if login_state.accepts(&callback.state, Utc::now()) => {
             let access_token = github_client
                 .exchange_code(&http_client, &callback.code)
                 .await?;
             let github_user = github_client.get_user(&http_client, &access_token).await?;
+            github_client
+                .ensure_member(&http_client, &access_token, &github_user)
+                .await?;
 
             match pool.find_tenant_by_github_id(github_user.id).await? {
                 Some(tenant) => session.set(TENANT_ID_KEY, tenant.id)?,
@@ -98,56 +262,191 @@ pub async fn callback(
                     session.set(TENANT_ID_KEY, tenant.id)?
                 }
             }
+            // It has been validated when it's stored.
+            let redirect = session
+                .get::<String>(REDIRECT_KEY)?
+                .unwrap_or_else(|| DEFAULT_REDIRECT.to_owned());
+            session.remove(REDIRECT_KEY);
             Ok(HttpResponse::Found()
-                .header("Location", "/#/user")
+                .header("Location", redirect)
                 .body(Body::Empty))
         }
-        _ => Ok(HttpResponse::Found()
-            .header("Location", "/")
-            .body(Body::Empty)),
+        _ => Err(Error::Forbidden("Invalid or expired OAuth state, please sign in again.").into()),
     }
 }
 
+// The old key and the send tokens issued with it stop working.
+async fn regenerate_key(pool: &Pool, tenant: Tenant) -> Result<UserTenant> {
+    let new_tenant = Tenant {
+        app_id: thread_rng().gen(),
+        ..tenant
+    };
+    pool.update_tenant(new_tenant.clone()).await?;
+
+    Ok(UserTenant::from(new_tenant))
+}
+
 #[post("/user/reset_key")]
 pub async fn reset_key(session: Session, pool: Pool) -> std::result::Result<HttpResponse, AWError> {
     if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
         if let Some(tenant) = pool.find_tenant_by_id(tenant_id).await? {
-            let new_tenant = Tenant {
-                id: tenant.id,
-                app_id: thread_rng().gen(),
-                github_login: tenant.github_login,
-                github_id: tenant.github_id,
-                block_list: tenant.block_list,
-            };
-            pool.update_tenant(new_tenant.clone()).await?;
+            return Ok(HttpResponse::Ok().json(regenerate_key(&pool, tenant).await?));
+        };
+    }
 
-            return Ok(HttpResponse::Ok().json(UserTenant::from(new_tenant)));
+    Err(Error::Unauthorized("Please sign in first.").into())
+}
+
+// The send url alone, so it can be looked up again without the settings.
+#[get("/api/callback-url")]
+pub async fn callback_url(
+    session: Session,
+    pool: Pool,
+) -> std::result::Result<HttpResponse, AWError> {
+    if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
+        if let Some(tenant) = pool.find_tenant_by_id(tenant_id).await? {
+            return Ok(HttpResponse::Ok().json(MinimalUserTenant::from(UserTenant::from(tenant))));
+        };
+    }
+
+    Err(Error::Unauthorized("Please sign in first.").into())
+}
+
+// Replaces the key like `/user/reset_key`, replying only the new send url.
+#[post("/api/callback-url")]
+pub async fn regenerate_callback_url(
+    session: Session,
+    pool: Pool,
+) -> std::result::Result<HttpResponse, AWError> {
+    if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
+        if let Some(tenant) = pool.find_tenant_by_id(tenant_id).await? {
+            let tenant = regenerate_key(&pool, tenant).await?;
+            return Ok(HttpResponse::Ok().json(MinimalUserTenant::from(tenant)));
         };
     }
 
-    Ok(HttpResponse::Unauthorized().body(Body::Empty))
+    Err(Error::Unauthorized("Please sign in first.").into())
 }
 
 #[put("/user")]
 pub async fn update(
     session: Session,
     pool: Pool,
+    config: web::Data<PipeHubConfig>,
     web::Json(new_tenant): web::Json<Tenant>,
 ) -> std::result::Result<HttpResponse, AWError> {
     if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
         if let Some(tenant) = pool.find_tenant_by_id(tenant_id).await? {
-            let new_tenant = Tenant {
-                id: tenant.id,
-                app_id: tenant.app_id,
-                github_login: tenant.github_login,
-                github_id: tenant.github_id,
-                block_list: new_tenant.block_list,
-            };
+            let new_tenant = with_settings(tenant, new_tenant, &config)?;
+            let wechats = pool.find_wechats_by_tenant_id(tenant_id).await?;
+            validate_default_agent(&new_tenant, &wechats)?;
             pool.update_tenant(new_tenant.clone()).await?;
 
             return Ok(HttpResponse::Ok().json(UserTenant::from(new_tenant)));
         };
     }
 
-    Ok(HttpResponse::Unauthorized().body(Body::Empty))
+    Err(Error::Unauthorized("Please sign in first.").into())
+}
+
+// Takes the settings of the new tenant after normalizing and validating them.
+pub fn with_settings(tenant: Tenant, new_tenant: Tenant, config: &PipeHubConfig) -> Result<Tenant> {
+    let default_message_type = non_empty(new_tenant.default_message_type);
+    if let Some(ref message_type) = default_message_type {
+        message_type.parse::<MessageType>()?;
+    }
+    let response_format = non_empty(new_tenant.response_format);
+    if let Some(ref response_format) = response_format {
+        response_format.parse::<ResponseFormat>()?;
+    }
+    let new_tenant = Tenant {
+        block_list: new_tenant.block_list,
+        allow_list: non_empty(new_tenant.allow_list),
+        default_message_type,
+        quiet_hours_start: non_empty(new_tenant.quiet_hours_start),
+        quiet_hours_end: non_empty(new_tenant.quiet_hours_end),
+        time_zone: non_empty(new_tenant.time_zone),
+        quiet_days: non_empty(new_tenant.quiet_days),
+        drop_in_quiet_hours: new_tenant.drop_in_quiet_hours,
+        rate_limits: non_empty(new_tenant.rate_limits),
+        response_format,
+        default_agent_id: new_tenant.default_agent_id,
+        callback_token: non_empty(new_tenant.callback_token),
+        callback_aes_key: non_empty(new_tenant.callback_aes_key),
+        callback_forward_url: non_empty(new_tenant.callback_forward_url),
+        matrix_homeserver: non_empty(new_tenant.matrix_homeserver),
+        matrix_access_token: non_empty(new_tenant.matrix_access_token),
+        matrix_room_id: non_empty(new_tenant.matrix_room_id),
+        smtp_host: non_empty(new_tenant.smtp_host),
+        smtp_port: new_tenant.smtp_port,
+        smtp_tls: non_empty(new_tenant.smtp_tls),
+        smtp_username: non_empty(new_tenant.smtp_username),
+        smtp_password: non_empty(new_tenant.smtp_password),
+        email_from: non_empty(new_tenant.email_from),
+        email_to: non_empty(new_tenant.email_to),
+        default_to_user: non_empty(new_tenant.default_to_user),
+        default_to_party: non_empty(new_tenant.default_to_party),
+        default_to_tag: non_empty(new_tenant.default_to_tag),
+        prefix: new_tenant.prefix,
+        suffix: new_tenant.suffix,
+        append_request_id: new_tenant.append_request_id,
+        use_outbox: new_tenant.use_outbox,
+        log_bodies_until: new_tenant
+            .log_bodies_until
+            .map(|until| until.min(Utc::now() + Duration::hours(MAX_LOG_BODIES_HOURS))),
+        ..tenant
+    };
+    schedule::validate_quiet_hours(&new_tenant)?;
+    rate_limit::validate_rate_limits(&new_tenant)?;
+    callback::validate_callback(&new_tenant, config)?;
+    matrix::validate_matrix(&new_tenant)?;
+    email::validate_email(&new_tenant)?;
+    send::validate_default_targets(&new_tenant)?;
+
+    Ok(new_tenant)
+}
+
+pub fn validate_default_agent(tenant: &Tenant, wechats: &[WechatWork]) -> Result<()> {
+    match tenant.default_agent_id {
+        Some(agent_id) if !wechats.iter().any(|wechat| wechat.agent_id == agent_id) => Err(
+            Error::User("default_agent_id must be the agent id of configured WeChat credentials."),
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(redirect: &str) -> bool {
+        validate_redirect(redirect, &["https://app.example.com/".to_owned()]).is_ok()
+    }
+
+    #[test]
+    fn allows_paths_of_the_site_and_the_allowed_origins() {
+        assert!(allows("/"));
+        assert!(allows("/user?tab=wechat#top"));
+        assert!(allows("/a/../b"));
+        assert!(allows("https://app.example.com/user"));
+    }
+
+    #[test]
+    fn rejects_redirects_off_the_site() {
+        assert!(!allows("//evil.com"));
+        assert!(!allows("/\\evil.com"));
+        assert!(!allows("/\t/evil.com"));
+        assert!(!allows("/\n/evil.com"));
+        assert!(!allows("/ /evil.com"));
+        assert!(!allows("user"));
+        assert!(!allows("https://evil.com/"));
+        assert!(!allows("https://app.example.com.evil.com/"));
+        assert!(!allows("javascript:alert(1)"));
+    }
 }
@@ -1,3 +1,7 @@
+use crate::schedule;
+use chrono_tz::Tz;
+use regex::{Captures, Regex};
+use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serializer};
 use std::ops::Add;
 use std::time::{Duration, Instant};
@@ -18,3 +22,275 @@ where
     Deserialize::deserialize(deserializer)
         .map(|expires_in| Instant::now().add(Duration::from_secs(expires_in)))
 }
+
+pub fn comma_separated<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input: String = Deserialize::deserialize(deserializer)?;
+    Ok(input
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_owned())
+        .collect())
+}
+
+// Splits at the commas not escaped as `\,`, so an expression can have one like `\d{1\,3}`.
+fn split_unescaped_commas(input: &str) -> Vec<String> {
+    let mut items = vec![String::new()];
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        let item = items.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some(',') => item.push(','),
+                // Other escapes are the expression's, `\\,` is a backslash and a separator.
+                Some(next) => {
+                    item.push('\\');
+                    item.push(next);
+                }
+                None => item.push('\\'),
+            },
+            ',' => items.push(String::new()),
+            c => item.push(c),
+        }
+    }
+    items
+        .iter()
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_owned())
+        .collect()
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Patterns {
+    List(Vec<String>),
+    Joined(String),
+}
+
+// A list in config files, or comma separated in the environment with commas escaped as `\,`.
+// Malformed expressions fail the config, so they are caught at startup.
+pub fn regexes<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let patterns = match Patterns::deserialize(deserializer)? {
+        Patterns::List(patterns) => patterns,
+        Patterns::Joined(patterns) => split_unescaped_commas(&patterns),
+    };
+    patterns
+        .iter()
+        .map(|pattern| Regex::new(pattern).map_err(D::Error::custom))
+        .collect()
+}
+
+// Unknown time zones fail the config like malformed expressions.
+pub fn time_zone<'de, D>(deserializer: D) -> Result<Tz, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input: String = Deserialize::deserialize(deserializer)?;
+    schedule::parse_time_zone(input.trim()).map_err(D::Error::custom)
+}
+
+// Truncates to at most `max_length` bytes without breaking a character.
+pub fn truncate(input: &mut String, max_length: usize) {
+    if input.len() <= max_length {
+        return;
+    }
+    let mut end = max_length;
+    while !input.is_char_boundary(end) {
+        end -= 1;
+    }
+    input.truncate(end);
+}
+
+fn is_stripped_control(c: char) -> bool {
+    c.is_control() && c != '\n' && c != '\r' && c != '\t'
+}
+
+// Strips control characters like null bytes and escapes of terminal colors from binary logs,
+// line breaks and tabs are kept.
+pub fn strip_control_chars(input: String) -> String {
+    if !input.chars().any(is_stripped_control) {
+        return input;
+    }
+
+    input.chars().filter(|c| !is_stripped_control(*c)).collect()
+}
+
+lazy_static! {
+    // The syntax WeChat supports in markdown messages.
+    static ref MARKDOWN_HEADING: Regex = Regex::new(r"(?m)^\s{0,3}#{1,6}\s+").unwrap();
+    static ref MARKDOWN_QUOTE: Regex = Regex::new(r"(?m)^\s{0,3}>\s?").unwrap();
+    static ref MARKDOWN_LINK: Regex = Regex::new(r"\[([^\]]*)\]\(([^)\s]*)\)").unwrap();
+    static ref MARKDOWN_EMPHASIS: Regex = Regex::new(r"\*\*(.+?)\*\*|\*(.+?)\*|`([^`]*)`").unwrap();
+    static ref MARKDOWN_FONT: Regex = Regex::new(r"(?i)</?font[^>]*>").unwrap();
+    static ref MARKDOWN_HEADING_LEVEL: Regex = Regex::new(r"^\s{0,3}(#{1,6})\s+").unwrap();
+}
+
+// Strips the markdown to plain text for where it isn't rendered, links keep their urls.
+pub fn strip_markdown(markdown: &str) -> String {
+    let text = MARKDOWN_FONT.replace_all(markdown, "");
+    let text = MARKDOWN_HEADING.replace_all(&text, "");
+    let text = MARKDOWN_QUOTE.replace_all(&text, "");
+    let text = MARKDOWN_LINK.replace_all(&text, |caps: &Captures| match (&caps[1], &caps[2]) {
+        (title, url) if title.is_empty() || title == url => url.to_owned(),
+        (title, url) => format!("{} ({})", title, url),
+    });
+    let text = MARKDOWN_EMPHASIS.replace_all(&text, |caps: &Captures| {
+        caps.iter()
+            .skip(1)
+            .flatten()
+            .next()
+            .map(|inner| inner.as_str().to_owned())
+            .unwrap_or_default()
+    });
+
+    text.into_owned()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn inline_html(line: &str) -> String {
+    let html = escape_html(line);
+    let html = MARKDOWN_LINK.replace_all(&html, |caps: &Captures| {
+        let url = &caps[2];
+        // Other schemes like `javascript:` are left as text.
+        if url.starts_with("http://") || url.starts_with("https://") {
+            let title = if caps[1].is_empty() { url } else { &caps[1] };
+            format!("<a href=\"{}\">{}</a>", url, title)
+        } else {
+            caps[0].to_owned()
+        }
+    });
+    let html = MARKDOWN_EMPHASIS.replace_all(&html, |caps: &Captures| {
+        match (caps.get(1), caps.get(2), caps.get(3)) {
+            (Some(strong), _, _) => format!("<strong>{}</strong>", strong.as_str()),
+            (_, Some(em), _) => format!("<em>{}</em>", em.as_str()),
+            (_, _, Some(code)) => format!("<code>{}</code>", code.as_str()),
+            _ => caps[0].to_owned(),
+        }
+    });
+
+    html.into_owned()
+}
+
+// Renders the markdown WeChat supports to HTML, for where markdown isn't taken as is.
+pub fn markdown_to_html(markdown: &str) -> String {
+    let markdown = MARKDOWN_FONT.replace_all(markdown, "");
+    let mut html = String::with_capacity(markdown.len());
+    let mut lines = markdown.lines().peekable();
+    while let Some(line) = lines.next() {
+        if let Some(caps) = MARKDOWN_HEADING_LEVEL.captures(line) {
+            let level = caps[1].len();
+            let heading = inline_html(&line[caps[0].len()..]);
+            html.push_str(&format!("<h{0}>{1}</h{0}>", level, heading));
+        } else if let Some(quote) = MARKDOWN_QUOTE.find(line) {
+            let quote = inline_html(&line[quote.end()..]);
+            html.push_str(&format!("<blockquote>{}</blockquote>", quote));
+        } else {
+            html.push_str(&inline_html(line));
+            if lines.peek().is_some() {
+                html.push_str("<br>");
+            }
+        }
+    }
+
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(input: &str) -> Vec<String> {
+        regexes(serde_json::Value::from(input))
+            .unwrap()
+            .iter()
+            .map(|regex| regex.as_str().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn splits_expressions_at_unescaped_commas() {
+        assert_eq!(
+            patterns(r"password=\S+, token=\S+"),
+            vec![r"password=\S+", r"token=\S+"]
+        );
+        assert_eq!(patterns(r"\d{1\,3},AKIA"), vec![r"\d{1,3}", "AKIA"]);
+        assert_eq!(patterns(r"a\\,b"), vec![r"a\\", "b"]);
+        assert_eq!(patterns(" , "), Vec::<String>::new());
+    }
+
+    #[test]
+    fn takes_lists_of_expressions() {
+        let regexes = regexes(serde_json::json!([r"\d{1,3}", "a,b"])).unwrap();
+        assert_eq!(regexes[0].as_str(), r"\d{1,3}");
+        assert_eq!(regexes[1].as_str(), "a,b");
+        assert!(super::regexes(serde_json::json!(["("])).is_err());
+    }
+
+    #[test]
+    fn strips_headings() {
+        assert_eq!(
+            strip_markdown("# Title\n  ### Section\ntext"),
+            "Title\nSection\ntext"
+        );
+        assert_eq!(strip_markdown("#hashtag"), "#hashtag");
+    }
+
+    #[test]
+    fn strips_quotes() {
+        assert_eq!(
+            strip_markdown("> quoted\n>also\nplain > not"),
+            "quoted\nalso\nplain > not"
+        );
+    }
+
+    #[test]
+    fn strips_emphasis() {
+        assert_eq!(strip_markdown("**bold** and *italic*"), "bold and italic");
+    }
+
+    #[test]
+    fn strips_code() {
+        assert_eq!(strip_markdown("run `cargo test` now"), "run cargo test now");
+    }
+
+    #[test]
+    fn strips_font_tags() {
+        assert_eq!(
+            strip_markdown("<font color=\"warning\">132</font> failed, <FONT color=info>ok</FONT>"),
+            "132 failed, ok"
+        );
+    }
+
+    #[test]
+    fn keeps_urls_of_links() {
+        assert_eq!(
+            strip_markdown("[Details](https://example.com/1)"),
+            "Details (https://example.com/1)"
+        );
+        assert_eq!(
+            strip_markdown(
+                "[](https://example.com/1) [https://example.com/2](https://example.com/2)"
+            ),
+            "https://example.com/1 https://example.com/2"
+        );
+    }
+
+    #[test]
+    fn leaves_plain_text_alone() {
+        let text = "Deployed 1.2.3 to production (2 * 3 = 6).";
+        assert_eq!(strip_markdown(text), text);
+    }
+}
@@ -0,0 +1,25 @@
+use actix_web::{get, HttpResponse};
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+struct Version {
+    version: &'static str,
+    commit: &'static str,
+    built_at: String,
+}
+
+// Tells which build is deployed, the commit and build time are captured by build.rs.
+#[get("/version")]
+pub async fn version() -> HttpResponse {
+    let built_at = env!("PIPEHUB_BUILT_AT")
+        .parse()
+        .map(|built_at| Utc.timestamp(built_at, 0).to_rfc3339())
+        .unwrap_or_default();
+
+    HttpResponse::Ok().json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("PIPEHUB_GIT_COMMIT"),
+        built_at,
+    })
+}
@@ -1,9 +1,11 @@
+use crate::config::PipeHubConfig;
 use crate::data::Pool;
-use crate::models::WechatWork;
+use crate::error::Error;
+use crate::models::{Tenant, WechatWork};
 use crate::user::TENANT_ID_KEY;
 use actix_session::Session;
 use actix_web::body::Body;
-use actix_web::{get, put, web, Error as AWError, HttpResponse};
+use actix_web::{delete, get, post, put, web, Error as AWError, HttpResponse};
 
 #[get("/wechat")]
 pub async fn wechat(session: Session, pool: Pool) -> std::result::Result<HttpResponse, AWError> {
@@ -14,23 +16,104 @@ pub async fn wechat(session: Session, pool: Pool) -> std::result::Result<HttpRes
             Ok(HttpResponse::Ok().json(WechatWork::default()))
         }
     } else {
-        Ok(HttpResponse::Unauthorized().body(Body::Empty))
+        Err(Error::Unauthorized("Please sign in first.").into())
     }
 }
 
+// Updates the primary WeChat credentials, which are used for sending.
 #[put("/wechat")]
 pub async fn update(
     session: Session,
     pool: Pool,
+    config: web::Data<PipeHubConfig>,
     web::Json(mut entity): web::Json<WechatWork>,
 ) -> std::result::Result<HttpResponse, AWError> {
     if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
         entity.tenant_id = tenant_id;
         entity.corp_id = entity.corp_id.trim().to_string();
         entity.secret = entity.secret.trim().to_string();
-        pool.upsert_wechat(entity).await?;
+        entity.external_secret = entity
+            .external_secret
+            .map(|secret| secret.trim().to_string())
+            .filter(|secret| !secret.is_empty());
+        if let Some(primary) = pool.find_wechat_by_id(tenant_id).await? {
+            entity.id = primary.id;
+            pool.update_wechat(entity).await?;
+        } else {
+            insert_within_capacity(&pool, &config, entity).await?;
+        }
         Ok(HttpResponse::NoContent().body(Body::Empty))
     } else {
-        Ok(HttpResponse::Unauthorized().body(Body::Empty))
+        Err(Error::Unauthorized("Please sign in first.").into())
     }
 }
+
+#[get("/wechats")]
+pub async fn list(session: Session, pool: Pool) -> std::result::Result<HttpResponse, AWError> {
+    if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
+        let wechats = pool.find_wechats_by_tenant_id(tenant_id).await?;
+        Ok(HttpResponse::Ok().json(wechats))
+    } else {
+        Err(Error::Unauthorized("Please sign in first.").into())
+    }
+}
+
+#[post("/wechats")]
+pub async fn create(
+    session: Session,
+    pool: Pool,
+    config: web::Data<PipeHubConfig>,
+    web::Json(mut entity): web::Json<WechatWork>,
+) -> std::result::Result<HttpResponse, AWError> {
+    if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
+        entity.tenant_id = tenant_id;
+        entity.corp_id = entity.corp_id.trim().to_string();
+        entity.secret = entity.secret.trim().to_string();
+        entity.external_secret = entity
+            .external_secret
+            .map(|secret| secret.trim().to_string())
+            .filter(|secret| !secret.is_empty());
+        let wechat = insert_within_capacity(&pool, &config, entity).await?;
+        Ok(HttpResponse::Created().json(wechat))
+    } else {
+        Err(Error::Unauthorized("Please sign in first.").into())
+    }
+}
+
+#[delete("/wechats/{id}")]
+pub async fn remove(
+    session: Session,
+    pool: Pool,
+    id: web::Path<i64>,
+) -> std::result::Result<HttpResponse, AWError> {
+    if let Some(tenant_id) = session.get::<i64>(TENANT_ID_KEY)? {
+        if pool.delete_wechat(tenant_id, id.into_inner()).await? {
+            Ok(HttpResponse::NoContent().body(Body::Empty))
+        } else {
+            Err(Error::NotFound("Unknown WeChat credentials.").into())
+        }
+    } else {
+        Err(Error::Unauthorized("Please sign in first.").into())
+    }
+}
+
+// How many WeChat credentials the tenant can create, admins may raise it for some tenants.
+pub fn capacity(tenant: &Tenant, config: &PipeHubConfig) -> usize {
+    tenant
+        .max_wechat_works
+        .map(|max_wechat_works| max_wechat_works.max(0) as usize)
+        .unwrap_or(config.max_wechat_works)
+}
+
+// The capacity is checked by the insert itself, so concurrent creations can't go past it.
+async fn insert_within_capacity(
+    pool: &Pool,
+    config: &PipeHubConfig,
+    entity: WechatWork,
+) -> std::result::Result<WechatWork, Error> {
+    pool.insert_wechat(entity, |tenant| capacity(tenant, config))
+        .await?
+        .ok_or(Error::Conflict(
+            "Too many WeChat credentials, please remove one before adding another.",
+        ))
+}
@@ -1,3 +1,4 @@
+use pipehub_client::{Error as ClientError, PipeHubClient, SendOptions, SendResponse};
 use reqwest::redirect::Policy;
 use serde::Deserialize;
 use serde::Serialize;
@@ -7,6 +8,13 @@ use std::str::FromStr;
 use std::{env, process, thread};
 use tokio::time::Duration;
 
+mod wechat_stub;
+
+const CONTENT_POLICY: &str = "PIPEHUB-BANNED-[0-9]+";
+const STUB_WECHAT_ADDR: &str = "127.0.0.1:18089";
+// Another server sharing the database, which calls the stub of WeChat.
+const STUBBED_SERVER_PORT: u16 = 18090;
+
 // Arguments:
 // - Executable
 // - Workdir
@@ -35,15 +43,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let work_dir = args.next().unwrap();
     let endpoint = args.next().unwrap();
     let access_token = env::var("ACCESS_TOKEN").expect("No access_token found.");
+    let database_url = env::var("DATABASE_URL").expect("No database_url found.");
     let corp_id = env::var("CORP_ID").unwrap();
     let secret = env::var("SECRET").unwrap();
     let agent_id = i32::from_str(&env::var("AGENT_ID").unwrap()).unwrap();
 
     // 0. Start server.
+    let (stubbed_executable, stubbed_work_dir) = (executable.clone(), work_dir.clone());
     thread::spawn(move || {
         println!("Starting the server.");
         Command::new(executable)
             .current_dir(work_dir)
+            .env("pipehub_content_policy", CONTENT_POLICY)
             .spawn()
             .expect("Failed to start");
     });
@@ -58,6 +69,49 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let resp = client.get(&format!("{}/", endpoint)).send().await?;
     assert!(resp.status().is_success());
 
+    #[derive(Deserialize)]
+    pub struct User {
+        github_id: i64,
+        app_key: String,
+        callback_url: String,
+    }
+
+    // 1.1. Logging in concurrently for the first time creates one tenant.
+    assert_eq!(
+        count_tenants(&database_url, "TRUE"),
+        0,
+        "The tests need a database without tenants."
+    );
+    let login = |client: reqwest::Client| {
+        let endpoint = endpoint.clone();
+        let access_token = access_token.clone();
+        async move {
+            let resp = client
+                .post(&format!("{}/login", endpoint))
+                .query(&[("access_token", &access_token)])
+                .send()
+                .await?;
+            assert!(resp.status().is_redirection());
+            let resp = client.get(&format!("{}/user", endpoint)).send().await?;
+            assert!(resp.status().is_success());
+            resp.json::<User>().await
+        }
+    };
+    let new_client = || {
+        reqwest::Client::builder()
+            .cookie_store(true)
+            .redirect(Policy::none())
+            .build()
+            .unwrap()
+    };
+    let (first, second) = tokio::join!(login(new_client()), login(new_client()));
+    let (first, second) = (first?, second?);
+    assert_eq!(first.app_key, second.app_key);
+    assert_eq!(
+        count_tenants(&database_url, &format!("github_id = {}", first.github_id)),
+        1
+    );
+
     // 2. I have to login.
     let resp = client
         .post(&format!("{}/login", endpoint))
@@ -86,22 +140,44 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await?;
     assert!(resp.status().is_success());
 
-    #[derive(Deserialize)]
-    pub struct User {
-        callback_url: String,
-    }
-
     #[derive(Debug, Deserialize)]
     pub struct Response {
         success: bool,
     }
 
+    #[derive(Debug, Deserialize)]
+    pub struct EchoResponse {
+        message: EchoMessage,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct EchoMessage {
+        body: EchoBody,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct EchoBody {
+        msgtype: String,
+        text: Option<EchoText>,
+        markdown: Option<EchoText>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct EchoText {
+        content: String,
+    }
+
     // 4. Get my callback url.
     let resp = client.get(&format!("{}/user", endpoint)).send().await?;
     assert!(resp.status().is_success());
     let callback = resp.json::<User>().await?;
     assert!(!callback.callback_url.is_empty());
 
+    // 4.1. Logging in concurrently again keeps the tenant.
+    let (first, second) = tokio::join!(login(new_client()), login(new_client()));
+    assert_eq!(callback.app_key, first?.app_key);
+    assert_eq!(callback.app_key, second?.app_key);
+
     // 5. Send message.
     let resp = client
         .get(&callback.callback_url)
@@ -112,5 +188,255 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let resp = resp.json::<Response>().await?;
     assert!(resp.success);
 
+    // 5.1. Bodies in GBK are transcoded to UTF-8.
+    let resp = client
+        .post(&format!("{}/echo", callback.callback_url))
+        .header("Content-Type", "text/plain; charset=gbk")
+        // 你好, 世界
+        .body(vec![
+            0xC4, 0xE3, 0xBA, 0xC3, 0x2C, 0x20, 0xCA, 0xC0, 0xBD, 0xE7,
+        ])
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+    let resp = resp.json::<EchoResponse>().await?;
+    assert_eq!(resp.message.body.msgtype, "text");
+    assert!(resp
+        .message
+        .body
+        .text
+        .expect("No text in the echo.")
+        .content
+        .contains("你好, 世界"));
+
+    // 5.2. Unknown charsets are rejected.
+    let resp = client
+        .post(&format!("{}/echo", callback.callback_url))
+        .header("Content-Type", "text/plain; charset=klingon")
+        .body("Message challenge sent from PipeHub test.")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    // 5.3. Bodies of text/markdown are markdown unless the type is given.
+    let resp = client
+        .post(&format!("{}/echo", callback.callback_url))
+        .header("Content-Type", "text/markdown; charset=utf-8")
+        .body("**Message challenge** sent from PipeHub test.")
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+    let resp = resp.json::<EchoResponse>().await?;
+    assert_eq!(resp.message.body.msgtype, "markdown");
+    assert!(resp.message.body.markdown.is_some());
+
+    let resp = client
+        .post(&format!("{}/echo", callback.callback_url))
+        .query(&[("type", "text")])
+        .header("Content-Type", "text/markdown; charset=utf-8")
+        .body("**Message challenge** sent from PipeHub test.")
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+    let resp = resp.json::<EchoResponse>().await?;
+    assert_eq!(resp.message.body.msgtype, "text");
+
+    // 5.4. Markdown to external contacts is stripped to text, which is truncated to its limit.
+    let resp = client
+        .post(&format!("{}/echo", callback.callback_url))
+        .query(&[("type", "markdown"), ("external_userid", "wmTestContact")])
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!(
+            "# Report\n> **3** <font color=\"warning\">failed</font>, [details](https://example.com)\n{}",
+            "x".repeat(3000)
+        ))
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+    let resp = resp.json::<EchoResponse>().await?;
+    assert_eq!(resp.message.body.msgtype, "text");
+    let content = resp
+        .message
+        .body
+        .text
+        .expect("No text in the echo.")
+        .content;
+    assert!(content.starts_with("Report\n3 failed, details (https://example.com)\n"));
+    assert!(content.len() <= 2048);
+
+    // 5.5. The content policy applies to the title too, not only the text.
+    let resp = client
+        .get(&callback.callback_url)
+        .query(&[
+            ("title", "PIPEHUB-BANNED-42"),
+            ("text", "Message challenge sent from PipeHub test."),
+        ])
+        .send()
+        .await?;
+    assert_eq!(resp.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+
+    // 6. Send message with the client.
+    let pipehub = PipeHubClient::new(&endpoint, &callback.app_key);
+    let resp = pipehub
+        .send_text("Message challenge sent from PipeHub client test.")
+        .await?;
+    assert!(resp.response.success);
+    assert!(resp.msgid.is_some());
+    let resp = pipehub
+        .send_markdown("**Message challenge** sent from PipeHub client test.")
+        .await?;
+    assert!(resp.response.success);
+
+    // 7. The targets WeChat couldn't deliver to are replied, the others still get the message.
+    // Started now that the first server has migrated the database.
+    tokio::spawn(wechat_stub::serve(STUB_WECHAT_ADDR.parse()?));
+    thread::spawn(move || {
+        println!("Starting the server calling the stub of WeChat.");
+        Command::new(stubbed_executable)
+            .current_dir(stubbed_work_dir)
+            .env("pipehub_port", STUBBED_SERVER_PORT.to_string())
+            .env(
+                "pipehub_wechat_api_url",
+                format!("http://{}", STUB_WECHAT_ADDR),
+            )
+            .spawn()
+            .expect("Failed to start");
+    });
+    tokio::time::delay_for(Duration::from_secs(10)).await;
+    let stubbed_send_url = format!(
+        "http://127.0.0.1:{}/send/{}",
+        STUBBED_SERVER_PORT, callback.app_key
+    );
+    let resp = client
+        .get(&stubbed_send_url)
+        .query(&[
+            ("text", "Message challenge sent from PipeHub test."),
+            (
+                "to_user",
+                format!("alice|{}", wechat_stub::INVALID_USER).as_str(),
+            ),
+            (
+                "to_party",
+                format!("1|{}", wechat_stub::INVALID_PARTY).as_str(),
+            ),
+            ("to_tag", "1"),
+        ])
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+    let resp = resp.json::<SendResponse>().await?;
+    assert!(resp.response.success);
+    assert_eq!(resp.msgid.as_deref(), Some("stub-msgid"));
+    let invalid_targets = resp.invalid_targets;
+    assert_eq!(
+        invalid_targets.invalid_users,
+        vec![wechat_stub::INVALID_USER]
+    );
+    assert_eq!(
+        invalid_targets.invalid_parties,
+        vec![wechat_stub::INVALID_PARTY]
+    );
+    assert!(invalid_targets.invalid_tags.is_empty());
+
+    let resp = client
+        .get(&stubbed_send_url)
+        .query(&[
+            ("text", "Message challenge sent from PipeHub test."),
+            ("to_tag", format!("1|{}", wechat_stub::INVALID_TAG).as_str()),
+        ])
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+    let resp = resp.json::<SendResponse>().await?;
+    assert_eq!(
+        resp.invalid_targets.invalid_tags,
+        vec![wechat_stub::INVALID_TAG]
+    );
+    assert!(resp.invalid_targets.invalid_users.is_empty());
+
+    // 7.1. Failing for every target fails the send with the error of WeChat.
+    let resp = client
+        .get(&stubbed_send_url)
+        .query(&[
+            ("text", "Message challenge sent from PipeHub test."),
+            ("to_user", wechat_stub::INVALID_USER),
+        ])
+        .send()
+        .await?;
+    assert_eq!(resp.status(), reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+    let resp = resp.json::<SendResponse>().await?;
+    assert!(!resp.response.success);
+    assert!(resp.response.error_message.contains("81013"));
+    assert!(resp.invalid_targets.is_empty());
+
+    // 7.2. The client sends its options and reads the targets WeChat couldn't deliver to.
+    let pipehub = PipeHubClient::new(
+        format!("http://127.0.0.1:{}", STUBBED_SERVER_PORT),
+        &callback.app_key,
+    );
+    let resp = pipehub
+        .send(
+            "Message challenge sent from PipeHub client test.",
+            &SendOptions::new()
+                .to_user(format!("alice|{}", wechat_stub::INVALID_USER))
+                .to_party(format!("1|{}", wechat_stub::INVALID_PARTY))
+                .to_tag(format!("1|{}", wechat_stub::INVALID_TAG)),
+        )
+        .await?;
+    assert_eq!(resp.msgid.as_deref(), Some("stub-msgid"));
+    assert_eq!(
+        resp.invalid_targets.invalid_users,
+        vec![wechat_stub::INVALID_USER]
+    );
+    assert_eq!(
+        resp.invalid_targets.invalid_parties,
+        vec![wechat_stub::INVALID_PARTY]
+    );
+    assert_eq!(
+        resp.invalid_targets.invalid_tags,
+        vec![wechat_stub::INVALID_TAG]
+    );
+
+    let resp = pipehub
+        .send(
+            "Message challenge sent from PipeHub client test.",
+            &SendOptions::new().chat_id(wechat_stub::CHAT_ID),
+        )
+        .await?;
+    assert!(resp.response.success);
+    // Only the group chat fails, the message would go to everyone if the chat id weren't sent.
+    match pipehub
+        .send(
+            "Message challenge sent from PipeHub client test.",
+            &SendOptions::new().chat_id("missingchat"),
+        )
+        .await
+    {
+        Err(ClientError::Api { status, response }) => {
+            assert_eq!(status, 500);
+            assert!(response.error_message.contains("86003"));
+        }
+        resp => panic!("Expected the chat to be missing, got {:?}.", resp),
+    }
+
     Ok(())
 }
+
+// Counts the tenants matching the condition with psql, nothing else reads the database directly.
+fn count_tenants(database_url: &str, condition: &str) -> i64 {
+    let output = Command::new("psql")
+        .arg(database_url)
+        .args(&["--tuples-only", "--no-align", "--command"])
+        .arg(format!("SELECT COUNT(*) FROM tenants WHERE {}", condition))
+        .output()
+        .expect("Failed to run psql.");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .expect("Malformed count.")
+}
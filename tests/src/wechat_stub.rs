@@ -0,0 +1,78 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+// Members, parties and tags of these ids aren't in the stub corp, like departed employees.
+pub const INVALID_USER: &str = "departed";
+pub const INVALID_PARTY: &str = "404";
+pub const INVALID_TAG: &str = "405";
+// Group chats other than this one don't exist.
+pub const CHAT_ID: &str = "stubchat";
+
+// Replies like the API of WeChat Work, a send to only invalid targets fails.
+pub async fn serve(addr: SocketAddr) {
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    if let Err(e) = Server::bind(&addr).serve(make_service).await {
+        eprintln!("The stub of WeChat stopped {:?}.", e);
+    }
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let (method, path) = (req.method().clone(), req.uri().path().to_owned());
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .unwrap_or_default();
+    let message: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let reply = match (method, path.as_str()) {
+        (Method::GET, "/cgi-bin/gettoken") => json!({
+            "errcode": 0,
+            "errmsg": "ok",
+            "access_token": "stub-access-token",
+            "expires_in": 7200,
+        }),
+        (Method::POST, "/cgi-bin/message/send") => send(&message),
+        (Method::POST, "/cgi-bin/appchat/send") => send_to_chat(&message),
+        _ => json!({ "errcode": 40035, "errmsg": "invalid api" }),
+    };
+
+    Ok(Response::new(Body::from(reply.to_string())))
+}
+
+fn send_to_chat(message: &Value) -> Value {
+    if message["chatid"] == CHAT_ID {
+        json!({ "errcode": 0, "errmsg": "ok" })
+    } else {
+        json!({ "errcode": 86003, "errmsg": "chat not found" })
+    }
+}
+
+fn send(message: &Value) -> Value {
+    let split = |field: &str, invalid: &str| {
+        let ids: Vec<String> = message[field]
+            .as_str()
+            .unwrap_or_default()
+            .split('|')
+            .filter(|id| !id.is_empty())
+            .map(str::to_owned)
+            .collect();
+        let (invalid, valid): (Vec<_>, Vec<_>) = ids.into_iter().partition(|id| id == invalid);
+        (valid, invalid.join("|"))
+    };
+    let (users, invalid_users) = split("touser", INVALID_USER);
+    let (parties, invalid_parties) = split("toparty", INVALID_PARTY);
+    let (tags, invalid_tags) = split("totag", INVALID_TAG);
+    if users.is_empty() && parties.is_empty() && tags.is_empty() {
+        return json!({ "errcode": 81013, "errmsg": "user & party & tag all invalid" });
+    }
+
+    json!({
+        "errcode": 0,
+        "errmsg": "ok",
+        "msgid": "stub-msgid",
+        "invaliduser": invalid_users,
+        "invalidparty": invalid_parties,
+        "invalidtag": invalid_tags,
+    })
+}
@@ -0,0 +1,102 @@
+//! The bodies the PipeHub API replies with. The server serializes and the client deserializes
+//! these same types, so the two can't drift apart.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// The body PipeHub replies with, for both successful and failed requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub request_id: Uuid,
+    pub success: bool,
+    pub error_message: String,
+    pub hint: String,
+}
+
+/// The targets WeChat couldn't deliver to, like members who have left.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InvalidTargets {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub invalid_users: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub invalid_parties: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub invalid_tags: Vec<String>,
+}
+
+impl InvalidTargets {
+    pub fn is_empty(&self) -> bool {
+        self.invalid_users.is_empty()
+            && self.invalid_parties.is_empty()
+            && self.invalid_tags.is_empty()
+    }
+}
+
+/// The reply of `/send/{key}`, failures only have the `Response`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendResponse {
+    #[serde(flatten)]
+    pub response: Response,
+    /// Only synchronous deliveries to WeChat have one, it can be recalled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msgid: Option<String>,
+    /// The reply of WeChat as is, if asked for with `raw_response=true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_response: Option<Value>,
+    #[serde(flatten)]
+    pub invalid_targets: InvalidTargets,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn round_trips_send_responses() {
+        let response = SendResponse {
+            response: Response {
+                request_id: Uuid::nil(),
+                success: true,
+                error_message: "".to_owned(),
+                hint: "".to_owned(),
+            },
+            msgid: Some("msgid".to_owned()),
+            raw_response: None,
+            invalid_targets: InvalidTargets {
+                invalid_users: vec!["departed".to_owned()],
+                ..InvalidTargets::default()
+            },
+        };
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "request_id": "00000000-0000-0000-0000-000000000000",
+                "success": true,
+                "error_message": "",
+                "hint": "",
+                "msgid": "msgid",
+                "invalid_users": ["departed"],
+            })
+        );
+
+        let parsed: SendResponse = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.msgid, response.msgid);
+        assert_eq!(parsed.invalid_targets, response.invalid_targets);
+    }
+
+    #[test]
+    fn reads_failures_as_send_responses() {
+        let parsed: SendResponse = serde_json::from_value(json!({
+            "request_id": "00000000-0000-0000-0000-000000000000",
+            "success": false,
+            "error_message": "Message blocked.",
+            "hint": "",
+        }))
+        .unwrap();
+        assert!(!parsed.response.success);
+        assert_eq!(parsed.msgid, None);
+        assert!(parsed.invalid_targets.is_empty());
+    }
+}
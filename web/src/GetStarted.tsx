@@ -28,13 +28,94 @@ function GetStarted() {
             在右上角通过 GitHub 登录后, 填上你之前得到的企业 ID, Agent ID 和 Secret 并更新后, 就可以通过 User 页面中的 Callback URL 发送消息了. 请求示例:
           </Text>
           <Text block style={{ marginTop: '5px' }}>
-            1. GET https://www.pipehub.net/send/abcde?text=helloworld.
+            1. GET https://www.pipehub.net/send/abcde?title=Build&text=hello+world. text 和 title 需要经过 URL 编码, 空格可以写作 + 或 %20, 加号需要写作 %2B. title 可选, 会作为消息的第一行. text 不能为空. GET 仅适合短消息(text 和 title 合计不超过 2048 字节), 较长的消息请使用 POST. 日志中不会记录 URL 中的参数.
           </Text>
           <Text block style={{ marginTop: '5px' }}>
-            2. POST https://www.pipehub.net/send/abcde. 在 Payload 中的所有内容都会被推送.
+            2. POST https://www.pipehub.net/send/abcde. 在 Payload 中的所有内容都会被推送. 默认按 UTF-8 解码, 其他编码(如 GBK)需要在 Content-Type 中声明 charset, 例如 text/plain; charset=gbk.
           </Text>
           <Text block style={{ marginTop: '5px' }}>
-            3. 也可以在 GET 或者 POST 的 URL 中添加参数 to_party=$(对应的部门 ID, 可以用|(0x7C)连接), 会推送到对应的部门中所有人.
+            3. 也可以在 GET 或者 POST 的 URL 中添加参数 to_party=$(对应的部门 ID, 可以用|(0x7C)连接), 会推送到对应的部门中所有人. 添加参数 to_tag=$(标签 ID, 可以用,或|连接) 会推送给标签中的成员, 添加参数 to_user=$(成员 ID, 用|连接) 会推送给对应的成员, 三者可以同时使用. 都未指定时推送给设置中的默认推送对象, 未设置时推送给所有人.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            4. 添加参数 dup_check_interval=$(秒数, 最大 14400) 可以开启企业微信的重复消息检查, 在该时间内内容相同的消息只会推送一次.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            5. 添加参数 async=true 后会立即返回 202 并在后台推送, 可以通过 GET /send/status/$(返回的 request_id) 查询推送状态(queued, sending, delivered, failed).
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            6. 添加参数 type=markdown 可以推送 Markdown 消息. 未指定时, POST 的 Content-Type 为 text/markdown 则推送 Markdown 消息, 否则使用 User 页面中设置的默认类型, 默认为 text. 即 type 参数优先于 Content-Type, Content-Type 优先于默认类型.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            7. 设置免打扰时段(以及全天免打扰的星期, 如周末)后, 期间的消息会在免打扰结束后推送, 也可以设置为直接丢弃. 添加参数 urgent=true 或 respect_quiet_hours=false 可以立即推送.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            8. 添加参数 type=miniprogram_notice 可以推送小程序通知消息, Payload 为企业微信中 miniprogram_notice 的 JSON(appid, page, title, description, emphasis_first_item, content_item).
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            9. 在 User 页面开启 Outbox 后, 消息会先持久化再在后台推送, 立即返回 202, 同样可以通过 GET /send/status/$(返回的 request_id) 查询推送状态.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            10. 添加参数 level=error|warn|info 会在 Text 和 Markdown 消息前添加对应的 ❌/⚠️/ℹ️.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            11. POST https://www.pipehub.net/send/abcde/echo 接受和 /send 相同的参数, 只校验 Key 和消息并返回解析后的消息, 不会推送, 适合调试集成.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            12. 添加参数 type=template_card 可以推送模板卡片消息, Payload 为企业微信中 template_card 的 JSON, 目前仅支持 card_type 为 text_notice 的卡片(main_title, sub_title_text, jump_list, card_action 等).
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            13. 完整的 API 描述(OpenAPI 3)见 https://www.pipehub.net/openapi.json, 可以用来生成客户端.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            14. 在 User 页面填上客户联系 Secret 后, 添加参数 external_userid=id1|id2 可以向外部联系人推送 Text 消息(Markdown 消息会转为纯文本, 也可以用参数 text_fallback 指定), 参数 sender 可以指定发送的成员. 消息需要成员在企业微信中确认后才会发出.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            15. 不便暴露 App Key 时(如在浏览器中发送), 可以先在服务端 POST https://www.pipehub.net/send/abcde/token?ttl=300 获取一次性 Token, 之后用 Token 代替 App Key 调用 /send/$(返回的 token), 每个 Token 只能使用一次, 过期后失效. 添加参数 reusable=true 可以获取过期前可重复使用的 Token(最长 30 天), 适合临时分享给一次性的集成, 返回的 url 即可直接调用; scope=send|raw|recall 可以限制 Token 只能用于对应的接口. 重置 App Key 会使已发出的 Token 全部失效.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            16. 配置了多组企业微信凭据时, 添加 Header X-PipeHub-Agent: [Agent ID] 可以选择用哪个应用推送, 未配置的 Agent ID 会返回 400. 未添加时使用设置中的默认 Agent ID, 没有设置则使用第一组凭据.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            17. 添加参数 priority=high|normal|low 可以指定优先级, 每个优先级的频率限制相互独立, 刷屏的低优先级消息不会挤占重要告警. 超出限制时返回 429 和 Retry-After.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            18. 添加参数 response_format=wechat 或在设置中选择企业微信格式后, 推送成功时返回 {'{"errcode":0,"errmsg":"ok"}'}, 方便已经按企业微信接口解析返回值的调用方迁移, 出错时仍返回 PipeHub 的格式.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            19. 添加参数 meta.[key]=[value](如 meta.service=billing)可以给消息附加元数据, 它们只会记录在日志中用于检索, 不会推送到企业微信. 最多 10 个.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            20. POST https://www.pipehub.net/send/abcde/raw 可以直接推送企业微信 message/send 接口的 JSON, 适用于 PipeHub 尚未支持的消息类型. agentid 和 access_token 会自动填写, 其余内容由企业微信校验. 这类消息会立即推送, 不受免打扰时段影响, 也不会添加前后缀.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            21. 在企业微信应用的接收消息设置中, 将 URL 设为 https://www.pipehub.net/callback/abcde, 并把 Token 和 EncodingAESKey 填到 User 页面, 即可通过 URL 验证. 收到的事件会记录在日志中, 填写转发 URL 后还会把解密后的 XML POST 到该 URL.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            22. 添加参数 type=image 可以推送图片, Payload 为 {"{"}"image_base64": "..."{"}"}, 图片为不超过 10MB 的 PNG 或 JPG. 图片会在推送时上传到企业微信.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            23. 同步推送成功时, 响应中会带有企业微信的 msgid, 部分成员, 部门或标签无效(如成员已离职)时还会在 invalid_users, invalid_parties 和 invalid_tags 中列出. 24 小时内 POST https://www.pipehub.net/send/abcde/recall?msgid=xxx 可以撤回这条消息, 超过 24 小时会返回 410.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            24. 添加参数 format=text, 或请求头 Accept: text/plain 时, 推送成功只返回纯文本 ok, 适合无法解析 JSON 的脚本和嵌入式设备. 所有响应都带有 X-PipeHub-Request-Id 头.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            25. 登录后 PUT /templates/deploy 可以保存消息模板, 如 {'{"content": "{{service}} 已部署到 {{env}}", "message_type": "markdown"}'}. 推送时添加参数 template=deploy&var.service=billing&var.env=prod 即可由服务端渲染消息, Markdown 中的变量会被转义.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            26. 默认会严格校验消息格式, 添加参数 validation=lenient 可以只做必要的检查, 把 WeChat 新增的字段原样传递, 由 WeChat 决定是否接受.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            27. 在用户页配置 Matrix 房间后, 没有企业微信凭据的消息会推送到该房间, 也可以添加参数 channel=matrix 指定. Matrix 只支持 Text 和 Markdown, priority=high 的消息以 m.text 发送会提醒成员, 其他以 m.notice 发送.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            28. 在用户页配置 SMTP 服务器后, 没有企业微信凭据和 Matrix 房间的消息会以邮件发送, 也可以添加参数 channel=email 指定. 参数 title 为邮件主题, Markdown 消息会同时附上 HTML 版本.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            29. 调试时添加参数 raw_response=true, 同步推送到企业微信成功后会在响应的 raw_response 中原样返回企业微信的响应, 如部分成员无效时的 invaliduser. 使用企业微信格式时直接返回企业微信的响应.
+          </Text>
+          <Text block style={{ marginTop: '5px' }}>
+            30. 添加参数 chat_id=群聊id 可以推送到应用创建的群聊(appchat), 不能与 to_user, to_party, to_tag, external_userid 或 dup_check_interval 同时使用, 不支持 miniprogram_notice 和 template_card 消息.
           </Text>
         </div>
       </Stack.Item>
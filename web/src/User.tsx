@@ -1,5 +1,5 @@
 import React, { useEffect, useState } from 'react';
-import { Label, TextField, PrimaryButton, Separator, Text, DefaultButton, Callout, Stack } from '@fluentui/react';
+import { Label, TextField, PrimaryButton, Separator, Text, DefaultButton, Callout, Stack, Dropdown, IDropdownOption, Checkbox } from '@fluentui/react';
 import { useBoolean } from '@uifabric/react-hooks';
 import Send from './Send';
 
@@ -74,6 +74,27 @@ function User() {
         setUser(entity);
       });
   }
+  const importConfig = (event: React.ChangeEvent<HTMLInputElement>) => {
+    const file = event.target.files?.[0];
+    if (!file) {
+      return;
+    }
+    file.text()
+      .then(backup => fetch('/user/import', {
+        method: "POST",
+        headers: {
+          'Content-Type': 'application/json'
+        },
+        body: backup
+      }))
+      .then(res => {
+        if (res.status < 400) {
+          window.location.reload();
+        } else {
+          return res.text().then(alert);
+        }
+      });
+  }
   const onCorpIdChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
     setWechat({
       ...wechat,
@@ -92,12 +113,204 @@ function User() {
       secret: newVal || '',
     });
   }
+  const onExternalSecretChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setWechat({
+      ...wechat,
+      external_secret: newVal || undefined,
+    });
+  }
   const onBlockListChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
     setUser({
       ...user,
       block_list: newVal || '',
     });
   }
+  const onAllowListChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      allow_list: newVal,
+    });
+  }
+  const onQuietHoursStartChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      quiet_hours_start: newVal || undefined,
+    });
+  }
+  const onQuietHoursEndChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      quiet_hours_end: newVal || undefined,
+    });
+  }
+  const onTimeZoneChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      time_zone: newVal || undefined,
+    });
+  }
+  const onQuietDaysChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      quiet_days: newVal || undefined,
+    });
+  }
+  const onRateLimitsChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      rate_limits: newVal || undefined,
+    });
+  }
+  const onDropInQuietHoursChange = (event?: React.FormEvent<HTMLElement | HTMLInputElement>, checked?: boolean) => {
+    setUser({
+      ...user,
+      drop_in_quiet_hours: !!checked,
+    });
+  }
+  const onPrefixChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      prefix: newVal || '',
+    });
+  }
+  const onSuffixChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      suffix: newVal || '',
+    });
+  }
+  const onAppendRequestIdChange = (event?: React.FormEvent<HTMLElement | HTMLInputElement>, checked?: boolean) => {
+    setUser({
+      ...user,
+      append_request_id: !!checked,
+    });
+  }
+  const onUseOutboxChange = (event?: React.FormEvent<HTMLElement | HTMLInputElement>, checked?: boolean) => {
+    setUser({
+      ...user,
+      use_outbox: !!checked,
+    });
+  }
+  const onLogBodiesChange = (event?: React.FormEvent<HTMLElement | HTMLInputElement>, checked?: boolean) => {
+    setUser({
+      ...user,
+      log_bodies_until: checked ? new Date(Date.now() + 60 * 60 * 1000).toISOString() : undefined,
+    });
+  }
+  const onDefaultAgentIdChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      default_agent_id: newVal ? parseInt(newVal) : undefined,
+    });
+  }
+  const onCallbackTokenChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      callback_token: newVal,
+    });
+  }
+  const onCallbackAesKeyChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      callback_aes_key: newVal,
+    });
+  }
+  const onCallbackForwardUrlChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      callback_forward_url: newVal,
+    });
+  }
+  const onMatrixHomeserverChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      matrix_homeserver: newVal,
+    });
+  }
+  const onMatrixAccessTokenChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      matrix_access_token: newVal,
+    });
+  }
+  const onMatrixRoomIdChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      matrix_room_id: newVal,
+    });
+  }
+  const onSmtpHostChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      smtp_host: newVal,
+    });
+  }
+  const onSmtpUsernameChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      smtp_username: newVal,
+    });
+  }
+  const onSmtpPasswordChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      smtp_password: newVal,
+    });
+  }
+  const onDefaultToUserChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      default_to_user: newVal,
+    });
+  }
+  const onDefaultToPartyChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      default_to_party: newVal,
+    });
+  }
+  const onDefaultToTagChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      default_to_tag: newVal,
+    });
+  }
+  const onEmailFromChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      email_from: newVal,
+    });
+  }
+  const onEmailToChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      email_to: newVal,
+    });
+  }
+  const onSmtpPortChange = (event: React.FormEvent<HTMLInputElement | HTMLTextAreaElement>, newVal?: string) => {
+    setUser({
+      ...user,
+      smtp_port: newVal ? parseInt(newVal) : undefined,
+    });
+  }
+  const onSmtpTlsChange = (event: React.FormEvent<HTMLDivElement>, option?: IDropdownOption) => {
+    setUser({
+      ...user,
+      smtp_tls: option ? option.key as string : undefined,
+    });
+  }
+  const onResponseFormatChange = (event: React.FormEvent<HTMLDivElement>, option?: IDropdownOption) => {
+    setUser({
+      ...user,
+      response_format: option ? option.key as string : undefined,
+    });
+  }
+  const onDefaultMessageTypeChange = (event: React.FormEvent<HTMLDivElement>, option?: IDropdownOption) => {
+    setUser({
+      ...user,
+      default_message_type: option ? option.key as string : undefined,
+    });
+  }
   return (
     <div>
       <Label>GitHub 账号</Label> <TextField readOnly value={user.github_login}></TextField>
@@ -106,7 +319,58 @@ function User() {
       <Label>企业 ID</Label> <TextField onChange={onCorpIdChange} value={wechat.corp_id}></TextField>
       <Label>Agent ID</Label> <TextField onChange={onAgentIdChange} value={wechat.agent_id ? wechat.agent_id.toString() : ""}></TextField>
       <Label>Secret</Label> <TextField onChange={onSecretChange} value={wechat.secret}></TextField>
+      <Label>客户联系 Secret(可选, 用于通过 external_userid 向外部联系人推送)</Label> <TextField onChange={onExternalSecretChange} value={wechat.external_secret ?? ""}></TextField>
       <Label>黑名单(使用英语逗号,分隔的一系列字符串, 如果消息包含任意一个, 将不会推送.)</Label> <TextField onChange={onBlockListChange} value={user.block_list}></TextField>
+      <Label>白名单(使用英语逗号,分隔的一系列字符串, 填写后只推送包含任意一个的消息. 先检查白名单, 再检查黑名单, 同时命中时不会推送.)</Label> <TextField onChange={onAllowListChange} value={user.allow_list ?? ''}></TextField>
+      <Label>消息前缀(会添加在每条 Text 和 Markdown 消息之前, 如 [Prod-CI] )</Label> <TextField onChange={onPrefixChange} value={user.prefix}></TextField>
+      <Label>消息后缀</Label> <TextField onChange={onSuffixChange} value={user.suffix}></TextField>
+      <Checkbox styles={{ root: { marginTop: '10px' } }} label="在文本和 Markdown 消息末尾附上 Request ID, 便于按消息查找日志" onChange={onAppendRequestIdChange} checked={user.append_request_id ?? false} />
+      <Label>默认消息类型</Label> <Dropdown onChange={onDefaultMessageTypeChange} selectedKey={user.default_message_type ?? 'text'} options={[{ key: 'text', text: 'Text' }, { key: 'markdown', text: 'Markdown' }]}></Dropdown>
+      <Label>默认 Agent ID(配置了多组企业微信凭据时使用, 留空使用第一组)</Label> <TextField type="number" onChange={onDefaultAgentIdChange} value={user.default_agent_id?.toString() ?? ''}></TextField>
+      <Label>默认推送对象(可选, 请求未指定 to_user、to_party 和 to_tag 时推送给这些成员、部门和标签, 而不是所有人)</Label>
+      <Stack horizontal gap={8}>
+        <TextField placeholder="zhangsan|lisi" onChange={onDefaultToUserChange} value={user.default_to_user ?? ''}></TextField>
+        <TextField placeholder="部门 ID, 如 1|2" onChange={onDefaultToPartyChange} value={user.default_to_party ?? ''}></TextField>
+        <TextField placeholder="标签 ID, 如 1,2" onChange={onDefaultToTagChange} value={user.default_to_tag ?? ''}></TextField>
+      </Stack>
+      <Label>推送成功时的返回格式</Label> <Dropdown onChange={onResponseFormatChange} selectedKey={user.response_format ?? 'native'} options={[{ key: 'native', text: 'PipeHub' }, { key: 'wechat', text: '企业微信({"errcode":0,"errmsg":"ok"})' }, { key: 'text', text: '纯文本(ok)' }]}></Dropdown>
+      <Label>接收消息的 Token 和 EncodingAESKey(在企业微信应用的接收消息设置中使用 URL {user.callback_url.replace('/send/', '/callback/')})</Label>
+      <Stack horizontal gap={8}>
+        <TextField placeholder="Token" onChange={onCallbackTokenChange} value={user.callback_token ?? ''}></TextField>
+        <TextField placeholder="EncodingAESKey" onChange={onCallbackAesKeyChange} value={user.callback_aes_key ?? ''}></TextField>
+      </Stack>
+      <Label>转发收到的消息(可选, 解密后的 XML 会 POST 到该 URL, 域名需要服务器允许, 否则须为公网 IP 的 https URL)</Label> <TextField placeholder="https://example.com/wechat/events" onChange={onCallbackForwardUrlChange} value={user.callback_forward_url ?? ''}></TextField>
+      <Label>Matrix 房间(可选, 未配置企业微信凭据或添加参数 channel=matrix 时推送到该房间, 机器人账号需已加入房间)</Label>
+      <Stack horizontal gap={8}>
+        <TextField placeholder="https://matrix.org" onChange={onMatrixHomeserverChange} value={user.matrix_homeserver ?? ''}></TextField>
+        <TextField placeholder="Access Token" onChange={onMatrixAccessTokenChange} value={user.matrix_access_token ?? ''}></TextField>
+        <TextField placeholder="!abc:matrix.org" onChange={onMatrixRoomIdChange} value={user.matrix_room_id ?? ''}></TextField>
+      </Stack>
+      <Label>SMTP 服务器(可选, 未配置企业微信凭据和 Matrix 房间或添加参数 channel=email 时以邮件推送, 标题为邮件主题, 端口留空时按加密方式使用 587 或 465)</Label>
+      <Stack horizontal gap={8}>
+        <TextField placeholder="smtp.example.com" onChange={onSmtpHostChange} value={user.smtp_host ?? ''}></TextField>
+        <TextField type="number" placeholder="587" onChange={onSmtpPortChange} value={user.smtp_port?.toString() ?? ''}></TextField>
+        <Dropdown onChange={onSmtpTlsChange} selectedKey={user.smtp_tls ?? 'starttls'} options={[{ key: 'starttls', text: 'STARTTLS' }, { key: 'implicit', text: 'TLS' }]}></Dropdown>
+      </Stack>
+      <Stack horizontal gap={8}>
+        <TextField placeholder="Username" onChange={onSmtpUsernameChange} value={user.smtp_username ?? ''}></TextField>
+        <TextField placeholder="Password" type="password" onChange={onSmtpPasswordChange} value={user.smtp_password ?? ''}></TextField>
+      </Stack>
+      <Stack horizontal gap={8}>
+        <TextField placeholder="PipeHub <pipehub@example.com>" onChange={onEmailFromChange} value={user.email_from ?? ''}></TextField>
+        <TextField placeholder="ops@example.com,dev@example.com" onChange={onEmailToChange} value={user.email_to ?? ''}></TextField>
+      </Stack>
+      <Label>免打扰时段(HH:MM, 期间的消息会在结束后推送, 添加参数 urgent=true 或 respect_quiet_hours=false 的消息除外, 时区留空时使用服务器的默认时区)</Label>
+      <Stack horizontal gap={8}>
+        <TextField placeholder="22:00" onChange={onQuietHoursStartChange} value={user.quiet_hours_start ?? ''}></TextField>
+        <TextField placeholder="08:00" onChange={onQuietHoursEndChange} value={user.quiet_hours_end ?? ''}></TextField>
+        <TextField placeholder="Asia/Shanghai" onChange={onTimeZoneChange} value={user.time_zone ?? ''}></TextField>
+      </Stack>
+      <Label>全天免打扰(使用英语逗号,分隔的星期, 如 sat,sun)</Label> <TextField placeholder="sat,sun" onChange={onQuietDaysChange} value={user.quiet_days ?? ''}></TextField>
+      <Checkbox styles={{ root: { marginTop: '10px' } }} label="丢弃免打扰期间的消息, 而不是在结束后推送" onChange={onDropInQuietHoursChange} checked={user.drop_in_quiet_hours ?? false} />
+      <Label>每分钟频率限制(按 priority 分别计算, 0 为不限制, 留空使用默认值)</Label> <TextField placeholder="high=120,normal=60,low=10" onChange={onRateLimitsChange} value={user.rate_limits ?? ''}></TextField>
+      <Checkbox styles={{ root: { marginTop: '10px' } }} label="所有消息先写入 Outbox 再在后台推送(立即返回 202, 重启后不会丢失)" onChange={onUseOutboxChange} checked={user.use_outbox ?? false} />
+      <Checkbox styles={{ root: { marginTop: '10px' } }} label="在接下来 1 小时内记录收到的消息内容, 用于排查消息未送达的问题" onChange={onLogBodiesChange} checked={!!user.log_bodies_until && new Date(user.log_bodies_until) > new Date()} />
       <PrimaryButton style={{ marginTop: '10px' }} onClick={update}>更新</PrimaryButton>
 
       <DefaultButton
@@ -115,6 +379,17 @@ function User() {
         id="resetKey"
         text="重置 AppKey"
       />
+      <DefaultButton
+        style={{ marginLeft: '20px' }}
+        href="/user/export"
+        text="导出配置(不含 Secret)"
+      />
+      <DefaultButton
+        style={{ marginLeft: '20px' }}
+        onClick={() => document.getElementById('importConfig')?.click()}
+        text="导入配置"
+      />
+      <input id="importConfig" type="file" accept="application/json" style={{ display: 'none' }} onChange={importConfig} />
       {isCalloutVisible ? (
         <div>
           <Callout
@@ -157,12 +432,44 @@ interface User {
   app_key: string,
   callback_url: string,
   block_list: string,
+  allow_list?: string,
+  prefix: string,
+  suffix: string,
+  append_request_id?: boolean,
+  default_message_type?: string,
+  quiet_hours_start?: string,
+  quiet_hours_end?: string,
+  time_zone?: string,
+  quiet_days?: string,
+  drop_in_quiet_hours?: boolean,
+  rate_limits?: string,
+  response_format?: string,
+  default_agent_id?: number,
+  callback_token?: string,
+  callback_aes_key?: string,
+  callback_forward_url?: string,
+  matrix_homeserver?: string,
+  matrix_access_token?: string,
+  matrix_room_id?: string,
+  smtp_host?: string,
+  smtp_port?: number,
+  smtp_tls?: string,
+  smtp_username?: string,
+  smtp_password?: string,
+  email_from?: string,
+  email_to?: string,
+  default_to_user?: string,
+  default_to_party?: string,
+  default_to_tag?: string,
+  use_outbox?: boolean,
+  log_bodies_until?: string,
 }
 
 interface Wechat {
   corp_id: string,
   agent_id: number,
   secret: string,
+  external_secret?: string,
 }
 
 export default User;